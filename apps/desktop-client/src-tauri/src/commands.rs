@@ -5,8 +5,13 @@ use std::sync::Arc;
 use tauri::{AppHandle, Emitter, State};
 use tracing::{debug, error, info};
 
-use shared_protocol::{InputEvent, PeerId, QualityPreset};
+use capture::{CaptureTarget, DirtyRect};
+use shared_protocol::{
+    DisplaySelection, InputEvent, PeerId, QualityPreset, RefinementPacket, RemoteDisplay,
+    VideoCodec,
+};
 
+use crate::pipeline::PipelineStats;
 use crate::session::{Session, SessionConfig};
 use crate::state::AppState;
 
@@ -110,6 +115,8 @@ pub struct SessionStatus {
     pub rtt_ms: Option<f64>,
     pub fps: Option<f64>,
     pub bitrate_kbps: Option<u32>,
+    pub encoder: Option<String>,
+    pub pipeline: Option<PipelineStats>,
 }
 
 /// Start a new remote session
@@ -192,6 +199,8 @@ pub async fn start_session(
                 rtt_ms: Some(0.0),
                 fps: Some(0.0),
                 bitrate_kbps: Some(0),
+                encoder: None,
+                pipeline: None,
             })
         }
         shared_protocol::SessionRole::Viewer => {
@@ -215,6 +224,8 @@ pub async fn start_session(
                 rtt_ms: Some(0.0),
                 fps: Some(0.0),
                 bitrate_kbps: Some(0),
+                encoder: None,
+                pipeline: None,
             })
         }
     }
@@ -224,11 +235,17 @@ pub async fn start_session(
 #[derive(Clone, serde::Serialize)]
 pub struct VideoFrameEvent {
     pub data: Vec<u8>,
+    pub codec: VideoCodec,
     pub is_keyframe: bool,
     pub timestamp_us: u64,
     pub width: u32,
     pub height: u32,
     pub frame_id: u64,
+    /// First frame at a new resolution; the decoder must be reconfigured
+    pub resolution_changed: bool,
+    /// Bounds of what changed since the previous frame; refinement tiles
+    /// overlapping it are stale
+    pub dirty_rect: Option<shared_protocol::DirtyRect>,
 }
 
 /// Session events emitted to frontend
@@ -242,6 +259,12 @@ pub enum SessionEvent {
         fps: f64,
         bitrate_kbps: u32,
     },
+    Displays {
+        displays: Vec<RemoteDisplay>,
+        active: DisplaySelection,
+    },
+    /// Lossless tile (or tile invalidation) to draw over the video
+    Refinement(RefinementPacket),
     Error(String),
 }
 
@@ -283,6 +306,8 @@ pub fn get_session_status(
         rtt_ms: Some(stats.rtt_ms),
         fps: Some(stats.fps),
         bitrate_kbps: Some(stats.bitrate_kbps),
+        encoder: stats.encoder,
+        pipeline: Some(stats.pipeline),
     })
 }
 
@@ -334,6 +359,130 @@ pub fn request_keyframe(state: State<'_, Arc<AppState>>, peer_id: String) -> Com
     Ok(())
 }
 
+/// A window the host can share
+#[derive(serde::Serialize)]
+pub struct WindowEntry {
+    pub id: u32,
+    pub title: String,
+    pub app_name: String,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// List windows available for capture on this machine
+#[tauri::command]
+pub fn list_windows() -> CommandResult<Vec<WindowEntry>> {
+    let capturer = capture::create_capture().map_err(|e| CommandError::Internal(e.to_string()))?;
+    let windows = capturer
+        .windows()
+        .map_err(|e| CommandError::Internal(e.to_string()))?;
+
+    Ok(windows
+        .into_iter()
+        .map(|w| WindowEntry {
+            id: w.id,
+            title: w.title,
+            app_name: w.app_name,
+            x: w.x,
+            y: w.y,
+            width: w.width,
+            height: w.height,
+        })
+        .collect())
+}
+
+/// Choose what a hosted session shares
+///
+/// Pass `window_id` to share a single window, or `region` as
+/// `[x, y, width, height]` on the current display; neither shares the
+/// whole display.
+#[tauri::command]
+pub fn set_capture_target(
+    state: State<'_, Arc<AppState>>,
+    peer_id: String,
+    window_id: Option<u32>,
+    region: Option<[u32; 4]>,
+) -> CommandResult<()> {
+    let target = match (window_id, region) {
+        (Some(id), _) => CaptureTarget::Window(id),
+        (None, Some([x, y, width, height])) => {
+            CaptureTarget::Region(DirtyRect::new(x, y, width, height))
+        }
+        (None, None) => CaptureTarget::Display,
+    };
+    info!(
+        "Setting capture target to {:?} for peer: {}",
+        target, peer_id
+    );
+
+    let remote_peer_id =
+        PeerId::from_display_string(&peer_id).ok_or(CommandError::InvalidPeerId)?;
+
+    let sessions = state.sessions.read();
+    let session = sessions
+        .get(&remote_peer_id)
+        .ok_or(CommandError::SessionNotFound)?;
+
+    session
+        .set_capture_target(target)
+        .map_err(|e| CommandError::Internal(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Ask the host for its displays; the answer arrives as `remote-displays`
+#[tauri::command]
+pub fn list_remote_displays(state: State<'_, Arc<AppState>>, peer_id: String) -> CommandResult<()> {
+    let remote_peer_id =
+        PeerId::from_display_string(&peer_id).ok_or(CommandError::InvalidPeerId)?;
+
+    let sessions = state.sessions.read();
+    let session = sessions
+        .get(&remote_peer_id)
+        .ok_or(CommandError::SessionNotFound)?;
+
+    session
+        .list_remote_displays()
+        .map_err(|e| CommandError::Internal(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Switch the host display being streamed
+///
+/// `display_id` of `None` streams all displays stitched together.
+#[tauri::command]
+pub fn select_remote_display(
+    state: State<'_, Arc<AppState>>,
+    peer_id: String,
+    display_id: Option<u32>,
+) -> CommandResult<()> {
+    let selection = match display_id {
+        Some(id) => DisplaySelection::Display(id),
+        None => DisplaySelection::All,
+    };
+    info!(
+        "Selecting remote display {:?} on peer: {}",
+        selection, peer_id
+    );
+
+    let remote_peer_id =
+        PeerId::from_display_string(&peer_id).ok_or(CommandError::InvalidPeerId)?;
+
+    let sessions = state.sessions.read();
+    let session = sessions
+        .get(&remote_peer_id)
+        .ok_or(CommandError::SessionNotFound)?;
+
+    session
+        .select_remote_display(selection)
+        .map_err(|e| CommandError::Internal(e.to_string()))?;
+
+    Ok(())
+}
+
 /// Set quality preset
 #[tauri::command]
 pub fn set_quality(
@@ -390,6 +539,34 @@ fn emit_session_event(app: &AppHandle, event: SessionEvent) {
                 }),
             );
         }
+        SessionEvent::Displays { displays, active } => {
+            let active = match active {
+                DisplaySelection::Display(id) => serde_json::json!(id),
+                DisplaySelection::All => serde_json::json!("all"),
+            };
+            let _ = app.emit(
+                "remote-displays",
+                serde_json::json!({
+                    "displays": displays,
+                    "active": active,
+                }),
+            );
+        }
+        SessionEvent::Refinement(packet) => {
+            let payload = match packet {
+                RefinementPacket::Tile { rect, png } => serde_json::json!({
+                    "kind": "tile",
+                    "rect": rect,
+                    "png": png,
+                }),
+                RefinementPacket::Invalidate(rects) => serde_json::json!({
+                    "kind": "invalidate",
+                    "rects": rects,
+                }),
+                RefinementPacket::Clear => serde_json::json!({ "kind": "clear" }),
+            };
+            let _ = app.emit("refinement", payload);
+        }
         SessionEvent::Error(err) => {
             error!("Session error: {}", err);
             let _ = app.emit("session-error", err);
@@ -5,6 +5,7 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod commands;
+mod pipeline;
 mod session;
 mod signaling;
 mod state;
@@ -48,6 +49,10 @@ fn main() {
             commands::send_input,
             commands::request_keyframe,
             commands::set_quality,
+            commands::list_windows,
+            commands::set_capture_target,
+            commands::list_remote_displays,
+            commands::select_remote_display,
             commands::accept_connection,
             commands::reject_connection,
         ])
@@ -0,0 +1,174 @@
+//! Plumbing for the host's capture, convert and encode stages
+//!
+//! Each stage runs on its own thread, so a slow encode delays the next
+//! encode rather than the next capture. Stages hand frames over through
+//! [`FrameQueue`]s, which drop their oldest frame when full instead of
+//! blocking, so every stage works on the freshest frame available.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use parking_lot::{Condvar, Mutex};
+use serde::Serialize;
+
+/// Weight of the newest sample in a stage's moving average
+const AVERAGE_WEIGHT: f64 = 1.0 / 16.0;
+
+/// Bounded queue between two stages that drops the oldest item when full
+pub struct FrameQueue<T> {
+    state: Mutex<QueueState<T>>,
+    ready: Condvar,
+    capacity: usize,
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> FrameQueue<T> {
+    /// Create a queue holding at most `capacity` items
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(QueueState {
+                items: VecDeque::with_capacity(capacity.max(1)),
+                closed: false,
+            }),
+            ready: Condvar::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Queue `item`; returns whether an older item was dropped for it
+    ///
+    /// A dropped item is handed to `merge` along with `item`, so whatever
+    /// it carried that later items rely on can be folded in.
+    pub fn push(&self, mut item: T, merge: impl FnOnce(&mut T, T)) -> bool {
+        let mut state = self.state.lock();
+        let dropped = if state.items.len() >= self.capacity {
+            state.items.pop_front().map(|older| merge(&mut item, older))
+        } else {
+            None
+        };
+        state.items.push_back(item);
+        drop(state);
+        self.ready.notify_one();
+        dropped.is_some()
+    }
+
+    /// Take the oldest item, waiting up to `timeout` for one
+    ///
+    /// Returns `None` if nothing arrived in time or the queue is closed
+    /// and empty.
+    pub fn pop(&self, timeout: Duration) -> Option<T> {
+        let mut state = self.state.lock();
+        if state.items.is_empty() && !state.closed && !timeout.is_zero() {
+            self.ready.wait_for(&mut state, timeout);
+        }
+        state.items.pop_front()
+    }
+
+    /// Tell both ends the other stage has stopped
+    pub fn close(&self) {
+        self.state.lock().closed = true;
+        self.ready.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().closed
+    }
+}
+
+/// Timing of one pipeline stage
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StageStats {
+    /// Frames the stage processed
+    pub frames: u64,
+    /// Frames the stage produced that were dropped before the next stage
+    /// took them
+    pub dropped: u64,
+    /// Moving average of the time spent per frame, in microseconds
+    pub avg_time_us: u64,
+}
+
+impl StageStats {
+    /// Account for one processed frame
+    pub fn record(&mut self, elapsed: Duration) {
+        let sample = elapsed.as_micros() as f64;
+        self.avg_time_us = if self.frames == 0 {
+            sample as u64
+        } else {
+            (self.avg_time_us as f64 + AVERAGE_WEIGHT * (sample - self.avg_time_us as f64)) as u64
+        };
+        self.frames += 1;
+    }
+}
+
+/// Timing of the host pipeline's stages
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PipelineStats {
+    pub capture: StageStats,
+    pub convert: StageStats,
+    pub encode: StageStats,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn test_overflow_drops_oldest_and_merges() {
+        let queue = FrameQueue::new(2);
+        let mut merged = Vec::new();
+        assert!(!queue.push(1, |_, _| unreachable!()));
+        assert!(!queue.push(2, |_, _| unreachable!()));
+
+        // The oldest item is folded into the newcomer
+        assert!(queue.push(3, |item, older| {
+            merged.push((*item, older));
+            *item += older * 10;
+        }));
+        assert_eq!(merged, vec![(3, 1)]);
+
+        assert_eq!(queue.pop(Duration::ZERO), Some(2));
+        assert_eq!(queue.pop(Duration::ZERO), Some(13));
+        assert_eq!(queue.pop(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_pop_times_out_when_empty() {
+        let queue = FrameQueue::<u32>::new(1);
+        let start = Instant::now();
+        assert_eq!(queue.pop(Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(!queue.is_closed());
+    }
+
+    #[test]
+    fn test_close_wakes_blocked_pop() {
+        let queue = Arc::new(FrameQueue::<u32>::new(1));
+        let waiter = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                let start = Instant::now();
+                (queue.pop(Duration::from_secs(10)), start.elapsed())
+            })
+        };
+
+        std::thread::sleep(Duration::from_millis(50));
+        queue.close();
+        let (item, waited) = waiter.join().unwrap();
+        assert_eq!(item, None);
+        assert!(waited < Duration::from_secs(5), "waited {:?}", waited);
+        assert!(queue.is_closed());
+
+        // Items queued before the close are still handed out
+        let queue = FrameQueue::new(1);
+        queue.push(7, |_, _| {});
+        queue.close();
+        assert_eq!(queue.pop(Duration::from_secs(10)), Some(7));
+        assert_eq!(queue.pop(Duration::from_secs(10)), None);
+    }
+}
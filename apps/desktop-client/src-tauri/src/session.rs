@@ -2,26 +2,39 @@
 //!
 //! This module contains the main capture -> encode -> send loop.
 
-use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 
 use crossbeam_channel::{Receiver, Sender, bounded};
 use parking_lot::{Mutex, RwLock};
 use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{debug, error, info, warn};
 
+use crate::pipeline::{FrameQueue, PipelineStats};
 use crate::signaling::SignalingClient;
-use capture::{CaptureConfig, CapturedFrame};
-use encoder::{EncodedFrame, EncoderConfig, OpenH264Encoder, VideoEncoder};
+use capture::{
+    CaptureConfig, CaptureError, CaptureResult, CaptureTarget, CapturedFrame, DirtyRect,
+    ScreenCapture,
+};
+use encoder::{
+    Codec, ColorSpace, DEFAULT_REFINE_STATIC_FRAMES, EncodedFrame, EncoderConfig, EncoderError,
+    EncoderRegistry, EncoderResult, RefinementTracker, RefinementUpdate, RoiRegion, VideoEncoder,
+    YuvFrame,
+};
 use input_injector::{InputProcessor, create_injector};
-use net_transport::QuicTransport;
+use net_transport::{
+    AssembledFrame, BandwidthConfig, BandwidthEstimator, FecEncoder, FeedbackRecorder,
+    FrameAssembler, PacedDatagram, Pacer, PacketPriority, Packetizer, QuicTransport,
+    RetransmitBuffer,
+};
 use shared_protocol::{
-    FrameType, IceCandidate, IceCandidateType, InputEvent, InputPacket, PeerId, QualityPreset,
-    SessionRole, SessionState, SignalingMessage, VideoCodec, VideoPacket, VideoPacketHeader,
-    MAX_DATAGRAM_SIZE,
+    DisplaySelection, FrameType, IceCandidate, IceCandidateType, InputEvent, InputPacket, PeerId,
+    QualityPreset, RefinementPacket, RemoteDisplay, SessionMessage, SessionRole, SessionState,
+    SignalingMessage, VideoAck, VideoPacket, VideoPacketHeader, MAX_DATAGRAM_SIZE,
 };
 
 /// Session error
@@ -60,96 +73,10 @@ pub struct SessionStats {
     pub frames_sent: u64,
     pub bytes_sent: u64,
     pub packets_lost: u64,
-}
-
-/// Reassembles fragmented video frames
-struct FrameAssembler {
-    frames: HashMap<u64, FrameAssembly>,
-    max_frames: usize,
-    max_age: Duration,
-}
-
-struct FrameAssembly {
-    header: VideoPacketHeader,
-    fragments: Vec<Option<Vec<u8>>>,
-    received: usize,
-    last_update: Instant,
-}
-
-impl FrameAssembler {
-    fn new(max_frames: usize, max_age: Duration) -> Self {
-        Self {
-            frames: HashMap::new(),
-            max_frames,
-            max_age,
-        }
-    }
-
-    fn push(&mut self, packet: VideoPacket) -> Option<VideoPacket> {
-        if packet.header.total_fragments <= 1 {
-            return Some(packet);
-        }
-
-        self.evict_old();
-
-        let frame_id = packet.header.frame_id;
-        let total = packet.header.total_fragments as usize;
-
-        let entry = self.frames.entry(frame_id).or_insert_with(|| FrameAssembly {
-            header: packet.header.clone(),
-            fragments: vec![None; total],
-            received: 0,
-            last_update: Instant::now(),
-        });
-
-        if entry.fragments.len() != total {
-            *entry = FrameAssembly {
-                header: packet.header.clone(),
-                fragments: vec![None; total],
-                received: 0,
-                last_update: Instant::now(),
-            };
-        }
-
-        let idx = packet.header.fragment_index as usize;
-        if idx < total && entry.fragments[idx].is_none() {
-            entry.fragments[idx] = Some(packet.payload);
-            entry.received += 1;
-            entry.last_update = Instant::now();
-        }
-
-        if entry.received == total {
-            let mut payload = Vec::new();
-            for fragment in entry.fragments.iter_mut() {
-                if let Some(data) = fragment.take() {
-                    payload.extend_from_slice(&data);
-                }
-            }
-            let header = entry.header.clone();
-            self.frames.remove(&frame_id);
-            return Some(VideoPacket { header, payload });
-        }
-
-        None
-    }
-
-    fn evict_old(&mut self) {
-        let cutoff = Instant::now() - self.max_age;
-        self.frames.retain(|_, frame| frame.last_update >= cutoff);
-
-        if self.frames.len() > self.max_frames {
-            let mut entries: Vec<(u64, Instant)> = self
-                .frames
-                .iter()
-                .map(|(id, frame)| (*id, frame.last_update))
-                .collect();
-            entries.sort_by_key(|(_, t)| *t);
-            let to_remove = entries.len().saturating_sub(self.max_frames);
-            for (frame_id, _) in entries.into_iter().take(to_remove) {
-                self.frames.remove(&frame_id);
-            }
-        }
-    }
+    /// Encoder backend in use (host)
+    pub encoder: Option<String>,
+    /// Per-stage timing of the capture pipeline (host)
+    pub pipeline: PipelineStats,
 }
 
 /// Session state machine
@@ -163,6 +90,35 @@ pub struct Session {
     input_tx: Mutex<Option<Sender<InputPacket>>>,
     input_sequence: AtomicU64,
     pending_connection: Mutex<Option<PendingConnection>>,
+    /// Capture target requested by the host UI, applied by the capture loop
+    pending_target: Mutex<Option<CaptureTarget>>,
+    /// Display switch requested by the viewer, applied by the capture loop
+    pending_display: Mutex<Option<DisplaySelection>>,
+    /// Newest frame the viewer decoded intact, applied by the capture loop
+    pending_ack: Mutex<Option<u64>>,
+    /// Last good frame before a loss the viewer reported
+    pending_recovery: Mutex<Option<u64>>,
+    /// Keyframe requested by the viewer
+    keyframe_requested: AtomicBool,
+    /// Bitrate the bandwidth estimate settled on, applied by the capture
+    /// loop
+    pending_bitrate: Mutex<Option<u32>>,
+    /// Loss is high enough to encode with long-term references, applied
+    /// by the capture loop; set and cleared by `LongTermRefsPolicy`
+    long_term_refs: AtomicBool,
+    /// Interval between captured frames, set by the capture loop
+    frame_duration: Mutex<Duration>,
+    /// Rate refinement tiles may use, a share of the bandwidth estimate
+    refine_kbps: AtomicU32,
+    /// Refinement bytes written to their stream so far
+    refine_bytes: AtomicU64,
+    /// Host pointer position after the last injected mouse event, as a
+    /// fraction of the screen; steers region-of-interest encoding
+    cursor: Mutex<Option<(f64, f64)>>,
+    /// Host displays and the one being streamed, as last published
+    displays: RwLock<(Vec<RemoteDisplay>, Option<DisplaySelection>)>,
+    /// Outgoing control messages (viewer)
+    control_tx: Mutex<Option<mpsc::UnboundedSender<SessionMessage>>>,
 }
 
 impl Session {
@@ -178,6 +134,19 @@ impl Session {
             input_tx: Mutex::new(None),
             input_sequence: AtomicU64::new(0),
             pending_connection: Mutex::new(None),
+            pending_target: Mutex::new(None),
+            pending_display: Mutex::new(None),
+            pending_ack: Mutex::new(None),
+            pending_recovery: Mutex::new(None),
+            keyframe_requested: AtomicBool::new(false),
+            pending_bitrate: Mutex::new(None),
+            long_term_refs: AtomicBool::new(false),
+            frame_duration: Mutex::new(Duration::ZERO),
+            refine_kbps: AtomicU32::new(0),
+            refine_bytes: AtomicU64::new(0),
+            cursor: Mutex::new(None),
+            displays: RwLock::new((Vec::new(), None)),
+            control_tx: Mutex::new(None),
         }
     }
 
@@ -441,13 +410,37 @@ impl Session {
         Ok(())
     }
 
-    /// Request a keyframe
+    /// Request a keyframe (viewer)
     pub fn request_keyframe(&self) -> SessionResult<()> {
-        // TODO: Send keyframe request over control channel
         debug!("Keyframe requested");
+        self.send_control(SessionMessage::RequestKeyframe)
+    }
+
+    /// Switch what the host shares (display, window or region)
+    pub fn set_capture_target(&self, target: CaptureTarget) -> SessionResult<()> {
+        debug!("Capture target requested: {:?}", target);
+        *self.pending_target.lock() = Some(target);
         Ok(())
     }
 
+    /// Ask the host for its display list (viewer)
+    ///
+    /// The reply arrives as a `SessionEvent::Displays`.
+    pub fn list_remote_displays(&self) -> SessionResult<()> {
+        self.send_control(SessionMessage::ListDisplays)
+    }
+
+    /// Ask the host to stream a different display (viewer)
+    pub fn select_remote_display(&self, selection: DisplaySelection) -> SessionResult<()> {
+        self.send_control(SessionMessage::SelectDisplay(selection))
+    }
+
+    fn send_control(&self, message: SessionMessage) -> SessionResult<()> {
+        let tx = self.control_tx.lock();
+        let tx = tx.as_ref().ok_or(SessionError::NotActive)?;
+        tx.send(message).map_err(|_| SessionError::ChannelError)
+    }
+
     /// Set quality preset
     pub fn set_quality(&self, _quality: QualityPreset) -> SessionResult<()> {
         // TODO: Update encoder settings
@@ -491,6 +484,334 @@ struct PendingConnection {
     response: oneshot::Sender<bool>,
 }
 
+/// Resend a recovery request the host hasn't answered after this long
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Temporal layers the host tracks references for
+const MAX_TEMPORAL_LAYERS: usize = 3;
+
+/// Intact frames the viewer remembers as possible references
+const INTACT_FRAMES: usize = 16;
+
+/// Sent frames the host keeps for retransmission
+const RETRANSMIT_FRAMES: usize = 32;
+
+/// Time beyond one round trip that a resent fragment is still useful, to
+/// absorb jitter. The host also allows a frame interval past when the
+/// fragment left, since the viewer only notices a loss when the next
+/// frame arrives.
+const RETRANSMIT_SLACK: Duration = Duration::from_millis(25);
+
+/// How often the viewer checks for frames to report or give up on
+const RETRANSMIT_TICK: Duration = Duration::from_millis(5);
+
+/// How often the viewer reports datagram arrival times
+const FEEDBACK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Drop in the estimated bandwidth worth retuning the encoder for, as a
+/// fraction of the current bitrate
+const BITRATE_DECREASE: f64 = 0.05;
+
+/// Rise worth retuning the encoder for; larger than a drop, so an
+/// estimate wavering around the current bitrate leaves it alone
+const BITRATE_INCREASE: f64 = 0.15;
+
+/// Shortest time between encoder bitrate changes
+const RETARGET_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Shortest time between bitrate changes for backends that restart on a
+/// keyframe to apply one
+const RESTART_RETARGET_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Video queued in the pacer beyond which enhancement layers are shed
+const SHED_QUEUE_DELAY: Duration = Duration::from_millis(100);
+
+/// Smoothed loss above which the encoder keeps long-term references.
+/// OpenH264 drops its screen content tools to keep them, which only pays
+/// off once recovering with a P-frame instead of a keyframe happens often.
+const LONG_TERM_REFS_ON_LOSS: f64 = 0.02;
+
+/// Smoothed loss below which the encoder goes back to screen content
+/// tuning without long-term references
+const LONG_TERM_REFS_OFF_LOSS: f64 = 0.005;
+
+/// Shortest time between long-term reference switches, each of which
+/// restarts the encoder on a keyframe
+const LONG_TERM_REFS_HOLD: Duration = Duration::from_secs(10);
+
+/// Share of the bandwidth estimate refinement tiles may use; what they
+/// use comes off the video's
+const REFINE_SHARE: f64 = 0.1;
+
+/// Frames each pipeline queue holds; one, so the encoder always gets the
+/// freshest frame
+const PIPELINE_QUEUE_DEPTH: usize = 1;
+
+/// A captured frame on its way through the host pipeline
+struct PipelineFrame {
+    frame: CapturedFrame,
+    /// First frame from a new capture source
+    source_changed: bool,
+    /// Pointer position at capture time, as a fraction of the frame, when
+    /// it maps onto the frame
+    cursor: Option<(f64, f64)>,
+}
+
+impl PipelineFrame {
+    /// Take over what a dropped older frame carried, so the changes it
+    /// reported still reach the encoder and refinement
+    fn absorb(&mut self, older: PipelineFrame) {
+        if older.frame.is_full_update() {
+            self.frame.dirty_rects.clear();
+        } else if !self.frame.is_full_update() {
+            self.frame.dirty_rects.extend(older.frame.dirty_rects);
+        }
+        self.source_changed |= older.source_changed;
+    }
+}
+
+/// How the convert stage prepares frames for the encoder
+struct YuvConversion {
+    color_space: ColorSpace,
+    chroma_444: bool,
+    frame_duration: Duration,
+}
+
+/// Pixels around the pointer encoded at higher quality
+const ROI_CURSOR_SIZE: u32 = 256;
+
+/// Quantizer offset around the pointer
+const ROI_CURSOR_QP_OFFSET: i8 = -6;
+
+/// Quantizer offset for the changed region
+const ROI_DIRTY_QP_OFFSET: i8 = -3;
+
+/// Changed regions covering more of the screen than this aren't a focus
+const ROI_MAX_DIRTY_PERCENTAGE: f64 = 50.0;
+
+/// Region-of-interest hints for a frame: around the pointer (given as a
+/// fraction of the frame) and over the part of the screen that changed
+fn roi_hints(frame: &CapturedFrame, cursor: Option<(f64, f64)>) -> Vec<RoiRegion> {
+    let mut regions = Vec::new();
+    if let Some((x, y)) = cursor
+        && (0.0..1.0).contains(&x)
+        && (0.0..1.0).contains(&y)
+    {
+        let half = ROI_CURSOR_SIZE / 2;
+        let x = ((x * frame.width as f64) as u32).saturating_sub(half);
+        let y = ((y * frame.height as f64) as u32).saturating_sub(half);
+        regions.push(RoiRegion {
+            rect: DirtyRect::new(x, y, ROI_CURSOR_SIZE, ROI_CURSOR_SIZE),
+            qp_offset: ROI_CURSOR_QP_OFFSET,
+        });
+    }
+    if !frame.is_full_update()
+        && frame.dirty_percentage() <= ROI_MAX_DIRTY_PERCENTAGE
+        && let Some(bounds) = frame.dirty_bounds()
+    {
+        regions.push(RoiRegion {
+            rect: bounds,
+            qp_offset: ROI_DIRTY_QP_OFFSET,
+        });
+    }
+    regions
+}
+
+/// Bounds covering both changed regions
+fn merge_dirty(a: Option<DirtyRect>, b: Option<DirtyRect>) -> Option<DirtyRect> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.merge(&b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Whole frame intervals since `since`, which advances past them
+fn elapsed_frames(since: &mut Instant, frame_duration: Duration) -> u32 {
+    let frames = (since.elapsed().as_secs_f64() / frame_duration.as_secs_f64()) as u32;
+    *since += frame_duration * frames;
+    frames
+}
+
+/// Refinement updates on their way to the refinement stream (host)
+///
+/// The stream sends at a fraction of the bandwidth estimate, so the
+/// channel to it fills up on a slow link. Tiles that don't fit are handed
+/// back to the tracker to refine later, so the convert stage never waits
+/// on the network. Invalidations must arrive, so they're held here until
+/// there's room, and new tiles wait behind them.
+struct RefinementOutbox {
+    tx: mpsc::Sender<RefinementPacket>,
+    held: VecDeque<RefinementPacket>,
+}
+
+impl RefinementOutbox {
+    fn new(tx: mpsc::Sender<RefinementPacket>) -> Self {
+        Self {
+            tx,
+            held: VecDeque::new(),
+        }
+    }
+
+    /// Queue the tracker's updates, deferring tiles that don't fit
+    fn send(
+        &mut self,
+        tracker: &mut RefinementTracker,
+        updates: EncoderResult<Vec<RefinementUpdate>>,
+    ) {
+        let updates = match updates {
+            Ok(updates) => updates,
+            Err(e) => {
+                debug!("Skipping refinement: {}", e);
+                return;
+            }
+        };
+
+        self.flush();
+        let rect = |r: DirtyRect| shared_protocol::DirtyRect::new(r.x, r.y, r.width, r.height);
+        for update in updates {
+            match update {
+                RefinementUpdate::Tile { rect: tile, png } => {
+                    let packet = RefinementPacket::Tile {
+                        rect: rect(tile),
+                        png: png.to_vec(),
+                    };
+                    if !self.held.is_empty() || self.tx.try_send(packet).is_err() {
+                        tracker.defer(&tile);
+                    }
+                }
+                RefinementUpdate::Invalidate(rects) => {
+                    let rects = rects.into_iter().map(rect).collect();
+                    self.hold(RefinementPacket::Invalidate(rects));
+                }
+                RefinementUpdate::Clear => {
+                    // Covers every invalidation still waiting
+                    self.held.clear();
+                    self.hold(RefinementPacket::Clear);
+                }
+            }
+        }
+    }
+
+    fn hold(&mut self, packet: RefinementPacket) {
+        self.held.push_back(packet);
+        self.flush();
+    }
+
+    /// Queue held packets while there's room
+    fn flush(&mut self) {
+        while let Some(packet) = self.held.pop_front() {
+            match self.tx.try_send(packet) {
+                Ok(()) => {}
+                Err(TrySendError::Full(packet)) => {
+                    self.held.push_front(packet);
+                    return;
+                }
+                // The stream is gone; nothing will arrive anyway
+                Err(TrySendError::Closed(_)) => self.held.clear(),
+            }
+        }
+    }
+}
+
+/// Viewer-side reference tracking for loss recovery
+///
+/// A frame decodes cleanly if it arrived whole and either stands alone
+/// (key or recovery frame) or is predicted from a cleanly decoded frame.
+/// Upper temporal layers the host sheds aren't referenced by what follows,
+/// so they leave no gap.
+#[derive(Default)]
+struct ReferenceTracker {
+    /// Recent intact frames and their temporal layers, newest last
+    intact: VecDeque<(u64, u8)>,
+    /// When recovery was last requested, while the stream is broken
+    recovery_requested: Option<Instant>,
+}
+
+impl ReferenceTracker {
+    /// Track a received frame; returns the feedback to send the host
+    fn receive(&mut self, frame: &AssembledFrame) -> Option<SessionMessage> {
+        let header = &frame.packet.header;
+        let intact = frame.complete
+            && match header.frame_type {
+                FrameType::Key | FrameType::Recovery => true,
+                _ => {
+                    self.recovery_requested.is_none()
+                        && self
+                            .intact
+                            .iter()
+                            .any(|&(id, _)| id == header.reference_frame_id)
+                }
+            };
+
+        if intact {
+            if self.intact.len() == INTACT_FRAMES {
+                self.intact.pop_front();
+            }
+            self.intact.push_back((header.frame_id, header.temporal_id));
+            self.recovery_requested = None;
+            return Some(SessionMessage::FrameAck {
+                frame_id: header.frame_id,
+            });
+        }
+
+        if self
+            .recovery_requested
+            .is_some_and(|requested| requested.elapsed() < RECOVERY_TIMEOUT)
+        {
+            return None;
+        }
+        self.recovery_requested = Some(Instant::now());
+        // Recover from a base-layer frame, which the encoder kept as a
+        // reference
+        let last_good = self
+            .intact
+            .iter()
+            .rev()
+            .find(|&&(_, layer)| layer == 0)
+            .map(|&(id, _)| id);
+        Some(match last_good {
+            Some(last_good) => SessionMessage::ReferenceLost { last_good },
+            None => SessionMessage::RequestKeyframe,
+        })
+    }
+}
+
+/// Decides when the host encodes with long-term references
+///
+/// They turn on when loss rises above one rate and off once it falls
+/// below a lower one, and stay put for a while after each switch, so a
+/// link hovering around the threshold doesn't restart the encoder over
+/// and over.
+#[derive(Default)]
+struct LongTermRefsPolicy {
+    enabled: bool,
+    switched_at: Option<Instant>,
+}
+
+impl LongTermRefsPolicy {
+    /// Account for the smoothed loss rate at `now`; returns the new
+    /// setting when it changes
+    fn update(&mut self, loss: f64, now: Instant) -> Option<bool> {
+        if self
+            .switched_at
+            .is_some_and(|at| now.duration_since(at) < LONG_TERM_REFS_HOLD)
+        {
+            return None;
+        }
+        let enabled = if self.enabled {
+            loss >= LONG_TERM_REFS_OFF_LOSS
+        } else {
+            loss > LONG_TERM_REFS_ON_LOSS
+        };
+        if enabled == self.enabled {
+            return None;
+        }
+        self.enabled = enabled;
+        self.switched_at = Some(now);
+        Some(enabled)
+    }
+}
+
 impl ActiveSession {
     /// Get the persistent session
     pub fn session(&self) -> Arc<Session> {
@@ -533,11 +854,12 @@ impl ActiveSession {
         F: Fn(crate::commands::SessionEvent) + Send + 'static,
     {
         // 1. Start Capture (Producer)
-        let (frame_tx, mut frame_rx) = mpsc::channel::<EncodedFrame>(10);
+        let (frame_tx, mut frame_rx) = mpsc::channel::<(EncodedFrame, Option<DirtyRect>)>(10);
+        let (refine_tx, refine_rx) = mpsc::channel::<RefinementPacket>(64);
         let session_clone = self.session.clone();
 
         std::thread::spawn(move || {
-            if let Err(e) = Self::capture_loop(session_clone, frame_tx) {
+            if let Err(e) = Self::capture_loop(session_clone, frame_tx, refine_tx) {
                 error!("Capture loop error: {}", e);
             }
         });
@@ -557,21 +879,103 @@ impl ActiveSession {
             }
         };
 
+        // Control channel: the viewer opens it, we answer display requests
+        let (control_in_tx, mut control_in) = mpsc::unbounded_channel();
+        let (control_out, control_out_rx) = mpsc::unbounded_channel();
+        Self::spawn_control_channel(
+            self.transport.clone(),
+            SessionRole::Host,
+            control_in_tx,
+            control_out_rx,
+        );
+        Self::spawn_refinement_sender(self.session.clone(), self.transport.clone(), refine_rx);
+
         // 2. Main Loop: Send Video & Receive Input
+        let packetizer = match Packetizer::new(MAX_DATAGRAM_SIZE) {
+            Ok(packetizer) => packetizer,
+            Err(e) => {
+                error!("Failed to create packetizer: {}", e);
+                return;
+            }
+        };
         let mut last_stats_time = Instant::now();
         let start_time = Instant::now();
         let mut frame_count = 0u64;
         let mut bytes_sent = 0u64;
+        let mut resolution = None;
+        // Newest frame of each temporal layer, and the layer above which
+        // frames are being shed
+        let mut layer_frames = [None::<u64>; MAX_TEMPORAL_LAYERS];
+        let mut shed_above = None::<u8>;
+        // Changes in frames that weren't sent, reported with the next one
+        let mut unsent_dirty = None::<DirtyRect>;
+        // Parity sized to the loss QUIC measures on the connection
+        let mut fec = FecEncoder::new();
+        // Fragments the viewer may ask for again, and the newest frame it
+        // decoded intact, to recover from once a resend is too late
+        let mut retransmits = RetransmitBuffer::new(RETRANSMIT_FRAMES);
+        let mut last_acked = None::<u64>;
+        // Encoder bitrate follows the delay-based estimate of the path
+        let mut bandwidth = BandwidthEstimator::new(BandwidthConfig::default());
+        let mut long_term_refs = LongTermRefsPolicy::default();
+        let mut bitrate_kbps = bandwidth.target_bitrate_kbps();
+        // Datagrams leave spread out at the estimated rate, not in bursts
+        let mut pacer = Pacer::new(bitrate_kbps);
+        // Refinement tiles get a share of the estimate; what they used in
+        // the last stats interval comes off the video's
+        self.session.refine_kbps.store(
+            (bitrate_kbps as f64 * REFINE_SHARE) as u32,
+            Ordering::Relaxed,
+        );
+        let mut refine_kbps = 0u32;
+        let mut refine_bytes = 0u64;
 
         loop {
             if !self.session.running.load(Ordering::SeqCst) {
                 break;
             }
 
+            let next_send = pacer.next_send_time(Instant::now());
             tokio::select! {
                 // Outgoing Video
-                Some(frame) = frame_rx.recv() => {
+                Some((frame, dirty)) = frame_rx.recv() => {
+                    let dirty = merge_dirty(unsent_dirty.take(), dirty);
                     let frame_len = frame.data.len();
+                    let resolution_changed =
+                        resolution.is_some_and(|dims| dims != (frame.width, frame.height));
+                    resolution = Some((frame.width, frame.height));
+
+                    // Each layer predicts from the newest frame of a lower
+                    // layer; the base layer from the previous base frame
+                    let layer = (frame.temporal_id as usize).min(MAX_TEMPORAL_LAYERS - 1);
+                    let reference_frame_id = layer_frames[..layer.max(1)]
+                        .iter()
+                        .flatten()
+                        .max()
+                        .copied()
+                        .unwrap_or(frame.sequence.saturating_sub(1));
+                    if frame.frame_type == encoder::EncodedFrameType::Key {
+                        layer_frames = [None; MAX_TEMPORAL_LAYERS];
+                    }
+                    layer_frames[layer] = Some(frame.sequence);
+
+                    // Shed enhancement layers while the pacer or datagram
+                    // buffer is backed up, along with the frames above them
+                    // that would reference a shed frame
+                    let dependent = shed_above.is_some_and(|layer| frame.temporal_id > layer);
+                    if !dependent {
+                        shed_above = None;
+                    }
+                    if frame.temporal_id > 0
+                        && (dependent
+                            || pacer.queue_delay() > SHED_QUEUE_DELAY
+                            || frame_len > self.transport.datagram_send_buffer_space())
+                    {
+                        debug!("Shedding layer {} frame {}", frame.temporal_id, frame.sequence);
+                        shed_above.get_or_insert(frame.temporal_id);
+                        unsent_dirty = dirty;
+                        continue;
+                    }
 
                     // Create Video Packet
                     let header = VideoPacketHeader {
@@ -581,68 +985,85 @@ impl ActiveSession {
                         timestamp_us: frame.pts_us,
                         frame_type: match frame.frame_type {
                             encoder::EncodedFrameType::Key => FrameType::Key,
+                            encoder::EncodedFrameType::Recovery => FrameType::Recovery,
                             _ => FrameType::Delta,
                         },
-                        codec: VideoCodec::H264, // Assume H.264 for MVP
+                        codec: frame.codec.into(),
                         width: frame.width,
                         height: frame.height,
-                        dirty_rect: None,
+                        // The viewer drops refinement tiles under it
+                        dirty_rect: dirty.map(|r| {
+                            shared_protocol::DirtyRect::new(r.x, r.y, r.width, r.height)
+                        }),
+                        resolution_changed,
+                        slice_aligned: false,
+                        temporal_id: frame.temporal_id,
+                        reference_frame_id,
+                        parity: None,
                     };
 
-                    let header_size = match bincode::serialized_size(&VideoPacket {
-                        header: header.clone(),
-                        payload: Vec::new(),
-                    }) {
-                        Ok(size) => size as usize,
+                    let packets = match packetizer.packetize(&header, &frame.data, &frame.slices) {
+                        Ok(packets) => packets,
                         Err(e) => {
-                            error!("Failed to compute header size: {}", e);
+                            error!("Failed to packetize frame: {}", e);
+                            unsent_dirty = dirty;
                             continue;
                         }
                     };
 
-                    let max_payload = MAX_DATAGRAM_SIZE.saturating_sub(header_size);
-                    if max_payload == 0 {
-                        warn!("Header too large for datagram");
-                        continue;
-                    }
-
-                    let total_fragments =
-                        ((frame_len + max_payload - 1) / max_payload) as u16;
-
-                    let mut sent_all = true;
-                    for (index, chunk) in frame.data.chunks(max_payload).enumerate() {
-                        let mut fragment_header = header.clone();
-                        fragment_header.fragment_index = index as u16;
-                        fragment_header.total_fragments = total_fragments;
-
-                        let packet = VideoPacket {
-                            header: fragment_header,
-                            payload: chunk.to_vec(),
-                        };
-
+                    let parity = fec.protect(&packets);
+                    let fragment_count = packets.len();
+                    let mut fragments = Vec::with_capacity(fragment_count);
+                    let mut queued_all = true;
+                    for (index, packet) in packets.into_iter().chain(parity).enumerate() {
                         match packet.to_bytes() {
-                            Ok(bytes) => {
-                                if let Err(e) = self.transport.send_datagram(bytes) {
-                                    warn!("Failed to send frame fragment: {}", e);
-                                    sent_all = false;
-                                    break;
-                                }
+                            Ok(data) => {
+                                let fragment = (index < fragment_count).then(|| {
+                                    fragments.push(data.clone());
+                                    (frame.sequence, index as u16)
+                                });
+                                pacer.enqueue(
+                                    PacketPriority::Video,
+                                    PacedDatagram { data, fragment },
+                                );
                             }
                             Err(e) => {
                                 error!("Failed to serialize video packet: {}", e);
-                                sent_all = false;
+                                queued_all = false;
                                 break;
                             }
                         }
                     }
 
-                    if sent_all {
+                    if queued_all {
                         frame_count += 1;
                         bytes_sent += frame_len as u64;
+                        retransmits.insert(frame.sequence, fragments);
                     }
+                    self.follow_bandwidth(&bandwidth, refine_kbps, &mut pacer, &mut bitrate_kbps);
 
                     // Stats logic
                     if last_stats_time.elapsed() >= Duration::from_secs(1) {
+                         let sent = self.session.refine_bytes.load(Ordering::Relaxed);
+                         refine_kbps = ((sent - refine_bytes) as f64 * 8.0
+                             / 1000.0
+                             / last_stats_time.elapsed().as_secs_f64()) as u32;
+                         refine_bytes = sent;
+                         if let Some(stats) = self.transport.stats() {
+                             fec.update_loss(stats.packets_sent, stats.packets_lost);
+                             bandwidth.update_loss(stats.packets_sent, stats.packets_lost);
+                             if let Some(enabled) =
+                                 long_term_refs.update(fec.loss_rate(), Instant::now())
+                             {
+                                 info!(
+                                     "Loss at {:.1}%, long-term references {}",
+                                     fec.loss_rate() * 100.0,
+                                     if enabled { "on" } else { "off" }
+                                 );
+                                 self.session.long_term_refs.store(enabled, Ordering::SeqCst);
+                             }
+                             self.follow_bandwidth(&bandwidth, refine_kbps, &mut pacer, &mut bitrate_kbps);
+                         }
                          let elapsed = start_time.elapsed().as_secs_f64();
                          let fps = frame_count as f64 / elapsed;
                          let bitrate = ((bytes_sent as f64 * 8.0) / 1000.0 / elapsed.max(1.0)) as u32;
@@ -657,6 +1078,36 @@ impl ActiveSession {
                     }
                 }
 
+                // Control requests from the viewer
+                Some(message) = control_in.recv() => match message {
+                    SessionMessage::Nack(ack) => {
+                        self.resend_fragments(&retransmits, &mut pacer, &ack, last_acked);
+                    }
+                    SessionMessage::TransportFeedback(feedback) => {
+                        if let Some(stats) = self.transport.stats() {
+                            bandwidth.set_rtt(stats.rtt);
+                        }
+                        bandwidth.on_feedback(&feedback, Instant::now());
+                        self.follow_bandwidth(&bandwidth, refine_kbps, &mut pacer, &mut bitrate_kbps);
+                    }
+                    message => {
+                        if let SessionMessage::FrameAck { frame_id } = message {
+                            last_acked =
+                                Some(last_acked.map_or(frame_id, |acked| acked.max(frame_id)));
+                        }
+                        if let Some(reply) = self.handle_host_control(message) {
+                            let _ = control_out.send(reply);
+                        }
+                    }
+                },
+
+                // Paced video datagrams, as the budget allows
+                _ = tokio::time::sleep_until(
+                    next_send.unwrap_or_else(Instant::now).into()
+                ), if next_send.is_some() => {
+                    self.send_paced(&mut pacer, &mut bandwidth, &mut retransmits);
+                }
+
                 // Incoming Input (via Datagrams for MVP, or Streams)
                 Ok(data) = self.transport.recv_datagram() => {
                     // Try to deserialize as InputPacket
@@ -667,6 +1118,13 @@ impl ActiveSession {
                             if let Err(e) = processor.process_packet(&packet) {
                                 warn!("Input injection failed: {}", e);
                             }
+                            if matches!(
+                                packet.event,
+                                InputEvent::MouseMove { .. } | InputEvent::MouseButton { .. }
+                            ) {
+                                *self.session.cursor.lock() =
+                                    processor.normalized_mouse_position().ok();
+                            }
                         }
                     }
                 }
@@ -691,72 +1149,426 @@ impl ActiveSession {
             Self::input_loop(session_clone, transport_clone, input_rx).await;
         });
 
+        // Control channel; the first message opens the stream on the host
+        let (control_in_tx, mut control_in) = mpsc::unbounded_channel();
+        let (control_tx, control_out_rx) = mpsc::unbounded_channel();
+        let _ = control_tx.send(SessionMessage::ListDisplays);
+        *self.session.control_tx.lock() = Some(control_tx);
+        Self::spawn_control_channel(
+            self.transport.clone(),
+            SessionRole::Viewer,
+            control_in_tx,
+            control_out_rx,
+        );
+
+        // Lossless tiles for static regions, drawn over the video
+        let (refine_in_tx, mut refine_in) = mpsc::unbounded_channel();
+        Self::spawn_refinement_receiver(self.transport.clone(), refine_in_tx);
+
         // 2. Receive Video Loop
         let mut assembler = FrameAssembler::new(128, Duration::from_secs(2));
+        let mut references = ReferenceTracker::default();
+        let mut feedback = FeedbackRecorder::new();
+        let mut last_feedback = Instant::now();
+        let mut retransmit_tick = tokio::time::interval(RETRANSMIT_TICK);
+        retransmit_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
             if !self.session.running.load(Ordering::SeqCst) {
                 break;
             }
 
-            match self.transport.recv_datagram().await {
-                Ok(data) => {
-                    // Deserialize Video Packet
-                    match VideoPacket::from_bytes(&data) {
+            let frames = tokio::select! {
+                received = self.transport.recv_datagram() => match received {
+                    Ok(data) => match VideoPacket::from_bytes(&data) {
                         Ok(packet) => {
-                            if let Some(packet) = assembler.push(packet) {
-                                let is_keyframe =
-                                    matches!(packet.header.frame_type, FrameType::Key);
-                                let event = crate::commands::VideoFrameEvent {
-                                    data: packet.payload,
-                                    is_keyframe,
-                                    timestamp_us: packet.header.timestamp_us,
-                                    width: packet.header.width,
-                                    height: packet.header.height,
-                                    frame_id: packet.header.frame_id,
-                                };
-
-                                event_callback(crate::commands::SessionEvent::VideoFrame(event));
-                            }
+                            feedback.record(&packet.header, Instant::now());
+                            assembler.push(packet)
                         }
                         Err(e) => {
                             warn!("Failed to deserialize packet: {}", e);
+                            continue;
+                        }
+                    },
+                    // Connection closed or error
+                    Err(_) => break,
+                },
+                // Lost fragments are worth waiting for about a round trip
+                _ = retransmit_tick.tick() => {
+                    let rtt = self
+                        .transport
+                        .stats()
+                        .map(|stats| stats.rtt)
+                        .unwrap_or_default();
+                    assembler.set_retransmit_wait(rtt + RETRANSMIT_SLACK);
+
+                    // Arrival times drive the host's bandwidth estimate
+                    if last_feedback.elapsed() >= FEEDBACK_INTERVAL {
+                        last_feedback = Instant::now();
+                        if let Some(report) = feedback.take() {
+                            let _ = self
+                                .session
+                                .send_control(SessionMessage::TransportFeedback(report));
                         }
                     }
+                    assembler.poll()
                 }
-                Err(_) => {
-                    // Connection closed or error
+                Some(message) = control_in.recv() => {
+                    if let SessionMessage::Displays { displays, active } = message {
+                        event_callback(crate::commands::SessionEvent::Displays {
+                            displays,
+                            active,
+                        });
+                    }
+                    continue;
+                }
+                Some(packet) = refine_in.recv() => {
+                    event_callback(crate::commands::SessionEvent::Refinement(packet));
+                    continue;
+                }
+            };
+
+            for mut ack in assembler.nacks() {
+                ack.rtt_us = self
+                    .transport
+                    .stats()
+                    .map_or(0, |stats| stats.rtt.as_micros() as u64);
+                let _ = self.session.send_control(SessionMessage::Nack(ack));
+            }
+
+            for frame in frames {
+                if let Some(message) = references.receive(&frame) {
+                    let _ = self.session.send_control(message);
+                }
+
+                let packet = frame.packet;
+                let is_keyframe = matches!(packet.header.frame_type, FrameType::Key);
+                let event = crate::commands::VideoFrameEvent {
+                    data: packet.payload,
+                    codec: packet.header.codec,
+                    is_keyframe,
+                    timestamp_us: packet.header.timestamp_us,
+                    width: packet.header.width,
+                    height: packet.header.height,
+                    frame_id: packet.header.frame_id,
+                    resolution_changed: packet.header.resolution_changed,
+                    dirty_rect: packet.header.dirty_rect,
+                };
+
+                event_callback(crate::commands::SessionEvent::VideoFrame(event));
+            }
+        }
+    }
+
+    /// Resend the fragments a viewer reported missing (host)
+    ///
+    /// A resend only helps within about a round trip and a frame interval
+    /// of the original leaving the pacer; past that the encoder recovers
+    /// from the newest frame the viewer acknowledged instead.
+    fn resend_fragments(
+        &self,
+        retransmits: &RetransmitBuffer,
+        pacer: &mut Pacer,
+        ack: &VideoAck,
+        last_acked: Option<u64>,
+    ) {
+        let rtt = self
+            .transport
+            .stats()
+            .map(|stats| stats.rtt)
+            .unwrap_or_default();
+        let budget = rtt + *self.session.frame_duration.lock() + RETRANSMIT_SLACK;
+        match retransmits.nack(ack, budget, Instant::now()) {
+            Some(fragments) => {
+                debug!(
+                    "Resending {} fragments of frame {}",
+                    fragments.len(),
+                    ack.frame_id
+                );
+                for (index, data) in fragments {
+                    let fragment = Some((ack.frame_id, index));
+                    pacer.enqueue(
+                        PacketPriority::Retransmission,
+                        PacedDatagram { data, fragment },
+                    );
+                }
+            }
+            None => match last_acked.filter(|&acked| acked < ack.frame_id) {
+                Some(last_good) => {
+                    debug!(
+                        "Frame {} too old to resend, recovering from {}",
+                        ack.frame_id, last_good
+                    );
+                    *self.session.pending_recovery.lock() = Some(last_good);
+                }
+                None => self
+                    .session
+                    .keyframe_requested
+                    .store(true, Ordering::SeqCst),
+            },
+        }
+    }
+
+    /// Send the datagrams the pacer releases now (host)
+    ///
+    /// Video fragments count as sent for bandwidth estimation and the
+    /// resend budget only here, so time spent in the pacer queue isn't
+    /// mistaken for network delay.
+    fn send_paced(
+        &self,
+        pacer: &mut Pacer,
+        bandwidth: &mut BandwidthEstimator,
+        retransmits: &mut RetransmitBuffer,
+    ) {
+        let now = Instant::now();
+        while let Some(datagram) = pacer.poll(now) {
+            if let Some((frame_id, fragment_index)) = datagram.fragment {
+                bandwidth.on_sent(frame_id, fragment_index, datagram.data.len(), now);
+                retransmits.on_sent(frame_id, now);
+            }
+            if let Err(e) = self.transport.send_datagram(datagram.data) {
+                warn!("Failed to send video datagram: {}", e);
+            }
+        }
+    }
+
+    /// Pace at the bandwidth estimate and retune the encoder to it, held
+    /// back while the pacer works through a backlog (host)
+    ///
+    /// The video gets the estimate less what refinement tiles recently
+    /// used, which is at most their share of it. Rises need a wider
+    /// margin than drops; the encode stage spaces the resulting changes
+    /// out.
+    fn follow_bandwidth(
+        &self,
+        bandwidth: &BandwidthEstimator,
+        refine_kbps: u32,
+        pacer: &mut Pacer,
+        bitrate_kbps: &mut u32,
+    ) {
+        let estimate = bandwidth.target_bitrate_kbps();
+        self.session
+            .refine_kbps
+            .store((estimate as f64 * REFINE_SHARE) as u32, Ordering::Relaxed);
+        let available = estimate.saturating_sub(refine_kbps);
+        pacer.set_target_bitrate(available);
+        let target = pacer.pushback_bitrate(available);
+        let margin = if target < *bitrate_kbps {
+            BITRATE_DECREASE
+        } else {
+            BITRATE_INCREASE
+        };
+        if target.abs_diff(*bitrate_kbps) as f64 > margin * *bitrate_kbps as f64 {
+            debug!("Encoder bitrate now {} kbps", target);
+            *bitrate_kbps = target;
+            *self.session.pending_bitrate.lock() = Some(target);
+        }
+    }
+
+    /// Answer a control message on the host; returns the reply, if any
+    fn handle_host_control(&self, message: SessionMessage) -> Option<SessionMessage> {
+        match message {
+            SessionMessage::ListDisplays => {
+                let (displays, active) = self.session.displays.read().clone();
+                Some(SessionMessage::Displays {
+                    displays,
+                    active: active.unwrap_or(DisplaySelection::All),
+                })
+            }
+            SessionMessage::FrameAck { frame_id } => {
+                let mut ack = self.session.pending_ack.lock();
+                *ack = Some(ack.map_or(frame_id, |acked| acked.max(frame_id)));
+                None
+            }
+            SessionMessage::ReferenceLost { last_good } => {
+                debug!("Viewer lost frames after {}", last_good);
+                *self.session.pending_recovery.lock() = Some(last_good);
+                None
+            }
+            SessionMessage::RequestKeyframe => {
+                self.session
+                    .keyframe_requested
+                    .store(true, Ordering::SeqCst);
+                None
+            }
+            SessionMessage::SelectDisplay(selection) => {
+                info!("Viewer selected display: {:?}", selection);
+                *self.session.pending_display.lock() = Some(selection);
+                let displays = self.session.displays.read().0.clone();
+                Some(SessionMessage::Displays {
+                    displays,
+                    active: selection,
+                })
+            }
+            other => {
+                debug!("Ignoring control message: {:?}", other);
+                None
+            }
+        }
+    }
+
+    /// Run the control stream: forward incoming messages, write outgoing ones
+    ///
+    /// The viewer opens the stream, the host accepts it.
+    fn spawn_control_channel(
+        transport: Arc<QuicTransport>,
+        role: SessionRole,
+        incoming: mpsc::UnboundedSender<SessionMessage>,
+        mut outgoing: mpsc::UnboundedReceiver<SessionMessage>,
+    ) {
+        tokio::spawn(async move {
+            let channel = match role {
+                SessionRole::Host => transport.accept_control().await,
+                SessionRole::Viewer => transport.open_control().await,
+            };
+            let (mut sender, mut receiver) = match channel {
+                Ok(channel) => channel,
+                Err(e) => {
+                    warn!("Control channel unavailable: {}", e);
+                    return;
+                }
+            };
+
+            tokio::spawn(async move {
+                while let Some(message) = outgoing.recv().await {
+                    if let Err(e) = sender.send(&message).await {
+                        warn!("Failed to send control message: {}", e);
+                        break;
+                    }
+                }
+            });
+
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => {
+                        if incoming.send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        debug!("Control channel closed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /// Write refinement packets to their stream, opening it with the first
+    ///
+    /// The stream bypasses the pacer, so writes are spaced to keep within
+    /// the refinement share of the bandwidth estimate.
+    fn spawn_refinement_sender(
+        session: Arc<Session>,
+        transport: Arc<QuicTransport>,
+        mut packets: mpsc::Receiver<RefinementPacket>,
+    ) {
+        tokio::spawn(async move {
+            let Some(first) = packets.recv().await else {
+                return;
+            };
+            let mut sender = match transport.open_refinement().await {
+                Ok(sender) => sender,
+                Err(e) => {
+                    warn!("Refinement stream unavailable: {}", e);
+                    return;
+                }
+            };
+
+            let mut next = Some(first);
+            while let Some(packet) = next {
+                if let Err(e) = sender.send(&packet).await {
+                    warn!("Failed to send refinement: {}", e);
                     break;
                 }
+                let bytes = bincode::serialized_size(&packet).unwrap_or(0);
+                session.refine_bytes.fetch_add(bytes, Ordering::Relaxed);
+                let kbps = session.refine_kbps.load(Ordering::Relaxed).max(1);
+                tokio::time::sleep(Duration::from_secs_f64(
+                    bytes as f64 * 8.0 / (kbps as f64 * 1000.0),
+                ))
+                .await;
+                next = packets.recv().await;
+            }
+        });
+    }
+
+    /// Forward refinement packets from the host's stream
+    fn spawn_refinement_receiver(
+        transport: Arc<QuicTransport>,
+        incoming: mpsc::UnboundedSender<RefinementPacket>,
+    ) {
+        tokio::spawn(async move {
+            let mut receiver = match transport.accept_refinement().await {
+                Ok(receiver) => receiver,
+                Err(e) => {
+                    debug!("Refinement stream unavailable: {}", e);
+                    return;
+                }
+            };
+
+            loop {
+                match receiver.recv().await {
+                    Ok(packet) => {
+                        if incoming.send(packet).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        debug!("Refinement stream closed: {}", e);
+                        break;
+                    }
+                }
             }
+        });
+    }
+
+    /// Create and start a capturer for a display selection
+    fn start_capture(
+        selection: Option<DisplaySelection>,
+        config: &mut CaptureConfig,
+    ) -> CaptureResult<Box<dyn ScreenCapture>> {
+        let mut capturer = match selection {
+            Some(DisplaySelection::All) => capture::create_stitched_capture()?,
+            _ => capture::create_capture()?,
+        };
+        Self::restart_capture(capturer.as_mut(), selection, config)?;
+        Ok(capturer)
+    }
+
+    /// Start a stopped capturer again, on a display selection of the same
+    /// kind (all displays or one)
+    ///
+    /// The backend keeps what it learned, such as the Wayland portal's
+    /// restore token, so switching doesn't prompt the user again.
+    fn restart_capture(
+        capturer: &mut dyn ScreenCapture,
+        selection: Option<DisplaySelection>,
+        config: &mut CaptureConfig,
+    ) -> CaptureResult<()> {
+        if let Some(DisplaySelection::Display(id)) = selection {
+            config.display_id = Some(id);
         }
+        capturer.start(config.clone())
     }
 
-    /// Capture and encode loop (runs on dedicated thread)
+    /// Host video pipeline: captures on the calling thread, and converts
+    /// and encodes on threads of their own
     fn capture_loop(
         session: Arc<Session>,
-        frame_tx: mpsc::Sender<EncodedFrame>,
+        frame_tx: mpsc::Sender<(EncodedFrame, Option<DirtyRect>)>,
+        refine_tx: mpsc::Sender<RefinementPacket>,
     ) -> SessionResult<()> {
         info!("Starting capture loop");
 
-        // Initialize capture
-        let mut capturer =
-            capture::create_capture().map_err(|e| SessionError::Capture(e.to_string()))?;
-
-        let capture_config = CaptureConfig {
-            display_id: None,
-            target_fps: 30,
-            dirty_rects: true,
-            capture_cursor: true,
-            capture_audio: false,
+        let mut capture_config = CaptureConfig {
+            target: session.pending_target.lock().take().unwrap_or_default(),
+            ..Default::default()
         };
 
-        capturer
-            .start(capture_config)
+        let selection = session.pending_display.lock().take();
+        let capturer = Self::start_capture(selection, &mut capture_config)
             .map_err(|e| SessionError::Capture(e.to_string()))?;
 
-        // Initialize encoder
-        let mut encoder = OpenH264Encoder::new();
-
         let displays = capturer
             .displays()
             .map_err(|e| SessionError::Capture(e.to_string()))?;
@@ -766,60 +1578,243 @@ impl ActiveSession {
             .find(|d| d.is_primary)
             .unwrap_or(&displays[0]);
 
+        // Advertise the layout to the viewer
+        let active = selection.unwrap_or(DisplaySelection::Display(
+            capture_config.display_id.unwrap_or(primary.id),
+        ));
+        *session.displays.write() = (
+            displays
+                .iter()
+                .map(|d| RemoteDisplay {
+                    id: d.id,
+                    name: d.name.clone(),
+                    width: d.width,
+                    height: d.height,
+                    x: d.x,
+                    y: d.y,
+                    is_primary: d.is_primary,
+                })
+                .collect(),
+            Some(active),
+        );
+
+        // Cap slices to one datagram so each fragment decodes on its own
+        let packetizer = Packetizer::new(MAX_DATAGRAM_SIZE)
+            .map_err(|e| SessionError::Transport(e.to_string()))?;
         let encoder_config = EncoderConfig {
             width: primary.width,
             height: primary.height,
-            bitrate_kbps: 3000,
-            fps: 30,
-            keyframe_interval: 60,
+            bitrate_kbps: BandwidthConfig::default().initial_bitrate_kbps,
+            fps: capture_config.target_fps,
+            // Periodic keyframes spike the bitrate every few seconds; loss
+            // is repaired by resends, long-term references and requested
+            // keyframes instead
+            keyframe_interval: 0,
             low_latency: true,
+            max_slice_bytes: Some(packetizer.max_payload() as u32),
             ..Default::default()
         };
 
-        encoder
-            .init(encoder_config)
+        // Text compresses much better with AV1, then VP9, so use the
+        // first of those with a backend built in. The bandwidth estimate
+        // retunes the bitrate all the time, so only backends that can
+        // change it without restarting on a keyframe qualify.
+        let registry = EncoderRegistry::with_defaults();
+        let encoder_config = [Codec::AV1, Codec::VP9]
+            .into_iter()
+            .map(|codec| EncoderConfig {
+                codec,
+                ..encoder_config.clone()
+            })
+            .find(|config| {
+                registry
+                    .candidates(config)
+                    .first()
+                    .is_some_and(|backend| backend.capabilities.runtime_bitrate)
+            })
+            .unwrap_or(encoder_config);
+        // Two temporal layers let the sender halve the frame rate under
+        // congestion, where the backend supports them
+        let layered = EncoderConfig {
+            temporal_layers: 2,
+            ..encoder_config.clone()
+        };
+        let encoder_config = if registry.candidates(&layered).is_empty() {
+            encoder_config
+        } else {
+            layered
+        };
+        // Refresh the picture a few blocks at a time where the backend
+        // can, so a viewer that missed something still converges without
+        // a keyframe. OpenH264 and rav1e can't, and go without.
+        let refreshed = EncoderConfig {
+            intra_refresh: true,
+            ..encoder_config.clone()
+        };
+        let encoder_config = if registry.candidates(&refreshed).is_empty() {
+            encoder_config
+        } else {
+            refreshed
+        };
+
+        let selected = registry
+            .create(&encoder_config)
             .map_err(|e| SessionError::Encoding(e.to_string()))?;
+        session.stats.write().encoder = Some(selected.backend.to_string());
+        let retarget_interval = if selected.capabilities.runtime_bitrate {
+            RETARGET_INTERVAL
+        } else {
+            RESTART_RETARGET_INTERVAL
+        };
+        let mut encoder = selected.encoder;
+
+        let frame_duration = Duration::from_secs_f64(1.0 / capture_config.target_fps.max(1) as f64);
+        *session.frame_duration.lock() = frame_duration;
+        let captured = Arc::new(FrameQueue::new(PIPELINE_QUEUE_DEPTH));
+        let converted = Arc::new(FrameQueue::new(PIPELINE_QUEUE_DEPTH));
+        // Converted frames the encoder is done with, for reuse
+        let spare = Arc::new(FrameQueue::new(PIPELINE_QUEUE_DEPTH + 1));
+
+        let convert = {
+            let session = session.clone();
+            let (captured, converted, spare) = (captured.clone(), converted.clone(), spare.clone());
+            let conversion = YuvConversion {
+                color_space: encoder_config.color_space,
+                chroma_444: encoder_config.chroma_444,
+                frame_duration,
+            };
+            std::thread::spawn(move || {
+                Self::convert_stage(
+                    &session, &captured, &converted, &spare, refine_tx, conversion,
+                );
+                captured.close();
+                converted.close();
+            })
+        };
+        let encode = {
+            let session = session.clone();
+            let (converted, spare) = (converted.clone(), spare.clone());
+            std::thread::spawn(move || {
+                let result = Self::encode_stage(
+                    &session,
+                    &converted,
+                    &spare,
+                    encoder.as_mut(),
+                    &frame_tx,
+                    frame_duration,
+                    retarget_interval,
+                );
+                converted.close();
+                result
+            })
+        };
 
-        // Main capture loop
-        let frame_duration = Duration::from_secs_f64(1.0 / 30.0);
-        let mut last_frame = None::<CapturedFrame>;
+        let result = Self::capture_stage(
+            &session,
+            capturer,
+            capture_config,
+            selection,
+            &captured,
+            frame_duration,
+        );
+        captured.close();
 
-        while session.running.load(Ordering::SeqCst) {
+        if convert.join().is_err() {
+            error!("Convert stage panicked");
+        }
+        let encoded = encode
+            .join()
+            .unwrap_or_else(|_| Err(SessionError::Encoding("Encode stage panicked".to_string())));
+        info!("Capture loop ended");
+
+        result.and(encoded)
+    }
+
+    /// Capture stage: grab frames on the capture schedule and switch source
+    /// when asked
+    fn capture_stage(
+        session: &Session,
+        mut capturer: Box<dyn ScreenCapture>,
+        mut capture_config: CaptureConfig,
+        mut selection: Option<DisplaySelection>,
+        captured: &FrameQueue<PipelineFrame>,
+        frame_duration: Duration,
+    ) -> SessionResult<()> {
+        let mut source_changed = false;
+
+        while session.running.load(Ordering::SeqCst) && !captured.is_closed() {
             let loop_start = Instant::now();
 
+            // Switch source if the host picked a window or region, or the
+            // viewer picked another display
+            let target = session.pending_target.lock().take();
+            let display = session.pending_display.lock().take();
+            if target.is_some() || display.is_some() {
+                capturer.stop().ok();
+                let previous = (selection, capture_config.clone());
+                if let Some(target) = target {
+                    capture_config.target = target;
+                }
+                if display.is_some() {
+                    selection = display;
+                    capture_config.target = CaptureTarget::Display;
+                }
+
+                // Only switching to or from all displays needs another
+                // backend; otherwise the running one starts over
+                let stitched =
+                    |selection: Option<DisplaySelection>| selection == Some(DisplaySelection::All);
+                let switched = if stitched(selection) == stitched(previous.0) {
+                    Self::restart_capture(capturer.as_mut(), selection, &mut capture_config)
+                } else {
+                    Self::start_capture(selection, &mut capture_config)
+                        .map(|started| capturer = started)
+                };
+                if let Err(e) = switched {
+                    warn!("Failed to switch capture source: {}", e);
+                    (selection, capture_config) = previous;
+                    Self::restart_capture(capturer.as_mut(), selection, &mut capture_config)
+                        .map_err(|e| SessionError::Capture(e.to_string()))?;
+                }
+                if let Some(selection) = selection {
+                    session.displays.write().1 = Some(selection);
+                }
+                source_changed = true;
+            }
+
             // Capture frame
             match capturer.capture_frame() {
                 Ok(frame) => {
-                    // Optional: Check if frame changed (dirty rect optimization)
-                    let should_encode = if let Some(ref _last) = last_frame {
-                        // Only encode if something changed
-                        !frame.dirty_rects.is_empty() || frame.dirty_percentage() > 0.01
+                    // I420 needs even dimensions; drop the odd edge row/column
+                    let frame = if frame.width % 2 == 1 || frame.height % 2 == 1 {
+                        frame.crop(&DirtyRect::full_screen(frame.width, frame.height))
                     } else {
-                        true
+                        Some(frame)
                     };
 
-                    if should_encode {
-                        // Encode frame
-                        match encoder.encode(&frame) {
-                            Ok(encoded) => {
-                                // Send to main loop
-                                if frame_tx.blocking_send(encoded).is_err() {
-                                    debug!("Frame channel closed, stopping capture");
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                if e.to_string().contains("Empty bitstream") {
-                                    encoder.force_keyframe();
-                                    debug!("Encoder returned empty bitstream, retrying keyframe");
-                                } else {
-                                    warn!("Encoding error: {}", e);
-                                }
-                            }
-                        }
-                    }
+                    if let Some(frame) = frame {
+                        // Spend bits where the user is looking; the pointer
+                        // maps onto the frame only when a display is captured
+                        let cursor = (capture_config.target == CaptureTarget::Display
+                            && selection != Some(DisplaySelection::All))
+                        .then(|| *session.cursor.lock())
+                        .flatten();
+                        let frame = PipelineFrame {
+                            frame,
+                            source_changed,
+                            cursor,
+                        };
+                        source_changed = false;
 
-                    last_frame = Some(frame);
+                        let dropped = captured.push(frame, PipelineFrame::absorb);
+                        let mut stats = session.stats.write();
+                        stats.pipeline.capture.record(loop_start.elapsed());
+                        stats.pipeline.capture.dropped += dropped as u64;
+                    }
+                }
+                Err(CaptureError::Timeout) => {
+                    // Damage-driven backends time out while the screen is idle
+                    debug!("No screen changes, waiting");
                 }
                 Err(e) => {
                     warn!("Capture error: {}", e);
@@ -834,7 +1829,198 @@ impl ActiveSession {
         }
 
         capturer.stop().ok();
-        info!("Capture loop ended");
+        Ok(())
+    }
+
+    /// Convert stage: convert frames for the encoder and refine the parts
+    /// of the screen that stay still
+    fn convert_stage(
+        session: &Session,
+        captured: &FrameQueue<PipelineFrame>,
+        converted: &FrameQueue<(PipelineFrame, YuvFrame)>,
+        spare: &FrameQueue<YuvFrame>,
+        refine_tx: mpsc::Sender<RefinementPacket>,
+        conversion: YuvConversion,
+    ) {
+        let frame_duration = conversion.frame_duration;
+        let mut last_frame = None::<CapturedFrame>;
+
+        // Sharpen regions that stay still with lossless tiles
+        let mut refinement = RefinementTracker::new(DEFAULT_REFINE_STATIC_FRAMES);
+        let mut outbox = RefinementOutbox::new(refine_tx);
+        let mut last_refined = Instant::now();
+
+        while session.running.load(Ordering::SeqCst) && !converted.is_closed() {
+            let Some(pipeline_frame) = captured.pop(frame_duration) else {
+                if captured.is_closed() {
+                    break;
+                }
+                // Nothing new for a frame interval; the screen is idle
+                if let Some(frame) = &last_frame {
+                    let frames = elapsed_frames(&mut last_refined, frame_duration);
+                    let updates = refinement.idle(frame, frames);
+                    outbox.send(&mut refinement, updates);
+                }
+                continue;
+            };
+
+            if pipeline_frame.source_changed
+                && let Some(clear) = refinement.reset()
+            {
+                outbox.send(&mut refinement, Ok(vec![clear]));
+            }
+
+            let start = Instant::now();
+            let mut yuv = spare
+                .pop(Duration::ZERO)
+                .unwrap_or_else(|| YuvFrame::new(conversion.chroma_444));
+            if let Err(e) = yuv.convert(&pipeline_frame.frame, conversion.color_space) {
+                warn!("Conversion error: {}", e);
+                continue;
+            }
+            let frame = pipeline_frame.frame.clone();
+            let dropped = converted.push((pipeline_frame, yuv), |(newer, _), (older, yuv)| {
+                newer.absorb(older);
+                spare.push(yuv, |_, _| {});
+            });
+            {
+                let mut stats = session.stats.write();
+                stats.pipeline.convert.record(start.elapsed());
+                stats.pipeline.convert.dropped += dropped as u64;
+            }
+
+            let frames = elapsed_frames(&mut last_refined, frame_duration);
+            let updates = refinement.update(&frame, frames);
+            outbox.send(&mut refinement, updates);
+            last_frame = Some(frame);
+        }
+    }
+
+    /// Encode stage: encode the freshest converted frame and apply the
+    /// viewer's feedback
+    fn encode_stage(
+        session: &Session,
+        converted: &FrameQueue<(PipelineFrame, YuvFrame)>,
+        spare: &FrameQueue<YuvFrame>,
+        encoder: &mut dyn VideoEncoder,
+        frame_tx: &mpsc::Sender<(EncodedFrame, Option<DirtyRect>)>,
+        frame_duration: Duration,
+        retarget_interval: Duration,
+    ) -> SessionResult<()> {
+        let mut last_retarget = Instant::now();
+        // What changed since the last output; frames the encoder holds
+        // back report with the next one
+        let mut dirty = None::<DirtyRect>;
+        while session.running.load(Ordering::SeqCst) {
+            let Some((pipeline_frame, yuv)) = converted.pop(frame_duration) else {
+                if converted.is_closed() {
+                    break;
+                }
+                continue;
+            };
+            let frame = &pipeline_frame.frame;
+
+            if pipeline_frame.source_changed {
+                encoder.force_keyframe();
+            }
+            let changed = if frame.is_full_update() || pipeline_frame.source_changed {
+                Some(DirtyRect::full_screen(frame.width, frame.height))
+            } else {
+                frame.dirty_bounds()
+            };
+            dirty = merge_dirty(dirty, changed);
+
+            // Lossy link: restart with long-term references so later
+            // losses recover with a P-frame, and without them once the
+            // link recovers; the restart's keyframe covers any loss still
+            // waiting for recovery
+            let long_term_refs = session.long_term_refs.load(Ordering::SeqCst);
+            if long_term_refs != encoder.config().long_term_refs {
+                let config = EncoderConfig {
+                    long_term_refs,
+                    ..encoder.config().clone()
+                };
+                encoder
+                    .init(config)
+                    .map_err(|e| SessionError::Encoding(e.to_string()))?;
+                session.pending_recovery.lock().take();
+            }
+
+            // Viewer feedback: acknowledged frames and losses
+            if let Some(frame_id) = session.pending_ack.lock().take()
+                && let Err(e) = encoder.acknowledge(frame_id)
+            {
+                debug!("Failed to acknowledge frame {}: {}", frame_id, e);
+            }
+            if session.keyframe_requested.swap(false, Ordering::SeqCst) {
+                session.pending_recovery.lock().take();
+                encoder.force_keyframe();
+            } else if let Some(last_good) = session.pending_recovery.lock().take()
+                && let Err(e) = encoder.invalidate_references(last_good)
+            {
+                warn!("Loss recovery failed, sending keyframe: {}", e);
+                encoder.force_keyframe();
+            }
+
+            // Bandwidth estimate, at most once per retarget interval; a
+            // newer estimate replaces one still waiting
+            if last_retarget.elapsed() >= retarget_interval
+                && let Some(bitrate_kbps) = session.pending_bitrate.lock().take()
+            {
+                last_retarget = Instant::now();
+                if let Err(e) = encoder.set_bitrate(bitrate_kbps) {
+                    debug!("Failed to set bitrate to {} kbps: {}", bitrate_kbps, e);
+                }
+            }
+
+            // Display mode changes, window resizes and target switches
+            if frame.width != encoder.config().width || frame.height != encoder.config().height {
+                info!(
+                    "Capture size changed from {}x{} to {}x{}, reconfiguring encoder",
+                    encoder.config().width,
+                    encoder.config().height,
+                    frame.width,
+                    frame.height
+                );
+                encoder
+                    .reconfigure(frame.width, frame.height)
+                    .map_err(|e| SessionError::Encoding(e.to_string()))?;
+            }
+
+            if let Err(e) = encoder.set_roi(&roi_hints(frame, pipeline_frame.cursor)) {
+                debug!("Failed to set ROI hints: {}", e);
+            }
+
+            let start = Instant::now();
+            let encoded = encoder.encode_yuv(&yuv);
+            session
+                .stats
+                .write()
+                .pipeline
+                .encode
+                .record(start.elapsed());
+            spare.push(yuv, |_, _| {});
+
+            match encoded {
+                Ok(encoded) => {
+                    // Send to main loop
+                    if frame_tx.blocking_send((encoded, dirty.take())).is_err() {
+                        debug!("Frame channel closed, stopping encoder");
+                        break;
+                    }
+                }
+                // Output follows with a later frame
+                Err(EncoderError::FrameBuffered) => {}
+                Err(e) => {
+                    if e.to_string().contains("Empty bitstream") {
+                        encoder.force_keyframe();
+                        debug!("Encoder returned empty bitstream, retrying keyframe");
+                    } else {
+                        warn!("Encoding error: {}", e);
+                    }
+                }
+            }
+        }
 
         Ok(())
     }
@@ -871,3 +2057,29 @@ impl ActiveSession {
         info!("Input loop ended");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_term_refs_switch_with_hysteresis() {
+        let mut policy = LongTermRefsPolicy::default();
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // Occasional loss leaves screen content tuning alone
+        assert_eq!(policy.update(0.01, at(0)), None);
+        assert_eq!(policy.update(0.03, at(1)), Some(true));
+
+        // Loss easing off, and a clean spell right after switching, keep
+        // them on
+        assert_eq!(policy.update(0.01, at(5)), None);
+        assert_eq!(policy.update(0.0, at(6)), None);
+
+        // Clean for long enough: back off, and not on again right away
+        assert_eq!(policy.update(0.0, at(12)), Some(false));
+        assert_eq!(policy.update(0.05, at(13)), None);
+        assert_eq!(policy.update(0.05, at(22)), Some(true));
+    }
+}
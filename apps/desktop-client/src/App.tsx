@@ -4,7 +4,11 @@ import { listen } from "@tauri-apps/api/event";
 import { ConnectionCard } from "./components/ConnectionCard";
 import { StatusIndicator } from "./components/StatusIndicator";
 import { VideoCanvas } from "./components/VideoCanvas";
-import { SessionToolbar } from "./components/SessionToolbar";
+import {
+  SessionToolbar,
+  type DisplaySelection,
+  type RemoteDisplay,
+} from "./components/SessionToolbar";
 import { Logo } from "./components/Logo";
 
 type ConnectionState = "disconnected" | "connecting" | "connected" | "error";
@@ -38,6 +42,10 @@ function App() {
   const [hostStarted, setHostStarted] = useState(false);
   const [permissions, setPermissions] = useState<PermissionStatus | null>(null);
   const [showPermissionGate, setShowPermissionGate] = useState(false);
+  const [remoteDisplays, setRemoteDisplays] = useState<RemoteDisplay[]>([]);
+  const [activeDisplay, setActiveDisplay] = useState<DisplaySelection | null>(
+    null,
+  );
 
   // Get our peer ID on mount
   useEffect(() => {
@@ -104,11 +112,20 @@ function App() {
       setIncomingRequestId(event.payload);
     });
 
+    const unlistenDisplays = listen<{
+      displays: RemoteDisplay[];
+      active: DisplaySelection;
+    }>("remote-displays", (event) => {
+      setRemoteDisplays(event.payload.displays);
+      setActiveDisplay(event.payload.active);
+    });
+
     return () => {
       unlistenState.then((fn) => fn());
       unlistenStats.then((fn) => fn());
       unlistenError.then((fn) => fn());
       unlistenIncoming.then((fn) => fn());
+      unlistenDisplays.then((fn) => fn());
     };
   }, []);
 
@@ -202,6 +219,8 @@ function App() {
       setConnectionState("disconnected");
       setIsSessionActive(false);
       setSessionStats(null);
+      setRemoteDisplays([]);
+      setActiveDisplay(null);
       setActivePeerId(null);
     } catch (err) {
       console.error("Disconnect failed:", err);
@@ -249,6 +268,19 @@ function App() {
     }
   };
 
+  const handleSelectDisplay = async (selection: DisplaySelection) => {
+    try {
+      const peerId = activePeerId ?? remotePeerId;
+      if (!peerId) return;
+      await invoke("select_remote_display", {
+        peerId,
+        displayId: selection === "all" ? null : selection,
+      });
+    } catch (err) {
+      console.error("Display switch failed:", err);
+    }
+  };
+
   const handleOpenSettings = async (
     kind: "screen_recording" | "accessibility",
   ) => {
@@ -343,6 +375,9 @@ function App() {
           <VideoCanvas remotePeerId={remotePeerId} />
           <SessionToolbar
             stats={sessionStats}
+            displays={remoteDisplays}
+            activeDisplay={activeDisplay}
+            onSelectDisplay={handleSelectDisplay}
             onDisconnect={handleDisconnect}
             onRequestKeyframe={handleRequestKeyframe}
           />
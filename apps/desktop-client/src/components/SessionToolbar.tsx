@@ -1,20 +1,46 @@
 import { useRef, useState } from "react";
-import { PhoneOff, RefreshCcw, Settings, Shield, Activity } from "lucide-react";
+import {
+  PhoneOff,
+  RefreshCcw,
+  Settings,
+  Shield,
+  Activity,
+  Monitor,
+} from "lucide-react";
 import { Button } from "./ui/button";
 import { Badge } from "./ui/badge";
 
+export interface RemoteDisplay {
+  id: number;
+  name: string;
+  width: number;
+  height: number;
+  x: number;
+  y: number;
+  is_primary: boolean;
+}
+
+/** Display id, or "all" for the stitched view */
+export type DisplaySelection = number | "all";
+
 interface SessionToolbarProps {
   stats: {
     rtt_ms: number;
     fps: number;
     bitrate_kbps: number;
   } | null;
+  displays: RemoteDisplay[];
+  activeDisplay: DisplaySelection | null;
+  onSelectDisplay: (selection: DisplaySelection) => void;
   onDisconnect: () => void;
   onRequestKeyframe: () => void;
 }
 
 export function SessionToolbar({
   stats,
+  displays,
+  activeDisplay,
+  onSelectDisplay,
   onDisconnect,
   onRequestKeyframe,
 }: SessionToolbarProps) {
@@ -69,6 +95,34 @@ export function SessionToolbar({
           )}
         </div>
 
+        {/* Display Picker */}
+        {displays.length > 1 && (
+          <div className="flex items-center gap-1 px-2 border-r border-white/10">
+            <Monitor className="w-3 h-3 text-white/50 mr-1" />
+            {displays.map((display, index) => (
+              <Button
+                key={display.id}
+                variant={activeDisplay === display.id ? "secondary" : "ghost"}
+                size="sm"
+                className="h-6 px-2 rounded-full text-[10px] font-mono"
+                onClick={() => onSelectDisplay(display.id)}
+                title={`${display.name} (${display.width}x${display.height})`}
+              >
+                {index + 1}
+              </Button>
+            ))}
+            <Button
+              variant={activeDisplay === "all" ? "secondary" : "ghost"}
+              size="sm"
+              className="h-6 px-2 rounded-full text-[10px] uppercase"
+              onClick={() => onSelectDisplay("all")}
+              title="All displays"
+            >
+              All
+            </Button>
+          </div>
+        )}
+
         {/* Controls Area */}
         <div className="flex items-center gap-2">
           <Button
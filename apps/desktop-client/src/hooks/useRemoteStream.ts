@@ -6,13 +6,64 @@ interface RemoteStreamOptions {
   enabled: boolean;
 }
 
+type VideoCodec = "H264" | "H265" | "VP9" | "AV1";
+
 interface VideoFrameEvent {
   data: number[];
+  codec: VideoCodec;
   is_keyframe: boolean;
   timestamp_us: number;
   width: number;
   height: number;
   frame_id: number;
+  resolution_changed: boolean;
+  // Bounds of what changed since the previous frame
+  dirty_rect: Rect | null;
+}
+
+interface Rect {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+// Lossless tiles for static regions, kept on an overlay drawn over
+// every decoded frame until a frame changes them or the host invalidates
+// them
+type RefinementEvent =
+  | { kind: "tile"; rect: Rect; png: number[] }
+  | { kind: "invalidate"; rects: Rect[] }
+  | { kind: "clear" };
+
+function rectsIntersect(a: Rect, b: Rect): boolean {
+  return (
+    a.x < b.x + b.width &&
+    b.x < a.x + a.width &&
+    a.y < b.y + b.height &&
+    b.y < a.y + a.height
+  );
+}
+
+function unionRect(a: Rect, b: Rect): Rect {
+  const x = Math.min(a.x, b.x);
+  const y = Math.min(a.y, b.y);
+  return {
+    x,
+    y,
+    width: Math.max(a.x + a.width, b.x + b.width) - x,
+    height: Math.max(a.y + a.height, b.y + b.height) - y,
+  };
+}
+
+// AV1 Main profile, level 5.1, 8-bit: covers up to 4K at 60fps
+const AV1_CODEC = "av01.0.13M.08";
+
+// VP9 at level 4.1, 8-bit, with the profile read from a keyframe's
+// uncompressed header: 0 for 4:2:0, 1 for 4:4:4
+function vp9CodecString(keyframe: Uint8Array): string {
+  const profile = ((keyframe[0] >> 5) & 1) | (((keyframe[0] >> 4) & 1) << 1);
+  return `vp09.0${profile}.41.08`;
 }
 
 function splitAnnexBNals(data: Uint8Array): Uint8Array[] {
@@ -110,6 +161,17 @@ export function useRemoteStream({ canvasRef, enabled }: RemoteStreamOptions) {
   const lastFrameTimeRef = useRef(0);
   const spsRef = useRef<Uint8Array | null>(null);
   const ppsRef = useRef<Uint8Array | null>(null);
+  // Resolution the decoder is currently configured for
+  const configuredSizeRef = useRef<{ width: number; height: number } | null>(
+    null
+  );
+  const overlayRef = useRef<HTMLCanvasElement | null>(null);
+  // Tiles drawn on the overlay
+  const tilesRef = useRef<Rect[]>([]);
+  // Changed regions of frames sent to the decoder, by timestamp
+  const dirtyRectsRef = useRef(new Map<number, Rect>());
+  // Tiles decode asynchronously; chain updates so they apply in order
+  const refinementQueueRef = useRef<Promise<void>>(Promise.resolve());
 
   const initDecoder = useCallback(() => {
     if (!("VideoDecoder" in window)) {
@@ -127,8 +189,38 @@ export function useRemoteStream({ canvasRef, enabled }: RemoteStreamOptions) {
     decoderRef.current = new VideoDecoder({
       output: (frame) => {
         // Render the frame to canvas as soon as it's decoded
-        ctx.drawImage(frame, 0, 0, canvasRef.current!.width, canvasRef.current!.height);
+        const canvas = canvasRef.current!;
+        ctx.drawImage(frame, 0, 0, canvas.width, canvas.height);
+
+        // What changed up to this frame, including frames that never
+        // made it out of the decoder
+        let dirty: Rect | null = null;
+        for (const [timestamp, rect] of dirtyRectsRef.current) {
+          if (timestamp > frame.timestamp) break;
+          dirty = dirty ? unionRect(dirty, rect) : rect;
+          dirtyRectsRef.current.delete(timestamp);
+        }
         frame.close(); // Important to release resources
+
+        const overlay = overlayRef.current;
+        if (
+          overlay &&
+          overlay.width === canvas.width &&
+          overlay.height === canvas.height
+        ) {
+          // Tiles the frame paints over are stale; don't wait for the
+          // host's invalidation, which queues behind other tiles
+          const changed = dirty;
+          if (changed) {
+            const overlayCtx = overlay.getContext("2d");
+            tilesRef.current = tilesRef.current.filter((tile) => {
+              if (!rectsIntersect(tile, changed)) return true;
+              overlayCtx?.clearRect(tile.x, tile.y, tile.width, tile.height);
+              return false;
+            });
+          }
+          ctx.drawImage(overlay, 0, 0);
+        }
         
         frameCountRef.current++;
         const now = performance.now();
@@ -145,22 +237,109 @@ export function useRemoteStream({ canvasRef, enabled }: RemoteStreamOptions) {
     // Configure happens lazily once we have SPS/PPS
   }, [canvasRef]);
 
+  const applyRefinement = useCallback(
+    async (update: RefinementEvent) => {
+      const canvas = canvasRef.current;
+      if (!canvas) return;
+
+      let overlay = overlayRef.current;
+      if (!overlay) {
+        overlay = document.createElement("canvas");
+        overlayRef.current = overlay;
+        tilesRef.current = [];
+      }
+      if (overlay.width !== canvas.width || overlay.height !== canvas.height) {
+        overlay.width = canvas.width;
+        overlay.height = canvas.height;
+        tilesRef.current = [];
+      }
+      const overlayCtx = overlay.getContext("2d");
+      if (!overlayCtx) return;
+
+      switch (update.kind) {
+        case "tile": {
+          const bitmap = await createImageBitmap(
+            new Blob([new Uint8Array(update.png)], { type: "image/png" })
+          );
+          const { x, y } = update.rect;
+          overlayCtx.drawImage(bitmap, x, y);
+          tilesRef.current.push(update.rect);
+          // Sharpen right away rather than waiting for the next frame
+          canvas.getContext("2d")?.drawImage(bitmap, x, y);
+          bitmap.close();
+          break;
+        }
+        case "invalidate":
+          for (const rect of update.rects) {
+            overlayCtx.clearRect(rect.x, rect.y, rect.width, rect.height);
+          }
+          tilesRef.current = tilesRef.current.filter(
+            (tile) => !update.rects.some((rect) => rectsIntersect(tile, rect))
+          );
+          break;
+        case "clear":
+          overlayCtx.clearRect(0, 0, overlay.width, overlay.height);
+          tilesRef.current = [];
+          break;
+      }
+    },
+    [canvasRef]
+  );
+
   useEffect(() => {
     if (!enabled) {
       if (decoderRef.current) {
         decoderRef.current.close();
         decoderRef.current = null;
       }
+      configuredSizeRef.current = null;
+      overlayRef.current = null;
       return;
     }
 
     initDecoder();
 
+    const unlistenRefinement = listen<RefinementEvent>("refinement", (event) => {
+      const update = event.payload;
+      refinementQueueRef.current = refinementQueueRef.current
+        .then(() => applyRefinement(update))
+        .catch((e) => console.warn("Refinement failed:", e));
+    });
+
     // Listen for frame events from Tauri
     const unlisten = listen<VideoFrameEvent>("video-frame", (event) => {
       if (!decoderRef.current) return;
 
-      const { data, is_keyframe, timestamp_us, width, height } = event.payload;
+      const {
+        data,
+        codec,
+        is_keyframe,
+        timestamp_us,
+        width,
+        height,
+        resolution_changed,
+        dirty_rect,
+      } = event.payload;
+
+      const configured = configuredSizeRef.current;
+      const sizeChanged =
+        configured !== null &&
+        (configured.width !== width || configured.height !== height);
+
+      if (sizeChanged && !is_keyframe) {
+        // Stale delta from before the switch; the keyframe is on its way
+        return;
+      }
+
+      if (resolution_changed || sizeChanged) {
+        // New SPS/PPS follow with this keyframe; reconfigure from scratch
+        if (decoderRef.current.state === "configured") {
+          decoderRef.current.reset();
+        }
+        configuredSizeRef.current = null;
+        spsRef.current = null;
+        ppsRef.current = null;
+      }
 
       if (canvasRef.current && width && height) {
         if (
@@ -169,10 +348,49 @@ export function useRemoteStream({ canvasRef, enabled }: RemoteStreamOptions) {
         ) {
           canvasRef.current.width = width;
           canvasRef.current.height = height;
+          // Tiles from the old size no longer line up
+          overlayRef.current = null;
         }
       }
 
       const chunkData = new Uint8Array(data);
+
+      const decode = (sample: Uint8Array) => {
+        if (dirty_rect) {
+          dirtyRectsRef.current.set(timestamp_us, dirty_rect);
+        }
+        const chunk = new EncodedVideoChunk({
+          type: is_keyframe ? "key" : "delta",
+          timestamp: timestamp_us, // microseconds
+          duration: 0,
+          data: sample,
+        });
+
+        try {
+          decoderRef.current?.decode(chunk);
+        } catch (e) {
+          console.warn("Decode failed:", e);
+        }
+      };
+
+      if (codec === "AV1" || codec === "VP9") {
+        // AV1 temporal units and VP9 frames decode as they are; the
+        // stream parameters travel in-band with each keyframe
+        if (decoderRef.current.state !== "configured") {
+          if (!is_keyframe) return;
+          decoderRef.current.configure({
+            codec: codec === "AV1" ? AV1_CODEC : vp9CodecString(chunkData),
+            codedWidth: width,
+            codedHeight: height,
+            optimizeForLatency: true,
+            hardwareAcceleration: "prefer-hardware",
+          });
+          configuredSizeRef.current = { width, height };
+        }
+        decode(chunkData);
+        return;
+      }
+
       const nals = splitAnnexBNals(chunkData);
 
       for (const nal of nals) {
@@ -195,6 +413,8 @@ export function useRemoteStream({ canvasRef, enabled }: RemoteStreamOptions) {
             decoderRef.current.configure({
               codec,
               description,
+              codedWidth: width,
+              codedHeight: height,
               optimizeForLatency: true,
               hardwareAcceleration: "prefer-hardware",
             });
@@ -203,10 +423,13 @@ export function useRemoteStream({ canvasRef, enabled }: RemoteStreamOptions) {
             decoderRef.current.configure({
               codec: "avc1.42e01e",
               description,
+              codedWidth: width,
+              codedHeight: height,
               optimizeForLatency: true,
               hardwareAcceleration: "prefer-hardware",
             });
           }
+          configuredSizeRef.current = { width, height };
         }
       }
 
@@ -214,29 +437,20 @@ export function useRemoteStream({ canvasRef, enabled }: RemoteStreamOptions) {
         return;
       }
 
-      const avccSample = toAvccSample(nals);
-      const chunk = new EncodedVideoChunk({
-        type: is_keyframe ? "key" : "delta",
-        timestamp: timestamp_us, // microseconds
-        duration: 0,
-        data: avccSample,
-      });
-
-      try {
-        decoderRef.current.decode(chunk);
-      } catch (e) {
-        console.warn("Decode failed:", e);
-      }
+      decode(toAvccSample(nals));
     });
 
     return () => {
       unlisten.then((fn) => fn());
+      unlistenRefinement.then((fn) => fn());
       if (decoderRef.current) {
         decoderRef.current.close();
         decoderRef.current = null;
       }
+      configuredSizeRef.current = null;
+      overlayRef.current = null;
     };
-  }, [enabled, initDecoder]);
+  }, [enabled, initDecoder, applyRefinement]);
 
   return {
     isSupported: "VideoDecoder" in window,
@@ -0,0 +1,40 @@
+//! Dirty-rect detection on representative screen content
+//!
+//! Feeds each clip to [`FrameDiffer`] as full updates, the way backends
+//! without damage reporting deliver them, and measures the tile diff.
+
+use capture::{ClipRenderer, DirtyRect, FrameDiffer, ScreenClip};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+/// Frames rendered per clip and cycled through while measuring
+const FRAMES: usize = 30;
+
+fn bench_frame_differ(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_differ");
+    let (width, height) = (1920, 1080);
+    group.throughput(Throughput::Elements((width * height) as u64));
+
+    for clip in ScreenClip::ALL {
+        let mut renderer = ClipRenderer::new(clip, width, height);
+        let frames: Vec<_> = (0..FRAMES)
+            .map(|_| {
+                let mut frame = renderer.next_frame();
+                frame.dirty_rects = vec![DirtyRect::full_screen(width, height)];
+                frame
+            })
+            .collect();
+
+        let mut differ = FrameDiffer::new();
+        let mut index = 0;
+        group.bench_function(BenchmarkId::from_parameter(clip.name()), |b| {
+            b.iter(|| {
+                index = (index + 1) % FRAMES;
+                differ.diff(&frames[index])
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_frame_differ);
+criterion_main!(benches);
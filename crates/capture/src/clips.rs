@@ -0,0 +1,656 @@
+//! Representative screen-content clips for benchmarks
+//!
+//! Each [`ScreenClip`] renders a deterministic sequence of frames that
+//! stresses the encoder the way a real desktop does: a few glyphs at a
+//! time while typing, whole-viewport motion while scrolling, natural
+//! high-entropy content in a video, and a large rect moving over a
+//! static background while dragging a window. Every frame reports the
+//! exact regions that changed since the previous one.
+
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use crate::synthetic::{glyph_bits, line_text};
+use crate::{CapturedFrame, DirtyRect, PixelFormat};
+
+/// Glyph cell size in pixels
+const CELL_WIDTH: u32 = 8;
+const LINE_HEIGHT: u32 = 16;
+/// Caret width in pixels
+const CARET_WIDTH: u32 = 2;
+/// Pixels scrolled per frame
+const SCROLL_STEP: u32 = 12;
+/// Pixels the dragged window moves per frame, horizontally and vertically
+const DRAG_STEP: (u32, u32) = (9, 5);
+/// Height of the browser toolbar and window title bars
+const TOOLBAR_HEIGHT: u32 = 32;
+
+const EDITOR_BACKGROUND: [u8; 3] = [30, 30, 30];
+const SIDEBAR_BACKGROUND: [u8; 3] = [37, 37, 38];
+const GUTTER_TEXT: [u8; 3] = [133, 133, 133];
+const CARET_COLOR: [u8; 3] = [174, 175, 173];
+const PAGE_BACKGROUND: [u8; 3] = [255, 255, 255];
+const PAGE_TEXT: [u8; 3] = [32, 33, 36];
+const TOOLBAR_BACKGROUND: [u8; 3] = [222, 225, 230];
+const TITLE_BAR: [u8; 3] = [52, 101, 164];
+const SYNTAX_COLORS: [[u8; 3]; 4] = [
+    [212, 212, 212],
+    [86, 156, 214],
+    [206, 145, 120],
+    [181, 206, 168],
+];
+const ACCENT_COLORS: [[u8; 3]; 4] = [[26, 115, 232], [217, 48, 37], [30, 142, 62], [249, 171, 0]];
+
+/// Kind of screen activity a clip reproduces
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenClip {
+    /// Typing into a code editor: a glyph and the caret change per frame
+    Typing,
+    /// Scrolling a long web page of text and images
+    Scrolling,
+    /// Playing a video in the middle of an otherwise static page
+    Video,
+    /// Dragging a window across the desktop
+    WindowDrag,
+}
+
+impl ScreenClip {
+    /// Every clip, in reporting order
+    pub const ALL: [ScreenClip; 4] = [
+        ScreenClip::Typing,
+        ScreenClip::Scrolling,
+        ScreenClip::Video,
+        ScreenClip::WindowDrag,
+    ];
+
+    /// Short name for reports and benchmark ids
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScreenClip::Typing => "typing",
+            ScreenClip::Scrolling => "scrolling",
+            ScreenClip::Video => "video",
+            ScreenClip::WindowDrag => "window_drag",
+        }
+    }
+}
+
+/// BGRA pixel buffer
+#[derive(Clone)]
+struct Canvas {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: u32, height: u32, color: [u8; 3]) -> Self {
+        let mut canvas = Self {
+            width,
+            height,
+            data: vec![0; width as usize * height as usize * 4],
+        };
+        canvas.fill(DirtyRect::full_screen(width, height), color);
+        canvas
+    }
+
+    fn clip(&self, rect: DirtyRect) -> Option<DirtyRect> {
+        DirtyRect::full_screen(self.width, self.height).intersect(&rect)
+    }
+
+    fn row_mut(&mut self, x: u32, y: u32, width: u32) -> &mut [u8] {
+        let start = (y as usize * self.width as usize + x as usize) * 4;
+        &mut self.data[start..start + width as usize * 4]
+    }
+
+    fn fill(&mut self, rect: DirtyRect, color: [u8; 3]) {
+        let Some(rect) = self.clip(rect) else {
+            return;
+        };
+        let pixel = [color[2], color[1], color[0], 255];
+        for y in rect.y..rect.y + rect.height {
+            for chunk in self.row_mut(rect.x, y, rect.width).chunks_exact_mut(4) {
+                chunk.copy_from_slice(&pixel);
+            }
+        }
+    }
+
+    fn put(&mut self, x: u32, y: u32, color: [u8; 3]) {
+        if x < self.width && y < self.height {
+            self.row_mut(x, y, 1)
+                .copy_from_slice(&[color[2], color[1], color[0], 255]);
+        }
+    }
+
+    /// Draw one 5x7 glyph in an 8x16 cell
+    fn draw_glyph(&mut self, ch: u8, x: u32, y: u32, color: [u8; 3]) {
+        let bits = glyph_bits(ch);
+        for row in 0..7u32 {
+            for col in 0..5u32 {
+                if bits & (1 << (row * 5 + col)) != 0 {
+                    self.put(x + 1 + col, y + 2 * row + 1, color);
+                    self.put(x + 1 + col, y + 2 * row + 2, color);
+                }
+            }
+        }
+    }
+
+    /// Draw text, one color per word when `colors` has more than one
+    fn draw_text(&mut self, text: &str, x: u32, y: u32, max_x: u32, colors: &[[u8; 3]]) {
+        let mut word = 0;
+        for (column, ch) in text.bytes().enumerate() {
+            let cell_x = x + column as u32 * CELL_WIDTH;
+            if cell_x + CELL_WIDTH > max_x {
+                break;
+            }
+            if ch == b' ' {
+                word += 1;
+            } else {
+                self.draw_glyph(ch, cell_x, y, colors[word % colors.len()]);
+            }
+        }
+    }
+
+    /// Copy `source`'s rows `source_y..` into `rect` of this canvas
+    fn blit(&mut self, source: &Canvas, source_x: u32, source_y: u32, rect: DirtyRect) {
+        let Some(rect) = self.clip(rect) else {
+            return;
+        };
+        for row in 0..rect.height {
+            let start = ((source_y + row) as usize * source.width as usize + source_x as usize) * 4;
+            let source_row = &source.data[start..start + rect.width as usize * 4];
+            self.row_mut(rect.x, rect.y + row, rect.width)
+                .copy_from_slice(source_row);
+        }
+    }
+}
+
+/// Renders a [`ScreenClip`] frame by frame
+pub struct ClipRenderer {
+    clip: ScreenClip,
+    canvas: Canvas,
+    sequence: u64,
+    scene: Scene,
+}
+
+/// Per-clip state carried between frames
+enum Scene {
+    Typing {
+        editor: DirtyRect,
+        /// Line and column the next glyph goes to
+        line: u32,
+        column: u32,
+        text: String,
+    },
+    Scrolling {
+        page: Canvas,
+        viewport: DirtyRect,
+    },
+    Video {
+        video: DirtyRect,
+    },
+    WindowDrag {
+        background: Canvas,
+        window: Canvas,
+        position: (u32, u32),
+    },
+}
+
+impl ClipRenderer {
+    /// Create a renderer for `clip` at `width` x `height`
+    pub fn new(clip: ScreenClip, width: u32, height: u32) -> Self {
+        let (width, height) = (width.max(64), height.max(64));
+        let (canvas, scene) = match clip {
+            ScreenClip::Typing => Self::typing(width, height),
+            ScreenClip::Scrolling => Self::scrolling(width, height),
+            ScreenClip::Video => Self::video(width, height),
+            ScreenClip::WindowDrag => Self::window_drag(width, height),
+        };
+        Self {
+            clip,
+            canvas,
+            sequence: 0,
+            scene,
+        }
+    }
+
+    pub fn clip(&self) -> ScreenClip {
+        self.clip
+    }
+
+    /// Render the next frame
+    ///
+    /// The first frame is a full update; later ones list what changed.
+    pub fn next_frame(&mut self) -> CapturedFrame {
+        let dirty_rects = if self.sequence == 0 {
+            self.render();
+            vec![DirtyRect::full_screen(
+                self.canvas.width,
+                self.canvas.height,
+            )]
+        } else {
+            self.render()
+        };
+        let frame = CapturedFrame {
+            data: Bytes::copy_from_slice(&self.canvas.data),
+            width: self.canvas.width,
+            height: self.canvas.height,
+            stride: self.canvas.width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence: self.sequence,
+            dirty_rects,
+            display_id: 1,
+        };
+        self.sequence += 1;
+        frame
+    }
+
+    /// Advance the scene by one frame and return what changed
+    fn render(&mut self) -> Vec<DirtyRect> {
+        let sequence = self.sequence;
+        let canvas = &mut self.canvas;
+        match &mut self.scene {
+            Scene::Typing {
+                editor,
+                line,
+                column,
+                text,
+            } => {
+                let visible_lines = editor.height / LINE_HEIGHT;
+                let caret = |line: u32, column: u32| {
+                    DirtyRect::new(
+                        editor.x + column * CELL_WIDTH,
+                        editor.y + line * LINE_HEIGHT,
+                        CARET_WIDTH,
+                        LINE_HEIGHT,
+                    )
+                };
+                let old_caret = caret(*line, *column);
+                canvas.fill(old_caret, EDITOR_BACKGROUND);
+
+                let mut dirty = Vec::new();
+                let fits = (*column + 1) * CELL_WIDTH + CARET_WIDTH <= editor.width;
+                if let Some(&ch) = text.as_bytes().get(*column as usize)
+                    && fits
+                {
+                    let cell = DirtyRect::new(
+                        old_caret.x,
+                        old_caret.y,
+                        CELL_WIDTH + CARET_WIDTH,
+                        LINE_HEIGHT,
+                    );
+                    let color = SYNTAX_COLORS
+                        [text[..*column as usize].matches(' ').count() % SYNTAX_COLORS.len()];
+                    canvas.draw_glyph(ch, cell.x, cell.y, color);
+                    *column += 1;
+                    dirty.push(cell);
+                } else {
+                    // Line done: start the next one, wrapping back to the
+                    // top of a cleared editor once it fills up
+                    dirty.push(old_caret);
+                    *line += 1;
+                    *column = 0;
+                    *text = line_text(sequence);
+                    if *line >= visible_lines {
+                        canvas.fill(*editor, EDITOR_BACKGROUND);
+                        dirty = vec![*editor];
+                        *line = visible_lines / 2;
+                    }
+                    dirty.push(caret(*line, *column));
+                }
+                canvas.fill(caret(*line, *column), CARET_COLOR);
+                dirty
+            }
+            Scene::Scrolling { page, viewport } => {
+                let range = page.height - viewport.height;
+                let offset = (sequence as u32 * SCROLL_STEP) % range.max(1);
+                canvas.blit(page, 0, offset, *viewport);
+                vec![*viewport]
+            }
+            Scene::Video { video } => {
+                draw_video(canvas, *video, sequence);
+                vec![*video]
+            }
+            Scene::WindowDrag {
+                background,
+                window,
+                position,
+            } => {
+                let old = DirtyRect::new(position.0, position.1, window.width, window.height);
+                let new_position = drag_position(
+                    sequence,
+                    canvas.width - window.width,
+                    canvas.height - window.height,
+                );
+                let new =
+                    DirtyRect::new(new_position.0, new_position.1, window.width, window.height);
+                canvas.blit(background, old.x, old.y, old);
+                canvas.blit(window, 0, 0, new);
+                *position = new_position;
+                vec![old, new]
+            }
+        }
+    }
+
+    fn typing(width: u32, height: u32) -> (Canvas, Scene) {
+        let mut canvas = Canvas::new(width, height, EDITOR_BACKGROUND);
+        let sidebar = DirtyRect::new(0, 0, width / 5, height);
+        canvas.fill(sidebar, SIDEBAR_BACKGROUND);
+        for row in 0..height / LINE_HEIGHT {
+            let name = format!(
+                "  {}.rs",
+                line_text(1000 + row as u64).trim().replace(' ', "_")
+            );
+            canvas.draw_text(
+                &name,
+                0,
+                row * LINE_HEIGHT,
+                sidebar.width,
+                &[SYNTAX_COLORS[0]],
+            );
+        }
+
+        // Line numbers, then the code being edited
+        let gutter = 5 * CELL_WIDTH;
+        let editor = DirtyRect::new(
+            sidebar.width + gutter,
+            0,
+            width - sidebar.width - gutter,
+            height / LINE_HEIGHT * LINE_HEIGHT,
+        );
+        let visible_lines = editor.height / LINE_HEIGHT;
+        for row in 0..visible_lines {
+            canvas.draw_text(
+                &format!("{:>4}", row + 1),
+                sidebar.width,
+                row * LINE_HEIGHT,
+                editor.x,
+                &[GUTTER_TEXT],
+            );
+        }
+        let typed_from = visible_lines / 2;
+        for row in 0..typed_from {
+            canvas.draw_text(
+                &line_text(row as u64),
+                editor.x,
+                row * LINE_HEIGHT,
+                editor.x + editor.width,
+                &SYNTAX_COLORS,
+            );
+        }
+
+        let scene = Scene::Typing {
+            editor,
+            line: typed_from,
+            column: 0,
+            text: line_text(typed_from as u64),
+        };
+        (canvas, scene)
+    }
+
+    fn scrolling(width: u32, height: u32) -> (Canvas, Scene) {
+        let mut canvas = Canvas::new(width, height, PAGE_BACKGROUND);
+        let toolbar = DirtyRect::new(0, 0, width, TOOLBAR_HEIGHT.min(height / 4));
+        canvas.fill(toolbar, TOOLBAR_BACKGROUND);
+        canvas.fill(
+            DirtyRect::new(
+                width / 8,
+                toolbar.height / 4,
+                width * 3 / 4,
+                toolbar.height / 2,
+            ),
+            PAGE_BACKGROUND,
+        );
+        let viewport = DirtyRect::new(0, toolbar.height, width, height - toolbar.height);
+
+        // A page four viewports tall of headings, paragraphs and images
+        let mut page = Canvas::new(width, viewport.height * 4, PAGE_BACKGROUND);
+        let margin = width / 10;
+        let mut y = LINE_HEIGHT;
+        let mut block = 0u64;
+        while y + LINE_HEIGHT < page.height {
+            match block % 4 {
+                0 => {
+                    let heading = DirtyRect::new(margin, y, width - 2 * margin, 2 * LINE_HEIGHT);
+                    page.fill(
+                        heading,
+                        ACCENT_COLORS[(block / 4) as usize % ACCENT_COLORS.len()],
+                    );
+                    y += 3 * LINE_HEIGHT;
+                }
+                1 | 2 => {
+                    for line in 0..6 {
+                        page.draw_text(
+                            &line_text(block * 8 + line),
+                            margin,
+                            y,
+                            width - margin,
+                            &[PAGE_TEXT],
+                        );
+                        y += LINE_HEIGHT;
+                    }
+                    y += LINE_HEIGHT;
+                }
+                _ => {
+                    let image =
+                        DirtyRect::new(margin, y, (width - 2 * margin) / 2, 8 * LINE_HEIGHT);
+                    let image = page.clip(image).unwrap_or(image);
+                    for row in image.y..image.y + image.height {
+                        for column in image.x..image.x + image.width {
+                            let (u, v) = (column - image.x, row - image.y);
+                            page.put(
+                                column,
+                                row,
+                                [
+                                    (u * 255 / image.width) as u8,
+                                    (v * 2) as u8,
+                                    (block * 40) as u8,
+                                ],
+                            );
+                        }
+                    }
+                    y += image.height + LINE_HEIGHT;
+                }
+            }
+            block += 1;
+        }
+
+        (canvas, Scene::Scrolling { page, viewport })
+    }
+
+    fn video(width: u32, height: u32) -> (Canvas, Scene) {
+        let mut canvas = Canvas::new(width, height, PAGE_BACKGROUND);
+        canvas.fill(
+            DirtyRect::new(0, 0, width, TOOLBAR_HEIGHT.min(height / 4)),
+            TOOLBAR_BACKGROUND,
+        );
+        // A 16:9 player covering half the page's width
+        let video_width = (width / 2) & !1;
+        let video_height = (video_width * 9 / 16).min(height / 2) & !1;
+        let video = DirtyRect::new(
+            ((width - video_width) / 2) & !1,
+            ((height - video_height) / 2) & !1,
+            video_width,
+            video_height,
+        );
+        let below = video.y + video.height + LINE_HEIGHT;
+        for line in 0..(height.saturating_sub(below) / LINE_HEIGHT) {
+            canvas.draw_text(
+                &line_text(line as u64),
+                video.x,
+                below + line * LINE_HEIGHT,
+                video.x + video.width,
+                &[PAGE_TEXT],
+            );
+        }
+        (canvas, Scene::Video { video })
+    }
+
+    fn window_drag(width: u32, height: u32) -> (Canvas, Scene) {
+        let mut background = Canvas::new(width, height, [0, 0, 0]);
+        for y in 0..height {
+            for x in 0..width {
+                background.put(
+                    x,
+                    y,
+                    [
+                        (20 + y * 60 / height) as u8,
+                        (60 + x * 80 / width) as u8,
+                        (120 + y * 100 / height) as u8,
+                    ],
+                );
+            }
+        }
+
+        let mut window = Canvas::new(width * 2 / 5, height * 2 / 5, PAGE_BACKGROUND);
+        let title_height = TOOLBAR_HEIGHT.min(window.height / 4);
+        window.fill(DirtyRect::new(0, 0, window.width, title_height), TITLE_BAR);
+        window.draw_text(
+            "Terminal",
+            CELL_WIDTH,
+            (title_height.saturating_sub(LINE_HEIGHT)) / 2,
+            window.width,
+            &[PAGE_BACKGROUND],
+        );
+        for line in 0..(window.height - title_height) / LINE_HEIGHT {
+            window.draw_text(
+                &line_text(500 + line as u64),
+                CELL_WIDTH,
+                title_height + line * LINE_HEIGHT,
+                window.width,
+                &[PAGE_TEXT],
+            );
+        }
+
+        let mut canvas = background.clone();
+        let position = drag_position(0, width - window.width, height - window.height);
+        canvas.blit(
+            &window,
+            0,
+            0,
+            DirtyRect::new(position.0, position.1, window.width, window.height),
+        );
+        let scene = Scene::WindowDrag {
+            background,
+            window,
+            position,
+        };
+        (canvas, scene)
+    }
+}
+
+/// Window position at `sequence`, bouncing within `max_x` x `max_y`
+fn drag_position(sequence: u64, max_x: u32, max_y: u32) -> (u32, u32) {
+    let bounce = |step: u32, max: u32| {
+        if max == 0 {
+            return 0;
+        }
+        let t = (sequence * step as u64 % (2 * max as u64)) as u32;
+        if t <= max { t } else { 2 * max - t }
+    };
+    (bounce(DRAG_STEP.0, max_x), bounce(DRAG_STEP.1, max_y))
+}
+
+/// Render a frame of moving color fields, a bright disc and film grain
+fn draw_video(canvas: &mut Canvas, video: DirtyRect, sequence: u64) {
+    let t = sequence as i64;
+    let disc_x = (t * 7).rem_euclid(video.width as i64);
+    let disc_y = (t * 3).rem_euclid(video.height as i64);
+    let radius = (video.height / 6) as i64;
+    for row in 0..video.height {
+        for column in 0..video.width {
+            let (x, y) = (column as i64, row as i64);
+            let wave =
+                |scale: i64, speed: i64| triangle(x * scale / 8 + y * (9 - scale) / 8 + t * speed);
+            let grain = (hash(column, row, sequence) % 24) as i64 - 12;
+            let (dx, dy) = (x - disc_x, y - disc_y);
+            let disc = if dx * dx + dy * dy < radius * radius {
+                90
+            } else {
+                0
+            };
+            let channel = |value: i64| (value + grain + disc).clamp(0, 255) as u8;
+            canvas.put(
+                video.x + column,
+                video.y + row,
+                [
+                    channel(wave(3, 5)),
+                    channel(wave(5, 3) * 3 / 4),
+                    channel(wave(7, -4)),
+                ],
+            );
+        }
+    }
+}
+
+/// 0..=255 triangle wave with a 512 period
+fn triangle(value: i64) -> i64 {
+    let phase = value.rem_euclid(512);
+    if phase < 256 { phase } else { 511 - phase }
+}
+
+fn hash(x: u32, y: u32, sequence: u64) -> u64 {
+    let state =
+        ((x as u64) << 40 ^ (y as u64) << 20 ^ sequence).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    state ^ (state >> 29)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &CapturedFrame, x: u32, y: u32) -> &[u8] {
+        let offset = (y * frame.stride + x * 4) as usize;
+        &frame.data[offset..offset + 4]
+    }
+
+    #[test]
+    fn test_dirty_rects_cover_every_change() {
+        for clip in ScreenClip::ALL {
+            let mut renderer = ClipRenderer::new(clip, 320, 240);
+            let mut previous = renderer.next_frame();
+            assert!(previous.is_full_update());
+            let mut changed_frames = 0;
+
+            for _ in 0..60 {
+                let frame = renderer.next_frame();
+                let mut changed = false;
+                for y in 0..frame.height {
+                    for x in 0..frame.width {
+                        if pixel(&frame, x, y) == pixel(&previous, x, y) {
+                            continue;
+                        }
+                        changed = true;
+                        let point = DirtyRect::new(x, y, 1, 1);
+                        assert!(
+                            frame.dirty_rects.iter().any(|rect| rect.contains(&point)),
+                            "{} frame {}: ({}, {}) changed outside {:?}",
+                            clip.name(),
+                            frame.sequence,
+                            x,
+                            y,
+                            frame.dirty_rects
+                        );
+                    }
+                }
+                changed_frames += changed as u32;
+                previous = frame;
+            }
+            assert!(changed_frames > 30, "{} is mostly static", clip.name());
+        }
+    }
+
+    #[test]
+    fn test_clips_are_deterministic() {
+        for clip in ScreenClip::ALL {
+            let mut first = ClipRenderer::new(clip, 200, 120);
+            let mut second = ClipRenderer::new(clip, 200, 120);
+            for _ in 0..10 {
+                let (a, b) = (first.next_frame(), second.next_frame());
+                assert_eq!(a.data, b.data, "{}", clip.name());
+                assert_eq!(a.dirty_rects, b.dirty_rects, "{}", clip.name());
+            }
+        }
+    }
+}
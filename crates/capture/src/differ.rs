@@ -0,0 +1,430 @@
+//! Software dirty-rect detection
+//!
+//! Backends that cannot report damage (ScreenCaptureKit, portal streams
+//! without damage metadata) mark every frame as a full update. The
+//! [`FrameDiffer`] compares such frames tile by tile against the previous
+//! one and replaces the full-screen rect with a small set of changed
+//! regions; [`DiffingCapture`] applies it to any backend and swallows
+//! frames in which nothing changed.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    CaptureConfig, CaptureError, CaptureResult, CaptureStats, CapturedFrame, DirtyRect,
+    DisplayInfo, ScreenCapture, WindowInfo,
+};
+
+/// Default tile edge in pixels
+pub const DEFAULT_TILE_SIZE: u32 = 64;
+/// Default upper bound on rects reported per frame
+pub const DEFAULT_MAX_RECTS: usize = 16;
+/// How long `DiffingCapture::capture_frame` waits for a change
+const UNCHANGED_TIMEOUT: Duration = Duration::from_secs(1);
+/// Most rects the pairwise merge search runs on; beyond this rows are
+/// collapsed first, since the search is cubic in the rect count
+const PAIRWISE_LIMIT: usize = 64;
+
+/// Tile-based frame comparator
+pub struct FrameDiffer {
+    tile_size: u32,
+    max_rects: usize,
+    previous: Option<CapturedFrame>,
+}
+
+impl FrameDiffer {
+    pub fn new() -> Self {
+        Self::with_tile_size(DEFAULT_TILE_SIZE)
+    }
+
+    pub fn with_tile_size(tile_size: u32) -> Self {
+        Self {
+            tile_size: tile_size.max(1),
+            max_rects: DEFAULT_MAX_RECTS,
+            previous: None,
+        }
+    }
+
+    /// Limit the number of rects produced; excess rects are merged
+    pub fn with_max_rects(mut self, max_rects: usize) -> Self {
+        self.max_rects = max_rects.max(1);
+        self
+    }
+
+    /// Forget the reference frame so the next frame is a full update
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    /// Compute changed regions of `frame` relative to the last frame seen
+    ///
+    /// Returns `None` when there is no comparable reference (first frame,
+    /// resolution or format change), meaning the whole frame is dirty.
+    /// An empty vector means nothing changed.
+    pub fn diff(&mut self, frame: &CapturedFrame) -> Option<Vec<DirtyRect>> {
+        let previous = self.previous.replace(frame.clone())?;
+
+        let bpp = frame.format.bytes_per_pixel()?;
+        if previous.width != frame.width
+            || previous.height != frame.height
+            || previous.format != frame.format
+            || previous.display_id != frame.display_id
+        {
+            return None;
+        }
+
+        let tiles = self.changed_tiles(&previous, frame, bpp);
+        let rects = self.tiles_to_rects(&tiles, frame.width, frame.height);
+        Some(reduce_rects(rects, self.max_rects))
+    }
+
+    /// Replace a full-update frame's dirty rects with the detected changes
+    ///
+    /// Frames that already carry backend damage are left untouched but
+    /// still become the new reference. Returns `false` if nothing changed.
+    pub fn apply(&mut self, frame: &mut CapturedFrame) -> bool {
+        if !frame.is_full_update() {
+            self.previous = Some(frame.clone());
+            return true;
+        }
+
+        match self.diff(frame) {
+            Some(rects) if rects.is_empty() => false,
+            Some(rects) => {
+                frame.dirty_rects = rects;
+                true
+            }
+            None => {
+                frame.dirty_rects = vec![DirtyRect::full_screen(frame.width, frame.height)];
+                true
+            }
+        }
+    }
+
+    /// Row-major grid of tiles whose pixels differ
+    fn changed_tiles(
+        &self,
+        previous: &CapturedFrame,
+        current: &CapturedFrame,
+        bpp: usize,
+    ) -> Vec<Vec<bool>> {
+        let tile = self.tile_size as usize;
+        let width = current.width as usize;
+        let height = current.height as usize;
+        let cols = width.div_ceil(tile);
+        let rows = height.div_ceil(tile);
+
+        let mut grid = vec![vec![false; cols]; rows];
+        for (tile_row, cells) in grid.iter_mut().enumerate() {
+            let y_start = tile_row * tile;
+            let y_end = (y_start + tile).min(height);
+
+            for y in y_start..y_end {
+                let old_row = &previous.data[y * previous.stride as usize..];
+                let new_row = &current.data[y * current.stride as usize..];
+
+                for (col, dirty) in cells.iter_mut().enumerate() {
+                    if *dirty {
+                        continue;
+                    }
+                    let start = col * tile * bpp;
+                    let end = ((col + 1) * tile).min(width) * bpp;
+                    if old_row[start..end] != new_row[start..end] {
+                        *dirty = true;
+                    }
+                }
+
+                if cells.iter().all(|d| *d) {
+                    break;
+                }
+            }
+        }
+
+        grid
+    }
+
+    /// Merge horizontal runs of dirty tiles, then stack equal runs vertically
+    fn tiles_to_rects(&self, grid: &[Vec<bool>], width: u32, height: u32) -> Vec<DirtyRect> {
+        let tile = self.tile_size;
+        let mut rects: Vec<DirtyRect> = Vec::new();
+        // Rects that ended on the previous tile row and may grow downward
+        let mut open: Vec<usize> = Vec::new();
+
+        for (row, cells) in grid.iter().enumerate() {
+            let y = row as u32 * tile;
+            let h = tile.min(height - y);
+            let mut next_open = Vec::new();
+
+            let mut col = 0;
+            while col < cells.len() {
+                if !cells[col] {
+                    col += 1;
+                    continue;
+                }
+                let run_start = col;
+                while col < cells.len() && cells[col] {
+                    col += 1;
+                }
+
+                let x = run_start as u32 * tile;
+                let w = (col as u32 * tile).min(width) - x;
+
+                let extends = open
+                    .iter()
+                    .copied()
+                    .find(|&i| rects[i].x == x && rects[i].width == w);
+                match extends {
+                    Some(i) => {
+                        rects[i].height += h;
+                        next_open.push(i);
+                    }
+                    None => {
+                        rects.push(DirtyRect::new(x, y, w, h));
+                        next_open.push(rects.len() - 1);
+                    }
+                }
+            }
+
+            open = next_open;
+        }
+
+        rects
+    }
+}
+
+impl Default for FrameDiffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drop contained rects and merge the cheapest pairs until under `max`
+fn reduce_rects(mut rects: Vec<DirtyRect>, max: usize) -> Vec<DirtyRect> {
+    let limit = max.max(PAIRWISE_LIMIT);
+    if rects.len() > limit {
+        rects = merge_rows(rects, limit);
+    }
+
+    while rects.len() > max {
+        let mut best = (0, 1, u64::MAX);
+        for i in 0..rects.len() {
+            for j in i + 1..rects.len() {
+                let merged = rects[i].merge(&rects[j]);
+                let cost = merged
+                    .area()
+                    .saturating_sub(rects[i].area() + rects[j].area());
+                if cost < best.2 {
+                    best = (i, j, cost);
+                }
+            }
+        }
+
+        let (i, j, _) = best;
+        let merged = rects[i].merge(&rects[j]);
+        rects.swap_remove(j);
+        rects[i] = merged;
+        rects.retain(|r| r == &merged || !merged.contains(r));
+    }
+
+    rects
+}
+
+/// Collapse rects starting on the same row into one, then merge
+/// neighbouring rows pairwise until at most `limit` remain
+fn merge_rows(mut rects: Vec<DirtyRect>, limit: usize) -> Vec<DirtyRect> {
+    rects.sort_unstable_by_key(|r| (r.y, r.x));
+
+    let mut rows: Vec<DirtyRect> = Vec::new();
+    for rect in rects {
+        match rows.last_mut() {
+            Some(row) if row.y == rect.y => *row = row.merge(&rect),
+            _ => rows.push(rect),
+        }
+    }
+
+    while rows.len() > limit {
+        rows = rows
+            .chunks(2)
+            .map(|pair| pair.iter().skip(1).fold(pair[0], |acc, r| acc.merge(r)))
+            .collect();
+    }
+    rows
+}
+
+/// Wraps a backend and fills in dirty rects it does not provide
+pub struct DiffingCapture {
+    inner: Box<dyn ScreenCapture>,
+    differ: FrameDiffer,
+    enabled: bool,
+}
+
+impl DiffingCapture {
+    pub fn new(inner: Box<dyn ScreenCapture>) -> Self {
+        Self {
+            inner,
+            differ: FrameDiffer::new(),
+            enabled: true,
+        }
+    }
+
+    /// Returns the frame if it changed, `None` if it can be skipped
+    fn process(&mut self, mut frame: CapturedFrame) -> Option<CapturedFrame> {
+        if !self.enabled {
+            return Some(frame);
+        }
+        self.differ.apply(&mut frame).then_some(frame)
+    }
+}
+
+impl ScreenCapture for DiffingCapture {
+    fn displays(&self) -> CaptureResult<Vec<DisplayInfo>> {
+        self.inner.displays()
+    }
+
+    fn windows(&self) -> CaptureResult<Vec<WindowInfo>> {
+        self.inner.windows()
+    }
+
+    fn start(&mut self, config: CaptureConfig) -> CaptureResult<()> {
+        self.enabled = config.dirty_rects;
+        self.differ.reset();
+        self.inner.start(config)
+    }
+
+    fn stop(&mut self) -> CaptureResult<()> {
+        self.inner.stop()
+    }
+
+    fn is_running(&self) -> bool {
+        self.inner.is_running()
+    }
+
+    fn capture_frame(&mut self) -> CaptureResult<CapturedFrame> {
+        let start = Instant::now();
+        loop {
+            let frame = self.inner.capture_frame()?;
+            if let Some(frame) = self.process(frame) {
+                return Ok(frame);
+            }
+            if start.elapsed() > UNCHANGED_TIMEOUT {
+                return Err(CaptureError::Timeout);
+            }
+        }
+    }
+
+    fn try_capture_frame(&mut self) -> CaptureResult<Option<CapturedFrame>> {
+        Ok(self
+            .inner
+            .try_capture_frame()?
+            .and_then(|frame| self.process(frame)))
+    }
+
+    fn stats(&self) -> CaptureStats {
+        self.inner.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PixelFormat;
+    use bytes::Bytes;
+
+    fn frame(width: u32, height: u32, data: Vec<u8>) -> CapturedFrame {
+        CapturedFrame {
+            data: Bytes::from(data),
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence: 0,
+            dirty_rects: vec![DirtyRect::full_screen(width, height)],
+            display_id: 0,
+        }
+    }
+
+    fn set_pixel(data: &mut [u8], width: u32, x: u32, y: u32) {
+        let offset = ((y * width + x) * 4) as usize;
+        data[offset] ^= 0xFF;
+    }
+
+    #[test]
+    fn test_static_frames_report_no_change() {
+        let mut differ = FrameDiffer::new();
+        let data = vec![0x40; 200 * 150 * 4];
+
+        let mut first = frame(200, 150, data.clone());
+        assert!(differ.apply(&mut first));
+        assert!(first.is_full_update());
+
+        let mut second = frame(200, 150, data);
+        assert!(!differ.apply(&mut second));
+    }
+
+    #[test]
+    fn test_adjacent_tiles_merge_into_one_rect() {
+        let mut differ = FrameDiffer::new();
+        let base = vec![0u8; 256 * 256 * 4];
+        differ.diff(&frame(256, 256, base.clone()));
+
+        // Touches tiles (1,1), (2,1), (1,2) and (2,2)
+        let mut changed = base;
+        set_pixel(&mut changed, 256, 70, 70);
+        set_pixel(&mut changed, 256, 130, 70);
+        set_pixel(&mut changed, 256, 70, 130);
+        set_pixel(&mut changed, 256, 130, 130);
+
+        let rects = differ.diff(&frame(256, 256, changed)).unwrap();
+        assert_eq!(rects, vec![DirtyRect::new(64, 64, 128, 128)]);
+    }
+
+    #[test]
+    fn test_rect_count_is_bounded_and_covers_changes() {
+        let mut differ = FrameDiffer::new().with_max_rects(4);
+        let width = 640;
+        let height = 640;
+        let base = vec![0u8; (width * height * 4) as usize];
+        differ.diff(&frame(width, height, base.clone()));
+
+        // Checkerboard of isolated dirty tiles
+        let mut changed = base;
+        let mut touched = Vec::new();
+        for ty in (0..10).step_by(2) {
+            for tx in (0..10).step_by(2) {
+                let (x, y) = (tx * 64 + 5, ty * 64 + 5);
+                set_pixel(&mut changed, width, x, y);
+                touched.push((x, y));
+            }
+        }
+
+        let rects = differ.diff(&frame(width, height, changed)).unwrap();
+        assert!(rects.len() <= 4);
+        for (x, y) in touched {
+            let pixel = DirtyRect::new(x, y, 1, 1);
+            assert!(rects.iter().any(|r| r.contains(&pixel)));
+        }
+    }
+
+    #[test]
+    fn test_scattered_tiles_reduce_quickly() {
+        // Every other 16px tile of a 4K frame: about 16000 isolated rects
+        let mut tiles = Vec::new();
+        for ty in 0..135 {
+            for tx in 0..240 {
+                if (tx + ty) % 2 == 0 {
+                    tiles.push(DirtyRect::new(tx * 16, ty * 16, 16, 16));
+                }
+            }
+        }
+
+        let start = Instant::now();
+        let rects = reduce_rects(tiles.clone(), DEFAULT_MAX_RECTS);
+        let elapsed = start.elapsed();
+
+        assert!(elapsed < Duration::from_millis(50), "took {:?}", elapsed);
+        assert!(rects.len() <= DEFAULT_MAX_RECTS);
+        for tile in &tiles {
+            assert!(rects.iter().any(|r| r.contains(tile)));
+        }
+    }
+}
@@ -16,6 +16,9 @@ pub enum CaptureError {
     #[error("Display not found: {0}")]
     DisplayNotFound(u32),
 
+    #[error("Window not found: {0}")]
+    WindowNotFound(u32),
+
     #[error("Capture initialization failed: {0}")]
     InitFailed(String),
 
@@ -81,6 +81,16 @@ impl DirtyRect {
     pub fn area(&self) -> u64 {
         self.width as u64 * self.height as u64
     }
+
+    /// Overlapping part of two rects, if any
+    pub fn intersect(&self, other: &DirtyRect) -> Option<DirtyRect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+
+        (x < right && y < bottom).then(|| DirtyRect::new(x, y, right - x, bottom - y))
+    }
 }
 
 /// Captured frame data
@@ -139,6 +149,45 @@ impl CapturedFrame {
         (dirty_area as f64 / total_area as f64) * 100.0
     }
 
+    /// Crop the frame to `rect` without copying pixel data
+    ///
+    /// The crop is clamped to the frame and its size rounded down to even
+    /// dimensions, as required by 4:2:0 encoders. Dirty rects are clipped
+    /// and translated into the cropped frame's coordinates.
+    pub fn crop(&self, rect: &DirtyRect) -> Option<CapturedFrame> {
+        let bpp = self.format.bytes_per_pixel()?;
+        let bounds = DirtyRect::full_screen(self.width, self.height);
+        let mut crop = bounds.intersect(rect)?;
+        crop.width &= !1;
+        crop.height &= !1;
+        if crop.width == 0 || crop.height == 0 {
+            return None;
+        }
+
+        let start = crop.y as usize * self.stride as usize + crop.x as usize * bpp;
+        let end = (crop.y + crop.height - 1) as usize * self.stride as usize
+            + (crop.x + crop.width) as usize * bpp;
+
+        let dirty_rects = self
+            .dirty_rects
+            .iter()
+            .filter_map(|r| r.intersect(&crop))
+            .map(|r| DirtyRect::new(r.x - crop.x, r.y - crop.y, r.width, r.height))
+            .collect();
+
+        Some(CapturedFrame {
+            data: self.data.slice(start..end),
+            width: crop.width,
+            height: crop.height,
+            stride: self.stride,
+            format: self.format,
+            timestamp: self.timestamp,
+            sequence: self.sequence,
+            dirty_rects,
+            display_id: self.display_id,
+        })
+    }
+
     /// Convert BGRA to RGBA in place (if needed for encoding)
     pub fn bgra_to_rgba(&mut self) {
         if self.format != PixelFormat::Bgra8 {
@@ -180,3 +229,22 @@ pub struct DisplayInfo {
     /// Y position in virtual screen
     pub y: i32,
 }
+
+/// Top-level window that can be captured on its own
+#[derive(Debug, Clone)]
+pub struct WindowInfo {
+    /// Window ID (platform window handle)
+    pub id: u32,
+    /// Window title
+    pub title: String,
+    /// Name of the owning application
+    pub app_name: String,
+    /// X position in virtual screen
+    pub x: i32,
+    /// Y position in virtual screen
+    pub y: i32,
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+}
@@ -3,9 +3,15 @@
 //! Provides abstraction over platform-specific capture APIs:
 //! - macOS: ScreenCaptureKit
 //! - Windows: DXGI Desktop Duplication
+//! - Linux: X11 (MIT-SHM + XDamage + XRandR), Wayland (portal + PipeWire)
+//! - Synthetic test pattern (headless CI and development VMs)
 
+mod clips;
+mod differ;
 mod error;
 mod frame;
+mod stitched;
+mod synthetic;
 mod traits;
 
 #[cfg(target_os = "macos")]
@@ -14,8 +20,18 @@ mod macos;
 #[cfg(target_os = "windows")]
 mod windows;
 
+#[cfg(all(target_os = "linux", feature = "x11"))]
+mod x11;
+
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+mod wayland;
+
+pub use clips::{ClipRenderer, ScreenClip};
+pub use differ::{DiffingCapture, FrameDiffer};
 pub use error::*;
 pub use frame::*;
+pub use stitched::{CaptureFactory, STITCHED_DISPLAY_ID, StitchedCapture};
+pub use synthetic::{ScriptedRegion, SyntheticCapture};
 pub use traits::*;
 
 #[cfg(target_os = "macos")]
@@ -44,19 +60,71 @@ pub use macos::MacOSCapture;
 #[cfg(target_os = "windows")]
 pub use windows::WindowsCapture;
 
+#[cfg(all(target_os = "linux", feature = "x11"))]
+pub use x11::X11Capture;
+
+#[cfg(all(target_os = "linux", feature = "wayland"))]
+pub use wayland::WaylandCapture;
+
 /// Create a platform-appropriate screen capture instance
+///
+/// With the `synthetic` feature enabled this always returns a
+/// [`SyntheticCapture`], regardless of platform. The backend is wrapped
+/// in a [`DiffingCapture`] so frames without backend damage still get
+/// dirty rects when `CaptureConfig::dirty_rects` is set.
 pub fn create_capture() -> CaptureResult<Box<dyn ScreenCapture>> {
-    #[cfg(target_os = "macos")]
+    Ok(Box::new(DiffingCapture::new(create_platform_capture()?)))
+}
+
+/// Create a capture of all displays stitched into one frame
+///
+/// Not available on Wayland, where every display backend would open a
+/// portal session of its own and prompt the user once per display.
+pub fn create_stitched_capture() -> CaptureResult<Box<dyn ScreenCapture>> {
+    #[cfg(all(not(feature = "synthetic"), target_os = "linux", feature = "wayland"))]
+    if wayland::is_wayland_session() {
+        return Err(CaptureError::InitFailed(
+            "capturing all displays is not supported on Wayland".to_string(),
+        ));
+    }
+
+    Ok(Box::new(StitchedCapture::new(Box::new(create_capture))?))
+}
+
+fn create_platform_capture() -> CaptureResult<Box<dyn ScreenCapture>> {
+    #[cfg(feature = "synthetic")]
+    {
+        Ok(Box::new(SyntheticCapture::new()))
+    }
+
+    #[cfg(all(not(feature = "synthetic"), target_os = "macos"))]
     {
         Ok(Box::new(MacOSCapture::new()?))
     }
 
-    #[cfg(target_os = "windows")]
+    #[cfg(all(not(feature = "synthetic"), target_os = "windows"))]
     {
         Ok(Box::new(WindowsCapture::new()?))
     }
 
-    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    #[cfg(all(not(feature = "synthetic"), target_os = "linux", feature = "wayland"))]
+    if wayland::is_wayland_session() {
+        return Ok(Box::new(WaylandCapture::new()?));
+    }
+
+    #[cfg(all(not(feature = "synthetic"), target_os = "linux", feature = "x11"))]
+    {
+        Ok(Box::new(X11Capture::new()?))
+    }
+
+    #[cfg(all(
+        not(feature = "synthetic"),
+        not(any(
+            target_os = "macos",
+            target_os = "windows",
+            all(target_os = "linux", feature = "x11")
+        ))
+    ))]
     {
         Err(CaptureError::UnsupportedPlatform)
     }
@@ -1,12 +1,23 @@
 //! macOS screen capture using ScreenCaptureKit
 //!
-//! Real implementation using `screencapturekit` crate.
+//! Real implementation using `screencapturekit` crate. A stream's output
+//! size is fixed when it starts, so window targets re-read the window's
+//! bounds periodically and restart the stream when it resizes.
 
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use bytes::Bytes;
+use core_foundation::base::{CFType, TCFType};
+use core_foundation::dictionary::{CFDictionary, CFDictionaryRef};
+use core_foundation::number::CFNumber;
+use core_foundation::string::CFString;
+use core_graphics::window::{
+    copy_window_info, kCGNullWindowID, kCGWindowBounds, kCGWindowListOptionOnScreenOnly,
+    kCGWindowNumber,
+};
 use screencapturekit::{
     cm_sample_buffer::CMSampleBuffer,
     sc_content_filter::{InitParams, SCContentFilter},
@@ -15,6 +26,7 @@ use screencapturekit::{
     sc_shareable_content::SCShareableContent,
     sc_stream::SCStream,
     sc_stream_configuration::{PixelFormat as SCStreamPixelFormat, SCStreamConfiguration},
+    sc_types::geometry::{CGPoint, CGRect, CGSize},
 };
 use screencapturekit_sys::{
     cv_pixel_buffer_ref::CVPixelBufferRef,
@@ -22,14 +34,63 @@ use screencapturekit_sys::{
 };
 
 use crate::{
-    CaptureConfig, CaptureError, CaptureResult, CaptureStats, CapturedFrame, DirtyRect,
-    DisplayInfo, PixelFormat, ScreenCapture,
+    CaptureConfig, CaptureError, CaptureResult, CaptureStats, CaptureTarget, CapturedFrame,
+    DirtyRect, DisplayInfo, PixelFormat, ScreenCapture, WindowInfo,
 };
 
+/// How often a captured window's size is re-read
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 fn has_screen_recording_permission() -> bool {
     core_graphics::access::ScreenCaptureAccess::default().preflight()
 }
 
+/// Bounds of on-screen windows by window id, in global display points
+///
+/// `SCWindow` only reports a window's size, so the origin comes from the
+/// window server's own list.
+fn window_bounds() -> HashMap<u32, (i32, i32, u32, u32)> {
+    let mut bounds = HashMap::new();
+    let Some(windows) = copy_window_info(kCGWindowListOptionOnScreenOnly, kCGNullWindowID) else {
+        return bounds;
+    };
+    let (number_key, bounds_key) = unsafe {
+        (
+            CFString::wrap_under_get_rule(kCGWindowNumber),
+            CFString::wrap_under_get_rule(kCGWindowBounds),
+        )
+    };
+
+    for info in windows.iter() {
+        let info: CFDictionary<CFString, CFType> =
+            unsafe { CFDictionary::wrap_under_get_rule(*info as CFDictionaryRef) };
+        let Some(id) = info
+            .find(&number_key)
+            .and_then(|number| number.downcast::<CFNumber>())
+            .and_then(|number| number.to_i64())
+        else {
+            continue;
+        };
+        let Some(rect) = info
+            .find(&bounds_key)
+            .and_then(|rect| rect.downcast::<CFDictionary>())
+            .and_then(|rect| core_graphics::geometry::CGRect::from_dict_representation(&rect))
+        else {
+            continue;
+        };
+        bounds.insert(
+            id as u32,
+            (
+                rect.origin.x as i32,
+                rect.origin.y as i32,
+                rect.size.width as u32,
+                rect.size.height as u32,
+            ),
+        );
+    }
+    bounds
+}
+
 struct OutputHandler {
     latest_frame: Arc<Mutex<Option<CapturedFrame>>>,
     stats: Arc<Mutex<CaptureStats>>,
@@ -125,6 +186,9 @@ pub struct MacOSCapture {
     stats: Arc<Mutex<CaptureStats>>,
     frame_count: Arc<std::sync::atomic::AtomicU64>,
     current_config: Option<CaptureConfig>,
+    /// Output size of a window capture, to notice the window resizing
+    window_size: Option<(u32, u32)>,
+    next_window_poll: Instant,
 }
 
 unsafe impl Send for MacOSCapture {}
@@ -139,8 +203,37 @@ impl MacOSCapture {
             stats: Arc::new(Mutex::new(CaptureStats::default())),
             frame_count: Arc::new(std::sync::atomic::AtomicU64::new(0)),
             current_config: None,
+            window_size: None,
+            next_window_poll: Instant::now(),
         })
     }
+
+    /// Restart a window capture at the window's new size once it resizes
+    fn follow_window(&mut self) -> CaptureResult<()> {
+        let Some(config) = &self.current_config else {
+            return Ok(());
+        };
+        let CaptureTarget::Window(id) = config.target else {
+            return Ok(());
+        };
+        if Instant::now() < self.next_window_poll {
+            return Ok(());
+        }
+        self.next_window_poll = Instant::now() + WINDOW_POLL_INTERVAL;
+
+        let (_, _, width, height) = *window_bounds()
+            .get(&id)
+            .ok_or(CaptureError::WindowNotFound(id))?;
+        let size = (width & !1, height & !1);
+        if Some(size) == self.window_size || size.0 == 0 || size.1 == 0 {
+            return Ok(());
+        }
+
+        tracing::debug!("Window {} resized to {}x{}", id, size.0, size.1);
+        let config = config.clone();
+        self.stop()?;
+        self.start(config)
+    }
 }
 
 impl ScreenCapture for MacOSCapture {
@@ -165,6 +258,33 @@ impl ScreenCapture for MacOSCapture {
         Ok(displays)
     }
 
+    fn windows(&self) -> CaptureResult<Vec<WindowInfo>> {
+        let content = SCShareableContent::current();
+        let bounds = window_bounds();
+        Ok(content
+            .windows
+            .into_iter()
+            .filter(|w| w.is_on_screen && w.window_layer == 0 && w.width > 0 && w.height > 0)
+            .filter_map(|w| {
+                // Listed by ScreenCaptureKit but already gone from the
+                // window server
+                let &(x, y, width, height) = bounds.get(&w.window_id)?;
+                Some(WindowInfo {
+                    id: w.window_id,
+                    title: w.title.unwrap_or_default(),
+                    app_name: w
+                        .owning_application
+                        .and_then(|app| app.application_name)
+                        .unwrap_or_default(),
+                    x,
+                    y,
+                    width,
+                    height,
+                })
+            })
+            .collect())
+    }
+
     fn start(&mut self, config: CaptureConfig) -> CaptureResult<()> {
         if self.running.load(Ordering::SeqCst) {
             return Err(CaptureError::AlreadyRunning);
@@ -186,13 +306,51 @@ impl ScreenCapture for MacOSCapture {
             .find(|d| d.display_id == display_id)
             .ok_or(CaptureError::NoDisplays)?;
 
-        // Filter
-        let filter = SCContentFilter::new(InitParams::Display(display.clone()));
+        // Filter and output size for the requested target
+        let (filter, width, height, source_rect) = match config.target {
+            CaptureTarget::Display => (
+                SCContentFilter::new(InitParams::Display(display.clone())),
+                display.width as u32,
+                display.height as u32,
+                CGRect::default(),
+            ),
+            CaptureTarget::Window(id) => {
+                let window = content
+                    .windows
+                    .into_iter()
+                    .find(|w| w.window_id == id)
+                    .ok_or(CaptureError::WindowNotFound(id))?;
+                let (width, height) = (window.width & !1, window.height & !1);
+                (
+                    SCContentFilter::new(InitParams::DesktopIndependentWindow(window)),
+                    width,
+                    height,
+                    CGRect::default(),
+                )
+            }
+            CaptureTarget::Region(region) => {
+                let full = DirtyRect::full_screen(display.width as u32, display.height as u32);
+                let region = full.intersect(&region).ok_or_else(|| {
+                    CaptureError::InitFailed("Capture region is outside the display".to_string())
+                })?;
+                let (width, height) = (region.width & !1, region.height & !1);
+                (
+                    SCContentFilter::new(InitParams::Display(display.clone())),
+                    width,
+                    height,
+                    CGRect {
+                        origin: CGPoint::new(region.x as f64, region.y as f64),
+                        size: CGSize::new(width as f64, height as f64),
+                    },
+                )
+            }
+        };
 
         // Config
         let stream_config = SCStreamConfiguration {
-            width: display.width as u32,
-            height: display.height as u32,
+            width,
+            height,
+            source_rect,
             shows_cursor: config.capture_cursor,
             pixel_format: SCStreamPixelFormat::ARGB8888,
             ..SCStreamConfiguration::default()
@@ -210,6 +368,10 @@ impl ScreenCapture for MacOSCapture {
             _dirty_rects_enabled: config.dirty_rects,
         };
 
+        self.window_size =
+            matches!(config.target, CaptureTarget::Window(_)).then_some((width, height));
+        self.next_window_poll = Instant::now() + WINDOW_POLL_INTERVAL;
+
         let mut stream = SCStream::new(filter, stream_config, ErrorHandler);
         stream.add_output(output_handler, SCStreamOutputType::Screen);
 
@@ -243,6 +405,7 @@ impl ScreenCapture for MacOSCapture {
         // But for low latency, we might just return the latest one available.
         // To implement blocking, we need a Condvar or logic to wait for sequence diff.
 
+        self.follow_window()?;
         let start = Instant::now();
         loop {
             // Check if we have a frame
@@ -268,6 +431,7 @@ impl ScreenCapture for MacOSCapture {
     }
 
     fn try_capture_frame(&mut self) -> CaptureResult<Option<CapturedFrame>> {
+        self.follow_window()?;
         let mut guard = self.latest_frame.lock().unwrap();
         Ok(guard.take())
     }
@@ -0,0 +1,377 @@
+//! All-displays capture
+//!
+//! [`StitchedCapture`] runs one backend per display and composes their
+//! frames onto a single canvas laid out by each display's `x`/`y`
+//! position in the virtual screen. Dirty rects from every display are
+//! translated into canvas coordinates, so the result looks like one
+//! large display to the encoder.
+//!
+//! Each backend is independent, so this is only offered where opening
+//! several is silent; see [`create_stitched_capture`](crate::create_stitched_capture).
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::{
+    CaptureConfig, CaptureError, CaptureResult, CaptureStats, CaptureTarget, CapturedFrame,
+    DirtyRect, DisplayInfo, PixelFormat, ScreenCapture, WindowInfo,
+};
+
+/// Display id reported for the stitched virtual display
+pub const STITCHED_DISPLAY_ID: u32 = u32::MAX;
+/// How long `capture_frame` waits for any display to change
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+/// Poll interval while no display has a new frame
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// Creates a fresh backend for one display
+pub type CaptureFactory = Box<dyn Fn() -> CaptureResult<Box<dyn ScreenCapture>> + Send + Sync>;
+
+/// One display's backend and its slot on the canvas
+struct Source {
+    capture: Box<dyn ScreenCapture>,
+    slot: DirtyRect,
+    /// Whether this display has delivered its first frame
+    primed: bool,
+}
+
+/// Captures every display into one frame
+pub struct StitchedCapture {
+    factory: CaptureFactory,
+    layout: Vec<DisplayInfo>,
+    sources: Vec<Source>,
+    canvas: Vec<u8>,
+    width: u32,
+    height: u32,
+    /// Set until the first complete canvas has been delivered
+    full_update: bool,
+    sequence: u64,
+    stats: CaptureStats,
+    last_frame_at: Option<Instant>,
+}
+
+impl StitchedCapture {
+    /// Create a stitched capture, probing the display layout with `factory`
+    pub fn new(factory: CaptureFactory) -> CaptureResult<Self> {
+        let layout = factory()?.displays()?;
+        if layout.is_empty() {
+            return Err(CaptureError::NoDisplays);
+        }
+
+        let (width, height) = canvas_size(&layout);
+        Ok(Self {
+            factory,
+            layout,
+            sources: Vec::new(),
+            canvas: Vec::new(),
+            width,
+            height,
+            full_update: true,
+            sequence: 0,
+            stats: CaptureStats::default(),
+            last_frame_at: None,
+        })
+    }
+
+    /// Copy a display frame's dirty area into its canvas slot
+    ///
+    /// Returns the updated canvas rects.
+    fn blit(&mut self, index: usize, frame: &CapturedFrame) -> Vec<DirtyRect> {
+        let slot = self.sources[index].slot;
+        let visible =
+            DirtyRect::full_screen(frame.width.min(slot.width), frame.height.min(slot.height));
+        let rects: Vec<DirtyRect> = if self.sources[index].primed {
+            frame
+                .dirty_rects
+                .iter()
+                .filter_map(|rect| rect.intersect(&visible))
+                .collect()
+        } else {
+            vec![visible]
+        };
+
+        let canvas_stride = self.width as usize * 4;
+        for rect in &rects {
+            let row_bytes = rect.width as usize * 4;
+            for row in 0..rect.height as usize {
+                let src = (rect.y as usize + row) * frame.stride as usize + rect.x as usize * 4;
+                let dst = (slot.y as usize + rect.y as usize + row) * canvas_stride
+                    + (slot.x as usize + rect.x as usize) * 4;
+                self.canvas[dst..dst + row_bytes]
+                    .copy_from_slice(&frame.data[src..src + row_bytes]);
+            }
+        }
+
+        self.sources[index].primed = true;
+        rects
+            .into_iter()
+            .map(|rect| DirtyRect::new(rect.x + slot.x, rect.y + slot.y, rect.width, rect.height))
+            .collect()
+    }
+
+    /// Poll every display once and compose whatever changed
+    fn poll(&mut self) -> CaptureResult<Option<CapturedFrame>> {
+        let start = Instant::now();
+        let mut dirty = Vec::new();
+        for index in 0..self.sources.len() {
+            let Some(frame) = self.sources[index].capture.try_capture_frame()? else {
+                continue;
+            };
+            if frame.format.bytes_per_pixel() != Some(4) {
+                return Err(CaptureError::UnsupportedFormat(format!(
+                    "{:?}",
+                    frame.format
+                )));
+            }
+            dirty.extend(self.blit(index, &frame));
+        }
+
+        // Hold output until every display has painted its slot once
+        if dirty.is_empty() || self.sources.iter().any(|s| !s.primed) {
+            return Ok(None);
+        }
+        if std::mem::take(&mut self.full_update) {
+            dirty = vec![DirtyRect::full_screen(self.width, self.height)];
+        }
+
+        let frame = CapturedFrame {
+            data: Bytes::copy_from_slice(&self.canvas),
+            width: self.width,
+            height: self.height,
+            stride: self.width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence: self.sequence,
+            dirty_rects: dirty,
+            display_id: STITCHED_DISPLAY_ID,
+        };
+        self.sequence += 1;
+        self.update_stats(start);
+
+        Ok(Some(frame))
+    }
+
+    fn update_stats(&mut self, start: Instant) {
+        let now = Instant::now();
+        self.stats.frames_captured += 1;
+        let latency_us = now.duration_since(start).as_micros() as u64;
+        self.stats.avg_capture_latency_us =
+            (self.stats.avg_capture_latency_us * 7 + latency_us) / 8;
+        if let Some(last) = self.last_frame_at {
+            let fps = 1.0 / now.duration_since(last).as_secs_f64().max(1e-6);
+            self.stats.current_fps = self.stats.current_fps * 0.9 + fps * 0.1;
+        }
+        self.last_frame_at = Some(now);
+    }
+}
+
+/// Bounding box of all displays, rounded up to even dimensions
+fn canvas_size(layout: &[DisplayInfo]) -> (u32, u32) {
+    let left = layout.iter().map(|d| d.x).min().unwrap_or(0);
+    let top = layout.iter().map(|d| d.y).min().unwrap_or(0);
+    let right = layout
+        .iter()
+        .map(|d| d.x + d.width as i32)
+        .max()
+        .unwrap_or(0);
+    let bottom = layout
+        .iter()
+        .map(|d| d.y + d.height as i32)
+        .max()
+        .unwrap_or(0);
+    let width = (right - left) as u32;
+    let height = (bottom - top) as u32;
+    (width + width % 2, height + height % 2)
+}
+
+impl ScreenCapture for StitchedCapture {
+    /// A single virtual display covering the whole layout
+    fn displays(&self) -> CaptureResult<Vec<DisplayInfo>> {
+        let left = self.layout.iter().map(|d| d.x).min().unwrap_or(0);
+        let top = self.layout.iter().map(|d| d.y).min().unwrap_or(0);
+        Ok(vec![DisplayInfo {
+            id: STITCHED_DISPLAY_ID,
+            name: "All displays".to_string(),
+            width: self.width,
+            height: self.height,
+            refresh_rate: self
+                .layout
+                .iter()
+                .map(|d| d.refresh_rate)
+                .fold(0.0, f64::max),
+            scale: 1.0,
+            is_primary: true,
+            x: left,
+            y: top,
+        }])
+    }
+
+    fn windows(&self) -> CaptureResult<Vec<WindowInfo>> {
+        match self.sources.first() {
+            Some(source) => source.capture.windows(),
+            None => (self.factory)()?.windows(),
+        }
+    }
+
+    /// Start one backend per display; `display_id` and `target` are ignored
+    fn start(&mut self, config: CaptureConfig) -> CaptureResult<()> {
+        if !self.sources.is_empty() {
+            return Err(CaptureError::AlreadyRunning);
+        }
+
+        let left = self.layout.iter().map(|d| d.x).min().unwrap_or(0);
+        let top = self.layout.iter().map(|d| d.y).min().unwrap_or(0);
+        let mut sources: Vec<Source> = Vec::with_capacity(self.layout.len());
+        for display in &self.layout {
+            let mut capture = (self.factory)()?;
+            let result = capture.start(CaptureConfig {
+                display_id: Some(display.id),
+                target: CaptureTarget::Display,
+                ..config.clone()
+            });
+            if let Err(e) = result {
+                for mut source in sources {
+                    source.capture.stop().ok();
+                }
+                return Err(e);
+            }
+
+            sources.push(Source {
+                capture,
+                slot: DirtyRect::new(
+                    (display.x - left) as u32,
+                    (display.y - top) as u32,
+                    display.width,
+                    display.height,
+                ),
+                primed: false,
+            });
+        }
+
+        self.sources = sources;
+        self.canvas = vec![0; self.width as usize * self.height as usize * 4];
+        self.full_update = true;
+        self.stats = CaptureStats::default();
+        self.last_frame_at = None;
+
+        tracing::info!(
+            "Stitched capture started over {} display(s) ({}x{})",
+            self.sources.len(),
+            self.width,
+            self.height
+        );
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> CaptureResult<()> {
+        for mut source in self.sources.drain(..) {
+            source.capture.stop()?;
+        }
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        !self.sources.is_empty() && self.sources.iter().all(|s| s.capture.is_running())
+    }
+
+    fn capture_frame(&mut self) -> CaptureResult<CapturedFrame> {
+        if self.sources.is_empty() {
+            return Err(CaptureError::NotRunning);
+        }
+
+        let deadline = Instant::now() + FRAME_TIMEOUT;
+        loop {
+            if let Some(frame) = self.poll()? {
+                return Ok(frame);
+            }
+            if Instant::now() >= deadline {
+                return Err(CaptureError::Timeout);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn try_capture_frame(&mut self) -> CaptureResult<Option<CapturedFrame>> {
+        if self.sources.is_empty() {
+            return Err(CaptureError::NotRunning);
+        }
+        self.poll()
+    }
+
+    fn stats(&self) -> CaptureStats {
+        let mut stats = self.stats.clone();
+        stats.frames_dropped = self
+            .sources
+            .iter()
+            .map(|s| s.capture.stats().frames_dropped)
+            .sum();
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SyntheticCapture;
+
+    fn display(id: u32, x: i32, y: i32) -> DisplayInfo {
+        DisplayInfo {
+            id,
+            name: format!("Synthetic {}", id),
+            width: 320,
+            height: 240,
+            refresh_rate: 60.0,
+            scale: 1.0,
+            is_primary: id == 1,
+            x,
+            y,
+        }
+    }
+
+    #[test]
+    fn test_displays_are_placed_by_layout() {
+        let layout = vec![display(1, 0, 0), display(2, 320, 120)];
+        let factory_layout = layout.clone();
+        let mut capture = StitchedCapture::new(Box::new(move || {
+            Ok(Box::new(SyntheticCapture::with_displays(factory_layout.clone())) as Box<_>)
+        }))
+        .unwrap();
+        capture
+            .start(CaptureConfig {
+                target_fps: 1000,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let frame = capture.capture_frame().unwrap();
+        assert_eq!((frame.width, frame.height), (640, 360));
+        assert!(frame.is_full_update());
+
+        // The right-hand slot holds display 2's own first frame
+        let mut single = SyntheticCapture::with_displays(layout);
+        single
+            .start(CaptureConfig {
+                display_id: Some(2),
+                ..Default::default()
+            })
+            .unwrap();
+        let expected = single.capture_frame().unwrap();
+        let slot = frame.crop(&DirtyRect::new(320, 120, 320, 240)).unwrap();
+        for y in 0..240 {
+            let row = (y * slot.stride) as usize;
+            let expected_row = (y * expected.stride) as usize;
+            assert_eq!(
+                slot.data[row..row + 320 * 4],
+                expected.data[expected_row..expected_row + 320 * 4]
+            );
+        }
+
+        let bounds = DirtyRect::full_screen(frame.width, frame.height);
+        for _ in 0..10 {
+            let frame = capture.capture_frame().unwrap();
+            assert!(frame.dirty_rects.iter().all(|r| bounds.contains(r)));
+        }
+    }
+}
@@ -0,0 +1,715 @@
+//! Synthetic screen capture for headless environments
+//!
+//! Renders a deterministic test pattern (moving gradient, scrolling text,
+//! blinking caret and scripted region updates) so the capture -> encode
+//! pipeline can run on CI machines and VMs without a display server.
+//! Frames are a pure function of their sequence number, and every frame
+//! reports the exact regions that changed since the previous one.
+
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::{
+    CaptureConfig, CaptureError, CaptureResult, CaptureStats, CaptureTarget, CapturedFrame,
+    DirtyRect, DisplayInfo, PixelFormat, ScreenCapture, WindowInfo,
+};
+
+/// Glyph cell width in pixels
+const CELL_WIDTH: u32 = 8;
+/// Text line height in pixels
+const LINE_HEIGHT: u32 = 14;
+/// Horizontal gradient speed in pixels per frame
+const GRADIENT_SPEED: u64 = 4;
+/// Frames between text scroll steps
+const SCROLL_PERIOD: u64 = 8;
+/// Frames between caret blink toggles
+const BLINK_PERIOD: u64 = 15;
+
+const PANEL_BACKGROUND: [u8; 3] = [30, 30, 30];
+const SIDEBAR_BACKGROUND: [u8; 3] = [45, 45, 48];
+const CARET_COLOR: [u8; 3] = [240, 240, 240];
+const TEXT_COLORS: [[u8; 3]; 4] = [
+    [212, 212, 212],
+    [86, 156, 214],
+    [206, 145, 120],
+    [181, 206, 168],
+];
+const REGION_COLORS: [[u8; 3]; 4] = [[220, 80, 60], [60, 180, 90], [70, 120, 220], [230, 190, 60]];
+const WORDS: [&str; 16] = [
+    "fn",
+    "let",
+    "mut",
+    "self",
+    "frame",
+    "encode",
+    "capture",
+    "match",
+    "Ok(())",
+    "=>",
+    "{",
+    "}",
+    "dirty_rects",
+    "&mut",
+    "return",
+    "sequence",
+];
+
+/// A screen region repainted on a fixed frame schedule
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptedRegion {
+    /// Region to repaint
+    pub rect: DirtyRect,
+    /// Repaint every `period` frames
+    pub period: u32,
+}
+
+/// Deterministic test-pattern capture backend
+pub struct SyntheticCapture {
+    displays: Vec<DisplayInfo>,
+    script: Option<Vec<ScriptedRegion>>,
+    active: Option<ActiveDisplay>,
+    running: bool,
+    sequence: u64,
+    frame_interval: Duration,
+    next_frame_at: Instant,
+    stats: CaptureStats,
+    last_frame_at: Option<Instant>,
+}
+
+/// Render state for the display being captured
+struct ActiveDisplay {
+    display_id: u32,
+    /// Display-local area delivered to the caller (window or region target)
+    crop: Option<DirtyRect>,
+    width: u32,
+    height: u32,
+    canvas: Vec<u8>,
+    layout: Layout,
+    script: Vec<ScriptedRegion>,
+}
+
+/// Scene layout derived from the display size
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    banner: DirtyRect,
+    panel: DirtyRect,
+    sidebar: DirtyRect,
+}
+
+impl SyntheticCapture {
+    /// Create a synthetic capture with a single 1280x720 display
+    pub fn new() -> Self {
+        Self::with_resolution(1280, 720)
+    }
+
+    /// Create a synthetic capture with a single display of the given size
+    pub fn with_resolution(width: u32, height: u32) -> Self {
+        Self::with_displays(vec![DisplayInfo {
+            id: 1,
+            name: "Synthetic Display".to_string(),
+            width,
+            height,
+            refresh_rate: 60.0,
+            scale: 1.0,
+            is_primary: true,
+            x: 0,
+            y: 0,
+        }])
+    }
+
+    /// Create a synthetic capture exposing the given displays
+    pub fn with_displays(displays: Vec<DisplayInfo>) -> Self {
+        Self {
+            displays,
+            script: None,
+            active: None,
+            running: false,
+            sequence: 0,
+            frame_interval: Duration::from_secs_f64(1.0 / 30.0),
+            next_frame_at: Instant::now(),
+            stats: CaptureStats::default(),
+            last_frame_at: None,
+        }
+    }
+
+    /// Replace the default scripted region updates
+    pub fn with_script(mut self, script: Vec<ScriptedRegion>) -> Self {
+        self.script = Some(script);
+        self
+    }
+
+    /// Render the next frame and advance the sequence number
+    fn next_frame(&mut self) -> CaptureResult<CapturedFrame> {
+        let render_start = Instant::now();
+        let sequence = self.sequence;
+        let active = self.active.as_mut().ok_or(CaptureError::NotRunning)?;
+
+        let dirty_rects = active.render(sequence);
+        let mut frame = CapturedFrame {
+            data: Bytes::copy_from_slice(&active.canvas),
+            width: active.width,
+            height: active.height,
+            stride: active.width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence,
+            dirty_rects,
+            display_id: active.display_id,
+        };
+        if let Some(crop) = active.crop {
+            frame = frame.crop(&crop).ok_or_else(|| {
+                CaptureError::CaptureFailed("Capture target is outside the display".to_string())
+            })?;
+        }
+
+        self.sequence += 1;
+        self.update_stats(render_start);
+
+        Ok(frame)
+    }
+
+    fn update_stats(&mut self, render_start: Instant) {
+        let now = Instant::now();
+        let latency_us = now.duration_since(render_start).as_micros() as u64;
+
+        self.stats.frames_captured += 1;
+        self.stats.avg_capture_latency_us = if self.stats.frames_captured == 1 {
+            latency_us
+        } else {
+            (self.stats.avg_capture_latency_us * 7 + latency_us) / 8
+        };
+
+        if let Some(last) = self.last_frame_at {
+            let instant_fps = 1.0 / now.duration_since(last).as_secs_f64().max(1e-6);
+            self.stats.current_fps = if self.stats.current_fps == 0.0 {
+                instant_fps
+            } else {
+                self.stats.current_fps * 0.9 + instant_fps * 0.1
+            };
+        }
+        self.last_frame_at = Some(now);
+    }
+
+    /// Advance the frame clock, counting frames missed by a slow consumer
+    fn advance_clock(&mut self) {
+        self.next_frame_at += self.frame_interval;
+
+        let now = Instant::now();
+        if now > self.next_frame_at + self.frame_interval {
+            let behind = now.duration_since(self.next_frame_at);
+            self.stats.frames_dropped +=
+                (behind.as_secs_f64() / self.frame_interval.as_secs_f64()) as u64;
+            self.next_frame_at = now;
+        }
+    }
+}
+
+/// Fake application windows laid over a display's panel and sidebar
+fn display_windows(display: &DisplayInfo) -> Vec<WindowInfo> {
+    let layout = Layout::new(display.width, display.height);
+    [
+        (1, "Build log", "Terminal", layout.panel),
+        (2, "Activity", "System Monitor", layout.sidebar),
+    ]
+    .into_iter()
+    .filter(|(_, _, _, rect)| rect.width > 0 && rect.height > 0)
+    .map(|(index, title, app_name, rect)| WindowInfo {
+        id: display.id * 100 + index,
+        title: title.to_string(),
+        app_name: app_name.to_string(),
+        x: display.x + rect.x as i32,
+        y: display.y + rect.y as i32,
+        width: rect.width,
+        height: rect.height,
+    })
+    .collect()
+}
+
+impl Default for SyntheticCapture {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScreenCapture for SyntheticCapture {
+    fn displays(&self) -> CaptureResult<Vec<DisplayInfo>> {
+        if self.displays.is_empty() {
+            return Err(CaptureError::NoDisplays);
+        }
+        Ok(self.displays.clone())
+    }
+
+    fn windows(&self) -> CaptureResult<Vec<WindowInfo>> {
+        Ok(self.displays.iter().flat_map(display_windows).collect())
+    }
+
+    fn start(&mut self, config: CaptureConfig) -> CaptureResult<()> {
+        if self.running {
+            return Err(CaptureError::AlreadyRunning);
+        }
+
+        // A window target picks its display; other targets use `display_id`
+        let window = match config.target {
+            CaptureTarget::Window(id) => Some(
+                self.displays
+                    .iter()
+                    .find_map(|d| {
+                        display_windows(d)
+                            .into_iter()
+                            .find(|w| w.id == id)
+                            .map(|w| (d.id, w))
+                    })
+                    .ok_or(CaptureError::WindowNotFound(id))?,
+            ),
+            _ => None,
+        };
+        let display_id = window.as_ref().map(|(id, _)| *id).or(config.display_id);
+
+        let display = match display_id {
+            Some(id) => self
+                .displays
+                .iter()
+                .find(|d| d.id == id)
+                .ok_or(CaptureError::DisplayNotFound(id))?,
+            None => self
+                .displays
+                .iter()
+                .find(|d| d.is_primary)
+                .or_else(|| self.displays.first())
+                .ok_or(CaptureError::NoDisplays)?,
+        };
+
+        if display.width == 0 || display.height == 0 {
+            return Err(CaptureError::InitFailed(format!(
+                "Invalid synthetic display size {}x{}",
+                display.width, display.height
+            )));
+        }
+
+        let layout = Layout::new(display.width, display.height);
+        let script = self
+            .script
+            .clone()
+            .unwrap_or_else(|| layout.default_script());
+
+        let crop = match (config.target, window) {
+            (CaptureTarget::Region(rect), _) => Some(rect),
+            (_, Some((_, window))) => Some(DirtyRect::new(
+                (window.x - display.x) as u32,
+                (window.y - display.y) as u32,
+                window.width,
+                window.height,
+            )),
+            _ => None,
+        };
+
+        self.active = Some(ActiveDisplay {
+            display_id: display.id,
+            crop,
+            width: display.width,
+            height: display.height,
+            canvas: vec![0; display.width as usize * display.height as usize * 4],
+            layout,
+            script,
+        });
+
+        self.frame_interval = Duration::from_secs_f64(1.0 / config.target_fps.max(1) as f64);
+        self.next_frame_at = Instant::now();
+        self.sequence = 0;
+        self.stats = CaptureStats::default();
+        self.last_frame_at = None;
+        self.running = true;
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> CaptureResult<()> {
+        self.running = false;
+        self.active = None;
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        self.running
+    }
+
+    fn capture_frame(&mut self) -> CaptureResult<CapturedFrame> {
+        if !self.running {
+            return Err(CaptureError::NotRunning);
+        }
+
+        let now = Instant::now();
+        if now < self.next_frame_at {
+            std::thread::sleep(self.next_frame_at - now);
+        }
+
+        self.advance_clock();
+        self.next_frame()
+    }
+
+    fn try_capture_frame(&mut self) -> CaptureResult<Option<CapturedFrame>> {
+        if !self.running {
+            return Err(CaptureError::NotRunning);
+        }
+
+        if Instant::now() < self.next_frame_at {
+            return Ok(None);
+        }
+
+        self.advance_clock();
+        self.next_frame().map(Some)
+    }
+
+    fn stats(&self) -> CaptureStats {
+        self.stats.clone()
+    }
+}
+
+impl Layout {
+    fn new(width: u32, height: u32) -> Self {
+        let banner_height = (height / 8).max(1);
+        let panel_width = width * 2 / 3;
+        let body_height = height.saturating_sub(banner_height);
+
+        Self {
+            banner: DirtyRect::new(0, 0, width, banner_height),
+            panel: DirtyRect::new(0, banner_height, panel_width, body_height),
+            sidebar: DirtyRect::new(panel_width, banner_height, width - panel_width, body_height),
+        }
+    }
+
+    /// A notification badge and a progress bar in the sidebar
+    fn default_script(&self) -> Vec<ScriptedRegion> {
+        let sidebar = self.sidebar;
+        let inset = 8.min(sidebar.width / 4);
+        let width = sidebar.width.saturating_sub(inset * 2);
+
+        vec![
+            ScriptedRegion {
+                rect: DirtyRect::new(
+                    sidebar.x + inset,
+                    sidebar.y + inset,
+                    width / 3,
+                    (sidebar.height / 8).min(24),
+                ),
+                period: 20,
+            },
+            ScriptedRegion {
+                rect: DirtyRect::new(
+                    sidebar.x + inset,
+                    sidebar.y + sidebar.height / 2,
+                    width,
+                    (sidebar.height / 16).min(12),
+                ),
+                period: 3,
+            },
+        ]
+    }
+}
+
+impl ActiveDisplay {
+    /// Bring the canvas up to date for `sequence` and return what changed
+    fn render(&mut self, sequence: u64) -> Vec<DirtyRect> {
+        let layout = self.layout;
+
+        if sequence == 0 {
+            self.fill(layout.sidebar, SIDEBAR_BACKGROUND);
+            self.draw_banner(sequence);
+            self.draw_panel(sequence);
+            for region in self.script.clone() {
+                self.draw_region(&region, sequence);
+            }
+            return vec![DirtyRect::full_screen(self.width, self.height)];
+        }
+
+        let mut dirty = Vec::new();
+
+        if layout.banner.area() > 0 {
+            self.draw_banner(sequence);
+            dirty.push(layout.banner);
+        }
+
+        if sequence.is_multiple_of(SCROLL_PERIOD) {
+            self.draw_panel(sequence);
+            dirty.push(layout.panel);
+        } else if sequence.is_multiple_of(BLINK_PERIOD)
+            && let Some(caret) = self.draw_caret(sequence)
+        {
+            dirty.push(caret);
+        }
+
+        for region in self.script.clone() {
+            if region.period > 0
+                && sequence.is_multiple_of(region.period as u64)
+                && let Some(rect) = self.draw_region(&region, sequence)
+            {
+                dirty.push(rect);
+            }
+        }
+
+        dirty
+    }
+
+    fn draw_banner(&mut self, sequence: u64) {
+        let banner = self.layout.banner;
+        let width = self.width as u64;
+        let offset = sequence * GRADIENT_SPEED;
+
+        for y in banner.y..banner.y + banner.height {
+            let g = (y * 255 / banner.height.max(1)) as u8;
+            for x in banner.x..banner.x + banner.width {
+                let t = (x as u64 + offset) % width;
+                let r = (t * 255 / width) as u8;
+                self.put(x, y, [r, g, 255 - r]);
+            }
+        }
+    }
+
+    fn draw_panel(&mut self, sequence: u64) {
+        let panel = self.layout.panel;
+        self.fill(panel, PANEL_BACKGROUND);
+
+        let first_line = sequence / SCROLL_PERIOD;
+        let visible_lines = panel.height / LINE_HEIGHT;
+        for row in 0..visible_lines {
+            let text = line_text(first_line + row as u64);
+            let y = panel.y + row * LINE_HEIGHT;
+            self.draw_text(&text, panel.x + CELL_WIDTH, y);
+        }
+
+        self.draw_caret(sequence);
+    }
+
+    /// Draw or erase the caret after the last visible line
+    fn draw_caret(&mut self, sequence: u64) -> Option<DirtyRect> {
+        let caret = self.caret_rect(sequence)?;
+        let visible = (sequence / BLINK_PERIOD).is_multiple_of(2);
+        let color = if visible {
+            CARET_COLOR
+        } else {
+            PANEL_BACKGROUND
+        };
+        self.fill(caret, color);
+        Some(caret)
+    }
+
+    fn caret_rect(&self, sequence: u64) -> Option<DirtyRect> {
+        let panel = self.layout.panel;
+        let visible_lines = panel.height / LINE_HEIGHT;
+        if visible_lines == 0 {
+            return None;
+        }
+
+        let last_line = sequence / SCROLL_PERIOD + visible_lines as u64 - 1;
+        let columns = line_text(last_line).len() as u32 + 1;
+        let x = panel.x + CELL_WIDTH * (columns + 1);
+        let y = panel.y + (visible_lines - 1) * LINE_HEIGHT + 1;
+
+        if x + 2 > panel.x + panel.width {
+            return None;
+        }
+        Some(DirtyRect::new(x, y, 2, LINE_HEIGHT - 2))
+    }
+
+    fn draw_region(&mut self, region: &ScriptedRegion, sequence: u64) -> Option<DirtyRect> {
+        let rect = self.clip(region.rect)?;
+        let step = sequence / region.period.max(1) as u64;
+        self.fill(rect, REGION_COLORS[step as usize % REGION_COLORS.len()]);
+        Some(rect)
+    }
+
+    fn draw_text(&mut self, text: &str, x: u32, y: u32) {
+        let panel = self.layout.panel;
+        let mut word_index = 0usize;
+
+        for (column, ch) in text.bytes().enumerate() {
+            if ch == b' ' {
+                word_index += 1;
+                continue;
+            }
+
+            let cell_x = x + column as u32 * CELL_WIDTH;
+            if cell_x + CELL_WIDTH > panel.x + panel.width {
+                break;
+            }
+
+            let color = TEXT_COLORS[word_index % TEXT_COLORS.len()];
+            let bits = glyph_bits(ch);
+            for row in 0..7u32 {
+                for col in 0..5u32 {
+                    if bits & (1 << (row * 5 + col)) != 0 {
+                        self.put(cell_x + 1 + col, y + 3 + row, color);
+                    }
+                }
+            }
+        }
+    }
+
+    fn clip(&self, rect: DirtyRect) -> Option<DirtyRect> {
+        let right = (rect.x + rect.width).min(self.width);
+        let bottom = (rect.y + rect.height).min(self.height);
+        if rect.x >= right || rect.y >= bottom {
+            return None;
+        }
+        Some(DirtyRect::new(
+            rect.x,
+            rect.y,
+            right - rect.x,
+            bottom - rect.y,
+        ))
+    }
+
+    fn fill(&mut self, rect: DirtyRect, color: [u8; 3]) {
+        let Some(rect) = self.clip(rect) else {
+            return;
+        };
+        let stride = self.width as usize * 4;
+        let pixel = [color[2], color[1], color[0], 255];
+
+        for y in rect.y..rect.y + rect.height {
+            let start = y as usize * stride + rect.x as usize * 4;
+            let row = &mut self.canvas[start..start + rect.width as usize * 4];
+            for chunk in row.chunks_exact_mut(4) {
+                chunk.copy_from_slice(&pixel);
+            }
+        }
+    }
+
+    fn put(&mut self, x: u32, y: u32, color: [u8; 3]) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let offset = (y as usize * self.width as usize + x as usize) * 4;
+        self.canvas[offset..offset + 4].copy_from_slice(&[color[2], color[1], color[0], 255]);
+    }
+}
+
+/// Deterministic pseudo-source line for the given line number
+pub(crate) fn line_text(line: u64) -> String {
+    let mut state = line
+        .wrapping_mul(6364136223846793005)
+        .wrapping_add(1442695040888963407);
+    let mut next = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 33) as usize
+    };
+
+    let indent = next() % 4;
+    let words = 2 + next() % 6;
+    let mut text = "    ".repeat(indent);
+    for i in 0..words {
+        if i > 0 {
+            text.push(' ');
+        }
+        text.push_str(WORDS[next() % WORDS.len()]);
+    }
+    text
+}
+
+/// 5x7 pseudo-glyph bitmap for an ASCII character
+pub(crate) fn glyph_bits(ch: u8) -> u64 {
+    let hash = (ch as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    (hash >> 17) & ((1 << 35) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(capture: &mut SyntheticCapture) {
+        capture
+            .start(CaptureConfig {
+                target_fps: 1000,
+                ..Default::default()
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_frames_are_deterministic() {
+        let mut a = SyntheticCapture::with_resolution(320, 240);
+        let mut b = SyntheticCapture::with_resolution(320, 240);
+        start(&mut a);
+        start(&mut b);
+
+        for expected_sequence in 0..40 {
+            let fa = a.capture_frame().unwrap();
+            let fb = b.capture_frame().unwrap();
+            assert_eq!(fa.sequence, expected_sequence);
+            assert_eq!(fa.data, fb.data);
+            assert_eq!(fa.dirty_rects, fb.dirty_rects);
+        }
+    }
+
+    #[test]
+    fn test_dirty_rects_cover_all_changes() {
+        let mut capture = SyntheticCapture::with_resolution(320, 240);
+        start(&mut capture);
+
+        let first = capture.capture_frame().unwrap();
+        assert!(first.is_full_update());
+
+        let mut previous = first;
+        for _ in 0..40 {
+            let frame = capture.capture_frame().unwrap();
+            assert!(!frame.dirty_rects.is_empty());
+
+            for y in 0..frame.height {
+                for x in 0..frame.width {
+                    let offset = (y * frame.stride + x * 4) as usize;
+                    if frame.data[offset..offset + 4] != previous.data[offset..offset + 4] {
+                        let pixel = DirtyRect::new(x, y, 1, 1);
+                        assert!(
+                            frame.dirty_rects.iter().any(|r| r.contains(&pixel)),
+                            "pixel ({}, {}) changed outside dirty rects in frame {}",
+                            x,
+                            y,
+                            frame.sequence
+                        );
+                    }
+                }
+            }
+
+            previous = frame;
+        }
+    }
+
+    #[test]
+    fn test_window_target_is_cropped_to_window() {
+        let mut capture = SyntheticCapture::with_resolution(320, 240);
+        let window = capture.windows().unwrap()[0].clone();
+        capture
+            .start(CaptureConfig {
+                target_fps: 1000,
+                target: CaptureTarget::Window(window.id),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let frame = capture.capture_frame().unwrap();
+        assert_eq!(frame.width, window.width & !1);
+        assert_eq!(frame.height, window.height & !1);
+        let bounds = DirtyRect::full_screen(frame.width, frame.height);
+        assert!(frame.dirty_rects.iter().all(|r| bounds.contains(r)));
+
+        capture.stop().unwrap();
+        let result = capture.start(CaptureConfig {
+            target: CaptureTarget::Window(9999),
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(CaptureError::WindowNotFound(9999))));
+    }
+
+    #[test]
+    fn test_unknown_display_is_rejected() {
+        let mut capture = SyntheticCapture::new();
+        let result = capture.start(CaptureConfig {
+            display_id: Some(42),
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(CaptureError::DisplayNotFound(42))));
+    }
+}
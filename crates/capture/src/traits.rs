@@ -1,12 +1,26 @@
 //! Screen capture trait abstraction
 
-use crate::{CaptureResult, CapturedFrame, DisplayInfo};
+use crate::{CaptureResult, CapturedFrame, DirtyRect, DisplayInfo, WindowInfo};
+
+/// What part of the screen to capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaptureTarget {
+    /// The whole display selected by `display_id`
+    #[default]
+    Display,
+    /// A single window, followed as it moves
+    Window(u32),
+    /// A fixed region of the display selected by `display_id`
+    Region(DirtyRect),
+}
 
 /// Capture configuration
 #[derive(Debug, Clone)]
 pub struct CaptureConfig {
     /// Target display ID (None for primary)
     pub display_id: Option<u32>,
+    /// Display, window or region to capture
+    pub target: CaptureTarget,
     /// Target FPS
     pub target_fps: u32,
     /// Enable dirty rect detection
@@ -21,6 +35,7 @@ impl Default for CaptureConfig {
     fn default() -> Self {
         Self {
             display_id: None,
+            target: CaptureTarget::Display,
             target_fps: 30,
             dirty_rects: true,
             capture_cursor: true,
@@ -34,6 +49,9 @@ pub trait ScreenCapture: Send + Sync {
     /// Get available displays
     fn displays(&self) -> CaptureResult<Vec<DisplayInfo>>;
 
+    /// Get capturable top-level windows
+    fn windows(&self) -> CaptureResult<Vec<WindowInfo>>;
+
     /// Start capturing with the given configuration
     fn start(&mut self, config: CaptureConfig) -> CaptureResult<()>;
 
@@ -0,0 +1,1061 @@
+//! Wayland screen capture via xdg-desktop-portal ScreenCast and PipeWire
+//!
+//! The portal negotiates a ScreenCast session over D-Bus and hands back a
+//! PipeWire remote; frames are consumed from that stream on a dedicated
+//! thread. Damage metadata attached to PipeWire buffers becomes the
+//! frame's dirty rects, and the portal's restore token is kept so that
+//! repeated `start()` calls reuse the user's earlier selection instead of
+//! prompting again. The picker lets the user share several monitors at
+//! once; each becomes a display, and `CaptureConfig::display_id` chooses
+//! which one is streamed.
+
+use std::os::fd::OwnedFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use ashpd::desktop::PersistMode;
+use ashpd::desktop::Session;
+use ashpd::desktop::screencast::{CursorMode, Screencast, SourceType};
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+use pipewire as pw;
+use pw::properties::properties;
+use pw::spa;
+use pw::spa::param::video::{VideoFormat, VideoInfoRaw};
+use pw::spa::pod::{ChoiceValue, Object, Pod, Property, Value};
+use pw::spa::utils::{Choice, ChoiceEnum, ChoiceFlags};
+
+use crate::{
+    CaptureConfig, CaptureError, CaptureResult, CaptureStats, CaptureTarget, CapturedFrame,
+    DirtyRect, DisplayInfo, PixelFormat, ScreenCapture, WindowInfo,
+};
+
+/// How long `capture_frame` waits for the compositor before giving up
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+/// Number of damage regions requested per buffer
+const MAX_DAMAGE_REGIONS: usize = 16;
+/// `DMA_BUF_IOCTL_SYNC` from `linux/dma-buf.h`
+const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x4008_6200;
+const DMA_BUF_SYNC_READ: u64 = 1 << 0;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+/// Packed 32-bit RGB formats offered to the compositor, preferred first
+const SUPPORTED_FORMATS: [VideoFormat; 4] = [
+    VideoFormat::BGRx,
+    VideoFormat::BGRA,
+    VideoFormat::RGBx,
+    VideoFormat::RGBA,
+];
+
+/// Whether the current session is running under a Wayland compositor
+pub(crate) fn is_wayland_session() -> bool {
+    std::env::var_os("WAYLAND_DISPLAY").is_some()
+        || std::env::var("XDG_SESSION_TYPE").is_ok_and(|t| t == "wayland")
+}
+
+fn portal_err(e: ashpd::Error) -> CaptureError {
+    match e {
+        ashpd::Error::Response(ashpd::desktop::ResponseError::Cancelled) => {
+            CaptureError::PermissionDenied
+        }
+        ashpd::Error::PortalNotFound(_) => CaptureError::NotAvailable,
+        e => CaptureError::InitFailed(format!("ScreenCast portal: {}", e)),
+    }
+}
+
+fn pipewire_err(e: pw::Error) -> CaptureError {
+    CaptureError::InitFailed(format!("PipeWire: {}", e))
+}
+
+/// Geometry of a stream selected through the portal
+#[derive(Debug, Clone)]
+struct PortalStream {
+    node_id: u32,
+    width: u32,
+    height: u32,
+    x: i32,
+    y: i32,
+}
+
+/// A frame handed from the PipeWire thread to the capture caller
+struct PendingFrame {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    /// `None` when the buffer carried no usable damage (full update)
+    dirty: Option<Vec<DirtyRect>>,
+    timestamp: Instant,
+}
+
+/// State shared between the capture object and the PipeWire thread
+#[derive(Default)]
+struct Shared {
+    pending: Mutex<Option<PendingFrame>>,
+    ready: Condvar,
+    frames_dropped: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    /// Publish a frame, merging its damage with any frame not yet consumed
+    fn publish(&self, mut frame: PendingFrame) {
+        let mut pending = self.pending.lock();
+        if let Some(previous) = pending.take() {
+            self.frames_dropped.fetch_add(1, Ordering::Relaxed);
+            frame.dirty = match (previous.dirty, frame.dirty) {
+                (Some(mut old), Some(new))
+                    if previous.width == frame.width && previous.height == frame.height =>
+                {
+                    old.extend(new);
+                    Some(old)
+                }
+                _ => None,
+            };
+        }
+        *pending = Some(frame);
+        self.ready.notify_one();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _guard = self.pending.lock();
+        self.ready.notify_all();
+    }
+}
+
+/// Portal session established on the worker thread
+struct PortalSession {
+    streams: Vec<PortalStream>,
+    restore_token: Option<String>,
+}
+
+/// Running PipeWire thread
+struct Worker {
+    quit: pw::channel::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+/// Wayland capture backend (xdg-desktop-portal + PipeWire)
+pub struct WaylandCapture {
+    restore_token: Option<String>,
+    streams: Vec<PortalStream>,
+    worker: Option<Worker>,
+    shared: Arc<Shared>,
+    /// Sub-rectangle cut from each frame for region targets
+    crop: Option<DirtyRect>,
+    /// Index of the stream being captured
+    display_id: u32,
+    frame_count: u64,
+    stats: CaptureStats,
+    last_frame_at: Option<Instant>,
+}
+
+impl WaylandCapture {
+    pub fn new() -> CaptureResult<Self> {
+        if !is_wayland_session() {
+            return Err(CaptureError::NotAvailable);
+        }
+
+        Ok(Self {
+            restore_token: None,
+            streams: Vec::new(),
+            worker: None,
+            shared: Arc::new(Shared::default()),
+            crop: None,
+            display_id: 0,
+            frame_count: 0,
+            stats: CaptureStats::default(),
+            last_frame_at: None,
+        })
+    }
+
+    /// Reuse a source selection persisted from an earlier session
+    pub fn with_restore_token(mut self, token: impl Into<String>) -> Self {
+        self.restore_token = Some(token.into());
+        self
+    }
+
+    /// Token the portal issued for the current selection, if any
+    ///
+    /// Persist this to skip the source picker across process restarts.
+    pub fn restore_token(&self) -> Option<&str> {
+        self.restore_token.as_deref()
+    }
+
+    fn finish_frame(&mut self, pending: PendingFrame) -> CapturedFrame {
+        let dirty_rects = pending
+            .dirty
+            .unwrap_or_else(|| vec![DirtyRect::full_screen(pending.width, pending.height)]);
+
+        let mut frame = CapturedFrame {
+            data: Bytes::from(pending.data),
+            width: pending.width,
+            height: pending.height,
+            stride: pending.width * 4,
+            format: pending.format,
+            timestamp: pending.timestamp,
+            sequence: self.frame_count,
+            dirty_rects,
+            display_id: self.display_id,
+        };
+        if let Some(cropped) = self.crop.and_then(|crop| frame.crop(&crop)) {
+            frame = cropped;
+        }
+        self.frame_count += 1;
+
+        let now = Instant::now();
+        self.stats.frames_captured += 1;
+        self.stats.frames_dropped = self.shared.frames_dropped.load(Ordering::Relaxed);
+        let latency_us = now.duration_since(pending.timestamp).as_micros() as u64;
+        self.stats.avg_capture_latency_us =
+            (self.stats.avg_capture_latency_us * 7 + latency_us) / 8;
+        if let Some(last) = self.last_frame_at {
+            let fps = 1.0 / now.duration_since(last).as_secs_f64().max(1e-6);
+            self.stats.current_fps = self.stats.current_fps * 0.9 + fps * 0.1;
+        }
+        self.last_frame_at = Some(now);
+
+        frame
+    }
+}
+
+impl ScreenCapture for WaylandCapture {
+    /// Streams chosen in the most recent portal session
+    ///
+    /// The portal cannot enumerate monitors without prompting, so this is
+    /// empty until the first `start()`, and then lists the monitors the
+    /// user shared. Ids are indices into the session's streams.
+    fn displays(&self) -> CaptureResult<Vec<DisplayInfo>> {
+        Ok(self
+            .streams
+            .iter()
+            .enumerate()
+            .map(|(index, stream)| DisplayInfo {
+                id: index as u32,
+                name: format!("PipeWire node {}", stream.node_id),
+                width: stream.width,
+                height: stream.height,
+                refresh_rate: 60.0,
+                scale: 1.0,
+                is_primary: index == 0,
+                x: stream.x,
+                y: stream.y,
+            })
+            .collect())
+    }
+
+    /// Always empty: the portal's own picker chooses windows
+    ///
+    /// Start with `CaptureTarget::Window` (any id) to have the portal offer
+    /// windows instead of monitors.
+    fn windows(&self) -> CaptureResult<Vec<WindowInfo>> {
+        Ok(Vec::new())
+    }
+
+    fn start(&mut self, config: CaptureConfig) -> CaptureResult<()> {
+        if self.worker.is_some() {
+            return Err(CaptureError::AlreadyRunning);
+        }
+
+        // The restore token remembers a monitor selection, so it is not
+        // offered when asking for a window
+        let (source_type, restore_token) = match config.target {
+            CaptureTarget::Window(_) => (SourceType::Window, None),
+            _ => (SourceType::Monitor, self.restore_token.clone()),
+        };
+        self.crop = match config.target {
+            CaptureTarget::Region(region) => Some(region),
+            _ => None,
+        };
+
+        let display_id = config.display_id;
+
+        self.shared = Arc::new(Shared::default());
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let (quit_tx, quit_rx) = pw::channel::channel::<()>();
+        let shared = self.shared.clone();
+
+        let thread = std::thread::Builder::new()
+            .name("wayland-capture".to_string())
+            .spawn(move || {
+                run_worker(
+                    source_type,
+                    restore_token,
+                    config.capture_cursor,
+                    display_id,
+                    shared,
+                    ready_tx,
+                    quit_rx,
+                )
+            })
+            .map_err(|e| CaptureError::InitFailed(e.to_string()))?;
+
+        let session = match ready_rx.recv() {
+            Ok(Ok(session)) => session,
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                return Err(e);
+            }
+            Err(_) => {
+                let _ = thread.join();
+                return Err(CaptureError::Internal(
+                    "Capture thread exited during setup".to_string(),
+                ));
+            }
+        };
+
+        if session.restore_token.is_some() && source_type == SourceType::Monitor {
+            self.restore_token = session.restore_token;
+        }
+        self.streams = session.streams;
+        self.display_id = display_id.unwrap_or(0);
+        self.worker = Some(Worker {
+            quit: quit_tx,
+            thread,
+        });
+        self.stats = CaptureStats::default();
+        self.last_frame_at = None;
+
+        tracing::info!(
+            "Wayland capture started ({} stream(s), restore token {})",
+            self.streams.len(),
+            if self.restore_token.is_some() {
+                "saved"
+            } else {
+                "not issued"
+            }
+        );
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> CaptureResult<()> {
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.quit.send(());
+            worker
+                .thread
+                .join()
+                .map_err(|_| CaptureError::Internal("Capture thread panicked".to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        self.worker.is_some() && !self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    fn capture_frame(&mut self) -> CaptureResult<CapturedFrame> {
+        if self.worker.is_none() {
+            return Err(CaptureError::NotRunning);
+        }
+
+        let deadline = Instant::now() + FRAME_TIMEOUT;
+        let pending = {
+            let mut pending = self.shared.pending.lock();
+            loop {
+                if let Some(frame) = pending.take() {
+                    break frame;
+                }
+                if self.shared.closed.load(Ordering::SeqCst) {
+                    return Err(CaptureError::CaptureFailed(
+                        "PipeWire stream closed".to_string(),
+                    ));
+                }
+                if self
+                    .shared
+                    .ready
+                    .wait_until(&mut pending, deadline)
+                    .timed_out()
+                {
+                    return Err(CaptureError::Timeout);
+                }
+            }
+        };
+
+        Ok(self.finish_frame(pending))
+    }
+
+    fn try_capture_frame(&mut self) -> CaptureResult<Option<CapturedFrame>> {
+        if self.worker.is_none() {
+            return Err(CaptureError::NotRunning);
+        }
+
+        let pending = self.shared.pending.lock().take();
+        match pending {
+            Some(pending) => Ok(Some(self.finish_frame(pending))),
+            None if self.shared.closed.load(Ordering::SeqCst) => Err(CaptureError::CaptureFailed(
+                "PipeWire stream closed".to_string(),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn stats(&self) -> CaptureStats {
+        self.stats.clone()
+    }
+}
+
+impl Drop for WaylandCapture {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Negotiate a ScreenCast session and open its PipeWire remote
+async fn open_portal(
+    source_type: SourceType,
+    restore_token: Option<String>,
+    capture_cursor: bool,
+) -> Result<
+    (
+        Screencast<'static>,
+        Session<'static, Screencast<'static>>,
+        PortalSession,
+        OwnedFd,
+    ),
+    ashpd::Error,
+> {
+    let proxy = Screencast::new().await?;
+    let session = proxy.create_session().await?;
+
+    let cursor_modes = proxy.available_cursor_modes().await.unwrap_or_default();
+    let cursor_mode = if capture_cursor && cursor_modes.contains(CursorMode::Embedded) {
+        CursorMode::Embedded
+    } else {
+        CursorMode::Hidden
+    };
+
+    // Every monitor the user shares becomes a display to switch between;
+    // a window is captured on its own
+    let multiple = source_type == SourceType::Monitor;
+    proxy
+        .select_sources(
+            &session,
+            cursor_mode,
+            source_type.into(),
+            multiple,
+            restore_token.as_deref(),
+            PersistMode::ExplicitlyRevoked,
+        )
+        .await?;
+
+    let response = proxy.start(&session, None).await?.response()?;
+    let streams = response
+        .streams()
+        .iter()
+        .map(|stream| {
+            let (width, height) = stream.size().unwrap_or((0, 0));
+            let (x, y) = stream.position().unwrap_or((0, 0));
+            PortalStream {
+                node_id: stream.pipe_wire_node_id(),
+                width: width.max(0) as u32,
+                height: height.max(0) as u32,
+                x,
+                y,
+            }
+        })
+        .collect();
+    let portal = PortalSession {
+        streams,
+        restore_token: response.restore_token().map(str::to_string),
+    };
+
+    let fd = proxy.open_pipe_wire_remote(&session).await?;
+
+    Ok((proxy, session, portal, fd))
+}
+
+/// Body of the capture thread: portal negotiation, then the PipeWire loop
+///
+/// `display_id` picks one of the portal's streams by index, the first
+/// when `None`.
+fn run_worker(
+    source_type: SourceType,
+    restore_token: Option<String>,
+    capture_cursor: bool,
+    display_id: Option<u32>,
+    shared: Arc<Shared>,
+    ready: std::sync::mpsc::Sender<CaptureResult<PortalSession>>,
+    quit: pw::channel::Receiver<()>,
+) {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            let _ = ready.send(Err(CaptureError::InitFailed(e.to_string())));
+            return;
+        }
+    };
+
+    let (_proxy, session, portal, fd) =
+        match runtime.block_on(open_portal(source_type, restore_token, capture_cursor)) {
+            Ok(opened) => opened,
+            Err(e) => {
+                let _ = ready.send(Err(portal_err(e)));
+                return;
+            }
+        };
+
+    let index = display_id.unwrap_or(0) as usize;
+    let Some(node_id) = portal.streams.get(index).map(|s| s.node_id) else {
+        let _ = ready.send(Err(match display_id {
+            Some(id) if !portal.streams.is_empty() => CaptureError::DisplayNotFound(id),
+            _ => CaptureError::NoDisplays,
+        }));
+        if let Err(e) = runtime.block_on(session.close()) {
+            tracing::debug!("Failed to close ScreenCast session: {}", e);
+        }
+        return;
+    };
+
+    if let Err(e) = run_stream(fd, node_id, shared.clone(), portal, &ready, quit) {
+        let _ = ready.send(Err(e));
+    }
+
+    shared.close();
+    if let Err(e) = runtime.block_on(session.close()) {
+        tracing::debug!("Failed to close ScreenCast session: {}", e);
+    }
+}
+
+/// Per-stream state owned by the PipeWire listener
+struct StreamData {
+    format: VideoInfoRaw,
+    shared: Arc<Shared>,
+}
+
+fn run_stream(
+    fd: OwnedFd,
+    node_id: u32,
+    shared: Arc<Shared>,
+    portal: PortalSession,
+    ready: &std::sync::mpsc::Sender<CaptureResult<PortalSession>>,
+    quit: pw::channel::Receiver<()>,
+) -> CaptureResult<()> {
+    pw::init();
+
+    let mainloop = pw::main_loop::MainLoop::new(None).map_err(pipewire_err)?;
+    let context = pw::context::Context::new(&mainloop).map_err(pipewire_err)?;
+    let core = context.connect_fd(fd, None).map_err(pipewire_err)?;
+
+    let stream = pw::stream::Stream::new(
+        &core,
+        "entangle-screen",
+        properties! {
+            *pw::keys::MEDIA_TYPE => "Video",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Screen",
+        },
+    )
+    .map_err(pipewire_err)?;
+
+    let weak_loop = mainloop.downgrade();
+    let _listener = stream
+        .add_local_listener_with_user_data(StreamData {
+            format: VideoInfoRaw::default(),
+            shared: shared.clone(),
+        })
+        .state_changed(move |_, _, old, new| {
+            tracing::debug!("PipeWire stream state: {:?} -> {:?}", old, new);
+            if let pw::stream::StreamState::Error(e) = new {
+                tracing::warn!("PipeWire stream failed: {}", e);
+                if let Some(mainloop) = weak_loop.upgrade() {
+                    mainloop.quit();
+                }
+            }
+        })
+        .param_changed(|stream, data, id, param| {
+            let Some(param) = param else {
+                return;
+            };
+            if id != spa::param::ParamType::Format.as_raw() {
+                return;
+            }
+            if data.format.parse(param).is_err() {
+                return;
+            }
+
+            let size = data.format.size();
+            tracing::info!(
+                "PipeWire format: {:?} {}x{}",
+                data.format.format(),
+                size.width,
+                size.height
+            );
+
+            let meta = serialize_pod(damage_meta_param());
+            let buffers = serialize_pod(buffers_param());
+            let mut params = [
+                Pod::from_bytes(&meta).expect("valid meta pod"),
+                Pod::from_bytes(&buffers).expect("valid buffers pod"),
+            ];
+            if let Err(e) = stream.update_params(&mut params) {
+                tracing::warn!("Failed to request damage metadata: {}", e);
+            }
+        })
+        .process(|stream, data| process_buffers(stream, data))
+        .register()
+        .map_err(pipewire_err)?;
+
+    let format = serialize_pod(enum_format_param());
+    let mut params = [Pod::from_bytes(&format).expect("valid format pod")];
+    stream
+        .connect(
+            spa::utils::Direction::Input,
+            Some(node_id),
+            pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )
+        .map_err(pipewire_err)?;
+
+    let weak_loop = mainloop.downgrade();
+    let _quit = quit.attach(mainloop.loop_(), move |_| {
+        if let Some(mainloop) = weak_loop.upgrade() {
+            mainloop.quit();
+        }
+    });
+
+    let _ = ready.send(Ok(portal));
+    mainloop.run();
+
+    let _ = stream.disconnect();
+    Ok(())
+}
+
+fn serialize_pod(object: Object) -> Vec<u8> {
+    spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &Value::Object(object),
+    )
+    .expect("pod serialization into a Vec cannot fail")
+    .0
+    .into_inner()
+}
+
+/// [`SUPPORTED_FORMATS`], any size and framerate
+fn enum_format_param() -> Object {
+    spa::pod::object!(
+        spa::utils::SpaTypes::ObjectParamFormat,
+        spa::param::ParamType::EnumFormat,
+        spa::pod::property!(
+            spa::param::format::FormatProperties::MediaType,
+            Id,
+            spa::param::format::MediaType::Video
+        ),
+        spa::pod::property!(
+            spa::param::format::FormatProperties::MediaSubtype,
+            Id,
+            spa::param::format::MediaSubtype::Raw
+        ),
+        spa::pod::property!(
+            spa::param::format::FormatProperties::VideoFormat,
+            Choice,
+            Enum,
+            Id,
+            SUPPORTED_FORMATS[0],
+            SUPPORTED_FORMATS[0],
+            SUPPORTED_FORMATS[1],
+            SUPPORTED_FORMATS[2],
+            SUPPORTED_FORMATS[3],
+        ),
+        spa::pod::property!(
+            spa::param::format::FormatProperties::VideoSize,
+            Choice,
+            Range,
+            Rectangle,
+            spa::utils::Rectangle {
+                width: 1920,
+                height: 1080
+            },
+            spa::utils::Rectangle {
+                width: 1,
+                height: 1
+            },
+            spa::utils::Rectangle {
+                width: 8192,
+                height: 8192
+            }
+        ),
+        spa::pod::property!(
+            spa::param::format::FormatProperties::VideoFramerate,
+            Choice,
+            Range,
+            Fraction,
+            spa::utils::Fraction { num: 60, denom: 1 },
+            spa::utils::Fraction { num: 0, denom: 1 },
+            spa::utils::Fraction { num: 360, denom: 1 }
+        ),
+    )
+}
+
+/// Ask the producer to attach `SPA_META_VideoDamage` to each buffer
+fn damage_meta_param() -> Object {
+    let region_size = std::mem::size_of::<spa::sys::spa_meta_region>() * MAX_DAMAGE_REGIONS;
+    Object {
+        type_: spa::utils::SpaTypes::ObjectParamMeta.as_raw(),
+        id: spa::param::ParamType::Meta.as_raw(),
+        properties: vec![
+            Property::new(
+                spa::sys::SPA_PARAM_META_type,
+                Value::Id(spa::utils::Id(spa::sys::SPA_META_VideoDamage)),
+            ),
+            Property::new(
+                spa::sys::SPA_PARAM_META_size,
+                Value::Int(region_size as i32),
+            ),
+        ],
+    }
+}
+
+/// Accept shared memory and DMA-BUF buffers
+fn buffers_param() -> Object {
+    let data_types = (1 << spa::sys::SPA_DATA_MemPtr)
+        | (1 << spa::sys::SPA_DATA_MemFd)
+        | (1 << spa::sys::SPA_DATA_DmaBuf);
+    Object {
+        type_: spa::utils::SpaTypes::ObjectParamBuffers.as_raw(),
+        id: spa::param::ParamType::Buffers.as_raw(),
+        properties: vec![Property::new(
+            spa::sys::SPA_PARAM_BUFFERS_dataType,
+            Value::Choice(ChoiceValue::Int(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Flags {
+                    default: data_types,
+                    flags: vec![data_types],
+                },
+            ))),
+        )],
+    }
+}
+
+/// Drain the stream's queue, keeping the newest buffer and all damage
+fn process_buffers(stream: &pw::stream::StreamRef, data: &mut StreamData) {
+    let mut newest: *mut pw::sys::pw_buffer = std::ptr::null_mut();
+    let mut dirty = Some(Vec::new());
+
+    loop {
+        let next = unsafe { stream.dequeue_raw_buffer() };
+        if next.is_null() {
+            break;
+        }
+        if !newest.is_null() {
+            dirty = merge_damage(dirty, unsafe { read_damage(newest) });
+            unsafe { stream.queue_raw_buffer(newest) };
+        }
+        newest = next;
+    }
+    if newest.is_null() {
+        return;
+    }
+
+    dirty = merge_damage(dirty, unsafe { read_damage(newest) });
+    let frame = unsafe { read_frame(newest, &data.format) };
+    unsafe { stream.queue_raw_buffer(newest) };
+
+    match frame {
+        Ok(Some((bytes, format))) => {
+            let size = data.format.size();
+            // Drop damage that falls outside the frame (e.g. after a resize)
+            let dirty = dirty.map(|rects| {
+                rects
+                    .into_iter()
+                    .filter_map(|r| clip_rect(r, size.width, size.height))
+                    .collect::<Vec<_>>()
+            });
+            if dirty.as_ref().is_some_and(|rects| rects.is_empty()) {
+                return;
+            }
+            data.shared.publish(PendingFrame {
+                data: bytes,
+                width: size.width,
+                height: size.height,
+                format,
+                dirty,
+                timestamp: Instant::now(),
+            });
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Failed to read PipeWire buffer: {}", e),
+    }
+}
+
+fn merge_damage(
+    acc: Option<Vec<DirtyRect>>,
+    next: Option<Vec<DirtyRect>>,
+) -> Option<Vec<DirtyRect>> {
+    match (acc, next) {
+        (Some(mut acc), Some(next)) => {
+            acc.extend(next);
+            Some(acc)
+        }
+        _ => None,
+    }
+}
+
+fn clip_rect(rect: DirtyRect, width: u32, height: u32) -> Option<DirtyRect> {
+    let right = (rect.x + rect.width).min(width);
+    let bottom = (rect.y + rect.height).min(height);
+    (rect.x < right && rect.y < bottom)
+        .then(|| DirtyRect::new(rect.x, rect.y, right - rect.x, bottom - rect.y))
+}
+
+/// Damage regions attached to a buffer, `None` if the producer sent none
+///
+/// # Safety
+/// `buffer` must be a buffer dequeued from a live stream.
+unsafe fn read_damage(buffer: *mut pw::sys::pw_buffer) -> Option<Vec<DirtyRect>> {
+    let spa_buffer = unsafe { (*buffer).buffer };
+    if spa_buffer.is_null() {
+        return None;
+    }
+    let (metas, n_metas) = unsafe { ((*spa_buffer).metas, (*spa_buffer).n_metas) };
+    if metas.is_null() || n_metas == 0 {
+        return None;
+    }
+
+    let metas = unsafe { std::slice::from_raw_parts(metas, n_metas as usize) };
+    let meta = metas
+        .iter()
+        .find(|m| m.type_ == spa::sys::SPA_META_VideoDamage && !m.data.is_null())?;
+
+    let count = meta.size as usize / std::mem::size_of::<spa::sys::spa_meta_region>();
+    let regions =
+        unsafe { std::slice::from_raw_parts(meta.data as *const spa::sys::spa_meta_region, count) };
+    Some(parse_damage(regions))
+}
+
+/// Dirty rects from a `SPA_META_VideoDamage` region list, which ends at
+/// its first zero-sized entry
+fn parse_damage(regions: &[spa::sys::spa_meta_region]) -> Vec<DirtyRect> {
+    regions
+        .iter()
+        .map(|r| r.region)
+        .take_while(|r| r.size.width > 0 && r.size.height > 0)
+        .map(|r| {
+            DirtyRect::new(
+                r.position.x.max(0) as u32,
+                r.position.y.max(0) as u32,
+                r.size.width,
+                r.size.height,
+            )
+        })
+        .collect()
+}
+
+/// Frame pixel format for a negotiated PipeWire video format
+fn pixel_format(format: VideoFormat) -> CaptureResult<PixelFormat> {
+    match format {
+        VideoFormat::BGRx | VideoFormat::BGRA => Ok(PixelFormat::Bgra8),
+        VideoFormat::RGBx | VideoFormat::RGBA => Ok(PixelFormat::Rgba8),
+        other => Err(CaptureError::UnsupportedFormat(format!("{:?}", other))),
+    }
+}
+
+/// Pack `height` rows of `width` 32-bit pixels out of `src`, the first at
+/// `offset` and each `stride` bytes after the last
+fn copy_rows(
+    src: &[u8],
+    offset: usize,
+    stride: usize,
+    width: u32,
+    height: u32,
+) -> CaptureResult<Vec<u8>> {
+    if width == 0 || height == 0 {
+        return Err(CaptureError::CaptureFailed(format!(
+            "empty {}x{} frame",
+            width, height
+        )));
+    }
+    let row_bytes = width as usize * 4;
+    if stride < row_bytes {
+        return Err(CaptureError::CaptureFailed(format!(
+            "stride {} is shorter than a {} byte row",
+            stride, row_bytes
+        )));
+    }
+
+    let needed = stride
+        .checked_mul(height as usize - 1)
+        .and_then(|rows| rows.checked_add(offset))
+        .and_then(|end| end.checked_add(row_bytes));
+    if needed.is_none_or(|needed| src.len() < needed) {
+        return Err(CaptureError::CaptureFailed(format!(
+            "buffer holds {} bytes, {}x{} frame at offset {} with stride {} needs more",
+            src.len(),
+            width,
+            height,
+            offset,
+            stride
+        )));
+    }
+
+    let mut out = Vec::with_capacity(row_bytes * height as usize);
+    for row in 0..height as usize {
+        let start = offset + row * stride;
+        out.extend_from_slice(&src[start..start + row_bytes]);
+    }
+    Ok(out)
+}
+
+/// Copy a buffer's pixels into a tightly packed vector
+///
+/// Returns `None` for metadata-only buffers (e.g. cursor moves with an
+/// embedded cursor disabled).
+///
+/// # Safety
+/// `buffer` must be a buffer dequeued from a live stream.
+unsafe fn read_frame(
+    buffer: *mut pw::sys::pw_buffer,
+    format: &VideoInfoRaw,
+) -> CaptureResult<Option<(Vec<u8>, PixelFormat)>> {
+    let pixel_format = pixel_format(format.format())?;
+
+    let spa_buffer = unsafe { (*buffer).buffer };
+    if spa_buffer.is_null() || unsafe { (*spa_buffer).n_datas } == 0 {
+        return Ok(None);
+    }
+    let data = unsafe { &*(*spa_buffer).datas };
+    let chunk = unsafe { &*data.chunk };
+    if chunk.size == 0 || chunk.flags & spa::sys::SPA_CHUNK_FLAG_CORRUPTED as i32 != 0 {
+        return Ok(None);
+    }
+
+    let size = format.size();
+    let stride = if chunk.stride > 0 {
+        chunk.stride as usize
+    } else {
+        size.width as usize * 4
+    };
+    let copy_rows =
+        |src: &[u8]| copy_rows(src, chunk.offset as usize, stride, size.width, size.height);
+
+    if !data.data.is_null() {
+        let src =
+            unsafe { std::slice::from_raw_parts(data.data as *const u8, data.maxsize as usize) };
+        return copy_rows(src).map(|bytes| Some((bytes, pixel_format)));
+    }
+
+    if data.type_ == spa::sys::SPA_DATA_DmaBuf {
+        // Unmapped DMA-BUF: map it for CPU reads (linear layouts only)
+        let fd = data.fd as libc::c_int;
+        let len = data.mapoffset as usize + data.maxsize as usize;
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(CaptureError::CaptureFailed(format!(
+                "mmap of DMA-BUF failed: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        let sync = |flags: u64| unsafe {
+            libc::ioctl(fd, DMA_BUF_IOCTL_SYNC, &flags as *const u64);
+        };
+        sync(DMA_BUF_SYNC_READ);
+        let src = unsafe {
+            std::slice::from_raw_parts(
+                (map as *const u8).add(data.mapoffset as usize),
+                data.maxsize as usize,
+            )
+        };
+        let result = copy_rows(src);
+        sync(DMA_BUF_SYNC_READ | DMA_BUF_SYNC_END);
+        unsafe { libc::munmap(map, len) };
+
+        return result.map(|bytes| Some((bytes, pixel_format)));
+    }
+
+    Err(CaptureError::CaptureFailed(format!(
+        "unmappable buffer type {}",
+        data.type_
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: i32, y: i32, width: u32, height: u32) -> spa::sys::spa_meta_region {
+        spa::sys::spa_meta_region {
+            region: spa::sys::spa_region {
+                position: spa::sys::spa_point { x, y },
+                size: spa::sys::spa_rectangle { width, height },
+            },
+        }
+    }
+
+    #[test]
+    fn test_damage_stops_at_first_empty_region() {
+        let regions = [
+            region(10, 20, 30, 40),
+            region(-5, 0, 8, 8),
+            region(0, 0, 0, 0),
+            region(100, 100, 10, 10),
+        ];
+        assert_eq!(
+            parse_damage(&regions),
+            vec![DirtyRect::new(10, 20, 30, 40), DirtyRect::new(0, 0, 8, 8)]
+        );
+        assert!(parse_damage(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_copy_rows_drops_stride_padding() {
+        // 2x3 frame, 4 bytes of padding per row, after a 2 byte offset
+        let mut src = vec![0xEE; 2];
+        for row in 0..3u8 {
+            src.extend((0..8).map(|i| row * 10 + i));
+            src.extend([0xFF; 4]);
+        }
+
+        let out = copy_rows(&src, 2, 12, 2, 3).unwrap();
+        assert_eq!(out.len(), 2 * 3 * 4);
+        assert_eq!(&out[..8], &[0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(&out[16..], &[20, 21, 22, 23, 24, 25, 26, 27]);
+        assert!(!out.contains(&0xFF));
+
+        // The last row may end without its padding
+        assert!(copy_rows(&src[..src.len() - 4], 2, 12, 2, 3).is_ok());
+    }
+
+    #[test]
+    fn test_copy_rows_rejects_bad_geometry() {
+        let src = vec![0; 64];
+        assert!(copy_rows(&src, 0, 8, 0, 2).is_err());
+        assert!(copy_rows(&src, 0, 8, 2, 0).is_err());
+        // Stride shorter than a row
+        assert!(copy_rows(&src, 0, 4, 2, 2).is_err());
+        // One byte past the end
+        assert!(copy_rows(&src, 1, 8, 2, 8).is_err());
+        assert!(copy_rows(&src, 0, 8, 2, 8).is_ok());
+        assert!(copy_rows(&src, usize::MAX - 4, 8, 2, 2).is_err());
+    }
+
+    #[test]
+    fn test_offered_formats_are_all_accepted() {
+        for format in SUPPORTED_FORMATS {
+            assert!(pixel_format(format).is_ok(), "{:?}", format);
+        }
+        assert_eq!(pixel_format(VideoFormat::BGRx).unwrap(), PixelFormat::Bgra8);
+        assert_eq!(pixel_format(VideoFormat::RGBA).unwrap(), PixelFormat::Rgba8);
+        assert!(matches!(
+            pixel_format(VideoFormat::NV12),
+            Err(CaptureError::UnsupportedFormat(_))
+        ));
+
+        let pod = serialize_pod(enum_format_param());
+        assert!(Pod::from_bytes(&pod).is_some());
+    }
+}
@@ -0,0 +1,974 @@
+//! X11 screen capture using MIT-SHM and XDamage
+//!
+//! Frames are grabbed from the root window into a shared memory segment
+//! (no pixel data crosses the X socket), changed regions come from an
+//! XDamage object on the root window, and monitors are enumerated via
+//! XRandR. Window and region targets grab a sub-rectangle of the root
+//! window; window bounds are re-read periodically so the capture follows
+//! the window as it moves or resizes.
+
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::protocol::Event;
+use x11rb::protocol::damage::{self, ConnectionExt as _};
+use x11rb::protocol::randr::{self, ConnectionExt as _};
+use x11rb::protocol::shm::{self, ConnectionExt as _};
+use x11rb::protocol::xfixes::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{self, ConnectionExt as _, ImageFormat};
+use x11rb::rust_connection::RustConnection;
+
+use crate::{
+    CaptureConfig, CaptureError, CaptureResult, CaptureStats, CaptureTarget, CapturedFrame,
+    DirtyRect, DisplayInfo, PixelFormat, ScreenCapture, WindowInfo,
+};
+
+/// Above this many damage rectangles a frame reports their bounding box
+const MAX_DIRTY_RECTS: usize = 64;
+/// How long `capture_frame` waits for damage before giving up
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often a followed window's bounds are re-read
+const WINDOW_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn platform_err(e: impl std::fmt::Display) -> CaptureError {
+    CaptureError::Platform(e.to_string())
+}
+
+/// SysV shared memory segment attached to the X server
+struct ShmSegment {
+    seg: shm::Seg,
+    addr: *mut u8,
+    size: usize,
+}
+
+impl ShmSegment {
+    fn new(conn: &RustConnection, size: usize) -> CaptureResult<Self> {
+        let shmid = unsafe { libc::shmget(libc::IPC_PRIVATE, size, libc::IPC_CREAT | 0o600) };
+        if shmid < 0 {
+            return Err(CaptureError::InitFailed(format!(
+                "shmget failed: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        let addr = unsafe { libc::shmat(shmid, ptr::null(), 0) };
+        if addr as isize == -1 {
+            let err = std::io::Error::last_os_error();
+            unsafe { libc::shmctl(shmid, libc::IPC_RMID, ptr::null_mut()) };
+            return Err(CaptureError::InitFailed(format!("shmat failed: {}", err)));
+        }
+
+        let attach = conn.generate_id().map_err(platform_err).and_then(|seg| {
+            conn.shm_attach(seg, shmid as u32, false)
+                .map_err(platform_err)?
+                .check()
+                .map_err(platform_err)?;
+            Ok(seg)
+        });
+
+        // The segment is destroyed once both we and the server detach
+        unsafe { libc::shmctl(shmid, libc::IPC_RMID, ptr::null_mut()) };
+
+        match attach {
+            Ok(seg) => Ok(Self {
+                seg,
+                addr: addr as *mut u8,
+                size,
+            }),
+            Err(e) => {
+                unsafe { libc::shmdt(addr) };
+                Err(e)
+            }
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.addr, self.size) }
+    }
+
+    fn release(self, conn: &RustConnection) {
+        if let Ok(cookie) = conn.shm_detach(self.seg) {
+            cookie.ignore_error();
+        }
+        unsafe { libc::shmdt(self.addr as *const libc::c_void) };
+    }
+}
+
+/// Root-window area being captured: a monitor, or a window/region on it
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Monitor {
+    id: u32,
+    rect: DirtyRect,
+    x: i16,
+    y: i16,
+}
+
+/// Per-capture X resources
+struct ActiveCapture {
+    monitor: Monitor,
+    /// Window being followed, if the target is a window
+    window: Option<xproto::Window>,
+    next_window_poll: Instant,
+    segment: ShmSegment,
+    damage: damage::Damage,
+    region: xfixes::Region,
+    capture_cursor: bool,
+    last_cursor: Option<(DirtyRect, u32)>,
+    /// Pointer position on the root window at the last grab
+    last_pointer: Option<(i16, i16)>,
+    /// The cursor changed shape since the last grab
+    cursor_changed: bool,
+    frame_interval: Duration,
+    next_frame_at: Instant,
+    first_frame: bool,
+}
+
+/// X11 capture backend
+pub struct X11Capture {
+    conn: RustConnection,
+    root: xproto::Window,
+    root_width: u16,
+    root_height: u16,
+    active: Option<ActiveCapture>,
+    frame_count: u64,
+    stats: CaptureStats,
+    last_frame_at: Option<Instant>,
+}
+
+// SAFETY: the only raw pointer is the SHM mapping, which is owned by the
+// active capture and only read from `&mut self` methods; `&self` methods
+// only make requests on the `RustConnection`, which is itself Send + Sync
+unsafe impl Send for X11Capture {}
+unsafe impl Sync for X11Capture {}
+
+impl X11Capture {
+    /// Connect to the X server named by `$DISPLAY`
+    pub fn new() -> CaptureResult<Self> {
+        let (conn, screen_num) = x11rb::connect(None).map_err(|_| CaptureError::NotAvailable)?;
+        let screen = &conn.setup().roots[screen_num];
+        let root = screen.root;
+        let root_depth = screen.root_depth;
+        let (root_width, root_height) = (screen.width_in_pixels, screen.height_in_pixels);
+
+        let bpp = conn
+            .setup()
+            .pixmap_formats
+            .iter()
+            .find(|f| f.depth == root_depth)
+            .map(|f| f.bits_per_pixel);
+        if bpp != Some(32) {
+            return Err(CaptureError::UnsupportedFormat(format!(
+                "root depth {} with {:?} bits per pixel",
+                root_depth, bpp
+            )));
+        }
+
+        for (name, present) in [
+            (
+                "MIT-SHM",
+                conn.extension_information(shm::X11_EXTENSION_NAME),
+            ),
+            (
+                "DAMAGE",
+                conn.extension_information(damage::X11_EXTENSION_NAME),
+            ),
+            (
+                "XFIXES",
+                conn.extension_information(xfixes::X11_EXTENSION_NAME),
+            ),
+            (
+                "RANDR",
+                conn.extension_information(randr::X11_EXTENSION_NAME),
+            ),
+        ] {
+            if !matches!(present, Ok(Some(_))) {
+                return Err(CaptureError::InitFailed(format!(
+                    "X server lacks the {} extension",
+                    name
+                )));
+            }
+        }
+
+        // Extensions must be version-negotiated before first use
+        conn.shm_query_version()
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?;
+        conn.xfixes_query_version(5, 0)
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?;
+        conn.damage_query_version(1, 1)
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?;
+        conn.randr_query_version(1, 5)
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?;
+
+        Ok(Self {
+            conn,
+            root,
+            root_width,
+            root_height,
+            active: None,
+            frame_count: 0,
+            stats: CaptureStats::default(),
+            last_frame_at: None,
+        })
+    }
+
+    fn monitors(&self) -> CaptureResult<Vec<(Monitor, randr::MonitorInfo)>> {
+        let reply = self
+            .conn
+            .randr_get_monitors(self.root, true)
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?;
+
+        Ok(reply
+            .monitors
+            .into_iter()
+            .map(|info| {
+                let monitor = Monitor {
+                    id: info.name,
+                    rect: DirtyRect::new(0, 0, info.width as u32, info.height as u32),
+                    x: info.x,
+                    y: info.y,
+                };
+                (monitor, info)
+            })
+            .collect())
+    }
+
+    /// Refresh rate of the CRTC driving the monitor's first output
+    fn refresh_rate(&self, resources: &randr::GetScreenResourcesCurrentReply, output: u32) -> f64 {
+        let rate = || -> Option<f64> {
+            let timestamp = resources.config_timestamp;
+            let output = self
+                .conn
+                .randr_get_output_info(output, timestamp)
+                .ok()?
+                .reply()
+                .ok()?;
+            if output.crtc == x11rb::NONE {
+                return None;
+            }
+            let crtc = self
+                .conn
+                .randr_get_crtc_info(output.crtc, timestamp)
+                .ok()?
+                .reply()
+                .ok()?;
+            let mode = resources.modes.iter().find(|m| m.id == crtc.mode)?;
+            let total = mode.htotal as f64 * mode.vtotal as f64;
+            (total > 0.0).then(|| mode.dot_clock as f64 / total)
+        };
+
+        rate().unwrap_or(60.0)
+    }
+
+    fn intern_atom(&self, name: &str) -> CaptureResult<xproto::Atom> {
+        Ok(self
+            .conn
+            .intern_atom(false, name.as_bytes())
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?
+            .atom)
+    }
+
+    fn property_bytes(
+        &self,
+        window: xproto::Window,
+        property: xproto::Atom,
+        type_: impl Into<xproto::Atom>,
+    ) -> Option<Vec<u8>> {
+        self.conn
+            .get_property(false, window, property, type_, 0, u32::MAX)
+            .ok()?
+            .reply()
+            .ok()
+            .map(|reply| reply.value)
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Root-space bounds of a mapped window, `None` if it is gone or hidden
+    fn window_bounds(&self, window: xproto::Window) -> CaptureResult<Option<(i32, i32, u32, u32)>> {
+        let Some(attributes) = self
+            .conn
+            .get_window_attributes(window)
+            .map_err(platform_err)?
+            .reply()
+            .ok()
+        else {
+            return Ok(None);
+        };
+        if attributes.map_state != xproto::MapState::VIEWABLE {
+            return Ok(None);
+        }
+
+        let Some(geometry) = self
+            .conn
+            .get_geometry(window)
+            .map_err(platform_err)?
+            .reply()
+            .ok()
+        else {
+            return Ok(None);
+        };
+        let Some(origin) = self
+            .conn
+            .translate_coordinates(window, self.root, 0, 0)
+            .map_err(platform_err)?
+            .reply()
+            .ok()
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some((
+            origin.dst_x as i32,
+            origin.dst_y as i32,
+            geometry.width as u32,
+            geometry.height as u32,
+        )))
+    }
+
+    /// Capture area for a root-space rect, clipped to the screen with even size
+    fn area(&self, id: u32, x: i32, y: i32, width: u32, height: u32) -> Option<Monitor> {
+        let screen = DirtyRect::full_screen(self.root_width as u32, self.root_height as u32);
+        let left = x.max(0) as u32;
+        let top = y.max(0) as u32;
+        let right = (x + width as i32).max(0) as u32;
+        let bottom = (y + height as i32).max(0) as u32;
+        let clipped = screen.intersect(&DirtyRect::new(
+            left,
+            top,
+            right.saturating_sub(left),
+            bottom.saturating_sub(top),
+        ))?;
+
+        let (width, height) = (clipped.width & !1, clipped.height & !1);
+        (width > 0 && height > 0).then(|| Monitor {
+            id,
+            rect: DirtyRect::new(0, 0, width, height),
+            x: clipped.x as i16,
+            y: clipped.y as i16,
+        })
+    }
+
+    /// Re-read a followed window's bounds; a changed area forces a full frame
+    fn follow_window(&mut self) -> CaptureResult<()> {
+        let Some(active) = self.active.as_ref() else {
+            return Err(CaptureError::NotRunning);
+        };
+        let Some(window) = active.window else {
+            return Ok(());
+        };
+        if Instant::now() < active.next_window_poll {
+            return Ok(());
+        }
+
+        let id = active.monitor.id;
+        let bounds = self
+            .window_bounds(window)?
+            .ok_or(CaptureError::WindowNotFound(window))?;
+        let area = self
+            .area(id, bounds.0, bounds.1, bounds.2, bounds.3)
+            .ok_or(CaptureError::WindowNotFound(window))?;
+
+        let active = self.active.as_mut().ok_or(CaptureError::NotRunning)?;
+        active.next_window_poll = Instant::now() + WINDOW_POLL_INTERVAL;
+        if area != active.monitor {
+            tracing::debug!(
+                "Window {} moved to {}x{} at {},{}",
+                window,
+                area.rect.width,
+                area.rect.height,
+                area.x,
+                area.y
+            );
+            active.monitor = area;
+            active.last_cursor = None;
+            active.first_frame = true;
+        }
+
+        Ok(())
+    }
+
+    /// HiDPI scale from the `Xft.dpi` X resource
+    fn scale(&self) -> f64 {
+        let property = self
+            .conn
+            .get_property(
+                false,
+                self.root,
+                xproto::AtomEnum::RESOURCE_MANAGER,
+                xproto::AtomEnum::STRING,
+                0,
+                u32::MAX,
+            )
+            .ok()
+            .and_then(|cookie| cookie.reply().ok());
+
+        property
+            .and_then(|reply| {
+                String::from_utf8_lossy(&reply.value)
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Xft.dpi:"))
+                    .and_then(|dpi| dpi.trim().parse::<f64>().ok())
+            })
+            .map(|dpi| dpi / 96.0)
+            .unwrap_or(1.0)
+    }
+
+    /// Pull accumulated damage, clipped and translated to the monitor
+    fn take_damage(&mut self) -> CaptureResult<Vec<DirtyRect>> {
+        // Drain events: the region itself carries the damage, and
+        // CursorNotify only flags a new cursor shape
+        let mut cursor_changed = false;
+        while let Ok(Some(event)) = self.conn.poll_for_event() {
+            cursor_changed |= matches!(event, Event::XfixesCursorNotify(_));
+        }
+
+        let active = self.active.as_mut().ok_or(CaptureError::NotRunning)?;
+        active.cursor_changed |= cursor_changed;
+        self.conn
+            .damage_subtract(active.damage, x11rb::NONE, active.region)
+            .map_err(platform_err)?;
+        let reply = self
+            .conn
+            .xfixes_fetch_region(active.region)
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?;
+
+        let monitor = active.monitor;
+        let mut rects: Vec<DirtyRect> = reply
+            .rectangles
+            .iter()
+            .filter_map(|r| {
+                let left = (r.x as i32 - monitor.x as i32).max(0);
+                let top = (r.y as i32 - monitor.y as i32).max(0);
+                let right =
+                    (r.x as i32 + r.width as i32 - monitor.x as i32).min(monitor.rect.width as i32);
+                let bottom = (r.y as i32 + r.height as i32 - monitor.y as i32)
+                    .min(monitor.rect.height as i32);
+                (left < right && top < bottom).then(|| {
+                    DirtyRect::new(
+                        left as u32,
+                        top as u32,
+                        (right - left) as u32,
+                        (bottom - top) as u32,
+                    )
+                })
+            })
+            .collect();
+
+        if rects.len() > MAX_DIRTY_RECTS {
+            let bounds = rects.iter().copied().reduce(|acc, r| acc.merge(&r));
+            rects = bounds.into_iter().collect();
+        }
+
+        Ok(rects)
+    }
+
+    /// Whether the cursor moved or changed shape since the last grab
+    ///
+    /// Far cheaper than grabbing the monitor just to find the cursor
+    /// where it was.
+    fn cursor_moved(&mut self) -> CaptureResult<bool> {
+        let active = self.active.as_ref().ok_or(CaptureError::NotRunning)?;
+        if !active.capture_cursor {
+            return Ok(false);
+        }
+        let pointer = self
+            .conn
+            .query_pointer(self.root)
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?;
+
+        let active = self.active.as_mut().ok_or(CaptureError::NotRunning)?;
+        let position = Some((pointer.root_x, pointer.root_y));
+        let moved = active.last_pointer != position || active.cursor_changed;
+        active.last_pointer = position;
+        active.cursor_changed = false;
+        Ok(moved)
+    }
+
+    /// Blend the cursor into `data` and report its old/new position if it changed
+    fn composite_cursor(&mut self, data: &mut [u8], dirty: &mut Vec<DirtyRect>) {
+        let Some(active) = self.active.as_mut() else {
+            return;
+        };
+        if !active.capture_cursor {
+            return;
+        }
+
+        let Some(cursor) = self
+            .conn
+            .xfixes_get_cursor_image()
+            .ok()
+            .and_then(|cookie| cookie.reply().ok())
+        else {
+            return;
+        };
+
+        active.last_pointer = Some((cursor.x, cursor.y));
+        active.cursor_changed = false;
+
+        let monitor = active.monitor;
+        let origin_x = cursor.x as i32 - cursor.xhot as i32 - monitor.x as i32;
+        let origin_y = cursor.y as i32 - cursor.yhot as i32 - monitor.y as i32;
+        let width = monitor.rect.width as i32;
+        let height = monitor.rect.height as i32;
+
+        let left = origin_x.max(0);
+        let top = origin_y.max(0);
+        let right = (origin_x + cursor.width as i32).min(width);
+        let bottom = (origin_y + cursor.height as i32).min(height);
+        let visible = left < right && top < bottom;
+
+        let current = visible.then(|| {
+            (
+                DirtyRect::new(
+                    left as u32,
+                    top as u32,
+                    (right - left) as u32,
+                    (bottom - top) as u32,
+                ),
+                cursor.cursor_serial,
+            )
+        });
+
+        if current != active.last_cursor {
+            dirty.extend(active.last_cursor.map(|(rect, _)| rect));
+            dirty.extend(current.map(|(rect, _)| rect));
+            active.last_cursor = current;
+        }
+
+        if !visible {
+            return;
+        }
+
+        let stride = width as usize * 4;
+        for y in top..bottom {
+            for x in left..right {
+                let src_index = ((y - origin_y) * cursor.width as i32 + (x - origin_x)) as usize;
+                let argb = cursor.cursor_image[src_index];
+                let alpha = argb >> 24;
+                if alpha == 0 {
+                    continue;
+                }
+
+                let offset = y as usize * stride + x as usize * 4;
+                let inv = 255 - alpha;
+                // Cursor pixels are premultiplied ARGB; the frame is BGRX
+                for (channel, shift) in [(0, 0), (1, 8), (2, 16)] {
+                    let src = (argb >> shift) & 0xFF;
+                    let dst = data[offset + channel] as u32;
+                    data[offset + channel] = (src + dst * inv / 255).min(255) as u8;
+                }
+            }
+        }
+    }
+
+    fn grab(&mut self) -> CaptureResult<Option<CapturedFrame>> {
+        let start = Instant::now();
+        self.follow_window()?;
+        let mut dirty = self.take_damage()?;
+
+        let active = self.active.as_mut().ok_or(CaptureError::NotRunning)?;
+        let monitor = active.monitor;
+        let first_frame = std::mem::take(&mut active.first_frame);
+
+        if dirty.is_empty() && !first_frame && !self.cursor_moved()? {
+            return Ok(None);
+        }
+        let active = self.active.as_mut().ok_or(CaptureError::NotRunning)?;
+
+        self.conn
+            .shm_get_image(
+                self.root,
+                monitor.x,
+                monitor.y,
+                monitor.rect.width as u16,
+                monitor.rect.height as u16,
+                !0,
+                ImageFormat::Z_PIXMAP.into(),
+                active.segment.seg,
+                0,
+            )
+            .map_err(platform_err)?
+            .reply()
+            .map_err(|e| CaptureError::CaptureFailed(e.to_string()))?;
+
+        // The segment is sized for the root window; only the start holds the image
+        let len = monitor.rect.width as usize * monitor.rect.height as usize * 4;
+        let mut data = active.segment.as_slice()[..len].to_vec();
+        self.composite_cursor(&mut data, &mut dirty);
+
+        if first_frame {
+            dirty = vec![monitor.rect];
+        } else if dirty.is_empty() {
+            return Ok(None);
+        }
+
+        let frame = CapturedFrame {
+            data: Bytes::from(data),
+            width: monitor.rect.width,
+            height: monitor.rect.height,
+            stride: monitor.rect.width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence: self.frame_count,
+            dirty_rects: dirty,
+            display_id: monitor.id,
+        };
+        self.frame_count += 1;
+        self.update_stats(start);
+
+        Ok(Some(frame))
+    }
+
+    fn update_stats(&mut self, start: Instant) {
+        let now = Instant::now();
+        let latency_us = now.duration_since(start).as_micros() as u64;
+
+        self.stats.frames_captured += 1;
+        self.stats.avg_capture_latency_us =
+            (self.stats.avg_capture_latency_us * 7 + latency_us) / 8;
+        if let Some(last) = self.last_frame_at {
+            let fps = 1.0 / now.duration_since(last).as_secs_f64().max(1e-6);
+            self.stats.current_fps = self.stats.current_fps * 0.9 + fps * 0.1;
+        }
+        self.last_frame_at = Some(now);
+    }
+
+    fn release(&mut self) {
+        if let Some(active) = self.active.take() {
+            if let Ok(cookie) = self.conn.damage_destroy(active.damage) {
+                cookie.ignore_error();
+            }
+            if let Ok(cookie) = self.conn.xfixes_destroy_region(active.region) {
+                cookie.ignore_error();
+            }
+            if active.capture_cursor
+                && let Ok(cookie) = self
+                    .conn
+                    .xfixes_select_cursor_input(self.root, xfixes::CursorNotifyMask::from(0u32))
+            {
+                cookie.ignore_error();
+            }
+            active.segment.release(&self.conn);
+            let _ = self.conn.flush();
+        }
+    }
+}
+
+impl ScreenCapture for X11Capture {
+    fn displays(&self) -> CaptureResult<Vec<DisplayInfo>> {
+        let resources = self
+            .conn
+            .randr_get_screen_resources_current(self.root)
+            .map_err(platform_err)?
+            .reply()
+            .map_err(platform_err)?;
+        let scale = self.scale();
+
+        let displays: Vec<DisplayInfo> = self
+            .monitors()?
+            .into_iter()
+            .map(|(monitor, info)| {
+                let name = self
+                    .conn
+                    .get_atom_name(info.name)
+                    .ok()
+                    .and_then(|cookie| cookie.reply().ok())
+                    .map(|reply| String::from_utf8_lossy(&reply.name).into_owned())
+                    .unwrap_or_else(|| format!("Monitor {}", info.name));
+                let refresh_rate = info
+                    .outputs
+                    .first()
+                    .map(|output| self.refresh_rate(&resources, *output))
+                    .unwrap_or(60.0);
+
+                DisplayInfo {
+                    id: monitor.id,
+                    name,
+                    width: monitor.rect.width,
+                    height: monitor.rect.height,
+                    refresh_rate,
+                    scale,
+                    is_primary: info.primary,
+                    x: info.x as i32,
+                    y: info.y as i32,
+                }
+            })
+            .collect();
+
+        if displays.is_empty() {
+            return Err(CaptureError::NoDisplays);
+        }
+        Ok(displays)
+    }
+
+    fn windows(&self) -> CaptureResult<Vec<WindowInfo>> {
+        let client_list = self.intern_atom("_NET_CLIENT_LIST")?;
+        let net_wm_name = self.intern_atom("_NET_WM_NAME")?;
+        let utf8_string = self.intern_atom("UTF8_STRING")?;
+
+        let clients: Vec<xproto::Window> = self
+            .property_bytes(self.root, client_list, xproto::AtomEnum::WINDOW)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(|id| u32::from_ne_bytes([id[0], id[1], id[2], id[3]]))
+            .collect();
+
+        let mut windows = Vec::new();
+        for window in clients {
+            let Some((x, y, width, height)) = self.window_bounds(window)? else {
+                continue;
+            };
+
+            let title = self
+                .property_bytes(window, net_wm_name, utf8_string)
+                .or_else(|| {
+                    self.property_bytes(
+                        window,
+                        xproto::AtomEnum::WM_NAME.into(),
+                        xproto::AtomEnum::STRING,
+                    )
+                })
+                .map(|name| String::from_utf8_lossy(&name).into_owned())
+                .unwrap_or_default();
+            // WM_CLASS holds "instance\0class\0"; the class names the application
+            let app_name = self
+                .property_bytes(
+                    window,
+                    xproto::AtomEnum::WM_CLASS.into(),
+                    xproto::AtomEnum::STRING,
+                )
+                .and_then(|class| {
+                    class
+                        .split(|b| *b == 0)
+                        .rfind(|part| !part.is_empty())
+                        .map(|part| String::from_utf8_lossy(part).into_owned())
+                })
+                .unwrap_or_default();
+
+            windows.push(WindowInfo {
+                id: window,
+                title,
+                app_name,
+                x,
+                y,
+                width,
+                height,
+            });
+        }
+
+        Ok(windows)
+    }
+
+    fn start(&mut self, config: CaptureConfig) -> CaptureResult<()> {
+        if self.active.is_some() {
+            return Err(CaptureError::AlreadyRunning);
+        }
+
+        let monitors = self.monitors()?;
+        let window = match config.target {
+            CaptureTarget::Window(id) => Some((
+                id,
+                self.window_bounds(id)?
+                    .ok_or(CaptureError::WindowNotFound(id))?,
+            )),
+            _ => None,
+        };
+        // A window is attributed to the monitor holding its centre
+        let display_id = match window {
+            Some((_, (x, y, width, height))) => {
+                let (cx, cy) = (x + width as i32 / 2, y + height as i32 / 2);
+                monitors
+                    .iter()
+                    .find(|(m, _)| {
+                        cx >= m.x as i32
+                            && cy >= m.y as i32
+                            && cx < m.x as i32 + m.rect.width as i32
+                            && cy < m.y as i32 + m.rect.height as i32
+                    })
+                    .map(|(m, _)| m.id)
+            }
+            None => config.display_id,
+        };
+        let (monitor, _) = match display_id {
+            Some(id) => monitors
+                .iter()
+                .find(|(m, _)| m.id == id)
+                .ok_or(CaptureError::DisplayNotFound(id))?,
+            None => monitors
+                .iter()
+                .find(|(_, info)| info.primary)
+                .or_else(|| monitors.first())
+                .ok_or(CaptureError::NoDisplays)?,
+        };
+        let monitor = match (config.target, window) {
+            (_, Some((id, (x, y, width, height)))) => self
+                .area(monitor.id, x, y, width, height)
+                .ok_or(CaptureError::WindowNotFound(id))?,
+            (CaptureTarget::Region(region), _) => {
+                let region = monitor.rect.intersect(&region).ok_or_else(|| {
+                    CaptureError::InitFailed("Capture region is outside the display".to_string())
+                })?;
+                self.area(
+                    monitor.id,
+                    monitor.x as i32 + region.x as i32,
+                    monitor.y as i32 + region.y as i32,
+                    region.width,
+                    region.height,
+                )
+                .ok_or_else(|| CaptureError::InitFailed("Capture region is empty".to_string()))?
+            }
+            _ => *monitor,
+        };
+
+        // Sized for the whole screen so a followed window can grow freely
+        let size = self.root_width as usize * self.root_height as usize * 4;
+        let segment = ShmSegment::new(&self.conn, size)?;
+
+        let setup = || -> CaptureResult<(damage::Damage, xfixes::Region)> {
+            let damage = self.conn.generate_id().map_err(platform_err)?;
+            self.conn
+                .damage_create(damage, self.root, damage::ReportLevel::NON_EMPTY)
+                .map_err(platform_err)?
+                .check()
+                .map_err(platform_err)?;
+            let region = self.conn.generate_id().map_err(platform_err)?;
+            self.conn
+                .xfixes_create_region(region, &[])
+                .map_err(platform_err)?
+                .check()
+                .map_err(platform_err)?;
+            if config.capture_cursor {
+                self.conn
+                    .xfixes_select_cursor_input(self.root, xfixes::CursorNotifyMask::DISPLAY_CURSOR)
+                    .map_err(platform_err)?
+                    .check()
+                    .map_err(platform_err)?;
+            }
+            Ok((damage, region))
+        };
+        let (damage, region) = match setup() {
+            Ok(ids) => ids,
+            Err(e) => {
+                segment.release(&self.conn);
+                return Err(e);
+            }
+        };
+
+        self.active = Some(ActiveCapture {
+            monitor,
+            window: window.map(|(id, _)| id),
+            next_window_poll: Instant::now() + WINDOW_POLL_INTERVAL,
+            segment,
+            damage,
+            region,
+            capture_cursor: config.capture_cursor,
+            last_cursor: None,
+            last_pointer: None,
+            cursor_changed: false,
+            frame_interval: Duration::from_secs_f64(1.0 / config.target_fps.max(1) as f64),
+            next_frame_at: Instant::now(),
+            first_frame: true,
+        });
+        self.stats = CaptureStats::default();
+        self.last_frame_at = None;
+
+        tracing::info!(
+            "X11 capture started on monitor {} ({}x{} at {},{}, target {:?})",
+            monitor.id,
+            monitor.rect.width,
+            monitor.rect.height,
+            monitor.x,
+            monitor.y,
+            config.target
+        );
+
+        Ok(())
+    }
+
+    fn stop(&mut self) -> CaptureResult<()> {
+        self.release();
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        self.active.is_some()
+    }
+
+    fn capture_frame(&mut self) -> CaptureResult<CapturedFrame> {
+        let start = Instant::now();
+        loop {
+            let active = self.active.as_mut().ok_or(CaptureError::NotRunning)?;
+            let now = Instant::now();
+            if now < active.next_frame_at {
+                std::thread::sleep(active.next_frame_at - now);
+            }
+            active.next_frame_at = Instant::now() + active.frame_interval;
+
+            if let Some(frame) = self.grab()? {
+                return Ok(frame);
+            }
+
+            if start.elapsed() > FRAME_TIMEOUT {
+                return Err(CaptureError::Timeout);
+            }
+        }
+    }
+
+    fn try_capture_frame(&mut self) -> CaptureResult<Option<CapturedFrame>> {
+        let active = self.active.as_mut().ok_or(CaptureError::NotRunning)?;
+        if Instant::now() < active.next_frame_at {
+            return Ok(None);
+        }
+        active.next_frame_at = Instant::now() + active.frame_interval;
+
+        self.grab()
+    }
+
+    fn stats(&self) -> CaptureStats {
+        self.stats.clone()
+    }
+}
+
+impl Drop for X11Capture {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against whatever `$DISPLAY` points at (e.g. `Xvfb :99`)
+    #[test]
+    #[ignore = "needs an X server on $DISPLAY"]
+    fn test_capture_against_x_server() {
+        let mut capture = X11Capture::new().expect("no X server on $DISPLAY");
+
+        let displays = capture.displays().unwrap();
+        assert!(displays.iter().any(|d| d.width > 0 && d.height > 0));
+
+        capture.start(CaptureConfig::default()).unwrap();
+        let frame = capture.capture_frame().unwrap();
+        assert!(frame.is_full_update());
+        assert_eq!(frame.data.len(), (frame.width * frame.height * 4) as usize);
+        capture.stop().unwrap();
+    }
+}
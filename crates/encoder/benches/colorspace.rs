@@ -0,0 +1,102 @@
+//! RGB to I420 conversion throughput
+//!
+//! `legacy` is the scalar per-pixel BT.601 loop the OpenH264 encoder
+//! used before the colorspace module, including its copy of the output
+//! buffer, kept here as the baseline.
+
+use std::time::Instant;
+
+use bytes::Bytes;
+use capture::{CapturedFrame, PixelFormat};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use encoder::{ColorMatrix, ColorRange, ColorSpace, I420Buffer, rgb_to_i420};
+
+fn frame(width: u32, height: u32) -> CapturedFrame {
+    let data = (0..width * height * 4)
+        .map(|i| (i % 251) as u8 ^ (i / (width * 4)) as u8)
+        .collect::<Vec<_>>();
+    CapturedFrame {
+        data: Bytes::from(data),
+        width,
+        height,
+        stride: width * 4,
+        format: PixelFormat::Bgra8,
+        timestamp: Instant::now(),
+        sequence: 0,
+        dirty_rects: Vec::new(),
+        display_id: 0,
+    }
+}
+
+fn legacy(frame: &CapturedFrame, yuv_buffer: &mut Vec<u8>) -> Vec<u8> {
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let y_size = width * height;
+    let uv_size = (width / 2) * (height / 2);
+    yuv_buffer.resize(y_size + 2 * uv_size, 0);
+
+    let (y_plane, uv_planes) = yuv_buffer.split_at_mut(y_size);
+    let (u_plane, v_plane) = uv_planes.split_at_mut(uv_size);
+    for y in 0..height {
+        for x in 0..width {
+            let pixel_offset = y * frame.stride as usize + x * 4;
+            let (r, g, b) = (
+                frame.data[pixel_offset + 2] as i32,
+                frame.data[pixel_offset + 1] as i32,
+                frame.data[pixel_offset] as i32,
+            );
+
+            let y_val = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+            y_plane[y * width + x] = y_val.clamp(0, 255) as u8;
+
+            if (x % 2 == 0) && (y % 2 == 0) {
+                let u_val = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+                let v_val = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+                let uv_idx = (y / 2) * (width / 2) + (x / 2);
+                u_plane[uv_idx] = u_val.clamp(0, 255) as u8;
+                v_plane[uv_idx] = v_val.clamp(0, 255) as u8;
+            }
+        }
+    }
+
+    yuv_buffer.to_vec()
+}
+
+fn bench_rgb_to_i420(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rgb_to_i420");
+    for (name, width, height) in [("1080p", 1920, 1080), ("4k", 3840, 2160)] {
+        let input = frame(width, height);
+        group.throughput(Throughput::Elements((width * height) as u64));
+
+        let mut buffer = Vec::new();
+        group.bench_with_input(BenchmarkId::new("legacy", name), &input, |b, input| {
+            b.iter(|| legacy(input, &mut buffer))
+        });
+
+        for (label, color) in [
+            (
+                "bt601_limited",
+                ColorSpace {
+                    matrix: ColorMatrix::Bt601,
+                    range: ColorRange::Limited,
+                },
+            ),
+            (
+                "bt709_full",
+                ColorSpace {
+                    matrix: ColorMatrix::Bt709,
+                    range: ColorRange::Full,
+                },
+            ),
+        ] {
+            let mut out = I420Buffer::new();
+            group.bench_with_input(BenchmarkId::new(label, name), &input, |b, input| {
+                b.iter(|| rgb_to_i420(input, color, &mut out).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rgb_to_i420);
+criterion_main!(benches);
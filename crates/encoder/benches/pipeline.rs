@@ -0,0 +1,122 @@
+//! Host pipeline stages on representative screen content
+//!
+//! Each clip runs through the stages the host applies to every captured
+//! frame: conversion to I420, OpenH264 encoding and packetization. The
+//! `bench` binary reports the matching sizes and quality.
+
+use capture::{CapturedFrame, ClipRenderer, ScreenClip};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use encoder::{
+    EncodedFrame, EncoderConfig, I420Buffer, OpenH264Encoder, VideoEncoder, rgb_to_i420,
+};
+use net_transport::{MAX_DATAGRAM_SIZE, Packetizer};
+use shared_protocol::{FrameType, VideoPacketHeader};
+
+/// Frames rendered per clip and cycled through while measuring
+const FRAMES: usize = 30;
+const WIDTH: u32 = 1920;
+const HEIGHT: u32 = 1080;
+
+fn clip_frames(clip: ScreenClip) -> Vec<CapturedFrame> {
+    let mut renderer = ClipRenderer::new(clip, WIDTH, HEIGHT);
+    (0..FRAMES).map(|_| renderer.next_frame()).collect()
+}
+
+fn encoder() -> OpenH264Encoder {
+    let mut encoder = OpenH264Encoder::new();
+    encoder
+        .init(EncoderConfig {
+            width: WIDTH,
+            height: HEIGHT,
+            ..Default::default()
+        })
+        .unwrap();
+    encoder
+}
+
+fn bench_rgb_to_i420(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rgb_to_i420");
+    group.throughput(Throughput::Elements((WIDTH * HEIGHT) as u64));
+    for clip in ScreenClip::ALL {
+        let frames = clip_frames(clip);
+        let color = EncoderConfig::default().color_space;
+        let mut out = I420Buffer::new();
+        let mut index = 0;
+        group.bench_function(BenchmarkId::from_parameter(clip.name()), |b| {
+            b.iter(|| {
+                index = (index + 1) % FRAMES;
+                rgb_to_i420(&frames[index], color, &mut out).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("openh264_encode");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements((WIDTH * HEIGHT) as u64));
+    for clip in ScreenClip::ALL {
+        let frames = clip_frames(clip);
+        let mut encoder = encoder();
+        let mut index = 0;
+        group.bench_function(BenchmarkId::from_parameter(clip.name()), |b| {
+            b.iter(|| {
+                index = (index + 1) % FRAMES;
+                encoder.encode(&frames[index]).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn header(frame: &EncodedFrame) -> VideoPacketHeader {
+    VideoPacketHeader {
+        frame_id: frame.sequence,
+        fragment_index: 0,
+        total_fragments: 1,
+        timestamp_us: frame.pts_us,
+        frame_type: FrameType::Delta,
+        codec: frame.codec.into(),
+        width: frame.width,
+        height: frame.height,
+        dirty_rect: None,
+        resolution_changed: false,
+        slice_aligned: false,
+        temporal_id: frame.temporal_id,
+        reference_frame_id: 0,
+        parity: None,
+    }
+}
+
+fn bench_packetize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packetize");
+    let packetizer = Packetizer::new(MAX_DATAGRAM_SIZE).unwrap();
+    for clip in ScreenClip::ALL {
+        let mut encoder = encoder();
+        let encoded: Vec<_> = clip_frames(clip)
+            .iter()
+            .map(|frame| encoder.encode(frame).unwrap())
+            .collect();
+        let bytes: usize = encoded.iter().map(|frame| frame.data.len()).sum();
+        group.throughput(Throughput::Bytes((bytes / FRAMES) as u64));
+
+        let mut index = 0;
+        group.bench_function(BenchmarkId::from_parameter(clip.name()), |b| {
+            b.iter(|| {
+                index = (index + 1) % FRAMES;
+                let frame = &encoded[index];
+                packetizer
+                    .packetize(&header(frame), &frame.data, &frame.slices)
+                    .unwrap()
+                    .iter()
+                    .map(|packet| packet.to_bytes().unwrap().len())
+                    .sum::<usize>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rgb_to_i420, bench_encode, bench_packetize);
+criterion_main!(benches);
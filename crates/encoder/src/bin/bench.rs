@@ -0,0 +1,209 @@
+//! Encoder pipeline benchmark on synthetic screen content
+//!
+//! Runs each [`ScreenClip`] through colorspace conversion, the OpenH264
+//! encoder and the packetizer, decodes the result and reports per-frame
+//! timings, output size and quality against the converted source.
+//!
+//! ```text
+//! cargo run --release -p encoder --features bench --bin bench -- \
+//!     --frames 300 --size 1920x1080 --bitrate 8000
+//! ```
+
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use capture::{ClipRenderer, ScreenClip};
+use encoder::{
+    EncodedFrame, EncodedFrameType, EncoderConfig, I420Buffer, OpenH264Decoder, OpenH264Encoder,
+    VideoEncoder, psnr, rgb_to_i420, ssim,
+};
+use net_transport::{MAX_DATAGRAM_SIZE, Packetizer};
+use shared_protocol::{FrameType, VideoPacketHeader};
+
+/// Benchmark settings, from the command line
+struct Options {
+    frames: u32,
+    width: u32,
+    height: u32,
+    bitrate_kbps: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            frames: 300,
+            width: 1920,
+            height: 1080,
+            bitrate_kbps: 8000,
+        }
+    }
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Self::default();
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} needs a value", flag));
+            match flag.as_str() {
+                "--frames" => options.frames = number(&value()?)?,
+                "--bitrate" => options.bitrate_kbps = number(&value()?)?,
+                "--size" => {
+                    let size = value()?;
+                    let (width, height) = size
+                        .split_once('x')
+                        .ok_or(format!("size must be WIDTHxHEIGHT, got {}", size))?;
+                    options.width = number(width)?;
+                    options.height = number(height)?;
+                }
+                _ => return Err(format!("unknown argument {}", flag)),
+            }
+        }
+        if options.frames == 0 || options.width < 16 || options.height < 16 {
+            return Err("need at least one frame of at least 16x16".into());
+        }
+        Ok(options)
+    }
+}
+
+fn number(value: &str) -> Result<u32, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a number, got {}", value))
+}
+
+/// Totals for one clip
+#[derive(Default)]
+struct ClipReport {
+    frames: u32,
+    convert: Duration,
+    encode: Duration,
+    packetize: Duration,
+    bytes: u64,
+    packets: u64,
+    psnr: f64,
+    ssim: f64,
+}
+
+impl ClipReport {
+    fn print(&self, name: &str) {
+        let frames = self.frames.max(1);
+        let per_frame = |total: Duration| total.as_micros() as f64 / frames as f64;
+        println!(
+            "{:<12} {:>10.0} {:>10.0} {:>10.1} {:>10} {:>8.1} {:>8.2} {:>7.4}",
+            name,
+            per_frame(self.convert),
+            per_frame(self.encode),
+            per_frame(self.packetize),
+            self.bytes / frames as u64,
+            self.packets as f64 / frames as f64,
+            self.psnr / frames as f64,
+            self.ssim / frames as f64,
+        );
+    }
+}
+
+fn header(frame: &EncodedFrame) -> VideoPacketHeader {
+    VideoPacketHeader {
+        frame_id: frame.sequence,
+        fragment_index: 0,
+        total_fragments: 1,
+        timestamp_us: frame.pts_us,
+        frame_type: match frame.frame_type {
+            EncodedFrameType::Key => FrameType::Key,
+            EncodedFrameType::Recovery => FrameType::Recovery,
+            _ => FrameType::Delta,
+        },
+        codec: frame.codec.into(),
+        width: frame.width,
+        height: frame.height,
+        dirty_rect: None,
+        resolution_changed: false,
+        slice_aligned: false,
+        temporal_id: frame.temporal_id,
+        reference_frame_id: 0,
+        parity: None,
+    }
+}
+
+fn run_clip(clip: ScreenClip, options: &Options) -> Result<ClipReport, String> {
+    let config = EncoderConfig {
+        width: options.width,
+        height: options.height,
+        bitrate_kbps: options.bitrate_kbps,
+        ..Default::default()
+    };
+    let mut encoder = OpenH264Encoder::new();
+    encoder.init(config.clone()).map_err(|e| e.to_string())?;
+    let mut decoder = OpenH264Decoder::new().map_err(|e| e.to_string())?;
+    let packetizer = Packetizer::new(MAX_DATAGRAM_SIZE).map_err(|e| e.to_string())?;
+    let mut renderer = ClipRenderer::new(clip, options.width, options.height);
+    let (mut source, mut decoded) = (I420Buffer::new(), I420Buffer::new());
+
+    let mut report = ClipReport::default();
+    for _ in 0..options.frames {
+        let frame = renderer.next_frame();
+
+        // Conversion is timed on its own; the encoder repeats it inside
+        // encode, as the single-threaded host loop did
+        let started = Instant::now();
+        rgb_to_i420(&frame, config.color_space, &mut source).map_err(|e| e.to_string())?;
+        report.convert += started.elapsed();
+
+        let started = Instant::now();
+        let encoded = encoder.encode(&frame).map_err(|e| e.to_string())?;
+        report.encode += started.elapsed();
+
+        let started = Instant::now();
+        let packets = packetizer
+            .packetize(&header(&encoded), &encoded.data, &encoded.slices)
+            .map_err(|e| e.to_string())?;
+        for packet in &packets {
+            packet.to_bytes().map_err(|e| e.to_string())?;
+        }
+        report.packetize += started.elapsed();
+
+        report.frames += 1;
+        report.bytes += encoded.data.len() as u64;
+        report.packets += packets.len() as u64;
+        if !decoder
+            .decode(&encoded.data, &mut decoded)
+            .map_err(|e| e.to_string())?
+        {
+            return Err(format!("frame {} did not decode", encoded.sequence));
+        }
+        // An exact frame scores infinity; cap it so averages stay finite
+        report.psnr += psnr(&source, &decoded).min(100.0);
+        report.ssim += ssim(&source, &decoded);
+    }
+    Ok(report)
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: bench [--frames N] [--size WIDTHxHEIGHT] [--bitrate KBPS]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!(
+        "{} frames at {}x{}, {} kbps; times in us per frame",
+        options.frames, options.width, options.height, options.bitrate_kbps
+    );
+    println!(
+        "{:<12} {:>10} {:>10} {:>10} {:>10} {:>8} {:>8} {:>7}",
+        "clip", "convert", "encode", "packetize", "bytes", "packets", "psnr", "ssim"
+    );
+    for clip in ScreenClip::ALL {
+        match run_clip(clip, &options) {
+            Ok(report) => report.print(clip.name()),
+            Err(e) => {
+                eprintln!("{}: {}", clip.name(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,1067 @@
+//! RGB to I420 colorspace conversion
+//!
+//! Converts captured BGRA/RGBA frames straight into an [`I420Buffer`]
+//! using fixed-point BT.601 or BT.709 coefficients in limited or full
+//! range. Row pairs are converted in parallel with rayon, and a row
+//! kernel is picked at runtime: AVX2 or SSE4.1 on x86_64, a dedicated
+//! NEON kernel on aarch64, and the portable scalar kernel elsewhere.
+//!
+//! Chroma is the average of each 2x2 block; odd widths and heights
+//! replicate the last column/row, so chroma planes are
+//! `ceil(width / 2) x ceil(height / 2)`. [`rgb_to_i444`] skips the
+//! subsampling and writes an [`I444Buffer`] instead; [`YuvFrame`] holds
+//! either.
+
+use capture::{CapturedFrame, PixelFormat};
+use rayon::prelude::*;
+
+use crate::{EncoderError, EncoderResult};
+
+/// Plane strides are padded to a multiple of this many bytes
+const PLANE_ALIGN: usize = 32;
+/// Fixed-point precision of the conversion coefficients
+const SHIFT: u32 = 15;
+/// Row pairs per rayon task, so small frames aren't split too finely
+const MIN_ROW_PAIRS_PER_TASK: usize = 8;
+
+/// YUV matrix coefficients
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMatrix {
+    /// ITU-R BT.601 (SD)
+    Bt601,
+    /// ITU-R BT.709 (HD)
+    #[default]
+    Bt709,
+}
+
+/// YUV quantization range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorRange {
+    /// Y in 16..=235, UV in 16..=240
+    #[default]
+    Limited,
+    /// Y and UV in 0..=255
+    Full,
+}
+
+/// Colorspace used for RGB to YUV conversion and signalled in the bitstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ColorSpace {
+    pub matrix: ColorMatrix,
+    pub range: ColorRange,
+}
+
+/// Fixed-point conversion coefficients, scaled by `1 << SHIFT`
+#[derive(Debug, Clone, Copy)]
+struct Coefficients {
+    y: [i32; 3],
+    u: [i32; 3],
+    v: [i32; 3],
+    y_offset: i32,
+}
+
+impl Coefficients {
+    fn new(color: ColorSpace) -> Self {
+        let (kr, kb) = match color.matrix {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+        };
+        let kg = 1.0 - kr - kb;
+        let (y_scale, c_scale, y_offset) = match color.range {
+            ColorRange::Limited => (219.0 / 255.0, 224.0 / 255.0, 16),
+            ColorRange::Full => (1.0, 1.0, 0),
+        };
+
+        let fixed = |value: f64| (value * (1 << SHIFT) as f64).round() as i32;
+        let cb = c_scale / (2.0 * (1.0 - kb));
+        let cr = c_scale / (2.0 * (1.0 - kr));
+        Self {
+            y: [
+                fixed(kr * y_scale),
+                fixed(kg * y_scale),
+                fixed(kb * y_scale),
+            ],
+            u: [fixed(-kr * cb), fixed(-kg * cb), fixed(0.5 * c_scale)],
+            v: [fixed(0.5 * c_scale), fixed(-kg * cr), fixed(-kb * cr)],
+            y_offset,
+        }
+    }
+}
+
+/// Planar YUV 4:2:0 image with padded plane strides
+#[derive(Debug, Clone, Default)]
+pub struct I420Buffer {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl I420Buffer {
+    /// Create an empty buffer; it is sized on first conversion
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resize for a `width` x `height` image, reusing the allocation
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        let (y_stride, uv_stride) = self.strides();
+        let (_, uv_height) = self.chroma_size();
+        self.data
+            .resize(y_stride * height as usize + 2 * uv_stride * uv_height, 0);
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Size of the U and V planes
+    pub fn chroma_size(&self) -> (usize, usize) {
+        (
+            (self.width as usize).div_ceil(2),
+            (self.height as usize).div_ceil(2),
+        )
+    }
+
+    /// Luma and chroma strides in bytes
+    pub fn strides(&self) -> (usize, usize) {
+        let (uv_width, _) = self.chroma_size();
+        (
+            (self.width as usize).next_multiple_of(PLANE_ALIGN),
+            uv_width.next_multiple_of(PLANE_ALIGN),
+        )
+    }
+
+    /// Y, U and V planes, each `stride * rows` bytes
+    pub fn planes(&self) -> (&[u8], &[u8], &[u8]) {
+        let (y_size, uv_size) = self.plane_sizes();
+        let (y, uv) = self.data.split_at(y_size);
+        let (u, v) = uv.split_at(uv_size);
+        (y, u, v)
+    }
+
+    /// Mutable Y, U and V planes
+    pub fn planes_mut(&mut self) -> (&mut [u8], &mut [u8], &mut [u8]) {
+        let (y_size, uv_size) = self.plane_sizes();
+        let (y, uv) = self.data.split_at_mut(y_size);
+        let (u, v) = uv.split_at_mut(uv_size);
+        (y, u, v)
+    }
+
+    fn plane_sizes(&self) -> (usize, usize) {
+        let (y_stride, uv_stride) = self.strides();
+        let (_, uv_height) = self.chroma_size();
+        (y_stride * self.height as usize, uv_stride * uv_height)
+    }
+}
+
+/// Planar YUV 4:4:4 image; all three planes share one padded stride
+#[derive(Debug, Clone, Default)]
+pub struct I444Buffer {
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+impl I444Buffer {
+    /// Create an empty buffer; it is sized on first conversion
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resize for a `width` x `height` image, reusing the allocation
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        let plane_size = self.stride() * height as usize;
+        self.data.resize(3 * plane_size, 0);
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Stride of every plane in bytes
+    pub fn stride(&self) -> usize {
+        (self.width as usize).next_multiple_of(PLANE_ALIGN)
+    }
+
+    /// Y, U and V planes, each `stride * height` bytes
+    pub fn planes(&self) -> (&[u8], &[u8], &[u8]) {
+        let plane_size = self.stride() * self.height as usize;
+        let (y, uv) = self.data.split_at(plane_size);
+        let (u, v) = uv.split_at(plane_size);
+        (y, u, v)
+    }
+
+    /// Mutable Y, U and V planes
+    pub fn planes_mut(&mut self) -> (&mut [u8], &mut [u8], &mut [u8]) {
+        let plane_size = self.stride() * self.height as usize;
+        let (y, uv) = self.data.split_at_mut(plane_size);
+        let (u, v) = uv.split_at_mut(plane_size);
+        (y, u, v)
+    }
+}
+
+/// A frame converted to the planar layout an encoder takes
+///
+/// Lets conversion run ahead of the encoder, on another thread; see
+/// [`VideoEncoder::encode_yuv`](crate::VideoEncoder::encode_yuv).
+#[derive(Debug, Clone)]
+pub enum YuvFrame {
+    I420(I420Buffer),
+    I444(I444Buffer),
+}
+
+impl Default for YuvFrame {
+    fn default() -> Self {
+        Self::I420(I420Buffer::new())
+    }
+}
+
+impl YuvFrame {
+    /// Create an empty frame in 4:4:4 or 4:2:0 layout
+    pub fn new(chroma_444: bool) -> Self {
+        if chroma_444 {
+            Self::I444(I444Buffer::new())
+        } else {
+            Self::I420(I420Buffer::new())
+        }
+    }
+
+    /// Convert a BGRA/RGBA frame, keeping this frame's layout
+    pub fn convert(&mut self, frame: &CapturedFrame, color: ColorSpace) -> EncoderResult<()> {
+        match self {
+            Self::I420(out) => rgb_to_i420(frame, color, out),
+            Self::I444(out) => rgb_to_i444(frame, color, out),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        match self {
+            Self::I420(yuv) => yuv.width(),
+            Self::I444(yuv) => yuv.width(),
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        match self {
+            Self::I420(yuv) => yuv.height(),
+            Self::I444(yuv) => yuv.height(),
+        }
+    }
+
+    /// Whether chroma is kept at full resolution
+    pub fn is_444(&self) -> bool {
+        matches!(self, Self::I444(_))
+    }
+}
+
+/// Convert a BGRA/RGBA frame into `out`, resizing it to the frame
+pub fn rgb_to_i420(
+    frame: &CapturedFrame,
+    color: ColorSpace,
+    out: &mut I420Buffer,
+) -> EncoderResult<()> {
+    let bgra = check_source(frame)?;
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let stride = frame.stride as usize;
+
+    out.resize(frame.width, frame.height);
+    let (y_stride, uv_stride) = out.strides();
+    let coefficients = Coefficients::new(color);
+    let kernel = Kernel::detect();
+    let src = &frame.data[..];
+
+    let (y_plane, u_plane, v_plane) = out.planes_mut();
+    y_plane
+        .par_chunks_mut(2 * y_stride)
+        .zip(u_plane.par_chunks_mut(uv_stride))
+        .zip(v_plane.par_chunks_mut(uv_stride))
+        .enumerate()
+        .with_min_len(MIN_ROW_PAIRS_PER_TASK)
+        .for_each(|(pair, ((y, u), v))| {
+            let top = 2 * pair;
+            let row = |index: usize| &src[index * stride..index * stride + width * 4];
+            let rows = RowPair {
+                top: row(top),
+                bottom: row((top + 1).min(height - 1)),
+            };
+            let (y_top, y_bottom) = y.split_at_mut(y_stride.min(y.len()));
+            let outputs = PlaneRows {
+                y_top: &mut y_top[..width],
+                y_bottom: (top + 1 < height).then(|| &mut y_bottom[..width]),
+                u: &mut u[..width.div_ceil(2)],
+                v: &mut v[..width.div_ceil(2)],
+            };
+            kernel.convert(bgra, &coefficients, rows, outputs);
+        });
+
+    Ok(())
+}
+
+/// Convert a BGRA/RGBA frame into `out` without chroma subsampling
+///
+/// Only the portable kernel is implemented; 4:4:4 is meant for text
+/// where the encoder, not the conversion, dominates the frame time.
+pub fn rgb_to_i444(
+    frame: &CapturedFrame,
+    color: ColorSpace,
+    out: &mut I444Buffer,
+) -> EncoderResult<()> {
+    let bgra = check_source(frame)?;
+    let width = frame.width as usize;
+    let stride = frame.stride as usize;
+
+    out.resize(frame.width, frame.height);
+    let out_stride = out.stride();
+    let coefficients = Coefficients::new(color);
+    let src = &frame.data[..];
+
+    let (y_plane, u_plane, v_plane) = out.planes_mut();
+    y_plane
+        .par_chunks_mut(out_stride)
+        .zip(u_plane.par_chunks_mut(out_stride))
+        .zip(v_plane.par_chunks_mut(out_stride))
+        .enumerate()
+        .with_min_len(2 * MIN_ROW_PAIRS_PER_TASK)
+        .for_each(|(row, ((y, u), v))| {
+            let src = &src[row * stride..row * stride + width * 4];
+            let (y, u, v) = (&mut y[..width], &mut u[..width], &mut v[..width]);
+            if bgra {
+                full_row::<true>(&coefficients, src, y, u, v)
+            } else {
+                full_row::<false>(&coefficients, src, y, u, v)
+            }
+        });
+
+    Ok(())
+}
+
+/// Check a frame's format and size; returns whether it is BGRA
+fn check_source(frame: &CapturedFrame) -> EncoderResult<bool> {
+    let bgra = match frame.format {
+        PixelFormat::Bgra8 => true,
+        PixelFormat::Rgba8 => false,
+        _ => return Err(EncoderError::UnsupportedPixelFormat),
+    };
+
+    let width = frame.width as usize;
+    let height = frame.height as usize;
+    let stride = frame.stride as usize;
+    if width == 0 || height == 0 {
+        return Err(EncoderError::UnsupportedResolution {
+            width: frame.width,
+            height: frame.height,
+        });
+    }
+    if stride < width * 4 || frame.data.len() < (height - 1) * stride + width * 4 {
+        return Err(EncoderError::BufferTooSmall);
+    }
+    Ok(bgra)
+}
+
+/// Two source rows; `bottom` repeats `top` on the last row of an odd height
+struct RowPair<'a> {
+    top: &'a [u8],
+    bottom: &'a [u8],
+}
+
+/// Output rows for one row pair
+struct PlaneRows<'a> {
+    y_top: &'a mut [u8],
+    y_bottom: Option<&'a mut [u8]>,
+    u: &'a mut [u8],
+    v: &'a mut [u8],
+}
+
+/// Instruction set used for the row kernels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kernel {
+    Portable,
+    #[cfg(target_arch = "x86_64")]
+    Sse41,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Kernel {
+    fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return Self::Avx2;
+            }
+            if is_x86_feature_detected!("sse4.1") {
+                return Self::Sse41;
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if std::arch::is_aarch64_feature_detected!("neon") {
+                return Self::Neon;
+            }
+        }
+        Self::Portable
+    }
+
+    fn convert(self, bgra: bool, c: &Coefficients, rows: RowPair, out: PlaneRows) {
+        match self {
+            Self::Portable => convert_rows(bgra, c, rows, out),
+            // SAFETY: `detect` only selects these when the CPU supports them
+            #[cfg(target_arch = "x86_64")]
+            Self::Sse41 => unsafe { x86::convert_rows_sse41(bgra, c, rows, out) },
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => unsafe { x86::convert_rows_avx2(bgra, c, rows, out) },
+            #[cfg(target_arch = "aarch64")]
+            Self::Neon => unsafe { neon::convert_rows_neon(bgra, c, rows, out) },
+        }
+    }
+}
+
+/// Convert one row pair with the portable kernel
+fn convert_rows(bgra: bool, c: &Coefficients, rows: RowPair, out: PlaneRows) {
+    if bgra {
+        convert_rows_for::<true>(c, rows, out)
+    } else {
+        convert_rows_for::<false>(c, rows, out)
+    }
+}
+
+fn convert_rows_for<const BGRA: bool>(c: &Coefficients, rows: RowPair, out: PlaneRows) {
+    luma_row::<BGRA>(c, rows.top, out.y_top);
+    if let Some(y_bottom) = out.y_bottom {
+        luma_row::<BGRA>(c, rows.bottom, y_bottom);
+    }
+    chroma_row::<BGRA>(c, rows.top, rows.bottom, out.u, out.v);
+}
+
+/// Let the portable kernel finish a row pair from pixel `done` onwards
+///
+/// `done` must be even so chroma blocks stay aligned.
+fn convert_tail(bgra: bool, c: &Coefficients, rows: RowPair, out: PlaneRows, done: usize) {
+    if done == out.y_top.len() {
+        return;
+    }
+    convert_rows(
+        bgra,
+        c,
+        RowPair {
+            top: &rows.top[done * 4..],
+            bottom: &rows.bottom[done * 4..],
+        },
+        PlaneRows {
+            y_top: &mut out.y_top[done..],
+            y_bottom: out.y_bottom.map(|y| &mut y[done..]),
+            u: &mut out.u[done / 2..],
+            v: &mut out.v[done / 2..],
+        },
+    );
+}
+
+#[inline(always)]
+fn rgb<const BGRA: bool>(pixel: &[u8]) -> [i32; 3] {
+    if BGRA {
+        [pixel[2] as i32, pixel[1] as i32, pixel[0] as i32]
+    } else {
+        [pixel[0] as i32, pixel[1] as i32, pixel[2] as i32]
+    }
+}
+
+#[inline(always)]
+fn dot(coefficients: &[i32; 3], [r, g, b]: [i32; 3]) -> i32 {
+    coefficients[0] * r + coefficients[1] * g + coefficients[2] * b
+}
+
+/// Coefficients in the pixel's byte order, with alpha weighted zero
+fn memory_order(coefficients: &[i32; 3], bgra: bool) -> [i16; 4] {
+    let [r, g, b] = coefficients.map(|c| c as i16);
+    if bgra { [b, g, r, 0] } else { [r, g, b, 0] }
+}
+
+const fn luma_offset(c: &Coefficients) -> i32 {
+    (c.y_offset << SHIFT) + (1 << (SHIFT - 1))
+}
+
+/// Chroma is computed from sums of four pixels, hence two extra bits
+const CHROMA_SHIFT: u32 = SHIFT + 2;
+const CHROMA_OFFSET: i32 = (128 << CHROMA_SHIFT) + (1 << (CHROMA_SHIFT - 1));
+
+fn luma_row<const BGRA: bool>(c: &Coefficients, src: &[u8], y: &mut [u8]) {
+    let offset = luma_offset(c);
+    for (pixel, out) in src.chunks_exact(4).zip(y.iter_mut()) {
+        let value = (dot(&c.y, rgb::<BGRA>(pixel)) + offset) >> SHIFT;
+        *out = value.clamp(0, 255) as u8;
+    }
+}
+
+fn chroma_row<const BGRA: bool>(
+    c: &Coefficients,
+    top: &[u8],
+    bottom: &[u8],
+    u: &mut [u8],
+    v: &mut [u8],
+) {
+    let sample = |rgb: [i32; 3], u: &mut u8, v: &mut u8| {
+        *u = ((dot(&c.u, rgb) + CHROMA_OFFSET) >> CHROMA_SHIFT).clamp(0, 255) as u8;
+        *v = ((dot(&c.v, rgb) + CHROMA_OFFSET) >> CHROMA_SHIFT).clamp(0, 255) as u8;
+    };
+
+    let pairs = top.len() / 8;
+    let blocks = top
+        .chunks_exact(8)
+        .zip(bottom.chunks_exact(8))
+        .zip(u.iter_mut().zip(v.iter_mut()));
+    for ((top, bottom), (u, v)) in blocks {
+        let [r0, g0, b0] = rgb::<BGRA>(&top[..4]);
+        let [r1, g1, b1] = rgb::<BGRA>(&top[4..]);
+        let [r2, g2, b2] = rgb::<BGRA>(&bottom[..4]);
+        let [r3, g3, b3] = rgb::<BGRA>(&bottom[4..]);
+        sample(
+            [r0 + r1 + r2 + r3, g0 + g1 + g2 + g3, b0 + b1 + b2 + b3],
+            u,
+            v,
+        );
+    }
+
+    // Odd width: the last column stands in for its missing neighbour
+    if !top.len().is_multiple_of(8) {
+        let [r0, g0, b0] = rgb::<BGRA>(&top[pairs * 8..]);
+        let [r2, g2, b2] = rgb::<BGRA>(&bottom[pairs * 8..]);
+        sample(
+            [2 * (r0 + r2), 2 * (g0 + g2), 2 * (b0 + b2)],
+            &mut u[pairs],
+            &mut v[pairs],
+        );
+    }
+}
+
+/// One row at full chroma resolution
+fn full_row<const BGRA: bool>(
+    c: &Coefficients,
+    src: &[u8],
+    y: &mut [u8],
+    u: &mut [u8],
+    v: &mut [u8],
+) {
+    luma_row::<BGRA>(c, src, y);
+    for (pixel, (u, v)) in src.chunks_exact(4).zip(u.iter_mut().zip(v.iter_mut())) {
+        // Scaled like the 2x2 block sums `chroma_row` works with
+        let rgb = rgb::<BGRA>(pixel).map(|channel| 4 * channel);
+        *u = ((dot(&c.u, rgb) + CHROMA_OFFSET) >> CHROMA_SHIFT).clamp(0, 255) as u8;
+        *v = ((dot(&c.v, rgb) + CHROMA_OFFSET) >> CHROMA_SHIFT).clamp(0, 255) as u8;
+    }
+}
+
+/// SSE4.1 and AVX2 kernels
+///
+/// Pixels are widened to 16 bits and multiplied against the coefficients
+/// in byte order with `madd`, so BGRA and RGBA share one code path. Both
+/// produce exactly the portable kernel's output.
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::{
+        CHROMA_OFFSET, CHROMA_SHIFT, Coefficients, PlaneRows, RowPair, SHIFT, convert_tail,
+        luma_offset, memory_order,
+    };
+
+    /// Pixels converted per loop iteration
+    const BLOCK: usize = 16;
+
+    /// Broadcast byte-order coefficients to every pixel slot
+    #[target_feature(enable = "sse4.1")]
+    fn coefficients_sse(coefficients: &[i32; 3], bgra: bool) -> __m128i {
+        let [a, b, c, d] = memory_order(coefficients, bgra);
+        _mm_setr_epi16(a, b, c, d, a, b, c, d)
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    fn load_sse(bytes: &[u8]) -> __m128i {
+        assert!(bytes.len() >= 16);
+        // SAFETY: bounds checked above; unaligned load
+        unsafe { _mm_loadu_si128(bytes.as_ptr().cast()) }
+    }
+
+    /// Weighted channel sums of four pixels as `i32`
+    #[target_feature(enable = "sse4.1")]
+    fn weigh4_sse(pixels: __m128i, coefficients: __m128i) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let lo = _mm_madd_epi16(_mm_unpacklo_epi8(pixels, zero), coefficients);
+        let hi = _mm_madd_epi16(_mm_unpackhi_epi8(pixels, zero), coefficients);
+        _mm_hadd_epi32(lo, hi)
+    }
+
+    /// Per-channel sums of each 2x2 block of two 4-pixel row segments
+    #[target_feature(enable = "sse4.1")]
+    fn block_sums_sse(top: __m128i, bottom: __m128i) -> __m128i {
+        let zero = _mm_setzero_si128();
+        let lo = _mm_add_epi16(
+            _mm_unpacklo_epi8(top, zero),
+            _mm_unpacklo_epi8(bottom, zero),
+        );
+        let hi = _mm_add_epi16(
+            _mm_unpackhi_epi8(top, zero),
+            _mm_unpackhi_epi8(bottom, zero),
+        );
+        let lo = _mm_add_epi16(lo, _mm_srli_si128::<8>(lo));
+        let hi = _mm_add_epi16(hi, _mm_srli_si128::<8>(hi));
+        _mm_unpacklo_epi64(lo, hi)
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub fn convert_rows_sse41(bgra: bool, c: &Coefficients, rows: RowPair, mut out: PlaneRows) {
+        let y_coefficients = coefficients_sse(&c.y, bgra);
+        let u_coefficients = coefficients_sse(&c.u, bgra);
+        let v_coefficients = coefficients_sse(&c.v, bgra);
+        let y_offset = _mm_set1_epi32(luma_offset(c));
+        let c_offset = _mm_set1_epi32(CHROMA_OFFSET);
+
+        let luma = |src: &[u8], y: &mut [u8]| {
+            for (pixels, out) in src.chunks_exact(4 * BLOCK).zip(y.chunks_exact_mut(BLOCK)) {
+                let [a, b, c, d] = [0, 16, 32, 48].map(|at| {
+                    let sums = weigh4_sse(load_sse(&pixels[at..]), y_coefficients);
+                    _mm_srai_epi32::<{ SHIFT as i32 }>(_mm_add_epi32(sums, y_offset))
+                });
+                let bytes = _mm_packus_epi16(_mm_packs_epi32(a, b), _mm_packs_epi32(c, d));
+                // SAFETY: `out` is exactly 16 bytes
+                unsafe { _mm_storeu_si128(out.as_mut_ptr().cast(), bytes) };
+            }
+        };
+        luma(rows.top, &mut *out.y_top);
+        if let Some(y_bottom) = out.y_bottom.as_deref_mut() {
+            luma(rows.bottom, y_bottom);
+        }
+
+        let blocks = rows
+            .top
+            .chunks_exact(4 * BLOCK)
+            .zip(rows.bottom.chunks_exact(4 * BLOCK))
+            .zip(out.u.chunks_exact_mut(BLOCK / 2))
+            .zip(out.v.chunks_exact_mut(BLOCK / 2));
+        for (((top, bottom), u), v) in blocks {
+            let [s0, s1, s2, s3] = [0, 16, 32, 48]
+                .map(|at| block_sums_sse(load_sse(&top[at..]), load_sse(&bottom[at..])));
+            let plane = |coefficients: __m128i| {
+                let [lo, hi] = [[s0, s1], [s2, s3]].map(|[a, b]| {
+                    let sums = _mm_hadd_epi32(
+                        _mm_madd_epi16(a, coefficients),
+                        _mm_madd_epi16(b, coefficients),
+                    );
+                    _mm_srai_epi32::<{ CHROMA_SHIFT as i32 }>(_mm_add_epi32(sums, c_offset))
+                });
+                _mm_packs_epi32(lo, hi)
+            };
+            let bytes = _mm_packus_epi16(plane(u_coefficients), plane(v_coefficients));
+            // SAFETY: `u` and `v` are exactly 8 bytes each
+            unsafe {
+                _mm_storel_epi64(u.as_mut_ptr().cast(), bytes);
+                _mm_storel_epi64(v.as_mut_ptr().cast(), _mm_srli_si128::<8>(bytes));
+            }
+        }
+
+        let done = out.y_top.len() / BLOCK * BLOCK;
+        convert_tail(bgra, c, rows, out, done);
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn coefficients_avx2(coefficients: &[i32; 3], bgra: bool) -> __m256i {
+        let [a, b, c, d] = memory_order(coefficients, bgra);
+        _mm256_setr_epi16(a, b, c, d, a, b, c, d, a, b, c, d, a, b, c, d)
+    }
+
+    #[target_feature(enable = "avx2")]
+    fn load_avx2(bytes: &[u8]) -> __m256i {
+        assert!(bytes.len() >= 32);
+        // SAFETY: bounds checked above; unaligned load
+        unsafe { _mm256_loadu_si256(bytes.as_ptr().cast()) }
+    }
+
+    /// Weighted channel sums of eight pixels as `i32`, in pixel order
+    #[target_feature(enable = "avx2")]
+    fn weigh8_avx2(pixels: __m256i, coefficients: __m256i) -> __m256i {
+        let zero = _mm256_setzero_si256();
+        let lo = _mm256_madd_epi16(_mm256_unpacklo_epi8(pixels, zero), coefficients);
+        let hi = _mm256_madd_epi16(_mm256_unpackhi_epi8(pixels, zero), coefficients);
+        _mm256_hadd_epi32(lo, hi)
+    }
+
+    /// 2x2 block sums of two 8-pixel row segments, ordered `[0, 1 | 2, 3]`
+    #[target_feature(enable = "avx2")]
+    fn block_sums_avx2(top: __m256i, bottom: __m256i) -> __m256i {
+        let zero = _mm256_setzero_si256();
+        let lo = _mm256_add_epi16(
+            _mm256_unpacklo_epi8(top, zero),
+            _mm256_unpacklo_epi8(bottom, zero),
+        );
+        let hi = _mm256_add_epi16(
+            _mm256_unpackhi_epi8(top, zero),
+            _mm256_unpackhi_epi8(bottom, zero),
+        );
+        let lo = _mm256_add_epi16(lo, _mm256_srli_si256::<8>(lo));
+        let hi = _mm256_add_epi16(hi, _mm256_srli_si256::<8>(hi));
+        _mm256_unpacklo_epi64(lo, hi)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub fn convert_rows_avx2(bgra: bool, c: &Coefficients, rows: RowPair, mut out: PlaneRows) {
+        let y_coefficients = coefficients_avx2(&c.y, bgra);
+        let u_coefficients = coefficients_avx2(&c.u, bgra);
+        let v_coefficients = coefficients_avx2(&c.v, bgra);
+        let y_offset = _mm256_set1_epi32(luma_offset(c));
+        let c_offset = _mm256_set1_epi32(CHROMA_OFFSET);
+
+        let luma = |src: &[u8], y: &mut [u8]| {
+            for (pixels, out) in src.chunks_exact(4 * BLOCK).zip(y.chunks_exact_mut(BLOCK)) {
+                let [a, b] = [0, 32].map(|at| {
+                    let sums = weigh8_avx2(load_avx2(&pixels[at..]), y_coefficients);
+                    _mm256_srai_epi32::<{ SHIFT as i32 }>(_mm256_add_epi32(sums, y_offset))
+                });
+                // packs interleaves the 128-bit lanes; restore pixel order
+                let words = _mm256_permute4x64_epi64::<0b11_01_10_00>(_mm256_packs_epi32(a, b));
+                let bytes = _mm_packus_epi16(
+                    _mm256_castsi256_si128(words),
+                    _mm256_extracti128_si256::<1>(words),
+                );
+                // SAFETY: `out` is exactly 16 bytes
+                unsafe { _mm_storeu_si128(out.as_mut_ptr().cast(), bytes) };
+            }
+        };
+        luma(rows.top, &mut *out.y_top);
+        if let Some(y_bottom) = out.y_bottom.as_deref_mut() {
+            luma(rows.bottom, y_bottom);
+        }
+
+        let order = _mm256_setr_epi32(0, 4, 1, 5, 2, 6, 3, 7);
+        let blocks = rows
+            .top
+            .chunks_exact(4 * BLOCK)
+            .zip(rows.bottom.chunks_exact(4 * BLOCK))
+            .zip(out.u.chunks_exact_mut(BLOCK / 2))
+            .zip(out.v.chunks_exact_mut(BLOCK / 2));
+        for (((top, bottom), u), v) in blocks {
+            let [a, b] =
+                [0, 32].map(|at| block_sums_avx2(load_avx2(&top[at..]), load_avx2(&bottom[at..])));
+            // Samples come out as [0, 1, 4, 5 | 2, 3, 6, 7]
+            let plane = |coefficients: __m256i| {
+                let sums = _mm256_hadd_epi32(
+                    _mm256_madd_epi16(a, coefficients),
+                    _mm256_madd_epi16(b, coefficients),
+                );
+                _mm256_srai_epi32::<{ CHROMA_SHIFT as i32 }>(_mm256_add_epi32(sums, c_offset))
+            };
+            let words = _mm256_permutevar8x32_epi32(
+                _mm256_packs_epi32(plane(u_coefficients), plane(v_coefficients)),
+                order,
+            );
+            let bytes = _mm_packus_epi16(
+                _mm256_castsi256_si128(words),
+                _mm256_extracti128_si256::<1>(words),
+            );
+            // SAFETY: `u` and `v` are exactly 8 bytes each
+            unsafe {
+                _mm_storel_epi64(u.as_mut_ptr().cast(), bytes);
+                _mm_storel_epi64(v.as_mut_ptr().cast(), _mm_srli_si128::<8>(bytes));
+            }
+        }
+
+        let done = out.y_top.len() / BLOCK * BLOCK;
+        convert_tail(bgra, c, rows, out, done);
+    }
+}
+
+/// NEON kernel
+///
+/// `vld4` de-interleaves sixteen pixels into per-channel vectors, so the
+/// byte-order coefficients apply directly without shuffles.
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    use super::{
+        CHROMA_OFFSET, CHROMA_SHIFT, Coefficients, PlaneRows, RowPair, SHIFT, convert_tail,
+        luma_offset, memory_order,
+    };
+
+    /// Pixels converted per loop iteration
+    const BLOCK: usize = 16;
+
+    #[target_feature(enable = "neon")]
+    fn load(bytes: &[u8]) -> uint8x16x4_t {
+        assert!(bytes.len() >= 4 * BLOCK);
+        // SAFETY: bounds checked above
+        unsafe { vld4q_u8(bytes.as_ptr()) }
+    }
+
+    /// `(k[0] * a + k[1] * b + k[2] * c + offset) >> N`, saturated to `u8`
+    #[target_feature(enable = "neon")]
+    fn weigh<const N: i32>([a, b, c]: [int16x8_t; 3], k: [i16; 4], offset: int32x4_t) -> uint8x8_t {
+        let half = |a: int16x4_t, b: int16x4_t, c: int16x4_t| {
+            let sum = vmlal_n_s16(vmlal_n_s16(vmull_n_s16(a, k[0]), b, k[1]), c, k[2]);
+            vqmovn_s32(vshrq_n_s32::<N>(vaddq_s32(sum, offset)))
+        };
+        let lo = half(vget_low_s16(a), vget_low_s16(b), vget_low_s16(c));
+        let hi = half(vget_high_s16(a), vget_high_s16(b), vget_high_s16(c));
+        vqmovun_s16(vcombine_s16(lo, hi))
+    }
+
+    #[target_feature(enable = "neon")]
+    pub fn convert_rows_neon(bgra: bool, c: &Coefficients, rows: RowPair, mut out: PlaneRows) {
+        let y_coefficients = memory_order(&c.y, bgra);
+        let u_coefficients = memory_order(&c.u, bgra);
+        let v_coefficients = memory_order(&c.v, bgra);
+        let y_offset = vdupq_n_s32(luma_offset(c));
+        let c_offset = vdupq_n_s32(CHROMA_OFFSET);
+        let widen = |channel: uint8x8_t| vreinterpretq_s16_u16(vmovl_u8(channel));
+
+        let luma = |src: &[u8], y: &mut [u8]| {
+            for (pixels, out) in src.chunks_exact(4 * BLOCK).zip(y.chunks_exact_mut(BLOCK)) {
+                let p = load(pixels);
+                let [lo, hi] = [
+                    [vget_low_u8(p.0), vget_low_u8(p.1), vget_low_u8(p.2)],
+                    [vget_high_u8(p.0), vget_high_u8(p.1), vget_high_u8(p.2)],
+                ]
+                .map(|channels| {
+                    weigh::<{ SHIFT as i32 }>(channels.map(widen), y_coefficients, y_offset)
+                });
+                // SAFETY: `out` is exactly 16 bytes
+                unsafe { vst1q_u8(out.as_mut_ptr(), vcombine_u8(lo, hi)) };
+            }
+        };
+        luma(rows.top, &mut *out.y_top);
+        if let Some(y_bottom) = out.y_bottom.as_deref_mut() {
+            luma(rows.bottom, y_bottom);
+        }
+
+        let blocks = rows
+            .top
+            .chunks_exact(4 * BLOCK)
+            .zip(rows.bottom.chunks_exact(4 * BLOCK))
+            .zip(out.u.chunks_exact_mut(BLOCK / 2))
+            .zip(out.v.chunks_exact_mut(BLOCK / 2));
+        for (((top, bottom), u), v) in blocks {
+            let (t, b) = (load(top), load(bottom));
+            // Horizontal pairs of the top row, then add the bottom row's
+            let sum =
+                |t: uint8x16_t, b: uint8x16_t| vreinterpretq_s16_u16(vpadalq_u8(vpaddlq_u8(t), b));
+            let sums = [sum(t.0, b.0), sum(t.1, b.1), sum(t.2, b.2)];
+            let u_bytes = weigh::<{ CHROMA_SHIFT as i32 }>(sums, u_coefficients, c_offset);
+            let v_bytes = weigh::<{ CHROMA_SHIFT as i32 }>(sums, v_coefficients, c_offset);
+            // SAFETY: `u` and `v` are exactly 8 bytes each
+            unsafe {
+                vst1_u8(u.as_mut_ptr(), u_bytes);
+                vst1_u8(v.as_mut_ptr(), v_bytes);
+            }
+        }
+
+        let done = out.y_top.len() / BLOCK * BLOCK;
+        convert_tail(bgra, c, rows, out, done);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::time::Instant;
+
+    fn frame(width: u32, height: u32, stride: u32, format: PixelFormat) -> CapturedFrame {
+        let data = (0..stride * height)
+            .map(|i| {
+                let (x, y) = ((i % stride) / 4, i / stride);
+                (x * 7 + y * 13 + (i % 4) * 61) as u8
+            })
+            .collect::<Vec<_>>();
+        CapturedFrame {
+            data: Bytes::from(data),
+            width,
+            height,
+            stride,
+            format,
+            timestamp: Instant::now(),
+            sequence: 0,
+            dirty_rects: Vec::new(),
+            display_id: 0,
+        }
+    }
+
+    /// Floating-point reference for one RGB triple
+    fn reference(color: ColorSpace, [r, g, b]: [f64; 3]) -> [f64; 3] {
+        let (kr, kb) = match color.matrix {
+            ColorMatrix::Bt601 => (0.299, 0.114),
+            ColorMatrix::Bt709 => (0.2126, 0.0722),
+        };
+        let (y_scale, c_scale, y_offset) = match color.range {
+            ColorRange::Limited => (219.0 / 255.0, 224.0 / 255.0, 16.0),
+            ColorRange::Full => (1.0, 1.0, 0.0),
+        };
+        let luma = kr * r + (1.0 - kr - kb) * g + kb * b;
+        [
+            y_offset + y_scale * luma,
+            128.0 + c_scale * (b - luma) / (2.0 * (1.0 - kb)),
+            128.0 + c_scale * (r - luma) / (2.0 * (1.0 - kr)),
+        ]
+    }
+
+    #[test]
+    fn test_black_and_white_levels() {
+        for (range, black, white) in [(ColorRange::Limited, 16, 235), (ColorRange::Full, 0, 255)] {
+            let color = ColorSpace {
+                matrix: ColorMatrix::Bt709,
+                range,
+            };
+            let mut out = I420Buffer::new();
+            for (fill, luma) in [(0u8, black), (255u8, white)] {
+                let mut input = frame(4, 2, 16, PixelFormat::Bgra8);
+                input.data = Bytes::from(vec![fill; 32]);
+                rgb_to_i420(&input, color, &mut out).unwrap();
+                let (y, u, v) = out.planes();
+                assert_eq!(y[0], luma);
+                assert_eq!((u[0], v[0]), (128, 128));
+            }
+        }
+    }
+
+    #[test]
+    fn test_odd_sizes_and_padded_stride_match_reference() {
+        for format in [PixelFormat::Bgra8, PixelFormat::Rgba8] {
+            let input = frame(37, 23, 37 * 4 + 12, format);
+            for matrix in [ColorMatrix::Bt601, ColorMatrix::Bt709] {
+                for range in [ColorRange::Limited, ColorRange::Full] {
+                    let color = ColorSpace { matrix, range };
+                    let mut out = I420Buffer::new();
+                    rgb_to_i420(&input, color, &mut out).unwrap();
+                    assert_eq!(out.chroma_size(), (19, 12));
+
+                    let (y_stride, uv_stride) = out.strides();
+                    let (y_plane, u_plane, v_plane) = out.planes();
+                    let pixel = |x: usize, y: usize| {
+                        let x = x.min(36);
+                        let y = y.min(22);
+                        let p = &input.data[y * input.stride as usize + x * 4..];
+                        let [r, g, b] = match format {
+                            PixelFormat::Bgra8 => [p[2], p[1], p[0]],
+                            _ => [p[0], p[1], p[2]],
+                        };
+                        [r as f64, g as f64, b as f64]
+                    };
+
+                    for y in 0..23 {
+                        for x in 0..37 {
+                            let expected = reference(color, pixel(x, y))[0];
+                            let actual = y_plane[y * y_stride + x] as f64;
+                            assert!((actual - expected).abs() <= 1.0, "Y at {x},{y}");
+                        }
+                    }
+                    for y in 0..12 {
+                        for x in 0..19 {
+                            let mut sum = [0.0; 3];
+                            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                                let p = pixel(2 * x + dx, 2 * y + dy);
+                                (0..3).for_each(|i| sum[i] += p[i] / 4.0);
+                            }
+                            let expected = reference(color, sum);
+                            let u = u_plane[y * uv_stride + x] as f64;
+                            let v = v_plane[y * uv_stride + x] as f64;
+                            assert!((u - expected[1]).abs() <= 1.0, "U at {x},{y}");
+                            assert!((v - expected[2]).abs() <= 1.0, "V at {x},{y}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_i444_matches_reference_at_full_resolution() {
+        let input = frame(37, 23, 37 * 4 + 12, PixelFormat::Rgba8);
+        let color = ColorSpace::default();
+        let mut out = I444Buffer::new();
+        rgb_to_i444(&input, color, &mut out).unwrap();
+        let mut i420 = I420Buffer::new();
+        rgb_to_i420(&input, color, &mut i420).unwrap();
+
+        let stride = out.stride();
+        let (y_stride, _) = i420.strides();
+        let (y_plane, u_plane, v_plane) = out.planes();
+        for y in 0..23 {
+            assert_eq!(
+                y_plane[y * stride..y * stride + 37],
+                i420.planes().0[y * y_stride..y * y_stride + 37]
+            );
+            for x in 0..37 {
+                let p = &input.data[y * input.stride as usize + x * 4..];
+                let expected = reference(color, [p[0] as f64, p[1] as f64, p[2] as f64]);
+                let u = u_plane[y * stride + x] as f64;
+                let v = v_plane[y * stride + x] as f64;
+                assert!((u - expected[1]).abs() <= 1.0, "U at {x},{y}");
+                assert!((v - expected[2]).abs() <= 1.0, "V at {x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_simd_kernels_match_portable() {
+        let kernels: &[(Kernel, bool)] = &[
+            #[cfg(target_arch = "x86_64")]
+            (Kernel::Sse41, is_x86_feature_detected!("sse4.1")),
+            #[cfg(target_arch = "x86_64")]
+            (Kernel::Avx2, is_x86_feature_detected!("avx2")),
+            #[cfg(target_arch = "aarch64")]
+            (Kernel::Neon, true),
+        ];
+
+        // Two full 16-pixel blocks, an odd tail and a missing bottom row
+        let input = frame(37, 2, 37 * 4, PixelFormat::Bgra8);
+        let c = Coefficients::new(ColorSpace::default());
+        let convert = |kernel: Kernel, bgra: bool, bottom: bool| {
+            let mut y = vec![0u8; 74];
+            let mut u = vec![0u8; 19];
+            let mut v = vec![0u8; 19];
+            let (y_top, y_bottom) = y.split_at_mut(37);
+            kernel.convert(
+                bgra,
+                &c,
+                RowPair {
+                    top: &input.data[..148],
+                    bottom: &input.data[148..],
+                },
+                PlaneRows {
+                    y_top,
+                    y_bottom: bottom.then_some(y_bottom),
+                    u: &mut u,
+                    v: &mut v,
+                },
+            );
+            (y, u, v)
+        };
+
+        for &(kernel, supported) in kernels {
+            if !supported {
+                continue;
+            }
+            for bgra in [true, false] {
+                for bottom in [true, false] {
+                    assert_eq!(
+                        convert(kernel, bgra, bottom),
+                        convert(Kernel::Portable, bgra, bottom),
+                        "{kernel:?} bgra={bgra} bottom={bottom}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -22,6 +22,9 @@ pub enum EncoderError {
     #[error("Buffer too small")]
     BufferTooSmall,
 
+    #[error("Frame buffered; its output follows a later frame")]
+    FrameBuffered,
+
     #[error("Encoder not initialized")]
     NotInitialized,
 
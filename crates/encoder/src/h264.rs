@@ -0,0 +1,364 @@
+//! Minimal H.264 header parsing
+//!
+//! Just enough of the SPS and slice header to track reference pictures
+//! for loss recovery: `frame_num`, `idr_pic_id`, and which pictures are
+//! made or used as long-term references.
+
+/// NAL unit types
+const NAL_SLICE: u8 = 1;
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+
+/// Sequence parameters needed to parse slice headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sps {
+    pub log2_max_frame_num: u32,
+    pub poc_type: u32,
+    pub log2_max_poc_lsb: u32,
+}
+
+impl Sps {
+    /// Largest `frame_num` plus one
+    pub fn max_frame_num(&self) -> u32 {
+        1 << self.log2_max_frame_num
+    }
+}
+
+/// Reference information from a slice header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SliceRefs {
+    pub frame_num: u32,
+    /// Set for IDR pictures
+    pub idr_pic_id: Option<u32>,
+    /// `frame_num` of a picture this slice marks as long-term
+    pub marks_long_term: Option<u32>,
+    /// Whether the reference list is reordered to a long-term picture
+    pub uses_long_term: bool,
+}
+
+/// NAL unit type and payload after the header, without a start code
+fn nal_parts(nal: &[u8]) -> Option<(u8, u8, &[u8])> {
+    let nal = nal
+        .strip_prefix(&[0, 0, 0, 1])
+        .or_else(|| nal.strip_prefix(&[0, 0, 1]))
+        .unwrap_or(nal);
+    let (&header, payload) = nal.split_first()?;
+    Some((header & 0x1f, (header >> 5) & 0x3, payload))
+}
+
+/// Parse an SPS NAL unit
+pub(crate) fn parse_sps(nal: &[u8]) -> Option<Sps> {
+    let (nal_type, _, payload) = nal_parts(nal)?;
+    if nal_type != NAL_SPS {
+        return None;
+    }
+
+    let mut bits = BitReader::new(payload);
+    let profile_idc = bits.read_bits(8)?;
+    bits.read_bits(16)?; // constraint flags, level_idc
+    bits.read_ue()?; // seq_parameter_set_id
+    if matches!(
+        profile_idc,
+        44 | 83 | 86 | 100 | 110 | 118 | 122 | 128 | 244
+    ) {
+        let chroma_format_idc = bits.read_ue()?;
+        if chroma_format_idc == 3 {
+            bits.read_bits(1)?;
+        }
+        bits.read_ue()?; // bit_depth_luma_minus8
+        bits.read_ue()?; // bit_depth_chroma_minus8
+        bits.read_bits(1)?; // qpprime_y_zero_transform_bypass_flag
+        if bits.read_bits(1)? != 0 {
+            // Scaling matrices never appear in our own streams
+            return None;
+        }
+    }
+
+    let log2_max_frame_num = bits.read_ue()? + 4;
+    let poc_type = bits.read_ue()?;
+    let log2_max_poc_lsb = match poc_type {
+        0 => bits.read_ue()? + 4,
+        2 => 0,
+        _ => return None,
+    };
+
+    Some(Sps {
+        log2_max_frame_num,
+        poc_type,
+        log2_max_poc_lsb,
+    })
+}
+
+/// Parse the reference fields of a slice header
+///
+/// Assumes frame coding and no `bottom_field_pic_order_in_frame_present`,
+/// which holds for the streams OpenH264 produces.
+pub(crate) fn parse_slice_refs(nal: &[u8], sps: &Sps) -> Option<SliceRefs> {
+    let (nal_type, nal_ref_idc, payload) = nal_parts(nal)?;
+    if nal_type != NAL_SLICE && nal_type != NAL_IDR {
+        return None;
+    }
+    let idr = nal_type == NAL_IDR;
+
+    let mut bits = BitReader::new(payload);
+    bits.read_ue()?; // first_mb_in_slice
+    let slice_type = bits.read_ue()? % 5;
+    bits.read_ue()?; // pic_parameter_set_id
+    let frame_num = bits.read_bits(sps.log2_max_frame_num)?;
+    let idr_pic_id = if idr { Some(bits.read_ue()?) } else { None };
+    if sps.poc_type == 0 {
+        bits.read_bits(sps.log2_max_poc_lsb)?;
+    }
+
+    // P and SP slices
+    let predicted = slice_type == 0 || slice_type == 3;
+    if predicted && bits.read_bits(1)? != 0 {
+        bits.read_ue()?; // num_ref_idx_l0_active_minus1
+    }
+
+    let mut uses_long_term = false;
+    if predicted && bits.read_bits(1)? != 0 {
+        loop {
+            match bits.read_ue()? {
+                0 | 1 => {
+                    bits.read_ue()?;
+                }
+                2 => {
+                    bits.read_ue()?;
+                    uses_long_term = true;
+                }
+                3 => break,
+                _ => return None,
+            }
+        }
+    }
+
+    let mut marks_long_term = None;
+    if nal_ref_idc != 0 {
+        if idr {
+            bits.read_bits(1)?; // no_output_of_prior_pics_flag
+            if bits.read_bits(1)? != 0 {
+                marks_long_term = Some(frame_num);
+            }
+        } else if bits.read_bits(1)? != 0 {
+            loop {
+                match bits.read_ue()? {
+                    0 => break,
+                    1 => {
+                        bits.read_ue()?;
+                    }
+                    2 | 4 => {
+                        bits.read_ue()?;
+                    }
+                    3 => {
+                        // Short-term picture `difference + 1` frames back
+                        let difference = bits.read_ue()? + 1;
+                        bits.read_ue()?;
+                        let max = sps.max_frame_num();
+                        marks_long_term = Some((frame_num + max - difference % max) % max);
+                    }
+                    5 => {}
+                    6 => {
+                        bits.read_ue()?;
+                        marks_long_term = Some(frame_num);
+                    }
+                    _ => return None,
+                }
+            }
+        }
+    }
+
+    Some(SliceRefs {
+        frame_num,
+        idr_pic_id,
+        marks_long_term,
+        uses_long_term,
+    })
+}
+
+/// Bit reader over a NAL payload that skips emulation prevention bytes
+struct BitReader<'a> {
+    data: &'a [u8],
+    byte: usize,
+    bit: u32,
+    zeros: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            byte: 0,
+            bit: 0,
+            zeros: 0,
+        }
+    }
+
+    fn read_bit(&mut self) -> Option<u32> {
+        if self.bit == 0 {
+            // 0x000003 escapes a byte sequence that mimics a start code
+            if self.zeros >= 2 && self.data.get(self.byte) == Some(&3) {
+                self.byte += 1;
+                self.zeros = 0;
+            }
+            let byte = *self.data.get(self.byte)?;
+            self.zeros = if byte == 0 { self.zeros + 1 } else { 0 };
+        }
+
+        let value = (self.data[self.byte] >> (7 - self.bit)) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.byte += 1;
+        }
+        Some(value as u32)
+    }
+
+    fn read_bits(&mut self, count: u32) -> Option<u32> {
+        (0..count).try_fold(0, |value, _| Some((value << 1) | self.read_bit()?))
+    }
+
+    /// Unsigned Exp-Golomb code
+    fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes bits MSB first, inserting emulation prevention bytes
+    struct BitWriter {
+        bits: Vec<bool>,
+    }
+
+    impl BitWriter {
+        fn new() -> Self {
+            Self { bits: Vec::new() }
+        }
+
+        fn bits(&mut self, count: u32, value: u32) -> &mut Self {
+            for i in (0..count).rev() {
+                self.bits.push((value >> i) & 1 == 1);
+            }
+            self
+        }
+
+        fn ue(&mut self, value: u32) -> &mut Self {
+            let coded = value + 1;
+            let length = 32 - coded.leading_zeros();
+            self.bits(length - 1, 0).bits(length, coded)
+        }
+
+        fn nal(&mut self, header: u8) -> Vec<u8> {
+            self.bits.push(true);
+            while !self.bits.len().is_multiple_of(8) {
+                self.bits.push(false);
+            }
+            let mut out = vec![0, 0, 0, 1, header];
+            let mut zeros = 0;
+            for chunk in self.bits.chunks(8) {
+                let byte = chunk.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8);
+                if zeros >= 2 && byte <= 3 {
+                    out.push(3);
+                    zeros = 0;
+                }
+                zeros = if byte == 0 { zeros + 1 } else { 0 };
+                out.push(byte);
+            }
+            out
+        }
+    }
+
+    const SPS: Sps = Sps {
+        log2_max_frame_num: 15,
+        poc_type: 0,
+        log2_max_poc_lsb: 16,
+    };
+
+    #[test]
+    fn test_parse_sps() {
+        let nal = BitWriter::new()
+            .bits(8, 66)
+            .bits(16, 0x1f)
+            .ue(0)
+            .ue(11)
+            .ue(0)
+            .ue(12)
+            .nal(0x67);
+        assert_eq!(parse_sps(&nal), Some(SPS));
+    }
+
+    #[test]
+    fn test_parse_idr_marked_long_term() {
+        let nal = BitWriter::new()
+            .ue(0)
+            .ue(7)
+            .ue(0)
+            .bits(15, 0)
+            .ue(3)
+            .bits(16, 0)
+            .bits(1, 0)
+            .bits(1, 1)
+            .nal(0x65);
+        let refs = parse_slice_refs(&nal, &SPS).unwrap();
+        assert_eq!(refs.idr_pic_id, Some(3));
+        assert_eq!(refs.marks_long_term, Some(0));
+        assert!(!refs.uses_long_term);
+    }
+
+    #[test]
+    fn test_parse_recovery_slice() {
+        // P slice reordered to long-term picture 1 that also moves the
+        // picture two frames back to the long-term list
+        let nal = BitWriter::new()
+            .ue(0)
+            .ue(5)
+            .ue(0)
+            .bits(15, 40)
+            .bits(16, 80)
+            .bits(1, 0)
+            .bits(1, 1)
+            .ue(2)
+            .ue(1)
+            .ue(3)
+            .bits(1, 1)
+            .ue(3)
+            .ue(1)
+            .ue(0)
+            .ue(0)
+            .nal(0x41);
+        let refs = parse_slice_refs(&nal, &SPS).unwrap();
+        assert_eq!(refs.frame_num, 40);
+        assert_eq!(refs.idr_pic_id, None);
+        assert_eq!(refs.marks_long_term, Some(38));
+        assert!(refs.uses_long_term);
+    }
+
+    #[test]
+    fn test_emulation_prevention_is_skipped() {
+        // frame_num 0 with a 16-bit POC of 0 produces a 0x000003 escape
+        let nal = BitWriter::new()
+            .ue(0)
+            .ue(5)
+            .ue(0)
+            .bits(15, 0)
+            .bits(16, 1)
+            .bits(1, 0)
+            .bits(1, 0)
+            .bits(1, 0)
+            .nal(0x41);
+        assert!(nal.windows(3).any(|window| window == [0, 0, 3]));
+        let refs = parse_slice_refs(&nal, &SPS).unwrap();
+        assert_eq!(refs.frame_num, 0);
+        assert_eq!(refs.marks_long_term, None);
+    }
+}
@@ -2,13 +2,37 @@
 //!
 //! Provides abstraction over encoding backends:
 //! - OpenH264 (software, cross-platform)
+//! - rav1e (AV1 software, `av1` feature)
+//! - libvpx (VP9 software, `vp9` feature)
 //! - VideoToolbox (macOS hardware)
 //! - NVENC (NVIDIA hardware)
 
+mod colorspace;
 mod error;
+mod h264;
+mod openh264_decoder;
 mod openh264_encoder;
+mod openh264_raw;
+mod quality;
+#[cfg(feature = "av1")]
+mod rav1e_encoder;
+mod refine;
+mod registry;
 mod traits;
+#[cfg(feature = "vp9")]
+mod vpx_encoder;
+#[cfg(feature = "vp9")]
+mod vpx_raw;
 
+pub use colorspace::*;
 pub use error::*;
+pub use openh264_decoder::*;
 pub use openh264_encoder::*;
+pub use quality::*;
+#[cfg(feature = "av1")]
+pub use rav1e_encoder::*;
+pub use refine::*;
+pub use registry::*;
 pub use traits::*;
+#[cfg(feature = "vp9")]
+pub use vpx_encoder::*;
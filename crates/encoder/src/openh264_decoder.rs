@@ -0,0 +1,81 @@
+//! OpenH264 software decoder
+//!
+//! The viewer decodes in the webview, so this exists to measure what the
+//! encoder produces: benchmarks decode each frame and compare it against
+//! the source with [`psnr`](crate::psnr) and [`ssim`](crate::ssim).
+
+use crate::openh264_raw::RawDecoder;
+use crate::{EncoderResult, I420Buffer};
+
+/// Decodes an Annex-B H.264 stream into I420 pictures
+pub struct OpenH264Decoder {
+    decoder: RawDecoder,
+}
+
+impl OpenH264Decoder {
+    pub fn new() -> EncoderResult<Self> {
+        Ok(Self {
+            decoder: RawDecoder::new()?,
+        })
+    }
+
+    /// Decode one encoded frame into `out`, resizing it to the picture
+    ///
+    /// Returns `false` if the data completed no picture.
+    pub fn decode(&mut self, data: &[u8], out: &mut I420Buffer) -> EncoderResult<bool> {
+        let Some(picture) = self.decoder.decode(data)? else {
+            return Ok(false);
+        };
+
+        out.resize(picture.width as u32, picture.height as u32);
+        let (y_stride, uv_stride) = out.strides();
+        let (chroma_width, _) = out.chroma_size();
+        let (y, u, v) = out.planes_mut();
+        let widths = [picture.width, chroma_width, chroma_width];
+        for ((plane, stride), ((source, source_stride), width)) in
+            [(y, y_stride), (u, uv_stride), (v, uv_stride)]
+                .into_iter()
+                .zip(picture.planes.into_iter().zip(widths))
+        {
+            for (row, source_row) in plane
+                .chunks_exact_mut(stride)
+                .zip(source.chunks(source_stride))
+            {
+                row[..width].copy_from_slice(&source_row[..width]);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncoderConfig, OpenH264Encoder, VideoEncoder, psnr, rgb_to_i420, ssim};
+    use capture::{ClipRenderer, ScreenClip};
+
+    #[test]
+    fn test_decoded_frames_match_source() {
+        let config = EncoderConfig {
+            width: 320,
+            height: 240,
+            bitrate_kbps: 2000,
+            ..Default::default()
+        };
+        let mut encoder = OpenH264Encoder::new();
+        encoder.init(config.clone()).unwrap();
+        let mut decoder = OpenH264Decoder::new().unwrap();
+        let mut clip = ClipRenderer::new(ScreenClip::Typing, 320, 240);
+        let (mut source, mut decoded) = (I420Buffer::new(), I420Buffer::new());
+
+        for _ in 0..10 {
+            let frame = clip.next_frame();
+            let encoded = encoder.encode(&frame).unwrap();
+            assert!(decoder.decode(&encoded.data, &mut decoded).unwrap());
+            rgb_to_i420(&frame, config.color_space, &mut source).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (320, 240));
+            assert!(psnr(&source, &decoded) > 30.0);
+            assert!(ssim(&source, &decoded) > 0.9);
+        }
+    }
+}
@@ -1,26 +1,68 @@
 //! OpenH264 encoder implementation
 
 use bytes::Bytes;
-use capture::{CapturedFrame, PixelFormat};
-use openh264::Error as OpenH264Error;
-use openh264::encoder::{Encoder, EncoderConfig as OpenH264Config};
+use capture::CapturedFrame;
+use openh264_sys2::{
+    CAMERA_VIDEO_REAL_TIME, CM_BT709, CM_SMPTE170M, CP_BT709, CP_SMPTE170M,
+    ENCODER_LTR_MARKING_FEEDBACK, ENCODER_LTR_RECOVERY_REQUEST, ENCODER_OPTION_BITRATE,
+    ENCODER_OPTION_FRAME_RATE, ENCODER_OPTION_MAX_BITRATE, ENCODER_OPTION_TRACE_LEVEL,
+    HIGH_COMPLEXITY, LOW_COMPLEXITY, LTR_MARKING_SUCCESS, LTR_RECOVERY_REQUEST, MEDIUM_COMPLEXITY,
+    RC_BITRATE_MODE, RC_OFF_MODE, RC_QUALITY_MODE, SBitrateInfo, SCREEN_CONTENT_REAL_TIME,
+    SEncParamExt, SLTRMarkingFeedback, SLTRRecoverRequest, SM_SIZELIMITED_SLICE, SPATIAL_LAYER_0,
+    SPATIAL_LAYER_ALL, SSourcePicture, TRC_IEC61966_2_1, VF_UNDEF, WELS_LOG_QUIET, videoFormatI420,
+    videoFrameTypeIDR,
+};
+use std::collections::VecDeque;
+use std::os::raw::{c_int, c_uint};
+use std::ptr;
 use std::time::Instant;
 use tracing::{debug, info};
 
+use crate::h264::{self, Sps};
+use crate::openh264_raw::RawEncoder;
 use crate::{
-    Codec, EncodedFrame, EncodedFrameType, EncoderConfig, EncoderError, EncoderResult,
-    EncoderStats, VideoEncoder,
+    Codec, ColorMatrix, ColorRange, EncodedFrame, EncodedFrameType, EncoderConfig, EncoderError,
+    EncoderResult, EncoderStats, RateControl, VideoEncoder, YuvFrame,
 };
 
+/// Smallest slice cap OpenH264 accepts: a worst-case macroblock plus
+/// its NAL header allowance
+const MIN_SLICE_BYTES: u32 = 400 + 20 + 1;
+
+/// QP range available to rate control
+const MIN_QP: c_int = 12;
+const MAX_QP: c_int = 48;
+
+/// Most temporal layers we configure (L1T3)
+const MAX_TEMPORAL_LAYERS: u8 = 3;
+
+/// Frames whose reference state is kept for loss recovery
+const TRACKED_FRAMES: usize = 256;
+
+/// Reference state of an encoded frame
+#[derive(Debug, Clone, Copy)]
+struct FrameRefs {
+    sequence: u64,
+    idr_pic_id: u32,
+    frame_num: u32,
+    /// `frame_num` of the long-term reference this frame established
+    marks_long_term: Option<u32>,
+}
+
 /// OpenH264-based software encoder
 pub struct OpenH264Encoder {
-    encoder: Option<Encoder>,
+    encoder: Option<RawEncoder>,
     config: EncoderConfig,
     stats: EncoderStats,
     force_keyframe: bool,
     frame_counter: u64,
+    next_pts_us: u64,
     encode_times: Vec<u64>,
-    yuv_buffer: Vec<u8>,
+    yuv: YuvFrame,
+    sps: Option<Sps>,
+    references: VecDeque<FrameRefs>,
+    /// Long-term reference last reported to OpenH264 as received
+    acknowledged: Option<(u32, u32)>,
 }
 
 impl OpenH264Encoder {
@@ -32,67 +74,157 @@ impl OpenH264Encoder {
             stats: EncoderStats::default(),
             force_keyframe: false,
             frame_counter: 0,
+            next_pts_us: 0,
             encode_times: Vec::with_capacity(100),
-            yuv_buffer: Vec::new(),
+            yuv: YuvFrame::default(),
+            sps: None,
+            references: VecDeque::with_capacity(TRACKED_FRAMES),
+            acknowledged: None,
         }
     }
 
-    /// Convert BGRA/RGBA to I420 (YUV planar)
-    fn rgb_to_yuv(&mut self, frame: &CapturedFrame) -> EncoderResult<&[u8]> {
-        let width = frame.width as usize;
-        let height = frame.height as usize;
-
-        // I420 size: Y plane + U plane (1/4) + V plane (1/4) = 1.5 * width * height
-        let y_size = width * height;
-        let uv_size = (width / 2) * (height / 2);
-        let total_size = y_size + 2 * uv_size;
-
-        self.yuv_buffer.resize(total_size, 0);
-
-        let (y_plane, uv_planes) = self.yuv_buffer.split_at_mut(y_size);
-        let (u_plane, v_plane) = uv_planes.split_at_mut(uv_size);
-
-        let is_bgra = frame.format == PixelFormat::Bgra8;
-
-        // Convert RGB(A) to YUV
-        for y in 0..height {
-            for x in 0..width {
-                let pixel_offset = y * frame.stride as usize + x * 4;
-
-                let (r, g, b) = if is_bgra {
-                    (
-                        frame.data[pixel_offset + 2] as i32,
-                        frame.data[pixel_offset + 1] as i32,
-                        frame.data[pixel_offset] as i32,
-                    )
-                } else {
-                    (
-                        frame.data[pixel_offset] as i32,
-                        frame.data[pixel_offset + 1] as i32,
-                        frame.data[pixel_offset + 2] as i32,
-                    )
-                };
-
-                // BT.601 conversion
-                let y_val = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
-                y_plane[y * width + x] = y_val.clamp(0, 255) as u8;
-
-                // Subsample for U and V (every 2x2 block)
-                if (x % 2 == 0) && (y % 2 == 0) {
-                    let u_val = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
-                    let v_val = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
-
-                    let uv_idx = (y / 2) * (width / 2) + (x / 2);
-                    u_plane[uv_idx] = u_val.clamp(0, 255) as u8;
-                    v_plane[uv_idx] = v_val.clamp(0, 255) as u8;
-                }
+    /// Build OpenH264 parameters for `config`
+    fn params(encoder: &RawEncoder, config: &EncoderConfig) -> EncoderResult<SEncParamExt> {
+        let mut params = encoder.default_params()?;
+        params.iUsageType = SCREEN_CONTENT_REAL_TIME;
+        params.iPicWidth = config.width as c_int;
+        params.iPicHeight = config.height as c_int;
+        params.iTargetBitrate = (config.bitrate_kbps * 1000) as c_int;
+        params.iRCMode = match config.rate_control {
+            RateControl::Cbr => RC_BITRATE_MODE,
+            RateControl::Vbr => RC_QUALITY_MODE,
+            RateControl::Cqp => RC_OFF_MODE,
+        };
+        params.iComplexityMode = match config.preset {
+            0..=3 => LOW_COMPLEXITY,
+            4..=6 => MEDIUM_COMPLEXITY,
+            _ => HIGH_COMPLEXITY,
+        };
+        params.fMaxFrameRate = config.fps as f32;
+        params.bEnableFrameSkip = false;
+        // OpenH264 narrows screen content to QP 26-35 by default, which
+        // leaves rate control no room to meet low targets
+        params.iMinQp = MIN_QP;
+        params.iMaxQp = MAX_QP;
+        params.iSpatialLayerNum = 1;
+        params.iTemporalLayerNum = config.temporal_layers as c_int;
+        if config.long_term_refs {
+            // OpenH264 only honors long-term references on a lossless
+            // link in screen content mode, so they cost the screen
+            // content tools; text is softer until they're turned off
+            params.iUsageType = CAMERA_VIDEO_REAL_TIME;
+            params.bEnableLongTermReference = true;
+            params.iLTRRefNum = 2;
+        }
+
+        let layer = &mut params.sSpatialLayers[0];
+        layer.iVideoWidth = params.iPicWidth;
+        layer.iVideoHeight = params.iPicHeight;
+        layer.fFrameRate = params.fMaxFrameRate;
+        layer.iSpatialBitrate = params.iTargetBitrate;
+        match config.rate_control {
+            // Capping at the target keeps keyframes from bursting over it
+            RateControl::Cbr => layer.iMaxSpatialBitrate = params.iTargetBitrate,
+            RateControl::Vbr => {}
+            RateControl::Cqp => layer.iDLayerQp = config.qp.min(51) as c_int,
+        }
+
+        if let Some(max_slice_bytes) = config.max_slice_bytes {
+            if max_slice_bytes < MIN_SLICE_BYTES {
+                return Err(EncoderError::InvalidConfig(format!(
+                    "max_slice_bytes must be at least {}",
+                    MIN_SLICE_BYTES
+                )));
             }
+            // The NAL size limit is the hard cap; OpenH264 aims slices a
+            // little below it to leave room for headers
+            layer.sSliceArgument.uiSliceMode = SM_SIZELIMITED_SLICE;
+            layer.sSliceArgument.uiSliceSizeConstraint = max_slice_bytes;
+            params.uiMaxNalSize = max_slice_bytes;
+        }
+
+        // Signal the conversion colorspace in the SPS VUI. Captured pixels
+        // are sRGB, so that is the transfer function either way.
+        let (primaries, matrix) = match config.color_space.matrix {
+            ColorMatrix::Bt601 => (CP_SMPTE170M, CM_SMPTE170M),
+            ColorMatrix::Bt709 => (CP_BT709, CM_BT709),
+        };
+        layer.bVideoSignalTypePresent = true;
+        layer.uiVideoFormat = VF_UNDEF as u8;
+        layer.bFullRange = config.color_space.range == ColorRange::Full;
+        layer.bColorDescriptionPresent = true;
+        layer.uiColorPrimaries = primaries as u8;
+        layer.uiTransferCharacteristics = TRC_IEC61966_2_1 as u8;
+        layer.uiColorMatrix = matrix as u8;
+
+        Ok(params)
+    }
+
+    /// Retarget the running encoder's bitrate
+    fn apply_bitrate(&mut self, previous_kbps: u32) -> EncoderResult<()> {
+        let Some(encoder) = self.encoder.as_mut() else {
+            return Ok(());
+        };
+        let bitrate = (self.config.bitrate_kbps * 1000) as c_int;
+        let mut target = SBitrateInfo {
+            iLayer: SPATIAL_LAYER_ALL,
+            iBitrate: bitrate,
+        };
+        if self.config.rate_control != RateControl::Cbr {
+            return encoder.set_option(ENCODER_OPTION_BITRATE, &mut target);
+        }
+
+        // OpenH264 rejects a target above the cap, so move whichever
+        // one keeps the pair valid first
+        let mut cap = SBitrateInfo {
+            iLayer: SPATIAL_LAYER_0,
+            iBitrate: bitrate,
+        };
+        if self.config.bitrate_kbps > previous_kbps {
+            encoder.set_option(ENCODER_OPTION_MAX_BITRATE, &mut cap)?;
+            encoder.set_option(ENCODER_OPTION_BITRATE, &mut target)
+        } else {
+            encoder.set_option(ENCODER_OPTION_BITRATE, &mut target)?;
+            encoder.set_option(ENCODER_OPTION_MAX_BITRATE, &mut cap)
         }
+    }
 
-        Ok(&self.yuv_buffer)
+    /// Remember the reference state of the frame just encoded
+    fn track_references(&mut self, sequence: u64, refs: h264::SliceRefs) {
+        let idr_pic_id = match refs.idr_pic_id {
+            Some(id) => {
+                self.references.clear();
+                id
+            }
+            None => match self.references.back() {
+                Some(previous) => previous.idr_pic_id,
+                None => return,
+            },
+        };
+        if self.references.len() == TRACKED_FRAMES {
+            self.references.pop_front();
+        }
+        self.references.push_back(FrameRefs {
+            sequence,
+            idr_pic_id,
+            frame_num: refs.frame_num,
+            marks_long_term: refs.marks_long_term,
+        });
     }
 }
 
+/// Whether an Annex-B NAL unit carries slice data (types 1-5)
+fn is_slice(nal: &[u8]) -> bool {
+    let header = if nal.starts_with(&[0, 0, 0, 1]) {
+        nal.get(4)
+    } else if nal.starts_with(&[0, 0, 1]) {
+        nal.get(3)
+    } else {
+        nal.first()
+    };
+    header.is_some_and(|header| matches!(header & 0x1f, 1..=5))
+}
+
 impl Default for OpenH264Encoder {
     fn default() -> Self {
         Self::new()
@@ -106,93 +238,144 @@ impl VideoEncoder for OpenH264Encoder {
                 "OpenH264 only supports H.264".to_string(),
             ));
         }
+        // Callers avoiding keyframe spikes turn periodic keyframes off
+        // instead and recover from loss with long-term references
+        if config.intra_refresh {
+            return Err(EncoderError::InvalidConfig(
+                "OpenH264 has no rolling intra refresh".to_string(),
+            ));
+        }
+        if config.chroma_444 {
+            return Err(EncoderError::InvalidConfig(
+                "OpenH264 only encodes 4:2:0".to_string(),
+            ));
+        }
+        if !(1..=MAX_TEMPORAL_LAYERS).contains(&config.temporal_layers) {
+            return Err(EncoderError::InvalidConfig(format!(
+                "temporal_layers must be 1-{}",
+                MAX_TEMPORAL_LAYERS
+            )));
+        }
 
         info!(
             "Initializing OpenH264 encoder: {}x{} @ {} kbps, {} fps",
             config.width, config.height, config.bitrate_kbps, config.fps
         );
 
-        let mut openh264_config = OpenH264Config::new()
-            .set_bitrate_bps(config.bitrate_kbps * 1000)
-            .max_frame_rate(config.fps as f32)
-            .usage_type(openh264::encoder::UsageType::ScreenContentRealTime)
-            .enable_skip_frame(false);
-
-        let encoder =
-            Encoder::with_api_config(openh264::OpenH264API::from_source(), openh264_config)
-                .map_err(|e: OpenH264Error| EncoderError::InitFailed(e.to_string()))?;
+        let mut encoder = RawEncoder::new()?;
+        let mut trace_level = WELS_LOG_QUIET as c_int;
+        encoder.set_option(ENCODER_OPTION_TRACE_LEVEL, &mut trace_level)?;
+        let params = Self::params(&encoder, &config)?;
+        encoder.initialize(&params)?;
 
         self.encoder = Some(encoder);
         self.config = config;
         self.stats = EncoderStats::default();
-        self.frame_counter = 0;
+        self.next_pts_us = 0;
         self.encode_times.clear();
+        self.sps = None;
+        self.references.clear();
+        self.acknowledged = None;
 
         Ok(())
     }
 
     fn encode(&mut self, frame: &CapturedFrame) -> EncoderResult<EncodedFrame> {
+        let mut yuv = std::mem::take(&mut self.yuv);
+        let encoded = yuv
+            .convert(frame, self.config.color_space)
+            .and_then(|()| self.encode_yuv(&yuv));
+        self.yuv = yuv;
+        encoded
+    }
+
+    fn encode_yuv(&mut self, frame: &YuvFrame) -> EncoderResult<EncodedFrame> {
         if self.encoder.is_none() {
             return Err(EncoderError::NotInitialized);
         }
+        let YuvFrame::I420(yuv) = frame else {
+            return Err(EncoderError::UnsupportedPixelFormat);
+        };
 
-        if frame.width != self.config.width || frame.height != self.config.height {
+        if yuv.width() != self.config.width || yuv.height() != self.config.height {
             return Err(EncoderError::UnsupportedResolution {
-                width: frame.width,
-                height: frame.height,
+                width: yuv.width(),
+                height: yuv.height(),
             });
         }
 
         let start = Instant::now();
 
-        // Convert to YUV and clone to release the borrow on self
-        let yuv_data = self.rgb_to_yuv(frame)?.to_vec();
-
-        // Now we can borrow the encoder
         let encoder = self.encoder.as_mut().unwrap();
 
-        // Create the YUV source
-        let yuv_source = openh264::formats::YUVBuffer::from_vec(
-            yuv_data,
-            self.config.width as usize,
-            self.config.height as usize,
-        );
-
         // Encode
-        let is_keyframe =
-            self.force_keyframe || self.frame_counter % self.config.keyframe_interval as u64 == 0;
+        let force_idr = self.force_keyframe
+            || self
+                .frame_counter
+                .is_multiple_of(self.config.keyframe_interval as u64);
 
         if self.force_keyframe {
             self.force_keyframe = false;
             debug!("Forcing keyframe");
         }
 
-        if is_keyframe {
-            encoder.force_intra_frame();
+        if force_idr {
+            encoder.force_intra_frame()?;
         }
 
-        // For actual encoding, we'd use encoder.encode() here
-        let bitstream_result = encoder
-            .encode(&yuv_source)
-            .map_err(|e| EncoderError::EncodingFailed(e.to_string()))?;
+        let pts_us = self.next_pts_us;
+
+        let (y_stride, uv_stride) = yuv.strides();
+        let (y_plane, u_plane, v_plane) = yuv.planes();
+        let picture = SSourcePicture {
+            iColorFormat: videoFormatI420 as c_int,
+            iStride: [y_stride as c_int, uv_stride as c_int, uv_stride as c_int, 0],
+            pData: [
+                y_plane.as_ptr().cast_mut(),
+                u_plane.as_ptr().cast_mut(),
+                v_plane.as_ptr().cast_mut(),
+                ptr::null_mut(),
+            ],
+            iPicWidth: yuv.width() as c_int,
+            iPicHeight: yuv.height() as c_int,
+            uiTimeStamp: (pts_us / 1000) as i64,
+        };
+
+        let bitstream = encoder.encode(&picture)?;
+        let is_keyframe = bitstream.frame_type() == videoFrameTypeIDR;
+        let temporal_id = bitstream.temporal_id();
 
         let encode_time = start.elapsed().as_micros() as u64;
 
-        // Collect NAL units with Annex-B start codes
+        // Collect NAL units with Annex-B start codes. Parameter sets and
+        // SEI travel with the slice that follows them.
         let mut nal_data = Vec::new();
-        for l in 0..bitstream_result.num_layers() {
-            if let Some(layer) = bitstream_result.layer(l) {
-                for n in 0..layer.nal_count() {
-                    if let Some(nal) = layer.nal_unit(n) {
-                        let has_start_code = nal.starts_with(&[0, 0, 0, 1])
-                            || nal.starts_with(&[0, 0, 1]);
-                        if !has_start_code {
-                            nal_data.extend_from_slice(&[0, 0, 0, 1]);
-                        }
-                        nal_data.extend_from_slice(nal);
-                    }
-                }
+        let mut slices = Vec::new();
+        let mut slice_start = 0;
+        let mut slice_refs = None;
+        for nal in bitstream.nal_units() {
+            if let Some(sps) = h264::parse_sps(nal) {
+                self.sps = Some(sps);
+            }
+            if slice_refs.is_none()
+                && let Some(sps) = &self.sps
+            {
+                slice_refs = h264::parse_slice_refs(nal, sps);
             }
+
+            let has_start_code = nal.starts_with(&[0, 0, 0, 1]) || nal.starts_with(&[0, 0, 1]);
+            if !has_start_code {
+                nal_data.extend_from_slice(&[0, 0, 0, 1]);
+            }
+            nal_data.extend_from_slice(nal);
+            if is_slice(nal) {
+                slices.push(slice_start..nal_data.len());
+                slice_start = nal_data.len();
+            }
+        }
+        match slices.last_mut() {
+            Some(last) => last.end = nal_data.len(),
+            None => slices.push(0..nal_data.len()),
         }
 
         if nal_data.is_empty() {
@@ -203,25 +386,34 @@ impl VideoEncoder for OpenH264Encoder {
 
         let frame_type = if is_keyframe {
             EncodedFrameType::Key
+        } else if slice_refs.is_some_and(|refs| refs.uses_long_term) {
+            EncodedFrameType::Recovery
         } else {
             EncodedFrameType::Predicted
         };
-
-        let pts_us = (self.frame_counter as f64 / self.config.fps as f64 * 1_000_000.0) as u64;
+        if self.config.long_term_refs
+            && let Some(refs) = slice_refs
+        {
+            self.track_references(self.frame_counter, refs);
+        }
 
         let encoded = EncodedFrame {
             data: Bytes::from(nal_data),
-            width: frame.width,
-            height: frame.height,
+            slices,
+            codec: Codec::H264,
+            width: yuv.width(),
+            height: yuv.height(),
             frame_type,
             pts_us,
             dts_us: pts_us,
             sequence: self.frame_counter,
+            temporal_id,
             encode_time_us: encode_time,
         };
 
         // Update stats
         self.frame_counter += 1;
+        self.next_pts_us += 1_000_000 / self.config.fps.max(1) as u64;
         self.stats.frames_encoded += 1;
         self.stats.bytes_output += encoded.data.len() as u64;
 
@@ -248,16 +440,96 @@ impl VideoEncoder for OpenH264Encoder {
         self.force_keyframe = true;
     }
 
+    fn acknowledge(&mut self, sequence: u64) -> EncoderResult<()> {
+        let Some(encoder) = self.encoder.as_mut() else {
+            return Ok(());
+        };
+        let Some((idr_pic_id, frame_num)) = self
+            .references
+            .iter()
+            .rev()
+            .filter(|refs| refs.sequence <= sequence)
+            .find_map(|refs| Some((refs.idr_pic_id, refs.marks_long_term?)))
+        else {
+            return Ok(());
+        };
+        if self.acknowledged == Some((idr_pic_id, frame_num)) {
+            return Ok(());
+        }
+
+        let mut feedback = SLTRMarkingFeedback {
+            uiFeedbackType: LTR_MARKING_SUCCESS as c_uint,
+            uiIDRPicId: idr_pic_id,
+            iLTRFrameNum: frame_num as c_int,
+            iLayerId: 0,
+        };
+        encoder.set_option(ENCODER_LTR_MARKING_FEEDBACK, &mut feedback)?;
+        self.acknowledged = Some((idr_pic_id, frame_num));
+        Ok(())
+    }
+
+    fn invalidate_references(&mut self, last_good: u64) -> EncoderResult<()> {
+        let good = self
+            .references
+            .iter()
+            .find(|refs| refs.sequence == last_good)
+            .copied();
+        let (Some(encoder), Some(good), Some(&latest)) =
+            (self.encoder.as_mut(), good, self.references.back())
+        else {
+            debug!(
+                "No reference state for frame {}, forcing keyframe",
+                last_good
+            );
+            self.force_keyframe = true;
+            return Ok(());
+        };
+        if good.sequence == latest.sequence {
+            return Ok(());
+        }
+        if good.idr_pic_id != latest.idr_pic_id {
+            self.force_keyframe = true;
+            return Ok(());
+        }
+
+        // OpenH264 predicts the next frame from a long-term reference it
+        // was told the receiver has, or falls back to an IDR
+        let mut request = SLTRRecoverRequest {
+            uiFeedbackType: LTR_RECOVERY_REQUEST as c_uint,
+            uiIDRPicId: latest.idr_pic_id,
+            iLastCorrectFrameNum: good.frame_num as c_int,
+            iCurrentFrameNum: latest.frame_num as c_int,
+            iLayerId: 0,
+        };
+        encoder.set_option(ENCODER_LTR_RECOVERY_REQUEST, &mut request)?;
+        self.references.retain(|refs| refs.sequence <= last_good);
+        debug!("Recovering from frame {}", last_good);
+        Ok(())
+    }
+
     fn set_bitrate(&mut self, bitrate_kbps: u32) -> EncoderResult<()> {
-        self.config.bitrate_kbps = bitrate_kbps;
-        // OpenH264 requires re-initialization for bitrate change
-        // In a production implementation, we'd use the encoder's dynamic APIs
+        if bitrate_kbps == 0 {
+            return Err(EncoderError::InvalidConfig(
+                "Bitrate must be non-zero".to_string(),
+            ));
+        }
+        let previous = std::mem::replace(&mut self.config.bitrate_kbps, bitrate_kbps);
+        self.apply_bitrate(previous)?;
         debug!("Bitrate updated to {} kbps", bitrate_kbps);
         Ok(())
     }
 
     fn set_fps(&mut self, fps: u32) -> EncoderResult<()> {
+        if fps == 0 {
+            return Err(EncoderError::InvalidConfig(
+                "FPS must be non-zero".to_string(),
+            ));
+        }
         self.config.fps = fps;
+        if let Some(encoder) = self.encoder.as_mut() {
+            let mut frame_rate = fps as f32;
+            encoder.set_option(ENCODER_OPTION_FRAME_RATE, &mut frame_rate)?;
+        }
         debug!("FPS updated to {}", fps);
         Ok(())
     }
@@ -284,10 +556,286 @@ impl VideoEncoder for OpenH264Encoder {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use capture::PixelFormat;
+
+    fn frame(width: u32, height: u32) -> CapturedFrame {
+        CapturedFrame {
+            data: Bytes::from(vec![0x80; (width * height * 4) as usize]),
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence: 0,
+            dirty_rects: Vec::new(),
+            display_id: 0,
+        }
+    }
+
+    /// Gradient with fresh low-amplitude noise, so every frame costs
+    /// bits and the cost falls smoothly as QP rises
+    fn noisy_frame(width: u32, height: u32, index: u32) -> CapturedFrame {
+        let data = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                let noise = (i ^ index.wrapping_mul(0x9e37_79b9)).wrapping_mul(2_654_435_761) >> 28;
+                let value = (x / 4 + y / 4) as u8 / 2 + 64;
+                [value + noise as u8, value, value - noise as u8, 255]
+            })
+            .collect::<Vec<_>>();
+        CapturedFrame {
+            data: Bytes::from(data),
+            ..frame(width, height)
+        }
+    }
+
+    /// Average encoded bytes per frame over `count` frames
+    fn bytes_per_frame(encoder: &mut OpenH264Encoder, start: u32, count: u32) -> u64 {
+        let (width, height) = (encoder.config().width, encoder.config().height);
+        let total: usize = (start..start + count)
+            .map(|i| {
+                encoder
+                    .encode(&noisy_frame(width, height, i))
+                    .unwrap()
+                    .data
+                    .len()
+            })
+            .sum();
+        total as u64 / count as u64
+    }
 
     #[test]
     fn test_encoder_creation() {
         let encoder = OpenH264Encoder::new();
         assert!(encoder.encoder.is_none());
     }
+
+    #[test]
+    fn test_reconfigure_switches_resolution_with_keyframe() {
+        let mut encoder = OpenH264Encoder::new();
+        encoder
+            .init(EncoderConfig {
+                width: 320,
+                height: 240,
+                ..Default::default()
+            })
+            .unwrap();
+        encoder.encode(&frame(320, 240)).unwrap();
+        encoder.encode(&frame(320, 240)).unwrap();
+
+        let resized = frame(640, 360);
+        assert!(matches!(
+            encoder.encode(&resized),
+            Err(EncoderError::UnsupportedResolution { .. })
+        ));
+
+        encoder.reconfigure(640, 360).unwrap();
+        let encoded = encoder.encode(&resized).unwrap();
+        assert_eq!(encoded.frame_type, EncodedFrameType::Key);
+        assert_eq!((encoded.width, encoded.height), (640, 360));
+        assert_eq!(
+            encoder.config().bitrate_kbps,
+            EncoderConfig::default().bitrate_kbps
+        );
+    }
+
+    #[test]
+    fn test_preconverted_frames_encode_identically() {
+        let config = EncoderConfig {
+            width: 320,
+            height: 240,
+            ..Default::default()
+        };
+        let mut direct = OpenH264Encoder::new();
+        let mut converted = OpenH264Encoder::new();
+        direct.init(config.clone()).unwrap();
+        converted.init(config.clone()).unwrap();
+
+        let mut yuv = YuvFrame::new(false);
+        for i in 0..3 {
+            let input = noisy_frame(320, 240, i);
+            yuv.convert(&input, config.color_space).unwrap();
+            assert_eq!(
+                direct.encode(&input).unwrap().data,
+                converted.encode_yuv(&yuv).unwrap().data
+            );
+        }
+
+        assert!(matches!(
+            converted.encode_yuv(&YuvFrame::new(true)),
+            Err(EncoderError::UnsupportedPixelFormat)
+        ));
+    }
+
+    #[test]
+    fn test_bitrate_changes_track_output_size() {
+        let target = |kbps: u64| kbps * 1000 / 8 / 30;
+        let assert_near = |actual: u64, kbps: u64, what: &str| {
+            let expected = target(kbps);
+            assert!(
+                actual.abs_diff(expected) <= expected / 4,
+                "{what}: {actual} bytes/frame, expected about {expected}"
+            );
+        };
+
+        for rate_control in [RateControl::Cbr, RateControl::Vbr] {
+            let mut encoder = OpenH264Encoder::new();
+            encoder
+                .init(EncoderConfig {
+                    width: 320,
+                    height: 240,
+                    bitrate_kbps: 500,
+                    fps: 30,
+                    rate_control,
+                    ..Default::default()
+                })
+                .unwrap();
+
+            // Give rate control a second to settle after each change
+            bytes_per_frame(&mut encoder, 0, 30);
+            assert_near(bytes_per_frame(&mut encoder, 30, 30), 500, "initial");
+
+            encoder.set_bitrate(100).unwrap();
+            bytes_per_frame(&mut encoder, 60, 30);
+            assert_near(bytes_per_frame(&mut encoder, 90, 30), 100, "lowered");
+
+            encoder.set_bitrate(500).unwrap();
+            bytes_per_frame(&mut encoder, 120, 30);
+            assert_near(bytes_per_frame(&mut encoder, 150, 30), 500, "raised");
+        }
+    }
+
+    #[test]
+    fn test_constant_qp_ignores_bitrate() {
+        let encode_at = |qp: u8| {
+            let mut encoder = OpenH264Encoder::new();
+            encoder
+                .init(EncoderConfig {
+                    width: 320,
+                    height: 240,
+                    bitrate_kbps: 100,
+                    rate_control: RateControl::Cqp,
+                    qp,
+                    ..Default::default()
+                })
+                .unwrap();
+            bytes_per_frame(&mut encoder, 0, 10);
+            bytes_per_frame(&mut encoder, 10, 20)
+        };
+
+        let fine = encode_at(12);
+        let coarse = encode_at(40);
+        assert!(fine > 4 * coarse, "qp 12: {fine}, qp 40: {coarse}");
+        // Far above the 100 kbps (~416 bytes/frame) target
+        assert!(fine > 2 * 416, "qp 12: {fine}");
+    }
+
+    #[test]
+    fn test_lost_frames_recover_from_long_term_reference() {
+        let recover = |long_term_refs: bool| {
+            let mut encoder = OpenH264Encoder::new();
+            encoder
+                .init(EncoderConfig {
+                    width: 320,
+                    height: 240,
+                    long_term_refs,
+                    ..Default::default()
+                })
+                .unwrap();
+            for i in 0..40 {
+                let encoded = encoder.encode(&noisy_frame(320, 240, i)).unwrap();
+                encoder.acknowledge(encoded.sequence).unwrap();
+            }
+
+            // Frames after 35 never arrived
+            for i in 40..45 {
+                encoder.encode(&noisy_frame(320, 240, i)).unwrap();
+            }
+            encoder.invalidate_references(35).unwrap();
+            encoder
+                .encode(&noisy_frame(320, 240, 45))
+                .unwrap()
+                .frame_type
+        };
+
+        assert_eq!(recover(true), EncodedFrameType::Recovery);
+        assert_eq!(recover(false), EncodedFrameType::Key);
+    }
+
+    #[test]
+    fn test_temporal_layers_cycle_and_top_layer_is_unreferenced() {
+        for (layers, cycle) in [(1, &[0][..]), (2, &[0, 1]), (3, &[0, 2, 1, 2])] {
+            let mut encoder = OpenH264Encoder::new();
+            encoder
+                .init(EncoderConfig {
+                    width: 320,
+                    height: 240,
+                    temporal_layers: layers,
+                    long_term_refs: true,
+                    ..Default::default()
+                })
+                .unwrap();
+
+            for i in 0..12 {
+                let encoded = encoder.encode(&noisy_frame(320, 240, i)).unwrap();
+                let expected = cycle[i as usize % cycle.len()];
+                assert_eq!(encoded.temporal_id, expected, "L1T{layers} frame {i}");
+
+                // nal_ref_idc of each slice
+                let referenced = encoded.slices.iter().any(|slice| {
+                    let data = &encoded.data[slice.clone()];
+                    let nal = data.windows(4).rposition(|w| w == [0, 0, 0, 1]).unwrap();
+                    data[nal + 4] >> 5 != 0
+                });
+                let top = layers > 1 && expected == layers - 1;
+                assert_eq!(referenced, !top, "L1T{layers} frame {i}");
+            }
+        }
+
+        assert!(matches!(
+            OpenH264Encoder::new().init(EncoderConfig {
+                temporal_layers: 4,
+                ..Default::default()
+            }),
+            Err(EncoderError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_slices_respect_size_cap() {
+        let mut encoder = OpenH264Encoder::new();
+        encoder
+            .init(EncoderConfig {
+                width: 1280,
+                height: 720,
+                // Fine quantization so the keyframe needs many slices
+                rate_control: RateControl::Cqp,
+                qp: 12,
+                max_slice_bytes: Some(1100),
+                ..Default::default()
+            })
+            .unwrap();
+
+        for i in 0..3 {
+            let encoded = encoder.encode(&noisy_frame(1280, 720, i)).unwrap();
+            assert!(encoded.slices.len() > 10, "{} slices", encoded.slices.len());
+            assert_eq!(encoded.slices.first().unwrap().start, 0);
+            assert_eq!(encoded.slices.last().unwrap().end, encoded.data.len());
+            for pair in encoded.slices.windows(2) {
+                assert_eq!(pair[0].end, pair[1].start);
+            }
+            for slice in &encoded.slices {
+                assert!(slice.len() <= 1100, "{} byte slice", slice.len());
+                assert!(is_slice(&encoded.data[slice.start..]) || slice.start == 0);
+            }
+        }
+
+        assert!(matches!(
+            encoder.init(EncoderConfig {
+                max_slice_bytes: Some(300),
+                ..Default::default()
+            }),
+            Err(EncoderError::InvalidConfig(_))
+        ));
+    }
 }
@@ -0,0 +1,291 @@
+//! Minimal safe wrapper over the OpenH264 encoder and decoder C APIs
+//!
+//! The `openh264` crate's `Encoder` builds `SEncParamExt` internally and
+//! doesn't expose it, so fields such as the VUI colour description can't
+//! be set. This drives `ISVCEncoder` directly instead, and `ISVCDecoder`
+//! alongside it for measuring what the encoder produced.
+
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+use openh264_sys2::{
+    API, DynamicAPI, ENCODER_OPTION, ERROR_CON_DISABLE, EVideoFrameType, ISVCDecoder,
+    ISVCDecoderVtbl, ISVCEncoder, ISVCEncoderVtbl, SBufferInfo, SDecodingParam, SEncParamExt,
+    SFrameBSInfo, SSourcePicture, VIDEO_BITSTREAM_DEFAULT, VIDEO_CODING_LAYER, cmResultSuccess,
+    dsErrorFree,
+};
+
+use crate::{EncoderError, EncoderResult};
+
+/// An `ISVCEncoder` instance
+pub(crate) struct RawEncoder {
+    api: DynamicAPI,
+    encoder: *mut ISVCEncoder,
+    initialized: bool,
+    bitstream: Box<SFrameBSInfo>,
+}
+
+// SAFETY: the encoder instance is only used through `&mut self`
+unsafe impl Send for RawEncoder {}
+
+/// Check an OpenH264 return code
+fn check(code: c_int, what: &str) -> Result<(), String> {
+    if code == cmResultSuccess as c_int {
+        Ok(())
+    } else {
+        Err(format!("{} failed with code {}", what, code))
+    }
+}
+
+impl RawEncoder {
+    /// Create an uninitialized encoder using the bundled OpenH264 build
+    pub fn new() -> EncoderResult<Self> {
+        let api = DynamicAPI::from_source();
+        let mut encoder = ptr::null_mut();
+        // SAFETY: `encoder` is a valid out-pointer
+        let code = unsafe { api.WelsCreateSVCEncoder(&mut encoder) };
+        check(code, "WelsCreateSVCEncoder").map_err(EncoderError::InitFailed)?;
+        if encoder.is_null() {
+            return Err(EncoderError::InitFailed(
+                "WelsCreateSVCEncoder returned null".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            api,
+            encoder,
+            initialized: false,
+            bitstream: Box::default(),
+        })
+    }
+
+    fn vtable(&self) -> &ISVCEncoderVtbl {
+        // SAFETY: `encoder` points at a live instance whose first field is its vtable
+        unsafe { &**self.encoder }
+    }
+
+    /// OpenH264's default parameters, to be adjusted before `initialize`
+    pub fn default_params(&self) -> EncoderResult<SEncParamExt> {
+        let get_default_params = self.vtable().GetDefaultParams.ok_or_else(missing)?;
+        let mut params = SEncParamExt::default();
+        // SAFETY: `params` is a valid, writable SEncParamExt
+        let code = unsafe { get_default_params(self.encoder, &mut params) };
+        check(code, "GetDefaultParams").map_err(EncoderError::InitFailed)?;
+        Ok(params)
+    }
+
+    /// Initialize, or re-initialize, with `params`
+    pub fn initialize(&mut self, params: &SEncParamExt) -> EncoderResult<()> {
+        self.uninitialize();
+        let initialize_ext = self.vtable().InitializeExt.ok_or_else(missing)?;
+        // SAFETY: `params` is a valid SEncParamExt for the call's duration
+        let code = unsafe { initialize_ext(self.encoder, params) };
+        check(code, "InitializeExt").map_err(EncoderError::InitFailed)?;
+        self.initialized = true;
+        Ok(())
+    }
+
+    fn uninitialize(&mut self) {
+        if !std::mem::take(&mut self.initialized) {
+            return;
+        }
+        if let Some(uninitialize) = self.vtable().Uninitialize {
+            // SAFETY: the encoder was initialized
+            unsafe { uninitialize(self.encoder) };
+        }
+    }
+
+    /// Set an encoder option; `value` must be the type OpenH264 expects for it
+    pub fn set_option<T>(&mut self, option: ENCODER_OPTION, value: &mut T) -> EncoderResult<()> {
+        let set_option = self.vtable().SetOption.ok_or_else(missing)?;
+        // SAFETY: `value` is valid for writes; its type is the caller's contract
+        let code = unsafe { set_option(self.encoder, option, (value as *mut T).cast::<c_void>()) };
+        check(code, "SetOption").map_err(EncoderError::Internal)
+    }
+
+    /// Make the next encoded frame an IDR frame
+    pub fn force_intra_frame(&mut self) -> EncoderResult<()> {
+        let force_intra_frame = self.vtable().ForceIntraFrame.ok_or_else(missing)?;
+        // SAFETY: plain call on a live instance
+        let code = unsafe { force_intra_frame(self.encoder, true) };
+        check(code, "ForceIntraFrame").map_err(EncoderError::EncodingFailed)
+    }
+
+    /// Encode one picture
+    pub fn encode(&mut self, picture: &SSourcePicture) -> EncoderResult<Bitstream<'_>> {
+        if !self.initialized {
+            return Err(EncoderError::NotInitialized);
+        }
+        let encode_frame = self.vtable().EncodeFrame.ok_or_else(missing)?;
+        // SAFETY: the picture planes outlive the call and OpenH264 only reads
+        // them; the bitstream info stays valid until the next call
+        let code = unsafe { encode_frame(self.encoder, picture, &mut *self.bitstream) };
+        check(code, "EncodeFrame").map_err(EncoderError::EncodingFailed)?;
+        Ok(Bitstream {
+            info: &self.bitstream,
+        })
+    }
+}
+
+impl Drop for RawEncoder {
+    fn drop(&mut self) {
+        self.uninitialize();
+        // SAFETY: created by WelsCreateSVCEncoder and not used afterwards
+        unsafe { self.api.WelsDestroySVCEncoder(self.encoder) };
+    }
+}
+
+/// An initialized `ISVCDecoder` instance
+pub(crate) struct RawDecoder {
+    api: DynamicAPI,
+    decoder: *mut ISVCDecoder,
+}
+
+// SAFETY: the decoder instance is only used through `&mut self`
+unsafe impl Send for RawDecoder {}
+
+/// A decoded 4:2:0 picture, borrowed from the decoder
+pub(crate) struct DecodedPicture<'a> {
+    pub width: usize,
+    pub height: usize,
+    /// Y, U and V planes with their strides in bytes
+    pub planes: [(&'a [u8], usize); 3],
+}
+
+impl RawDecoder {
+    /// Create a decoder for an Annex-B H.264 stream
+    pub fn new() -> EncoderResult<Self> {
+        let api = DynamicAPI::from_source();
+        let mut decoder = ptr::null_mut();
+        // SAFETY: `decoder` is a valid out-pointer
+        let code = unsafe { api.WelsCreateDecoder(&mut decoder) };
+        check(code as c_int, "WelsCreateDecoder").map_err(EncoderError::InitFailed)?;
+        if decoder.is_null() {
+            return Err(EncoderError::InitFailed(
+                "WelsCreateDecoder returned null".to_string(),
+            ));
+        }
+        let raw = Self { api, decoder };
+
+        let initialize = raw.vtable().Initialize.ok_or_else(missing)?;
+        let mut params = SDecodingParam {
+            eEcActiveIdc: ERROR_CON_DISABLE,
+            ..Default::default()
+        };
+        params.sVideoProperty.eVideoBsType = VIDEO_BITSTREAM_DEFAULT;
+        // SAFETY: `params` is a valid SDecodingParam for the call's duration
+        let code = unsafe { initialize(raw.decoder, &params) };
+        check(code as c_int, "Initialize").map_err(EncoderError::InitFailed)?;
+        Ok(raw)
+    }
+
+    fn vtable(&self) -> &ISVCDecoderVtbl {
+        // SAFETY: `decoder` points at a live instance whose first field is its vtable
+        unsafe { &**self.decoder }
+    }
+
+    /// Decode one access unit; `None` if it completed no picture
+    pub fn decode(&mut self, data: &[u8]) -> EncoderResult<Option<DecodedPicture<'_>>> {
+        let decode_frame = self.vtable().DecodeFrameNoDelay.ok_or_else(missing)?;
+        let mut planes = [ptr::null_mut(); 3];
+        let mut info = SBufferInfo::default();
+        // SAFETY: `data` is only read; OpenH264 points `planes` at its own
+        // buffers, which stay valid until the next call
+        let state = unsafe {
+            decode_frame(
+                self.decoder,
+                data.as_ptr(),
+                data.len() as c_int,
+                planes.as_mut_ptr(),
+                &mut info,
+            )
+        };
+        if state != dsErrorFree {
+            return Err(EncoderError::Internal(format!(
+                "DecodeFrameNoDelay failed with state {:#x}",
+                state
+            )));
+        }
+        if info.iBufferStatus != 1 || planes.iter().any(|plane| plane.is_null()) {
+            return Ok(None);
+        }
+
+        // SAFETY: `sSystemBuffer` is the only union field
+        let buffer = unsafe { info.UsrData.sSystemBuffer };
+        let (width, height) = (buffer.iWidth as usize, buffer.iHeight as usize);
+        let (y_stride, uv_stride) = (buffer.iStride[0] as usize, buffer.iStride[1] as usize);
+        let plane = |index: usize, stride: usize, rows: usize| {
+            // SAFETY: OpenH264 holds `rows` rows of `stride` bytes per plane
+            unsafe { std::slice::from_raw_parts(planes[index], stride * rows) }
+        };
+        Ok(Some(DecodedPicture {
+            width,
+            height,
+            planes: [
+                (plane(0, y_stride, height), y_stride),
+                (plane(1, uv_stride, height.div_ceil(2)), uv_stride),
+                (plane(2, uv_stride, height.div_ceil(2)), uv_stride),
+            ],
+        }))
+    }
+}
+
+impl Drop for RawDecoder {
+    fn drop(&mut self) {
+        if let Some(uninitialize) = self.vtable().Uninitialize {
+            // SAFETY: the decoder was initialized in `new`
+            unsafe { uninitialize(self.decoder) };
+        }
+        // SAFETY: created by WelsCreateDecoder and not used afterwards
+        unsafe { self.api.WelsDestroyDecoder(self.decoder) };
+    }
+}
+
+fn missing() -> EncoderError {
+    EncoderError::Internal("OpenH264 vtable entry missing".to_string())
+}
+
+/// Output of one `encode` call, borrowed from the encoder
+pub(crate) struct Bitstream<'a> {
+    info: &'a SFrameBSInfo,
+}
+
+impl<'a> Bitstream<'a> {
+    pub fn frame_type(&self) -> EVideoFrameType {
+        self.info.eFrameType
+    }
+
+    /// Temporal layer of the coded picture
+    pub fn temporal_id(&self) -> u8 {
+        let layers = &self.info.sLayerInfo[..self.info.iLayerNum.max(0) as usize];
+        layers
+            .iter()
+            .filter(|layer| layer.uiLayerType == VIDEO_CODING_LAYER as u8)
+            .map(|layer| layer.uiTemporalId)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// All NAL units, each including its Annex-B start code
+    pub fn nal_units(&self) -> impl Iterator<Item = &'a [u8]> {
+        let layers = &self.info.sLayerInfo[..self.info.iLayerNum.max(0) as usize];
+        layers.iter().flat_map(|layer| {
+            let count = layer.iNalCount.max(0) as usize;
+            let lengths = if count == 0 || layer.pNalLengthInByte.is_null() {
+                &[][..]
+            } else {
+                // SAFETY: OpenH264 fills in `count` NAL lengths
+                unsafe { std::slice::from_raw_parts(layer.pNalLengthInByte, count) }
+            };
+            let mut offset = 0;
+            lengths.iter().map(move |&length| {
+                // SAFETY: `pBsBuf` holds the NAL units back to back
+                let nal = unsafe {
+                    std::slice::from_raw_parts(layer.pBsBuf.add(offset), length as usize)
+                };
+                offset += length as usize;
+                nal
+            })
+        })
+    }
+}
@@ -0,0 +1,156 @@
+//! Objective quality metrics for encoded video
+//!
+//! Both metrics compare the luma planes of two equally sized
+//! [`I420Buffer`]s; screen content carries nearly all of its detail in
+//! luma, and it keeps the numbers comparable with other tools' Y-PSNR.
+
+use crate::I420Buffer;
+
+/// SSIM window edge in pixels
+const SSIM_WINDOW: usize = 8;
+/// Offset between SSIM windows, so neighbouring windows overlap
+const SSIM_STEP: usize = 4;
+/// SSIM stabilizers for 8-bit samples: (0.01 * 255)^2 and (0.03 * 255)^2
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+
+/// Rows of both luma planes, cropped to the width
+fn luma_rows<'a>(
+    reference: &'a I420Buffer,
+    distorted: &'a I420Buffer,
+) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+    assert_eq!(
+        (reference.width(), reference.height()),
+        (distorted.width(), distorted.height()),
+        "compared pictures differ in size"
+    );
+    let width = reference.width() as usize;
+    let rows = |picture: &'a I420Buffer| {
+        let (stride, _) = picture.strides();
+        picture
+            .planes()
+            .0
+            .chunks(stride)
+            .take(picture.height() as usize)
+            .map(move |row| &row[..width])
+    };
+    rows(reference).zip(rows(distorted))
+}
+
+/// Peak signal-to-noise ratio of the luma plane in dB
+///
+/// Identical pictures give infinity.
+pub fn psnr(reference: &I420Buffer, distorted: &I420Buffer) -> f64 {
+    let mut squared_error = 0u64;
+    let mut samples = 0u64;
+    for (a, b) in luma_rows(reference, distorted) {
+        squared_error += a
+            .iter()
+            .zip(b)
+            .map(|(&a, &b)| (a as i64 - b as i64).pow(2) as u64)
+            .sum::<u64>();
+        samples += a.len() as u64;
+    }
+    if squared_error == 0 {
+        return f64::INFINITY;
+    }
+    let mse = squared_error as f64 / samples as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+/// Mean structural similarity of the luma plane, from 0 to 1
+///
+/// Averages SSIM over 8x8 windows spaced 4 pixels apart. Pictures
+/// smaller than a window compare as a single window.
+pub fn ssim(reference: &I420Buffer, distorted: &I420Buffer) -> f64 {
+    let rows: Vec<_> = luma_rows(reference, distorted).collect();
+    let (width, height) = (reference.width() as usize, rows.len());
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    let window_width = SSIM_WINDOW.min(width);
+    let window_height = SSIM_WINDOW.min(height);
+
+    let mut total = 0.0;
+    let mut windows = 0u64;
+    for top in (0..=height - window_height).step_by(SSIM_STEP) {
+        for left in (0..=width - window_width).step_by(SSIM_STEP) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0u64, 0u64, 0u64, 0u64, 0u64);
+            for (a, b) in &rows[top..top + window_height] {
+                for (&a, &b) in a[left..left + window_width]
+                    .iter()
+                    .zip(&b[left..left + window_width])
+                {
+                    let (a, b) = (a as u64, b as u64);
+                    sum_a += a;
+                    sum_b += b;
+                    sum_aa += a * a;
+                    sum_bb += b * b;
+                    sum_ab += a * b;
+                }
+            }
+
+            let n = (window_width * window_height) as f64;
+            let (mean_a, mean_b) = (sum_a as f64 / n, sum_b as f64 / n);
+            let variance_a = sum_aa as f64 / n - mean_a * mean_a;
+            let variance_b = sum_bb as f64 / n - mean_b * mean_b;
+            let covariance = sum_ab as f64 / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1)
+                    * (variance_a + variance_b + SSIM_C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picture(width: u32, height: u32, luma: impl Fn(usize, usize) -> u8) -> I420Buffer {
+        let mut picture = I420Buffer::new();
+        picture.resize(width, height);
+        let (stride, _) = picture.strides();
+        let (y, _, _) = picture.planes_mut();
+        for (row, line) in y.chunks_exact_mut(stride).enumerate() {
+            for (column, sample) in line[..width as usize].iter_mut().enumerate() {
+                *sample = luma(column, row);
+            }
+        }
+        picture
+    }
+
+    #[test]
+    fn test_psnr_matches_known_error() {
+        let reference = picture(64, 32, |x, y| (x * 3 + y) as u8);
+        assert_eq!(psnr(&reference, &reference.clone()), f64::INFINITY);
+
+        // A constant error of 5 gives an MSE of 25
+        let shifted = picture(64, 32, |x, y| (x * 3 + y) as u8 + 5);
+        let expected = 10.0 * (255.0f64 * 255.0 / 25.0).log10();
+        assert!((psnr(&reference, &shifted) - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ssim_ranks_distortions() {
+        let reference = picture(
+            64,
+            64,
+            |x, y| if (x / 4 + y / 4) % 2 == 0 { 30 } else { 220 },
+        );
+        assert!((ssim(&reference, &reference.clone()) - 1.0).abs() < 1e-9);
+
+        // Brightening keeps the structure; flattening destroys it
+        let brighter = picture(
+            64,
+            64,
+            |x, y| if (x / 4 + y / 4) % 2 == 0 { 40 } else { 230 },
+        );
+        let flat = picture(64, 64, |_, _| 125);
+        let (brighter, flat) = (ssim(&reference, &brighter), ssim(&reference, &flat));
+        assert!(brighter > 0.95, "brighter: {}", brighter);
+        assert!(flat < 0.1, "flat: {}", flat);
+    }
+}
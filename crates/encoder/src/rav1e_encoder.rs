@@ -0,0 +1,473 @@
+//! rav1e AV1 encoder implementation
+
+use bytes::Bytes;
+use capture::CapturedFrame;
+use rav1e::prelude::{
+    ChromaSampling, ColorDescription, ColorPrimaries, Config, Context,
+    EncoderConfig as Rav1eConfig, EncoderStatus, FrameParameters, FrameType, FrameTypeOverride,
+    MatrixCoefficients, PixelRange, Rational, SceneDetectionSpeed, SpeedSettings,
+    TransferCharacteristics, Tune,
+};
+use std::collections::VecDeque;
+use std::time::Instant;
+use tracing::{debug, info};
+
+use crate::{
+    Codec, ColorMatrix, ColorRange, EncodedFrame, EncodedFrameType, EncoderConfig, EncoderError,
+    EncoderResult, EncoderStats, RateControl, VideoEncoder, YuvFrame,
+};
+
+/// Most tiles to split a frame into; each is encoded on its own thread
+const MAX_TILES: usize = 16;
+
+/// rav1e-based AV1 software encoder
+///
+/// rav1e needs a few frames in flight even without lookahead, so the
+/// first calls to `encode` return [`EncoderError::FrameBuffered`] and
+/// every later call returns the oldest pending frame.
+pub struct Rav1eEncoder {
+    context: Option<Context<u8>>,
+    config: EncoderConfig,
+    stats: EncoderStats,
+    force_keyframe: bool,
+    frame_counter: u64,
+    next_pts_us: u64,
+    /// Timestamps of frames sent to rav1e but not yet emitted
+    pending_pts: VecDeque<u64>,
+    /// Encoded frames waiting to be returned
+    ready: VecDeque<EncodedFrame>,
+    encode_times: Vec<u64>,
+    yuv: YuvFrame,
+}
+
+impl Rav1eEncoder {
+    /// Create a new rav1e encoder
+    pub fn new() -> Self {
+        Self {
+            context: None,
+            config: EncoderConfig::default(),
+            stats: EncoderStats::default(),
+            force_keyframe: false,
+            frame_counter: 0,
+            next_pts_us: 0,
+            pending_pts: VecDeque::new(),
+            ready: VecDeque::new(),
+            encode_times: Vec::with_capacity(100),
+            yuv: YuvFrame::default(),
+        }
+    }
+
+    /// Build a rav1e context for `config`
+    fn context(config: &EncoderConfig) -> EncoderResult<Context<u8>> {
+        // Our presets run fastest to best, rav1e speeds best to fastest
+        let speed = 10u8.saturating_sub(config.preset);
+        let mut speed_settings = SpeedSettings::from_preset(speed);
+        // Keep as few frames in flight as rav1e allows
+        speed_settings.rdo_lookahead_frames = 1;
+        speed_settings.scene_detection_mode = SceneDetectionSpeed::None;
+
+        let threads = rayon::current_num_threads();
+        let mut encoder = Rav1eConfig {
+            width: config.width as usize,
+            height: config.height as usize,
+            time_base: Rational::new(1, config.fps.max(1) as u64),
+            chroma_sampling: ChromaSampling::Cs420,
+            pixel_range: match config.color_space.range {
+                ColorRange::Limited => PixelRange::Limited,
+                ColorRange::Full => PixelRange::Full,
+            },
+            // Captured pixels are sRGB, so that is the transfer function
+            // either way
+            color_description: Some(ColorDescription {
+                color_primaries: match config.color_space.matrix {
+                    ColorMatrix::Bt601 => ColorPrimaries::BT601,
+                    ColorMatrix::Bt709 => ColorPrimaries::BT709,
+                },
+                transfer_characteristics: TransferCharacteristics::SRGB,
+                matrix_coefficients: match config.color_space.matrix {
+                    ColorMatrix::Bt601 => MatrixCoefficients::BT601,
+                    ColorMatrix::Bt709 => MatrixCoefficients::BT709,
+                },
+            }),
+            // No frame reordering
+            low_latency: true,
+            // PSNR tuning keeps text edges sharper than psychovisual tuning
+            tune: Tune::Psnr,
+            tiles: threads.clamp(1, MAX_TILES),
+            speed_settings,
+            ..Default::default()
+        };
+        encoder.set_key_frame_interval(
+            config.keyframe_interval as u64,
+            config.keyframe_interval as u64,
+        );
+        match config.rate_control {
+            RateControl::Cbr | RateControl::Vbr => {
+                encoder.bitrate = (config.bitrate_kbps * 1000) as i32;
+                if config.rate_control == RateControl::Cbr {
+                    // Shortest window rav1e allows
+                    encoder.reservoir_frame_delay = Some(12);
+                }
+            }
+            // AV1 quantizer indices run 0-255
+            RateControl::Cqp => encoder.quantizer = config.qp.min(51) as usize * 255 / 51,
+        }
+
+        Config::new()
+            .with_encoder_config(encoder)
+            .with_threads(threads)
+            .new_context()
+            .map_err(|e| EncoderError::InitFailed(e.to_string()))
+    }
+
+    /// Move every packet rav1e has finished into `ready`
+    fn collect_packets(&mut self, encode_time_us: u64) -> EncoderResult<()> {
+        let Some(context) = self.context.as_mut() else {
+            return Ok(());
+        };
+
+        let mut packets = Vec::new();
+        loop {
+            match context.receive_packet() {
+                Ok(packet) => packets.push(packet),
+                // A frame was encoded but isn't shown yet
+                Err(EncoderStatus::Encoded) => {}
+                Err(EncoderStatus::NeedMoreData | EncoderStatus::LimitReached) => break,
+                Err(e) => return Err(EncoderError::EncodingFailed(e.to_string())),
+            }
+        }
+
+        for packet in packets {
+            let is_keyframe = packet.frame_type == FrameType::KEY;
+            let pts_us = self.pending_pts.pop_front().unwrap_or(self.next_pts_us);
+            // Tiles share the frame header, so the frame is one unit
+            let whole = 0..packet.data.len();
+            let encoded = EncodedFrame {
+                slices: vec![whole],
+                data: Bytes::from(packet.data),
+                codec: Codec::AV1,
+                width: self.config.width,
+                height: self.config.height,
+                frame_type: if is_keyframe {
+                    EncodedFrameType::Key
+                } else {
+                    EncodedFrameType::Predicted
+                },
+                pts_us,
+                dts_us: pts_us,
+                sequence: self.frame_counter,
+                temporal_id: 0,
+                encode_time_us,
+            };
+
+            // Update stats
+            self.frame_counter += 1;
+            self.stats.frames_encoded += 1;
+            self.stats.bytes_output += encoded.data.len() as u64;
+            if is_keyframe {
+                self.stats.keyframes += 1;
+            }
+            self.stats.avg_frame_size = self.stats.bytes_output / self.stats.frames_encoded;
+
+            self.ready.push_back(encoded);
+        }
+        Ok(())
+    }
+
+    /// Finish the frames in flight and start a new context for `config`
+    ///
+    /// rav1e can't retarget a running encoder; the new context begins
+    /// with a keyframe.
+    fn restart(&mut self, config: EncoderConfig) -> EncoderResult<()> {
+        if let Some(context) = self.context.as_mut() {
+            context.flush();
+            self.collect_packets(0)?;
+            self.pending_pts.clear();
+            self.context = Some(Self::context(&config)?);
+        }
+        self.config = config;
+        Ok(())
+    }
+}
+
+impl Default for Rav1eEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VideoEncoder for Rav1eEncoder {
+    fn init(&mut self, config: EncoderConfig) -> EncoderResult<()> {
+        if config.codec != Codec::AV1 {
+            return Err(EncoderError::InvalidConfig(
+                "rav1e only supports AV1".to_string(),
+            ));
+        }
+        if config.intra_refresh {
+            return Err(EncoderError::InvalidConfig(
+                "rav1e has no rolling intra refresh".to_string(),
+            ));
+        }
+        if config.chroma_444 {
+            return Err(EncoderError::InvalidConfig(
+                "rav1e only encodes 4:2:0".to_string(),
+            ));
+        }
+        if config.temporal_layers > 1 {
+            return Err(EncoderError::InvalidConfig(
+                "rav1e backend has no temporal layers".to_string(),
+            ));
+        }
+
+        info!(
+            "Initializing rav1e encoder: {}x{} @ {} kbps, {} fps",
+            config.width, config.height, config.bitrate_kbps, config.fps
+        );
+
+        self.context = Some(Self::context(&config)?);
+        self.config = config;
+        self.stats = EncoderStats::default();
+        self.next_pts_us = 0;
+        self.pending_pts.clear();
+        self.ready.clear();
+        self.encode_times.clear();
+
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: &CapturedFrame) -> EncoderResult<EncodedFrame> {
+        let mut yuv = std::mem::take(&mut self.yuv);
+        let encoded = yuv
+            .convert(frame, self.config.color_space)
+            .and_then(|()| self.encode_yuv(&yuv));
+        self.yuv = yuv;
+        encoded
+    }
+
+    fn encode_yuv(&mut self, frame: &YuvFrame) -> EncoderResult<EncodedFrame> {
+        let Some(context) = self.context.as_mut() else {
+            return Err(EncoderError::NotInitialized);
+        };
+        let YuvFrame::I420(yuv) = frame else {
+            return Err(EncoderError::UnsupportedPixelFormat);
+        };
+
+        if yuv.width() != self.config.width || yuv.height() != self.config.height {
+            return Err(EncoderError::UnsupportedResolution {
+                width: yuv.width(),
+                height: yuv.height(),
+            });
+        }
+
+        let start = Instant::now();
+
+        let mut input = context.new_frame();
+        let (y_stride, uv_stride) = yuv.strides();
+        let (y_plane, u_plane, v_plane) = yuv.planes();
+        input.planes[0].copy_from_raw_u8(y_plane, y_stride, 1);
+        input.planes[1].copy_from_raw_u8(u_plane, uv_stride, 1);
+        input.planes[2].copy_from_raw_u8(v_plane, uv_stride, 1);
+
+        let params = FrameParameters {
+            frame_type_override: if self.force_keyframe {
+                debug!("Forcing keyframe");
+                FrameTypeOverride::Key
+            } else {
+                FrameTypeOverride::No
+            },
+            ..Default::default()
+        };
+        self.force_keyframe = false;
+
+        context
+            .send_frame((input, params))
+            .map_err(|e| EncoderError::EncodingFailed(e.to_string()))?;
+        self.pending_pts.push_back(self.next_pts_us);
+        self.next_pts_us += 1_000_000 / self.config.fps.max(1) as u64;
+
+        let encode_time = start.elapsed().as_micros() as u64;
+        self.collect_packets(encode_time)?;
+
+        self.encode_times.push(encode_time);
+        if self.encode_times.len() > 100 {
+            self.encode_times.remove(0);
+        }
+        self.stats.avg_encode_time_us =
+            self.encode_times.iter().sum::<u64>() / self.encode_times.len() as u64;
+
+        self.ready.pop_front().ok_or(EncoderError::FrameBuffered)
+    }
+
+    fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    fn set_bitrate(&mut self, bitrate_kbps: u32) -> EncoderResult<()> {
+        if bitrate_kbps == 0 {
+            return Err(EncoderError::InvalidConfig(
+                "Bitrate must be non-zero".to_string(),
+            ));
+        }
+        let config = EncoderConfig {
+            bitrate_kbps,
+            ..self.config.clone()
+        };
+        if config.rate_control == RateControl::Cqp {
+            self.config = config;
+        } else {
+            self.restart(config)?;
+        }
+        debug!("Bitrate updated to {} kbps", bitrate_kbps);
+        Ok(())
+    }
+
+    fn set_fps(&mut self, fps: u32) -> EncoderResult<()> {
+        if fps == 0 {
+            return Err(EncoderError::InvalidConfig(
+                "FPS must be non-zero".to_string(),
+            ));
+        }
+        self.restart(EncoderConfig {
+            fps,
+            ..self.config.clone()
+        })?;
+        debug!("FPS updated to {}", fps);
+        Ok(())
+    }
+
+    fn config(&self) -> &EncoderConfig {
+        &self.config
+    }
+
+    fn stats(&self) -> EncoderStats {
+        let mut stats = self.stats.clone();
+        if self.stats.frames_encoded > 0 {
+            let elapsed_secs = self.stats.frames_encoded as f64 / self.config.fps as f64;
+            stats.current_fps = self.stats.frames_encoded as f64 / elapsed_secs;
+        }
+        stats
+    }
+
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedFrame>> {
+        self.restart(self.config.clone())?;
+        Ok(self.ready.drain(..).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capture::PixelFormat;
+
+    /// Text-like stripes that scroll by a row each frame
+    fn frame(width: u32, height: u32, index: u32) -> CapturedFrame {
+        let data = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width + index);
+                let value = if (x / 3 + y / 5) % 4 == 0 { 20 } else { 235 };
+                [value, value, value, 255]
+            })
+            .collect::<Vec<_>>();
+        CapturedFrame {
+            data: Bytes::from(data),
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence: 0,
+            dirty_rects: Vec::new(),
+            display_id: 0,
+        }
+    }
+
+    fn config() -> EncoderConfig {
+        EncoderConfig {
+            codec: Codec::AV1,
+            width: 160,
+            height: 128,
+            bitrate_kbps: 500,
+            preset: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Encode `count` frames, returning those emitted
+    fn encode(encoder: &mut Rav1eEncoder, start: u32, count: u32) -> Vec<EncodedFrame> {
+        (start..start + count)
+            .filter_map(|i| match encoder.encode(&frame(160, 128, i)) {
+                Ok(encoded) => Some(encoded),
+                Err(EncoderError::FrameBuffered) => None,
+                Err(e) => panic!("frame {i}: {e}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_frames_come_out_in_order_after_short_delay() {
+        let mut encoder = Rav1eEncoder::new();
+        encoder.init(config()).unwrap();
+
+        let emitted = encode(&mut encoder, 0, 20);
+        let delay = 20 - emitted.len();
+        assert!(delay <= 5, "{delay} frames held back");
+
+        let flushed = encoder.flush().unwrap();
+        assert_eq!(flushed.len(), delay);
+        let frames: Vec<_> = emitted.into_iter().chain(flushed).collect();
+        for (i, encoded) in frames.iter().enumerate() {
+            assert_eq!(encoded.sequence, i as u64);
+            assert_eq!(encoded.pts_us, i as u64 * (1_000_000 / 30));
+            assert_eq!(encoded.codec, Codec::AV1);
+            assert!(!encoded.data.is_empty());
+            let expected = if i == 0 {
+                EncodedFrameType::Key
+            } else {
+                EncodedFrameType::Predicted
+            };
+            assert_eq!(encoded.frame_type, expected, "frame {i}");
+        }
+    }
+
+    #[test]
+    fn test_forced_keyframe() {
+        let mut encoder = Rav1eEncoder::new();
+        encoder.init(config()).unwrap();
+        encode(&mut encoder, 0, 10);
+
+        encoder.force_keyframe();
+        let forced = encode(&mut encoder, 10, 10)
+            .into_iter()
+            .chain(encoder.flush().unwrap())
+            .find(|encoded| encoded.sequence == 10)
+            .unwrap();
+        assert_eq!(forced.frame_type, EncodedFrameType::Key);
+        assert_eq!(encoder.stats().keyframes, 2);
+    }
+
+    #[test]
+    fn test_bitrate_change_keeps_frames_in_flight() {
+        let mut encoder = Rav1eEncoder::new();
+        encoder.init(config()).unwrap();
+        let before = encode(&mut encoder, 0, 10).len();
+
+        encoder.set_bitrate(200).unwrap();
+        let after = encode(&mut encoder, 10, 10);
+        let flushed = encoder.flush().unwrap();
+        assert_eq!(before + after.len() + flushed.len(), 20);
+        assert!(
+            after
+                .iter()
+                .any(|encoded| encoded.frame_type == EncodedFrameType::Key)
+        );
+    }
+
+    #[test]
+    fn test_rejects_other_codecs() {
+        let mut encoder = Rav1eEncoder::new();
+        assert!(matches!(
+            encoder.init(EncoderConfig::default()),
+            Err(EncoderError::InvalidConfig(_))
+        ));
+    }
+}
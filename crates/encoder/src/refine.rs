@@ -0,0 +1,366 @@
+//! Lossless refinement of static screen regions
+//!
+//! At screen-sharing bitrates lossy video leaves text soft until the next
+//! keyframe. [`RefinementTracker`] splits the screen into square tiles and
+//! uses each captured frame's dirty rects to count how long every tile has
+//! gone unchanged. Once a tile has been static for long enough its pixels
+//! are compressed losslessly as PNG so the viewer can draw them over the
+//! video; a refined tile that changes again is invalidated so the viewer
+//! falls back to the video for it.
+
+use bytes::Bytes;
+use capture::{CapturedFrame, DirtyRect, PixelFormat};
+
+use crate::{EncoderError, EncoderResult};
+
+/// Default tile edge in pixels
+pub const DEFAULT_REFINE_TILE_SIZE: u32 = 128;
+
+/// Default number of unchanged frames before a tile is refined
+pub const DEFAULT_REFINE_STATIC_FRAMES: u32 = 15;
+
+/// Tiles refined per elapsed frame, bounding the burst after a scroll stops
+const MAX_TILES_PER_FRAME: usize = 4;
+
+/// What the viewer needs to learn after a frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefinementUpdate {
+    /// Lossless pixels for a static region, PNG-encoded RGB
+    Tile { rect: DirtyRect, png: Bytes },
+    /// Previously sent tiles that changed since; drop them
+    Invalidate(Vec<DirtyRect>),
+    /// Every previously sent tile is stale
+    Clear,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TileState {
+    /// Frames since the tile last changed
+    static_frames: u32,
+    /// Whether the viewer holds a lossless copy of the tile
+    refined: bool,
+}
+
+/// Decides which tiles to refine from the frames' dirty rects
+pub struct RefinementTracker {
+    tile_size: u32,
+    static_frames: u32,
+    width: u32,
+    height: u32,
+    columns: u32,
+    tiles: Vec<TileState>,
+}
+
+impl RefinementTracker {
+    /// Refine tiles that have been unchanged for `static_frames` frames
+    pub fn new(static_frames: u32) -> Self {
+        Self {
+            tile_size: DEFAULT_REFINE_TILE_SIZE,
+            static_frames: static_frames.max(1),
+            width: 0,
+            height: 0,
+            columns: 0,
+            tiles: Vec::new(),
+        }
+    }
+
+    /// Use square tiles of `tile_size` pixels
+    pub fn with_tile_size(mut self, tile_size: u32) -> Self {
+        self.tile_size = tile_size.max(1);
+        self.tiles.clear();
+        self.width = 0;
+        self.height = 0;
+        self
+    }
+
+    /// Account for a frame captured `frames` frame intervals after the last
+    ///
+    /// A frame without dirty rects counts as a full update, as elsewhere.
+    pub fn update(
+        &mut self,
+        frame: &CapturedFrame,
+        frames: u32,
+    ) -> EncoderResult<Vec<RefinementUpdate>> {
+        let full = [DirtyRect::full_screen(frame.width, frame.height)];
+        let dirty = if frame.dirty_rects.is_empty() {
+            &full[..]
+        } else {
+            &frame.dirty_rects[..]
+        };
+        self.advance(frame, dirty, frames)
+    }
+
+    /// Account for `frames` frame intervals in which nothing changed
+    ///
+    /// `frame` is the last captured frame, which still shows the screen.
+    pub fn idle(
+        &mut self,
+        frame: &CapturedFrame,
+        frames: u32,
+    ) -> EncoderResult<Vec<RefinementUpdate>> {
+        self.advance(frame, &[], frames)
+    }
+
+    /// Forget every tile, e.g. after switching capture source
+    ///
+    /// Returns [`RefinementUpdate::Clear`] if the viewer holds any tiles.
+    pub fn reset(&mut self) -> Option<RefinementUpdate> {
+        let refined = self.tiles.iter().any(|tile| tile.refined);
+        self.tiles.fill(TileState::default());
+        refined.then_some(RefinementUpdate::Clear)
+    }
+
+    /// The tile at `rect` never reached the viewer; refine it again once
+    /// it has stayed unchanged for another `static_frames` frames
+    pub fn defer(&mut self, rect: &DirtyRect) {
+        if rect.x >= self.width || rect.y >= self.height {
+            return;
+        }
+        let index = (rect.y / self.tile_size * self.columns + rect.x / self.tile_size) as usize;
+        self.tiles[index] = TileState::default();
+    }
+
+    fn advance(
+        &mut self,
+        frame: &CapturedFrame,
+        dirty: &[DirtyRect],
+        frames: u32,
+    ) -> EncoderResult<Vec<RefinementUpdate>> {
+        if frame.format.bytes_per_pixel().is_none() {
+            return Err(EncoderError::UnsupportedPixelFormat);
+        }
+
+        let mut updates = Vec::new();
+        if (frame.width, frame.height) != (self.width, self.height) {
+            updates.extend(self.reset());
+            self.resize(frame.width, frame.height);
+        }
+
+        for tile in &mut self.tiles {
+            tile.static_frames = tile.static_frames.saturating_add(frames);
+        }
+
+        let mut stale = Vec::new();
+        for rect in dirty {
+            let Some(rect) = DirtyRect::full_screen(self.width, self.height).intersect(rect) else {
+                continue;
+            };
+            let (left, top) = (rect.x / self.tile_size, rect.y / self.tile_size);
+            let right = (rect.x + rect.width).div_ceil(self.tile_size);
+            let bottom = (rect.y + rect.height).div_ceil(self.tile_size);
+            for row in top..bottom {
+                for column in left..right {
+                    let index = (row * self.columns + column) as usize;
+                    if self.tiles[index].refined {
+                        stale.push(self.tile_rect(index));
+                    }
+                    self.tiles[index] = TileState::default();
+                }
+            }
+        }
+        if !stale.is_empty() {
+            updates.push(RefinementUpdate::Invalidate(stale));
+        }
+
+        let budget = MAX_TILES_PER_FRAME.saturating_mul(frames.max(1) as usize);
+        let ready: Vec<_> = (0..self.tiles.len())
+            .filter(|&index| {
+                let tile = &self.tiles[index];
+                !tile.refined && tile.static_frames >= self.static_frames
+            })
+            .take(budget)
+            .collect();
+        for index in ready {
+            let rect = self.tile_rect(index);
+            let png = encode_png(frame, &rect)?;
+            self.tiles[index].refined = true;
+            updates.push(RefinementUpdate::Tile { rect, png });
+        }
+
+        Ok(updates)
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.columns = width.div_ceil(self.tile_size);
+        let rows = height.div_ceil(self.tile_size);
+        self.tiles = vec![TileState::default(); (self.columns * rows) as usize];
+    }
+
+    /// The tile's pixels, clipped to the frame
+    fn tile_rect(&self, index: usize) -> DirtyRect {
+        let x = (index as u32 % self.columns) * self.tile_size;
+        let y = (index as u32 / self.columns) * self.tile_size;
+        DirtyRect::new(
+            x,
+            y,
+            self.tile_size.min(self.width - x),
+            self.tile_size.min(self.height - y),
+        )
+    }
+}
+
+/// Compress `rect` of an RGB frame as an 8-bit RGB PNG
+fn encode_png(frame: &CapturedFrame, rect: &DirtyRect) -> EncoderResult<Bytes> {
+    let bgra = match frame.format {
+        PixelFormat::Bgra8 => true,
+        PixelFormat::Rgba8 => false,
+        _ => return Err(EncoderError::UnsupportedPixelFormat),
+    };
+
+    let mut rgb = Vec::with_capacity(rect.area() as usize * 3);
+    for y in rect.y..rect.y + rect.height {
+        let start = y as usize * frame.stride as usize + rect.x as usize * 4;
+        let row = frame
+            .data
+            .get(start..start + rect.width as usize * 4)
+            .ok_or(EncoderError::BufferTooSmall)?;
+        for pixel in row.chunks_exact(4) {
+            if bgra {
+                rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            } else {
+                rgb.extend_from_slice(&pixel[..3]);
+            }
+        }
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, rect.width, rect.height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Fast);
+    let failed = |e: png::EncodingError| EncoderError::EncodingFailed(e.to_string());
+    let mut writer = encoder.write_header().map_err(failed)?;
+    writer.write_image_data(&rgb).map_err(failed)?;
+    writer.finish().map_err(failed)?;
+    Ok(Bytes::from(png))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn frame(width: u32, height: u32, dirty_rects: Vec<DirtyRect>) -> CapturedFrame {
+        let data = (0..width * height * 4)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        CapturedFrame {
+            data: Bytes::from(data),
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence: 0,
+            dirty_rects,
+            display_id: 0,
+        }
+    }
+
+    fn tiles(updates: &[RefinementUpdate]) -> Vec<DirtyRect> {
+        updates
+            .iter()
+            .filter_map(|update| match update {
+                RefinementUpdate::Tile { rect, .. } => Some(*rect),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_static_tile_refined_once_after_threshold() {
+        let mut tracker = RefinementTracker::new(3).with_tile_size(16);
+        let typing = frame(32, 16, vec![DirtyRect::new(2, 2, 4, 4)]);
+
+        assert!(
+            tracker
+                .update(&frame(32, 16, Vec::new()), 1)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(tracker.update(&typing, 1).unwrap().is_empty());
+        assert!(tracker.update(&typing, 1).unwrap().is_empty());
+        let updates = tracker.update(&typing, 1).unwrap();
+        assert_eq!(tiles(&updates), vec![DirtyRect::new(16, 0, 16, 16)]);
+        assert!(tracker.update(&typing, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_tile_is_lossless_png() {
+        let mut tracker = RefinementTracker::new(1).with_tile_size(16);
+        let source = frame(20, 10, Vec::new());
+        tracker.update(&source, 1).unwrap();
+        let updates = tracker.idle(&source, 1).unwrap();
+        let Some(RefinementUpdate::Tile { rect, png }) = updates.last() else {
+            panic!("expected a tile, got {:?}", updates);
+        };
+        assert_eq!(*rect, DirtyRect::new(16, 0, 4, 10));
+
+        let decoder = png::Decoder::new(&png[..]);
+        let mut reader = decoder.read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+        assert_eq!((info.width, info.height), (4, 10));
+        for y in 0..10usize {
+            for x in 0..4usize {
+                let src = y * source.stride as usize + (16 + x) * 4;
+                let out = (y * 4 + x) * 3;
+                let bgr = &source.data[src..src + 3];
+                assert_eq!(rgb[out..out + 3], [bgr[2], bgr[1], bgr[0]]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_changed_refined_tile_is_invalidated() {
+        let mut tracker = RefinementTracker::new(1).with_tile_size(16);
+        let source = frame(32, 32, Vec::new());
+        tracker.update(&source, 1).unwrap();
+        assert_eq!(tiles(&tracker.idle(&source, 1).unwrap()).len(), 4);
+
+        let updates = tracker
+            .update(&frame(32, 32, vec![DirtyRect::new(20, 20, 2, 2)]), 1)
+            .unwrap();
+        assert_eq!(
+            updates,
+            vec![RefinementUpdate::Invalidate(vec![DirtyRect::new(
+                16, 16, 16, 16
+            )])]
+        );
+        let updates = tracker.idle(&source, 1).unwrap();
+        assert_eq!(tiles(&updates), vec![DirtyRect::new(16, 16, 16, 16)]);
+    }
+
+    #[test]
+    fn test_deferred_tile_is_refined_again_later() {
+        let mut tracker = RefinementTracker::new(2).with_tile_size(16);
+        let source = frame(16, 16, Vec::new());
+        tracker.update(&source, 1).unwrap();
+        let updates = tracker.idle(&source, 2).unwrap();
+        assert_eq!(tiles(&updates), vec![DirtyRect::new(0, 0, 16, 16)]);
+
+        tracker.defer(&DirtyRect::new(0, 0, 16, 16));
+        assert!(tracker.idle(&source, 1).unwrap().is_empty());
+        let updates = tracker.idle(&source, 1).unwrap();
+        assert_eq!(tiles(&updates), vec![DirtyRect::new(0, 0, 16, 16)]);
+        // Nothing the viewer holds, so nothing to clear
+        tracker.defer(&DirtyRect::new(0, 0, 16, 16));
+        assert_eq!(tracker.reset(), None);
+    }
+
+    #[test]
+    fn test_resize_clears_and_idle_limits_burst() {
+        let mut tracker = RefinementTracker::new(2).with_tile_size(8);
+        let small = frame(64, 64, Vec::new());
+        tracker.update(&small, 1).unwrap();
+        // 64 tiles are ready, but two elapsed frames only allow a few
+        let updates = tracker.idle(&small, 2).unwrap();
+        assert_eq!(tiles(&updates).len(), 2 * MAX_TILES_PER_FRAME);
+
+        let updates = tracker.update(&frame(32, 32, Vec::new()), 1).unwrap();
+        assert_eq!(updates, vec![RefinementUpdate::Clear]);
+        assert_eq!(tracker.reset(), None);
+    }
+}
@@ -0,0 +1,554 @@
+//! Encoder backend registry
+//!
+//! Each backend registers a factory together with the capabilities it
+//! advertises. [`EncoderRegistry::create`] ranks the backends that can
+//! handle an [`EncoderConfig`] and initializes the best one, falling
+//! back to the next candidate when initialization fails (for example a
+//! hardware encoder whose driver is missing).
+
+use capture::PixelFormat;
+use tracing::{info, warn};
+
+use crate::{Codec, EncoderConfig, EncoderError, EncoderResult, OpenH264Encoder, VideoEncoder};
+
+/// How much delay a backend adds between input and output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LatencyClass {
+    /// Output for a frame is available as soon as it is encoded
+    Realtime,
+    /// Holds back a few frames, without reordering
+    Low,
+    /// Uses lookahead or frame reordering; unsuitable for `low_latency`
+    Buffered,
+}
+
+/// What a backend can encode
+#[derive(Debug, Clone)]
+pub struct EncoderCapabilities {
+    /// Codecs the backend can produce
+    pub codecs: Vec<Codec>,
+    /// Largest supported frame width
+    pub max_width: u32,
+    /// Largest supported frame height
+    pub max_height: u32,
+    /// Whether encoding is offloaded to dedicated hardware
+    pub hardware: bool,
+    /// Captured pixel formats accepted by `encode`
+    pub pixel_formats: Vec<PixelFormat>,
+    /// Delay the backend adds
+    pub latency: LatencyClass,
+    /// Whether the backend can refresh the picture with intra-coded blocks
+    /// spread over several frames instead of periodic keyframes. OpenH264
+    /// and rav1e can't; without periodic keyframes they rely on loss
+    /// recovery alone.
+    pub intra_refresh: bool,
+    /// Whether the backend can encode full-resolution 4:4:4 chroma
+    pub chroma_444: bool,
+    /// Whether the backend applies `VideoEncoder::set_roi` hints
+    pub roi: bool,
+    /// Most temporal layers the backend can encode
+    pub max_temporal_layers: u8,
+    /// Whether `VideoEncoder::set_bitrate` takes effect on the running
+    /// stream; without it a change restarts the encoder with a keyframe
+    pub runtime_bitrate: bool,
+}
+
+impl EncoderCapabilities {
+    /// Whether a backend with these capabilities can run `config`
+    pub fn supports(&self, config: &EncoderConfig) -> bool {
+        self.codecs.contains(&config.codec)
+            && config.width <= self.max_width
+            && config.height <= self.max_height
+            && !(config.low_latency && self.latency == LatencyClass::Buffered)
+            && (self.intra_refresh || !config.intra_refresh)
+            && (self.chroma_444 || !config.chroma_444)
+            && config.temporal_layers <= self.max_temporal_layers
+    }
+
+    /// Whether frames in `format` can be encoded without conversion
+    pub fn accepts(&self, format: PixelFormat) -> bool {
+        self.pixel_formats.contains(&format)
+    }
+}
+
+/// Creates an uninitialized encoder
+pub type EncoderFactory = Box<dyn Fn() -> EncoderResult<Box<dyn VideoEncoder>> + Send + Sync>;
+
+/// A registered backend
+pub struct EncoderBackend {
+    /// Short name, reported in logs and session stats
+    pub name: &'static str,
+    pub capabilities: EncoderCapabilities,
+    factory: EncoderFactory,
+}
+
+impl std::fmt::Debug for EncoderBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncoderBackend")
+            .field("name", &self.name)
+            .field("capabilities", &self.capabilities)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An initialized encoder and the backend it came from
+pub struct SelectedEncoder {
+    pub backend: &'static str,
+    pub capabilities: EncoderCapabilities,
+    pub encoder: Box<dyn VideoEncoder>,
+}
+
+/// Set of available encoder backends
+#[derive(Debug, Default)]
+pub struct EncoderRegistry {
+    backends: Vec<EncoderBackend>,
+}
+
+impl EncoderRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a registry with every backend built into this crate
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register(
+            "openh264",
+            EncoderCapabilities {
+                codecs: vec![Codec::H264],
+                // Level 5.2 limit
+                max_width: 4096,
+                max_height: 2304,
+                hardware: false,
+                pixel_formats: vec![PixelFormat::Bgra8, PixelFormat::Rgba8],
+                latency: LatencyClass::Realtime,
+                intra_refresh: false,
+                chroma_444: false,
+                roi: false,
+                max_temporal_layers: 3,
+                runtime_bitrate: true,
+            },
+            Box::new(|| Ok(Box::new(OpenH264Encoder::new()))),
+        );
+        #[cfg(feature = "av1")]
+        registry.register(
+            "rav1e",
+            EncoderCapabilities {
+                codecs: vec![Codec::AV1],
+                // Level 6.3 limit
+                max_width: 16384,
+                max_height: 8704,
+                hardware: false,
+                pixel_formats: vec![PixelFormat::Bgra8, PixelFormat::Rgba8],
+                latency: LatencyClass::Low,
+                intra_refresh: false,
+                chroma_444: false,
+                roi: false,
+                max_temporal_layers: 1,
+                runtime_bitrate: false,
+            },
+            Box::new(|| Ok(Box::new(crate::Rav1eEncoder::new()))),
+        );
+        #[cfg(feature = "vp9")]
+        registry.register(
+            "libvpx",
+            EncoderCapabilities {
+                codecs: vec![Codec::VP9],
+                max_width: 16384,
+                max_height: 16384,
+                hardware: false,
+                pixel_formats: vec![PixelFormat::Bgra8, PixelFormat::Rgba8],
+                latency: LatencyClass::Realtime,
+                intra_refresh: true,
+                chroma_444: true,
+                roi: true,
+                max_temporal_layers: 1,
+                runtime_bitrate: true,
+            },
+            Box::new(|| Ok(Box::new(crate::VpxEncoder::new()))),
+        );
+        registry
+    }
+
+    /// Add a backend; earlier registrations win ties in ranking
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        capabilities: EncoderCapabilities,
+        factory: EncoderFactory,
+    ) {
+        self.backends.push(EncoderBackend {
+            name,
+            capabilities,
+            factory,
+        });
+    }
+
+    /// All registered backends, in registration order
+    pub fn backends(&self) -> &[EncoderBackend] {
+        &self.backends
+    }
+
+    /// Backends able to run `config`, best first
+    ///
+    /// Hardware encoders rank above software ones, then lower latency
+    /// classes above higher ones.
+    pub fn candidates(&self, config: &EncoderConfig) -> Vec<&EncoderBackend> {
+        let mut candidates: Vec<_> = self
+            .backends
+            .iter()
+            .filter(|backend| backend.capabilities.supports(config))
+            .collect();
+        candidates
+            .sort_by_key(|backend| (!backend.capabilities.hardware, backend.capabilities.latency));
+        candidates
+    }
+
+    /// Create and initialize the best backend for `config`
+    ///
+    /// Candidates that fail to construct or initialize are skipped; the
+    /// last error is returned if none succeeds.
+    pub fn create(&self, config: &EncoderConfig) -> EncoderResult<SelectedEncoder> {
+        let mut last_error = None;
+        for backend in self.candidates(config) {
+            let result = (backend.factory)().and_then(|mut encoder| {
+                encoder.init(config.clone())?;
+                Ok(encoder)
+            });
+            match result {
+                Ok(encoder) => {
+                    info!("Using {} encoder backend", backend.name);
+                    return Ok(SelectedEncoder {
+                        backend: backend.name,
+                        capabilities: backend.capabilities.clone(),
+                        encoder,
+                    });
+                }
+                Err(e) => {
+                    warn!("{} encoder backend unavailable: {}", backend.name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            EncoderError::InvalidConfig(format!(
+                "No encoder backend supports {:?} at {}x{}",
+                config.codec, config.width, config.height
+            ))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncodedFrame, EncoderStats, YuvFrame};
+    use capture::CapturedFrame;
+
+    /// Backend whose `init` always fails
+    struct Broken(EncoderConfig);
+
+    impl VideoEncoder for Broken {
+        fn init(&mut self, _config: EncoderConfig) -> EncoderResult<()> {
+            Err(EncoderError::InitFailed("no device".to_string()))
+        }
+
+        fn encode(&mut self, _frame: &CapturedFrame) -> EncoderResult<EncodedFrame> {
+            Err(EncoderError::NotInitialized)
+        }
+
+        fn encode_yuv(&mut self, _frame: &YuvFrame) -> EncoderResult<EncodedFrame> {
+            Err(EncoderError::NotInitialized)
+        }
+
+        fn force_keyframe(&mut self) {}
+
+        fn set_bitrate(&mut self, _bitrate_kbps: u32) -> EncoderResult<()> {
+            Ok(())
+        }
+
+        fn set_fps(&mut self, _fps: u32) -> EncoderResult<()> {
+            Ok(())
+        }
+
+        fn config(&self) -> &EncoderConfig {
+            &self.0
+        }
+
+        fn stats(&self) -> EncoderStats {
+            EncoderStats::default()
+        }
+
+        fn flush(&mut self) -> EncoderResult<Vec<EncodedFrame>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn capabilities(codec: Codec, hardware: bool, latency: LatencyClass) -> EncoderCapabilities {
+        EncoderCapabilities {
+            codecs: vec![codec],
+            max_width: 1920,
+            max_height: 1080,
+            hardware,
+            pixel_formats: vec![PixelFormat::Bgra8],
+            latency,
+            intra_refresh: false,
+            chroma_444: false,
+            roi: false,
+            max_temporal_layers: 1,
+            runtime_bitrate: true,
+        }
+    }
+
+    fn broken() -> EncoderFactory {
+        Box::new(|| Ok(Box::new(Broken(EncoderConfig::default()))))
+    }
+
+    #[test]
+    fn test_candidates_filter_and_rank() {
+        let mut registry = EncoderRegistry::new();
+        registry.register(
+            "software",
+            capabilities(Codec::H264, false, LatencyClass::Realtime),
+            broken(),
+        );
+        registry.register(
+            "buffered",
+            capabilities(Codec::H264, true, LatencyClass::Buffered),
+            broken(),
+        );
+        registry.register(
+            "hardware",
+            capabilities(Codec::H264, true, LatencyClass::Low),
+            broken(),
+        );
+        registry.register(
+            "hevc",
+            capabilities(Codec::H265, true, LatencyClass::Realtime),
+            broken(),
+        );
+
+        let names = |config: &EncoderConfig| {
+            registry
+                .candidates(config)
+                .iter()
+                .map(|backend| backend.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(&EncoderConfig::default()), ["hardware", "software"]);
+        let buffered = EncoderConfig {
+            low_latency: false,
+            ..Default::default()
+        };
+        assert_eq!(names(&buffered), ["hardware", "buffered", "software"]);
+        let too_large = EncoderConfig {
+            width: 3840,
+            height: 2160,
+            ..Default::default()
+        };
+        assert!(names(&too_large).is_empty());
+    }
+
+    #[test]
+    fn test_intra_refresh_requires_capability() {
+        let mut registry = EncoderRegistry::with_defaults();
+        registry.register(
+            "refresh",
+            EncoderCapabilities {
+                intra_refresh: true,
+                ..capabilities(Codec::H264, false, LatencyClass::Low)
+            },
+            broken(),
+        );
+
+        let config = EncoderConfig {
+            intra_refresh: true,
+            ..Default::default()
+        };
+        let names: Vec<_> = registry
+            .candidates(&config)
+            .iter()
+            .map(|backend| backend.name)
+            .collect();
+        assert_eq!(names, ["refresh"]);
+        assert_eq!(registry.candidates(&EncoderConfig::default()).len(), 2);
+    }
+
+    #[test]
+    fn test_create_falls_back_on_init_failure() {
+        let mut registry = EncoderRegistry::with_defaults();
+        registry.register(
+            "hardware",
+            capabilities(Codec::H264, true, LatencyClass::Realtime),
+            broken(),
+        );
+
+        let config = EncoderConfig {
+            width: 320,
+            height: 240,
+            ..Default::default()
+        };
+        let selected = registry.create(&config).unwrap();
+        assert_eq!(selected.backend, "openh264");
+        assert_eq!(selected.encoder.config().width, 320);
+    }
+
+    #[cfg(feature = "av1")]
+    #[test]
+    fn test_defaults_encode_av1_with_rav1e() {
+        let config = EncoderConfig {
+            codec: Codec::AV1,
+            width: 320,
+            height: 240,
+            ..Default::default()
+        };
+        let selected = EncoderRegistry::with_defaults().create(&config).unwrap();
+        assert_eq!(selected.backend, "rav1e");
+    }
+
+    #[cfg(feature = "vp9")]
+    #[test]
+    fn test_defaults_encode_vp9_444_with_libvpx() {
+        let config = EncoderConfig {
+            codec: Codec::VP9,
+            width: 320,
+            height: 240,
+            chroma_444: true,
+            ..Default::default()
+        };
+        let selected = EncoderRegistry::with_defaults().create(&config).unwrap();
+        assert_eq!(selected.backend, "libvpx");
+    }
+
+    #[test]
+    fn test_chroma_444_requires_capability() {
+        let mut registry = EncoderRegistry::new();
+        registry.register(
+            "420",
+            capabilities(Codec::H264, false, LatencyClass::Realtime),
+            broken(),
+        );
+        registry.register(
+            "444",
+            EncoderCapabilities {
+                chroma_444: true,
+                ..capabilities(Codec::H264, false, LatencyClass::Realtime)
+            },
+            broken(),
+        );
+
+        let config = EncoderConfig {
+            chroma_444: true,
+            ..Default::default()
+        };
+        let names: Vec<_> = registry
+            .candidates(&config)
+            .iter()
+            .map(|backend| backend.name)
+            .collect();
+        assert_eq!(names, ["444"]);
+    }
+
+    /// A square moving across a flat background
+    fn moving_square(index: u32) -> CapturedFrame {
+        let (width, height) = (160u32, 128u32);
+        let data = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                if x.abs_diff(index * 4 % width) < 16 && y.abs_diff(64) < 16 {
+                    [20, 40, 200, 255]
+                } else {
+                    [128, 128, 128, 255]
+                }
+            })
+            .collect::<Vec<u8>>();
+        CapturedFrame {
+            data: bytes::Bytes::from(data),
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: std::time::Instant::now(),
+            sequence: 0,
+            dirty_rects: Vec::new(),
+            display_id: 0,
+        }
+    }
+
+    #[test]
+    fn test_runtime_bitrate_changes_emit_no_keyframe() {
+        for backend in EncoderRegistry::with_defaults().backends() {
+            let mut encoder = (backend.factory)().unwrap();
+            encoder
+                .init(EncoderConfig {
+                    codec: backend.capabilities.codecs[0],
+                    width: 160,
+                    height: 128,
+                    bitrate_kbps: 500,
+                    keyframe_interval: 1000,
+                    ..Default::default()
+                })
+                .unwrap();
+
+            for index in 0..20 {
+                if index == 10 {
+                    encoder.set_bitrate(300).unwrap();
+                }
+                match encoder.encode(&moving_square(index)) {
+                    Ok(_) | Err(EncoderError::FrameBuffered) => {}
+                    Err(e) => panic!("{} frame {}: {}", backend.name, index, e),
+                }
+            }
+            encoder.flush().unwrap();
+
+            let keyframes = encoder.stats().keyframes;
+            if backend.capabilities.runtime_bitrate {
+                assert_eq!(keyframes, 1, "{}", backend.name);
+            } else {
+                assert!(keyframes > 1, "{}", backend.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_zero_keyframe_interval_sends_only_the_first_keyframe() {
+        for backend in EncoderRegistry::with_defaults().backends() {
+            let mut encoder = (backend.factory)().unwrap();
+            encoder
+                .init(EncoderConfig {
+                    codec: backend.capabilities.codecs[0],
+                    width: 160,
+                    height: 128,
+                    keyframe_interval: 0,
+                    intra_refresh: backend.capabilities.intra_refresh,
+                    ..Default::default()
+                })
+                .unwrap();
+
+            for index in 0..90 {
+                match encoder.encode(&moving_square(index)) {
+                    Ok(_) | Err(EncoderError::FrameBuffered) => {}
+                    Err(e) => panic!("{} frame {}: {}", backend.name, index, e),
+                }
+            }
+            encoder.flush().unwrap();
+            assert_eq!(encoder.stats().keyframes, 1, "{}", backend.name);
+        }
+    }
+
+    #[test]
+    fn test_create_reports_unsupported_config() {
+        let registry = EncoderRegistry::with_defaults();
+        let config = EncoderConfig {
+            codec: Codec::H265,
+            ..Default::default()
+        };
+        assert!(matches!(
+            registry.create(&config),
+            Err(EncoderError::InvalidConfig(_))
+        ));
+    }
+}
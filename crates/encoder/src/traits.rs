@@ -1,15 +1,20 @@
 //! Video encoder trait abstraction
 
+use std::ops::Range;
+
 use bytes::Bytes;
-use capture::CapturedFrame;
+use capture::{CapturedFrame, DirtyRect};
+use shared_protocol::VideoCodec;
 
-use crate::EncoderResult;
+use crate::{ColorSpace, EncoderResult, YuvFrame};
 
 /// Video codec type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Codec {
     H264,
     H265,
+    VP9,
+    AV1,
 }
 
 impl Default for Codec {
@@ -18,6 +23,17 @@ impl Default for Codec {
     }
 }
 
+impl From<Codec> for VideoCodec {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::H264 => VideoCodec::H264,
+            Codec::H265 => VideoCodec::H265,
+            Codec::VP9 => VideoCodec::VP9,
+            Codec::AV1 => VideoCodec::AV1,
+        }
+    }
+}
+
 /// Encoder rate control mode
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RateControl {
@@ -48,14 +64,44 @@ pub struct EncoderConfig {
     pub bitrate_kbps: u32,
     /// Target FPS
     pub fps: u32,
-    /// Keyframe interval (GOP size)
+    /// Keyframe interval (GOP size); 0 for no periodic keyframes, only
+    /// the first and forced ones
     pub keyframe_interval: u32,
+    /// Replace periodic keyframes with intra-coded blocks spread over
+    /// the frames that follow, at a pace the backend picks, keeping the
+    /// bitrate flat. Forced keyframes still go out. Only backends
+    /// advertising `EncoderCapabilities::intra_refresh` accept it, and
+    /// they may ignore ROI hints while it is on.
+    pub intra_refresh: bool,
+    /// Keep long-term reference frames so loss can be recovered from
+    /// with a P-frame instead of a keyframe. OpenH264 only honors them
+    /// with its camera tuning, giving up the screen content tools that
+    /// keep text sharp, so they're only worth it on a lossy link and
+    /// worth turning off again once it recovers. Changing this takes a
+    /// restart, which starts on a keyframe.
+    pub long_term_refs: bool,
+    /// Temporal layers (1-3, as in L1T1-L1T3). Frames above the base
+    /// layer are only referenced by higher layers, so the top layer can
+    /// be dropped to halve the frame rate without breaking decoding.
+    /// Limited by `EncoderCapabilities::max_temporal_layers`.
+    pub temporal_layers: u8,
     /// Rate control mode
     pub rate_control: RateControl,
+    /// Quantizer for `RateControl::Cqp` (0-51, lower is better)
+    pub qp: u8,
     /// Encoder preset (0-9, 0 = fastest, 9 = best quality)
     pub preset: u8,
     /// Enable low-latency mode
     pub low_latency: bool,
+    /// Cap on each slice's size in bytes, so slices can be sent as
+    /// separate datagrams; `None` encodes one slice per frame
+    pub max_slice_bytes: Option<u32>,
+    /// RGB to YUV conversion, signalled to the decoder
+    pub color_space: ColorSpace,
+    /// Keep chroma at full resolution (4:4:4) so coloured text stays
+    /// crisp. Only backends advertising `EncoderCapabilities::chroma_444`
+    /// accept it.
+    pub chroma_444: bool,
 }
 
 impl Default for EncoderConfig {
@@ -67,13 +113,30 @@ impl Default for EncoderConfig {
             bitrate_kbps: 3000,
             fps: 30,
             keyframe_interval: 60, // Keyframe every 2 seconds at 30fps
+            intra_refresh: false,
+            long_term_refs: false,
+            temporal_layers: 1,
             rate_control: RateControl::Vbr,
+            qp: 26,
             preset: 3, // Fast preset for low latency
             low_latency: true,
+            max_slice_bytes: None,
+            color_space: ColorSpace::default(),
+            chroma_444: false,
         }
     }
 }
 
+/// Region-of-interest hint for the next frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoiRegion {
+    /// Region in frame pixels
+    pub rect: DirtyRect,
+    /// Quantizer offset in H.264 QP steps; negative spends more bits on
+    /// the region, positive fewer
+    pub qp_offset: i8,
+}
+
 /// Encoded frame type
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EncodedFrameType {
@@ -81,6 +144,9 @@ pub enum EncodedFrameType {
     Key,
     /// Predicted frame (P-frame)
     Predicted,
+    /// P-frame predicted only from a long-term reference the receiver
+    /// acknowledged, so it decodes even after frames were lost
+    Recovery,
     /// Bidirectional frame (B-frame)
     Bidirectional,
 }
@@ -90,6 +156,11 @@ pub enum EncodedFrameType {
 pub struct EncodedFrame {
     /// NAL units data
     pub data: Bytes,
+    /// Byte ranges of `data` that decode independently of each other,
+    /// in order and covering all of it; one per slice
+    pub slices: Vec<Range<usize>>,
+    /// Codec the data is encoded with
+    pub codec: Codec,
     /// Frame width
     pub width: u32,
     /// Frame height
@@ -100,8 +171,11 @@ pub struct EncodedFrame {
     pub pts_us: u64,
     /// Decode timestamp in microseconds
     pub dts_us: u64,
-    /// Frame sequence number
+    /// Frame sequence number, kept increasing across reconfiguration
     pub sequence: u64,
+    /// Temporal layer, 0 for the base layer; never referenced by frames
+    /// of a lower layer
+    pub temporal_id: u8,
     /// Encoding took this many microseconds
     pub encode_time_us: u64,
 }
@@ -114,9 +188,42 @@ pub trait VideoEncoder: Send {
     /// Encode a captured frame
     fn encode(&mut self, frame: &CapturedFrame) -> EncoderResult<EncodedFrame>;
 
+    /// Encode a frame already converted with the configured `color_space`
+    ///
+    /// The frame must be 4:4:4 exactly when `chroma_444` is configured.
+    fn encode_yuv(&mut self, frame: &YuvFrame) -> EncoderResult<EncodedFrame>;
+
     /// Force next frame to be a keyframe
     fn force_keyframe(&mut self);
 
+    /// Report that the receiver decoded every frame up to `sequence`
+    ///
+    /// Lets the encoder rely on long-term references that frame
+    /// established.
+    fn acknowledge(&mut self, _sequence: u64) -> EncoderResult<()> {
+        Ok(())
+    }
+
+    /// Recover from loss after the receiver's last good frame
+    ///
+    /// Frames after `last_good` are no longer used as references; the
+    /// next frame is predicted from an acknowledged long-term reference
+    /// when one is available, and is a keyframe otherwise.
+    fn invalidate_references(&mut self, _last_good: u64) -> EncoderResult<()> {
+        self.force_keyframe();
+        Ok(())
+    }
+
+    /// Set the regions of interest for the following frames
+    ///
+    /// Replaces any previous hints; an empty slice clears them. Where
+    /// regions overlap the lowest offset wins. Backends without
+    /// per-block quantizer control (see `EncoderCapabilities::roi`)
+    /// ignore the hints.
+    fn set_roi(&mut self, _regions: &[RoiRegion]) -> EncoderResult<()> {
+        Ok(())
+    }
+
     /// Update bitrate dynamically
     fn set_bitrate(&mut self, bitrate_kbps: u32) -> EncoderResult<()>;
 
@@ -126,6 +233,20 @@ pub trait VideoEncoder: Send {
     /// Get current configuration
     fn config(&self) -> &EncoderConfig;
 
+    /// Re-initialize for a new frame size, keeping the other settings
+    ///
+    /// The next encoded frame is a keyframe at the new resolution.
+    fn reconfigure(&mut self, width: u32, height: u32) -> EncoderResult<()> {
+        let config = EncoderConfig {
+            width,
+            height,
+            ..self.config().clone()
+        };
+        self.init(config)?;
+        self.force_keyframe();
+        Ok(())
+    }
+
     /// Get encoder statistics
     fn stats(&self) -> EncoderStats;
 
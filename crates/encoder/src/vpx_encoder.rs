@@ -0,0 +1,628 @@
+//! libvpx VP9 encoder implementation
+
+use bytes::Bytes;
+use capture::CapturedFrame;
+use std::os::raw::c_int;
+use std::time::Instant;
+use tracing::{debug, info};
+use vpx_sys::{
+    vp8e_enc_control_id, vp9e_tune_content, vpx_codec_enc_cfg_t, vpx_color_range, vpx_color_space,
+    vpx_img_fmt, vpx_kf_mode, vpx_rc_mode,
+};
+
+use crate::vpx_raw::{Picture, RawVpxEncoder};
+use crate::{
+    Codec, ColorMatrix, ColorRange, EncodedFrame, EncodedFrameType, EncoderConfig, EncoderError,
+    EncoderResult, EncoderStats, RateControl, RoiRegion, VideoEncoder, YuvFrame,
+};
+
+/// Fastest realtime speed setting; 5 is the slowest
+const MAX_SPEED: u8 = 9;
+
+/// VP9 quantizer indices run 0-63
+const MAX_QUANTIZER: u32 = 63;
+
+/// Most tile columns to split a frame into, as a log2
+const MAX_LOG2_TILE_COLUMNS: u32 = 4;
+
+/// Segments available to ROI maps; segment 0 keeps the frame quantizer
+const MAX_SEGMENTS: usize = 8;
+
+/// ROI maps hold one segment per 8x8 block
+const ROI_BLOCK_SIZE: u32 = 8;
+
+/// VP9's 0-255 quantizer index moves about five steps per H.264 QP step
+const QINDEX_PER_QP: c_int = 5;
+
+/// Largest quantizer index delta libvpx accepts for a segment
+const MAX_SEGMENT_DELTA_Q: c_int = 63;
+
+/// `VP9E_SET_AQ_MODE` value for cyclic refresh, which intra-codes a
+/// rotating set of blocks in every frame
+const CYCLIC_REFRESH_AQ: c_int = 3;
+
+/// libvpx-based VP9 software encoder
+///
+/// Runs with zero lag in realtime mode, so every call to `encode`
+/// returns the frame it was given.
+pub struct VpxEncoder {
+    encoder: Option<RawVpxEncoder>,
+    config: EncoderConfig,
+    stats: EncoderStats,
+    force_keyframe: bool,
+    frame_counter: u64,
+    next_pts_us: u64,
+    encode_times: Vec<u64>,
+    yuv: YuvFrame,
+    roi: Vec<RoiRegion>,
+    /// `roi` changed since it was last handed to libvpx
+    roi_changed: bool,
+}
+
+impl VpxEncoder {
+    /// Create a new VP9 encoder
+    pub fn new() -> Self {
+        Self {
+            encoder: None,
+            config: EncoderConfig::default(),
+            stats: EncoderStats::default(),
+            force_keyframe: false,
+            frame_counter: 0,
+            next_pts_us: 0,
+            encode_times: Vec::with_capacity(100),
+            yuv: YuvFrame::default(),
+            roi: Vec::new(),
+            roi_changed: false,
+        }
+    }
+
+    /// Build libvpx's configuration for `config`
+    fn vpx_config(config: &EncoderConfig) -> EncoderResult<vpx_codec_enc_cfg_t> {
+        let mut vpx = RawVpxEncoder::default_config()?;
+        vpx.g_w = config.width;
+        vpx.g_h = config.height;
+        // Profile 1 carries 4:4:4
+        vpx.g_profile = config.chroma_444 as u32;
+        vpx.g_threads = rayon::current_num_threads() as u32;
+        // Timestamps are in microseconds
+        vpx.g_timebase.num = 1;
+        vpx.g_timebase.den = 1_000_000;
+        // No lookahead, and never skip a frame to make the bitrate
+        vpx.g_lag_in_frames = 0;
+        vpx.rc_dropframe_thresh = 0;
+        // Cyclic refresh replaces periodic keyframes; forced ones still go out
+        vpx.kf_mode = if config.intra_refresh || config.keyframe_interval == 0 {
+            vpx_kf_mode::VPX_KF_DISABLED
+        } else {
+            vpx_kf_mode::VPX_KF_AUTO
+        };
+        vpx.kf_min_dist = 0;
+        vpx.kf_max_dist = config.keyframe_interval;
+
+        match config.rate_control {
+            RateControl::Cbr => {
+                vpx.rc_end_usage = vpx_rc_mode::VPX_CBR;
+                // Buffer sizes in milliseconds, short enough for interactive use
+                vpx.rc_buf_sz = 1000;
+                vpx.rc_buf_initial_sz = 500;
+                vpx.rc_buf_optimal_sz = 600;
+                vpx.rc_undershoot_pct = 50;
+                vpx.rc_overshoot_pct = 50;
+            }
+            RateControl::Vbr => vpx.rc_end_usage = vpx_rc_mode::VPX_VBR,
+            RateControl::Cqp => {
+                vpx.rc_end_usage = vpx_rc_mode::VPX_Q;
+                let quantizer = Self::quantizer(config.qp);
+                vpx.rc_min_quantizer = quantizer;
+                vpx.rc_max_quantizer = quantizer;
+            }
+        }
+        vpx.rc_target_bitrate = config.bitrate_kbps;
+        Ok(vpx)
+    }
+
+    /// Map an H.264-style QP onto VP9's quantizer range
+    fn quantizer(qp: u8) -> u32 {
+        qp.min(51) as u32 * MAX_QUANTIZER / 51
+    }
+
+    /// Segment map and per-segment quantizer deltas for `regions`
+    ///
+    /// Each distinct offset gets a segment, keeping the seven strongest
+    /// boosts if there are more.
+    fn roi_map(regions: &[RoiRegion], width: u32, height: u32) -> (Vec<u8>, [c_int; MAX_SEGMENTS]) {
+        let cols = width.div_ceil(ROI_BLOCK_SIZE);
+        let rows = height.div_ceil(ROI_BLOCK_SIZE);
+        let mut map = vec![0u8; (cols * rows) as usize];
+
+        let mut offsets: Vec<i8> = regions
+            .iter()
+            .map(|region| region.qp_offset)
+            .filter(|&offset| offset != 0)
+            .collect();
+        offsets.sort_unstable();
+        offsets.dedup();
+        offsets.truncate(MAX_SEGMENTS - 1);
+        let mut delta_q = [0; MAX_SEGMENTS];
+        for (segment, &offset) in offsets.iter().enumerate() {
+            delta_q[segment + 1] =
+                (offset as c_int * QINDEX_PER_QP).clamp(-MAX_SEGMENT_DELTA_Q, MAX_SEGMENT_DELTA_Q);
+        }
+        let segment = |offset: i8| match offset {
+            0 => Some(0),
+            _ => offsets
+                .iter()
+                .position(|&o| o == offset)
+                .map(|i| i as u8 + 1),
+        };
+
+        // Paint the highest offsets first so the lowest wins on overlap
+        let mut regions = regions.to_vec();
+        regions.sort_by_key(|region| std::cmp::Reverse(region.qp_offset));
+        for region in regions {
+            let Some(segment) = segment(region.qp_offset) else {
+                continue;
+            };
+            let rect = region.rect;
+            let left = (rect.x / ROI_BLOCK_SIZE).min(cols);
+            let top = (rect.y / ROI_BLOCK_SIZE).min(rows);
+            let right = (rect.x.saturating_add(rect.width))
+                .div_ceil(ROI_BLOCK_SIZE)
+                .min(cols);
+            let bottom = (rect.y.saturating_add(rect.height))
+                .div_ceil(ROI_BLOCK_SIZE)
+                .min(rows);
+            for row in top..bottom {
+                let start = (row * cols) as usize;
+                map[start + left as usize..start + right as usize].fill(segment);
+            }
+        }
+
+        (map, delta_q)
+    }
+
+    /// Apply the controls that aren't part of the configuration struct
+    fn apply_controls(encoder: &mut RawVpxEncoder, config: &EncoderConfig) -> EncoderResult<()> {
+        use vp8e_enc_control_id::*;
+
+        // Our presets 0-9 run fastest to best, onto realtime speeds 9-5
+        let speed = MAX_SPEED - config.preset.min(9) / 2;
+        encoder.control(VP8E_SET_CPUUSED, speed as c_int)?;
+        // Favours sharp edges and skips static regions cheaply
+        encoder.control(
+            VP9E_SET_TUNE_CONTENT,
+            vp9e_tune_content::VP9E_CONTENT_SCREEN as c_int,
+        )?;
+        encoder.control(VP9E_SET_ROW_MT, 1)?;
+        if config.intra_refresh {
+            encoder.control(VP9E_SET_AQ_MODE, CYCLIC_REFRESH_AQ)?;
+        }
+        let threads = rayon::current_num_threads().max(1) as u32;
+        encoder.control(
+            VP9E_SET_TILE_COLUMNS,
+            threads.ilog2().min(MAX_LOG2_TILE_COLUMNS) as c_int,
+        )?;
+        if config.rate_control == RateControl::Cqp {
+            encoder.control(VP8E_SET_CQ_LEVEL, Self::quantizer(config.qp) as c_int)?;
+        }
+
+        let color_space = match config.color_space.matrix {
+            ColorMatrix::Bt601 => vpx_color_space::VPX_CS_BT_601,
+            ColorMatrix::Bt709 => vpx_color_space::VPX_CS_BT_709,
+        };
+        encoder.control(VP9E_SET_COLOR_SPACE, color_space as c_int)?;
+        let color_range = match config.color_space.range {
+            ColorRange::Limited => vpx_color_range::VPX_CR_STUDIO_RANGE,
+            ColorRange::Full => vpx_color_range::VPX_CR_FULL_RANGE,
+        };
+        encoder.control(VP9E_SET_COLOR_RANGE, color_range as c_int)
+    }
+}
+
+impl Default for VpxEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VideoEncoder for VpxEncoder {
+    fn init(&mut self, config: EncoderConfig) -> EncoderResult<()> {
+        if config.codec != Codec::VP9 {
+            return Err(EncoderError::InvalidConfig(
+                "libvpx backend only supports VP9".to_string(),
+            ));
+        }
+        if config.temporal_layers > 1 {
+            return Err(EncoderError::InvalidConfig(
+                "libvpx backend has no temporal layers".to_string(),
+            ));
+        }
+
+        info!(
+            "Initializing libvpx VP9 encoder: {}x{} @ {} kbps, {} fps{}",
+            config.width,
+            config.height,
+            config.bitrate_kbps,
+            config.fps,
+            if config.chroma_444 { ", 4:4:4" } else { "" }
+        );
+
+        let mut encoder = RawVpxEncoder::new(Self::vpx_config(&config)?)?;
+        Self::apply_controls(&mut encoder, &config)?;
+
+        self.encoder = Some(encoder);
+        self.yuv = YuvFrame::new(config.chroma_444);
+        self.config = config;
+        self.stats = EncoderStats::default();
+        self.next_pts_us = 0;
+        self.encode_times.clear();
+        // The new context starts without a map
+        self.roi_changed = !self.roi.is_empty();
+
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: &CapturedFrame) -> EncoderResult<EncodedFrame> {
+        let mut yuv = std::mem::take(&mut self.yuv);
+        let encoded = yuv
+            .convert(frame, self.config.color_space)
+            .and_then(|()| self.encode_yuv(&yuv));
+        self.yuv = yuv;
+        encoded
+    }
+
+    fn encode_yuv(&mut self, frame: &YuvFrame) -> EncoderResult<EncodedFrame> {
+        let Some(encoder) = self.encoder.as_mut() else {
+            return Err(EncoderError::NotInitialized);
+        };
+        if frame.is_444() != self.config.chroma_444 {
+            return Err(EncoderError::UnsupportedPixelFormat);
+        }
+
+        if frame.width() != self.config.width || frame.height() != self.config.height {
+            return Err(EncoderError::UnsupportedResolution {
+                width: frame.width(),
+                height: frame.height(),
+            });
+        }
+
+        let start = Instant::now();
+
+        // Cyclic refresh owns the segment map, so ROI hints wait until
+        // it is off again
+        if self.roi_changed && !self.config.intra_refresh {
+            let (mut map, delta_q) =
+                Self::roi_map(&self.roi, self.config.width, self.config.height);
+            encoder.set_roi_map(
+                &mut map,
+                self.config.height.div_ceil(ROI_BLOCK_SIZE),
+                self.config.width.div_ceil(ROI_BLOCK_SIZE),
+                delta_q,
+            )?;
+            self.roi_changed = false;
+        }
+
+        let picture = match frame {
+            YuvFrame::I420(yuv) => {
+                let (y_stride, uv_stride) = yuv.strides();
+                let (y, u, v) = yuv.planes();
+                Picture {
+                    format: vpx_img_fmt::VPX_IMG_FMT_I420,
+                    width: yuv.width(),
+                    height: yuv.height(),
+                    planes: [(y, y_stride), (u, uv_stride), (v, uv_stride)],
+                }
+            }
+            YuvFrame::I444(yuv) => {
+                let stride = yuv.stride();
+                let (y, u, v) = yuv.planes();
+                Picture {
+                    format: vpx_img_fmt::VPX_IMG_FMT_I444,
+                    width: yuv.width(),
+                    height: yuv.height(),
+                    planes: [(y, stride), (u, stride), (v, stride)],
+                }
+            }
+        };
+
+        if self.force_keyframe {
+            debug!("Forcing keyframe");
+        }
+        let duration_us = 1_000_000 / self.config.fps.max(1) as u64;
+        let packets = encoder.encode(
+            &picture,
+            self.next_pts_us as i64,
+            duration_us,
+            self.force_keyframe,
+        )?;
+        self.force_keyframe = false;
+
+        let encode_time = start.elapsed().as_micros() as u64;
+
+        // Without lag or frame dropping each picture makes one frame
+        let mut packets = packets.into_iter();
+        let (Some(packet), None) = (packets.next(), packets.next()) else {
+            return Err(EncoderError::EncodingFailed(
+                "libvpx didn't produce exactly one frame".to_string(),
+            ));
+        };
+
+        let pts_us = self.next_pts_us;
+        self.next_pts_us += duration_us;
+
+        // Tiles share the frame header, so the frame is one unit
+        let whole = 0..packet.data.len();
+        let encoded = EncodedFrame {
+            slices: vec![whole],
+            data: Bytes::from(packet.data),
+            codec: Codec::VP9,
+            width: self.config.width,
+            height: self.config.height,
+            frame_type: if packet.keyframe {
+                EncodedFrameType::Key
+            } else {
+                EncodedFrameType::Predicted
+            },
+            pts_us,
+            dts_us: pts_us,
+            sequence: self.frame_counter,
+            temporal_id: 0,
+            encode_time_us: encode_time,
+        };
+
+        // Update stats
+        self.frame_counter += 1;
+        self.stats.frames_encoded += 1;
+        self.stats.bytes_output += encoded.data.len() as u64;
+        if packet.keyframe {
+            self.stats.keyframes += 1;
+        }
+
+        self.encode_times.push(encode_time);
+        if self.encode_times.len() > 100 {
+            self.encode_times.remove(0);
+        }
+        self.stats.avg_encode_time_us =
+            self.encode_times.iter().sum::<u64>() / self.encode_times.len() as u64;
+        self.stats.avg_frame_size = self.stats.bytes_output / self.stats.frames_encoded;
+
+        Ok(encoded)
+    }
+
+    fn force_keyframe(&mut self) {
+        self.force_keyframe = true;
+    }
+
+    fn set_roi(&mut self, regions: &[RoiRegion]) -> EncoderResult<()> {
+        if regions != self.roi {
+            self.roi = regions.to_vec();
+            self.roi_changed = true;
+        }
+        Ok(())
+    }
+
+    fn set_bitrate(&mut self, bitrate_kbps: u32) -> EncoderResult<()> {
+        if bitrate_kbps == 0 {
+            return Err(EncoderError::InvalidConfig(
+                "Bitrate must be non-zero".to_string(),
+            ));
+        }
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.set_bitrate(bitrate_kbps)?;
+        }
+        self.config.bitrate_kbps = bitrate_kbps;
+        debug!("Bitrate updated to {} kbps", bitrate_kbps);
+        Ok(())
+    }
+
+    fn set_fps(&mut self, fps: u32) -> EncoderResult<()> {
+        if fps == 0 {
+            return Err(EncoderError::InvalidConfig(
+                "FPS must be non-zero".to_string(),
+            ));
+        }
+        // Rate control follows the frame durations passed to each encode
+        self.config.fps = fps;
+        debug!("FPS updated to {}", fps);
+        Ok(())
+    }
+
+    fn config(&self) -> &EncoderConfig {
+        &self.config
+    }
+
+    fn stats(&self) -> EncoderStats {
+        let mut stats = self.stats.clone();
+        if self.stats.frames_encoded > 0 {
+            let elapsed_secs = self.stats.frames_encoded as f64 / self.config.fps as f64;
+            stats.current_fps = self.stats.frames_encoded as f64 / elapsed_secs;
+        }
+        stats
+    }
+
+    fn flush(&mut self) -> EncoderResult<Vec<EncodedFrame>> {
+        // Nothing is held back with zero lag
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capture::{DirtyRect, PixelFormat};
+
+    /// Coloured text-like stripes that scroll by a row each frame
+    fn frame(width: u32, height: u32, index: u32) -> CapturedFrame {
+        let data = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width + index);
+                if (x / 3 + y / 5) % 4 == 0 {
+                    [200, 30, 20, 255]
+                } else {
+                    [235, 235, 235, 255]
+                }
+            })
+            .collect::<Vec<_>>();
+        CapturedFrame {
+            data: Bytes::from(data),
+            width,
+            height,
+            stride: width * 4,
+            format: PixelFormat::Bgra8,
+            timestamp: Instant::now(),
+            sequence: 0,
+            dirty_rects: Vec::new(),
+            display_id: 0,
+        }
+    }
+
+    fn config() -> EncoderConfig {
+        EncoderConfig {
+            codec: Codec::VP9,
+            width: 160,
+            height: 128,
+            bitrate_kbps: 500,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_every_frame_comes_out_immediately() {
+        let mut encoder = VpxEncoder::new();
+        encoder.init(config()).unwrap();
+
+        for i in 0..10 {
+            let encoded = encoder.encode(&frame(160, 128, i)).unwrap();
+            assert_eq!(encoded.sequence, i as u64);
+            assert_eq!(encoded.pts_us, i as u64 * (1_000_000 / 30));
+            assert_eq!(encoded.codec, Codec::VP9);
+            let expected = if i == 0 {
+                EncodedFrameType::Key
+            } else {
+                EncodedFrameType::Predicted
+            };
+            assert_eq!(encoded.frame_type, expected, "frame {i}");
+        }
+        assert!(encoder.flush().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_forced_keyframe() {
+        let mut encoder = VpxEncoder::new();
+        encoder.init(config()).unwrap();
+        for i in 0..5 {
+            encoder.encode(&frame(160, 128, i)).unwrap();
+        }
+
+        encoder.force_keyframe();
+        let forced = encoder.encode(&frame(160, 128, 5)).unwrap();
+        assert_eq!(forced.frame_type, EncodedFrameType::Key);
+        assert_eq!(encoder.stats().keyframes, 2);
+    }
+
+    #[test]
+    fn test_intra_refresh_keeps_frame_sizes_flat() {
+        let encode = |intra_refresh: bool| {
+            let mut encoder = VpxEncoder::new();
+            encoder
+                .init(EncoderConfig {
+                    keyframe_interval: 15,
+                    rate_control: RateControl::Cbr,
+                    intra_refresh,
+                    ..config()
+                })
+                .unwrap();
+            let sizes: Vec<usize> = (0..60)
+                .map(|i| encoder.encode(&frame(160, 128, i)).unwrap().data.len())
+                .collect();
+            (sizes, encoder.stats().keyframes)
+        };
+
+        let (periodic, keyframes) = encode(false);
+        assert_eq!(keyframes, 4);
+        let (refreshed, keyframes) = encode(true);
+        assert_eq!(keyframes, 1);
+
+        // Past the opening keyframe no frame stands out from the rest,
+        // while periodic keyframes do
+        let later = &refreshed[15..];
+        let average = later.iter().sum::<usize>() / later.len();
+        let largest = *later.iter().max().unwrap();
+        assert!(largest <= average * 3, "{} against {}", largest, average);
+        assert!(largest < *periodic[15..].iter().max().unwrap());
+    }
+
+    #[test]
+    fn test_444_keyframe_signals_profile_1() {
+        let mut encoder = VpxEncoder::new();
+        encoder
+            .init(EncoderConfig {
+                chroma_444: true,
+                ..config()
+            })
+            .unwrap();
+        encoder.set_bitrate(300).unwrap();
+
+        let encoded = encoder.encode(&frame(160, 128, 0)).unwrap();
+        // frame_marker, then the low and high profile bits
+        let header = encoded.data[0];
+        assert_eq!(header >> 6, 0b10);
+        assert_eq!((header >> 5) & 1 | ((header >> 4) & 1) << 1, 1);
+    }
+
+    #[test]
+    fn test_roi_map_lowest_offset_wins() {
+        let regions = [
+            RoiRegion {
+                rect: DirtyRect::new(0, 0, 32, 16),
+                qp_offset: -2,
+            },
+            RoiRegion {
+                rect: DirtyRect::new(12, 4, 8, 40),
+                qp_offset: -6,
+            },
+            RoiRegion {
+                rect: DirtyRect::new(0, 16, 200, 200),
+                qp_offset: 4,
+            },
+        ];
+        let (map, delta_q) = VpxEncoder::roi_map(&regions, 36, 24);
+
+        // 5x3 blocks; segments in offset order -6, -2, 4
+        assert_eq!(delta_q[..4], [0, -30, -10, 20]);
+        #[rustfmt::skip]
+        assert_eq!(map, [
+            2, 1, 1, 2, 0,
+            2, 1, 1, 2, 0,
+            3, 1, 1, 3, 3,
+        ]);
+    }
+
+    #[test]
+    fn test_roi_hints_survive_reconfigure() {
+        let mut encoder = VpxEncoder::new();
+        encoder.init(config()).unwrap();
+        let roi = [RoiRegion {
+            rect: DirtyRect::new(40, 40, 64, 32),
+            qp_offset: -8,
+        }];
+        encoder.set_roi(&roi).unwrap();
+        encoder.encode(&frame(160, 128, 0)).unwrap();
+        assert!(!encoder.roi_changed);
+
+        encoder.reconfigure(96, 64).unwrap();
+        assert!(encoder.roi_changed);
+        encoder.encode(&frame(96, 64, 1)).unwrap();
+        encoder.set_roi(&[]).unwrap();
+        encoder.encode(&frame(96, 64, 2)).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_other_codecs() {
+        let mut encoder = VpxEncoder::new();
+        assert!(matches!(
+            encoder.init(EncoderConfig::default()),
+            Err(EncoderError::InvalidConfig(_))
+        ));
+    }
+}
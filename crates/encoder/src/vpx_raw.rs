@@ -0,0 +1,217 @@
+//! Minimal safe wrapper over the libvpx VP9 encoder API
+
+use std::ffi::CStr;
+use std::mem::MaybeUninit;
+use std::os::raw::{c_int, c_uint, c_ulong};
+use std::ptr;
+
+use vpx_sys::{
+    VPX_DL_REALTIME, VPX_EFLAG_FORCE_KF, VPX_ENCODER_ABI_VERSION, VPX_FRAME_IS_KEY,
+    vp8e_enc_control_id, vpx_codec_control_, vpx_codec_ctx_t, vpx_codec_cx_pkt_kind,
+    vpx_codec_destroy, vpx_codec_enc_cfg_t, vpx_codec_enc_config_default, vpx_codec_enc_config_set,
+    vpx_codec_enc_init_ver, vpx_codec_encode, vpx_codec_err_t, vpx_codec_err_to_string,
+    vpx_codec_get_cx_data, vpx_codec_iter_t, vpx_codec_vp9_cx, vpx_enc_frame_flags_t, vpx_image_t,
+    vpx_img_fmt, vpx_img_wrap, vpx_roi_map_t,
+};
+
+use crate::{EncoderError, EncoderResult};
+
+/// An initialized VP9 encoder context
+pub(crate) struct RawVpxEncoder {
+    /// Boxed so its address stays fixed while libvpx holds on to it
+    context: Box<vpx_codec_ctx_t>,
+    config: vpx_codec_enc_cfg_t,
+}
+
+// SAFETY: the context is only used through `&mut self`, and the
+// configuration's pointers are unused in one-pass encoding
+unsafe impl Send for RawVpxEncoder {}
+
+/// An uncompressed 8-bit planar picture
+pub(crate) struct Picture<'a> {
+    pub format: vpx_img_fmt,
+    pub width: u32,
+    pub height: u32,
+    /// Y, U and V planes with their strides in bytes
+    pub planes: [(&'a [u8], usize); 3],
+}
+
+/// A compressed frame
+pub(crate) struct Packet {
+    pub data: Vec<u8>,
+    pub keyframe: bool,
+}
+
+/// Check a libvpx return code
+fn check(code: vpx_codec_err_t, what: &str) -> Result<(), String> {
+    if code == vpx_codec_err_t::VPX_CODEC_OK {
+        return Ok(());
+    }
+    // SAFETY: libvpx returns a static string for every error code
+    let message = unsafe { CStr::from_ptr(vpx_codec_err_to_string(code)) };
+    Err(format!("{} failed: {}", what, message.to_string_lossy()))
+}
+
+impl RawVpxEncoder {
+    /// libvpx's default VP9 configuration, to be adjusted before `new`
+    pub fn default_config() -> EncoderResult<vpx_codec_enc_cfg_t> {
+        let mut config = MaybeUninit::zeroed();
+        // SAFETY: `config` is a valid out-pointer; a zeroed config is a
+        // valid value even if the call fails
+        let code =
+            unsafe { vpx_codec_enc_config_default(vpx_codec_vp9_cx(), config.as_mut_ptr(), 0) };
+        check(code, "vpx_codec_enc_config_default").map_err(EncoderError::InitFailed)?;
+        // SAFETY: filled in by libvpx above
+        Ok(unsafe { config.assume_init() })
+    }
+
+    /// Create an encoder for `config`
+    pub fn new(config: vpx_codec_enc_cfg_t) -> EncoderResult<Self> {
+        // SAFETY: the context is plain data that libvpx fills in
+        let mut context =
+            Box::new(unsafe { MaybeUninit::<vpx_codec_ctx_t>::zeroed().assume_init() });
+        // SAFETY: `context` and `config` are valid for the call's duration
+        let code = unsafe {
+            vpx_codec_enc_init_ver(
+                &mut *context,
+                vpx_codec_vp9_cx(),
+                &config,
+                0,
+                VPX_ENCODER_ABI_VERSION as c_int,
+            )
+        };
+        check(code, "vpx_codec_enc_init").map_err(EncoderError::InitFailed)?;
+        Ok(Self { context, config })
+    }
+
+    /// Retarget rate control without restarting the stream
+    pub fn set_bitrate(&mut self, bitrate_kbps: u32) -> EncoderResult<()> {
+        self.config.rc_target_bitrate = bitrate_kbps;
+        // SAFETY: `config` is valid for the call's duration
+        let code = unsafe { vpx_codec_enc_config_set(&mut *self.context, &self.config) };
+        check(code, "vpx_codec_enc_config_set").map_err(EncoderError::InvalidConfig)
+    }
+
+    /// Set an integer encoder control
+    pub fn control(&mut self, id: vp8e_enc_control_id, value: c_int) -> EncoderResult<()> {
+        // SAFETY: every control set through here takes an int
+        let code = unsafe { vpx_codec_control_(&mut *self.context, id as c_int, value) };
+        check(code, &format!("{:?}", id)).map_err(EncoderError::InitFailed)
+    }
+
+    /// Set per-segment quantizer deltas, with `map` holding the segment
+    /// of each 8x8 block in raster order
+    ///
+    /// All-zero deltas turn the map off again.
+    pub fn set_roi_map(
+        &mut self,
+        map: &mut [u8],
+        rows: u32,
+        cols: u32,
+        delta_q: [c_int; 8],
+    ) -> EncoderResult<()> {
+        let mut roi = vpx_roi_map_t {
+            enabled: 1,
+            roi_map: map.as_mut_ptr(),
+            rows: rows as c_uint,
+            cols: cols as c_uint,
+            delta_q,
+            delta_lf: [0; 8],
+            skip: [0; 8],
+            // -1 leaves reference selection unconstrained
+            ref_frame: [-1; 8],
+            static_threshold: [0; 4],
+        };
+        // SAFETY: `roi` and its map are valid for the call; libvpx copies
+        // the map before returning
+        let code = unsafe {
+            vpx_codec_control_(
+                &mut *self.context,
+                vp8e_enc_control_id::VP9E_SET_ROI_MAP as c_int,
+                &mut roi as *mut vpx_roi_map_t,
+            )
+        };
+        check(code, "VP9E_SET_ROI_MAP").map_err(EncoderError::InvalidConfig)
+    }
+
+    /// Encode one picture, returning the compressed frames it produced
+    pub fn encode(
+        &mut self,
+        picture: &Picture,
+        pts: i64,
+        duration: u64,
+        force_keyframe: bool,
+    ) -> EncoderResult<Vec<Packet>> {
+        let [(y, y_stride), (u, u_stride), (v, v_stride)] = picture.planes;
+        let mut image = MaybeUninit::<vpx_image_t>::zeroed();
+        // SAFETY: `image` is valid for writes; the data pointer is only
+        // used to fill in plane pointers, which are replaced below
+        let image = unsafe {
+            vpx_img_wrap(
+                image.as_mut_ptr(),
+                picture.format,
+                picture.width as c_uint,
+                picture.height as c_uint,
+                1,
+                y.as_ptr().cast_mut(),
+            );
+            image.assume_init_mut()
+        };
+        // libvpx only reads from the planes
+        image.planes[0] = y.as_ptr().cast_mut();
+        image.planes[1] = u.as_ptr().cast_mut();
+        image.planes[2] = v.as_ptr().cast_mut();
+        image.stride[0] = y_stride as c_int;
+        image.stride[1] = u_stride as c_int;
+        image.stride[2] = v_stride as c_int;
+
+        let flags = if force_keyframe {
+            VPX_EFLAG_FORCE_KF as vpx_enc_frame_flags_t
+        } else {
+            0
+        };
+        // SAFETY: `image` and its planes outlive the call; libvpx copies
+        // the picture before returning when there is no lag
+        let code = unsafe {
+            vpx_codec_encode(
+                &mut *self.context,
+                image,
+                pts,
+                duration as c_ulong,
+                flags,
+                VPX_DL_REALTIME as c_ulong,
+            )
+        };
+        check(code, "vpx_codec_encode").map_err(EncoderError::EncodingFailed)?;
+
+        let mut packets = Vec::new();
+        let mut iter: vpx_codec_iter_t = ptr::null();
+        loop {
+            // SAFETY: `iter` is the iterator libvpx hands back each call
+            let packet = unsafe { vpx_codec_get_cx_data(&mut *self.context, &mut iter) };
+            // SAFETY: non-null packets stay valid until the next encode call
+            let Some(packet) = (unsafe { packet.as_ref() }) else {
+                break;
+            };
+            if packet.kind != vpx_codec_cx_pkt_kind::VPX_CODEC_CX_FRAME_PKT {
+                continue;
+            }
+            // SAFETY: `frame` is the active union field for frame packets
+            let frame = unsafe { packet.data.frame };
+            // SAFETY: libvpx owns `sz` bytes at `buf` until the next call
+            let data = unsafe { std::slice::from_raw_parts(frame.buf.cast::<u8>(), frame.sz) };
+            packets.push(Packet {
+                data: data.to_vec(),
+                keyframe: frame.flags & VPX_FRAME_IS_KEY != 0,
+            });
+        }
+        Ok(packets)
+    }
+}
+
+impl Drop for RawVpxEncoder {
+    fn drop(&mut self) {
+        // SAFETY: initialized in `new` and not used afterwards
+        unsafe { vpx_codec_destroy(&mut *self.context) };
+    }
+}
@@ -75,6 +75,15 @@ impl InputProcessor {
         })
     }
 
+    /// Current pointer position as a fraction of the screen size
+    pub fn normalized_mouse_position(&self) -> InjectorResult<(f64, f64)> {
+        let (x, y) = self.injector.mouse_position()?;
+        Ok((
+            x / self.screen_width.max(1) as f64,
+            y / self.screen_height.max(1) as f64,
+        ))
+    }
+
     /// Process an input packet from the network
     pub fn process_packet(&mut self, packet: &InputPacket) -> InjectorResult<()> {
         // Check for out-of-order packets
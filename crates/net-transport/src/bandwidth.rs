@@ -0,0 +1,781 @@
+//! Delay-based bandwidth estimation
+//!
+//! [`BandwidthEstimator`] follows Google Congestion Control. The viewer
+//! reports when each video datagram arrived ([`TransportFeedback`],
+//! gathered by a [`FeedbackRecorder`]); the host compares how far apart
+//! consecutive frames arrived with how far apart it sent them. A rising
+//! trend in that difference means a queue is building at the bottleneck,
+//! so the target bitrate drops below the throughput the viewer actually
+//! received; a flat trend lets it grow again. Packet loss reported by
+//! QUIC limits the target on its own.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use shared_protocol::{DatagramArrival, TransportFeedback, VideoPacketHeader};
+
+use crate::MAX_DATAGRAM_SIZE;
+
+/// Delay samples the trendline is fitted over
+const TRENDLINE_WINDOW: usize = 20;
+/// Smoothing applied to the accumulated delay before fitting
+const TRENDLINE_SMOOTHING: f64 = 0.9;
+/// Scales the fitted slope into the detector's units
+const TRENDLINE_GAIN: f64 = 4.0;
+/// Cap on the sample count the slope is multiplied by
+const TRENDLINE_MAX_DELTAS: usize = 60;
+
+/// Initial value and bounds of the adaptive overuse threshold, in ms
+const THRESHOLD_INITIAL: f64 = 12.5;
+const THRESHOLD_MIN: f64 = 6.0;
+const THRESHOLD_MAX: f64 = 600.0;
+/// How fast the threshold moves towards a trend above or below it
+const THRESHOLD_GAIN_UP: f64 = 0.0087;
+const THRESHOLD_GAIN_DOWN: f64 = 0.039;
+/// Trends this far past the threshold are spikes and leave it alone
+const THRESHOLD_SPIKE: f64 = 15.0;
+/// How long, in ms, the trend must stay over the threshold to count as
+/// overuse
+const OVERUSE_TIME_MS: f64 = 10.0;
+
+/// Fraction of the received throughput kept on overuse
+const DECREASE_FACTOR: f64 = 0.85;
+/// Growth per second while the link capacity is unknown
+const MULTIPLICATIVE_INCREASE: f64 = 1.08;
+/// Smoothing of the link capacity estimate
+const CAPACITY_WEIGHT: f64 = 0.05;
+/// Window over which the received throughput is measured
+const THROUGHPUT_WINDOW: Duration = Duration::from_millis(500);
+
+/// Loss below which the delay-based rate leads, and above which the
+/// rate is cut in proportion to the loss
+const LOW_LOSS: f64 = 0.02;
+const HIGH_LOSS: f64 = 0.10;
+
+/// How long sent datagrams wait for feedback before being forgotten
+const SENT_HISTORY: Duration = Duration::from_secs(2);
+
+/// Bitrate limits for the estimate
+#[derive(Debug, Clone)]
+pub struct BandwidthConfig {
+    /// Minimum bitrate in kbps
+    pub min_bitrate_kbps: u32,
+    /// Maximum bitrate in kbps
+    pub max_bitrate_kbps: u32,
+    /// Initial bitrate in kbps
+    pub initial_bitrate_kbps: u32,
+}
+
+impl Default for BandwidthConfig {
+    fn default() -> Self {
+        Self {
+            min_bitrate_kbps: 300,
+            max_bitrate_kbps: 20000,
+            initial_bitrate_kbps: 3000,
+        }
+    }
+}
+
+/// What the delay trend says about the bottleneck queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BandwidthUsage {
+    /// The queue is stable
+    Normal,
+    /// The queue is growing; the link is saturated
+    Overusing,
+    /// The queue is draining
+    Underusing,
+}
+
+/// Collects datagram arrival times on the viewer
+pub struct FeedbackRecorder {
+    epoch: Instant,
+    arrivals: Vec<DatagramArrival>,
+}
+
+impl Default for FeedbackRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeedbackRecorder {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            arrivals: Vec::new(),
+        }
+    }
+
+    /// Note that the datagram with `header` arrived at `at`
+    ///
+    /// Parity packets are not tracked by the sender and are skipped.
+    pub fn record(&mut self, header: &VideoPacketHeader, at: Instant) {
+        if header.parity.is_some() {
+            return;
+        }
+        self.arrivals.push(DatagramArrival {
+            frame_id: header.frame_id,
+            fragment_index: header.fragment_index,
+            arrival_us: at.saturating_duration_since(self.epoch).as_micros() as u64,
+        });
+    }
+
+    /// Arrivals recorded since the last call, if any
+    pub fn take(&mut self) -> Option<TransportFeedback> {
+        if self.arrivals.is_empty() {
+            return None;
+        }
+        Some(TransportFeedback {
+            arrivals: std::mem::take(&mut self.arrivals),
+        })
+    }
+}
+
+/// A sent datagram awaiting feedback
+struct SentDatagram {
+    at: Instant,
+    size: usize,
+}
+
+/// Datagrams of one frame, as they arrived
+#[derive(Clone, Copy)]
+struct ArrivalGroup {
+    frame_id: u64,
+    last_send: Instant,
+    last_arrival_us: u64,
+}
+
+/// Least-squares slope of the smoothed one-way delay over arrival time
+#[derive(Default)]
+struct Trendline {
+    first_arrival_us: Option<u64>,
+    accumulated_delay: f64,
+    smoothed_delay: f64,
+    deltas: usize,
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl Trendline {
+    /// Add one group's delay variation in ms; returns the scaled trend
+    /// once the window is full
+    fn update(&mut self, delay_variation_ms: f64, arrival_us: u64) -> Option<f64> {
+        let first = *self.first_arrival_us.get_or_insert(arrival_us);
+        self.deltas = (self.deltas + 1).min(TRENDLINE_MAX_DELTAS);
+        self.accumulated_delay += delay_variation_ms;
+        self.smoothed_delay = TRENDLINE_SMOOTHING * self.smoothed_delay
+            + (1.0 - TRENDLINE_SMOOTHING) * self.accumulated_delay;
+
+        if self.samples.len() == TRENDLINE_WINDOW {
+            self.samples.pop_front();
+        }
+        let x = arrival_us.saturating_sub(first) as f64 / 1000.0;
+        self.samples.push_back((x, self.smoothed_delay));
+        if self.samples.len() < TRENDLINE_WINDOW {
+            return None;
+        }
+        let slope = linear_fit(&self.samples)?;
+        Some(slope * self.deltas as f64 * TRENDLINE_GAIN)
+    }
+}
+
+fn linear_fit(points: &VecDeque<(f64, f64)>) -> Option<f64> {
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|&(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / n;
+    let (mut numerator, mut denominator) = (0.0, 0.0);
+    for &(x, y) in points {
+        numerator += (x - mean_x) * (y - mean_y);
+        denominator += (x - mean_x) * (x - mean_x);
+    }
+    (denominator > 0.0).then(|| numerator / denominator)
+}
+
+/// Compares the trend with a threshold that adapts to it, so competing
+/// TCP-like flows don't starve the video
+struct OveruseDetector {
+    threshold: f64,
+    last_update_ms: Option<f64>,
+    overuse_time_ms: Option<f64>,
+    overuse_count: u32,
+    previous_trend: f64,
+    usage: BandwidthUsage,
+}
+
+impl OveruseDetector {
+    fn new() -> Self {
+        Self {
+            threshold: THRESHOLD_INITIAL,
+            last_update_ms: None,
+            overuse_time_ms: None,
+            overuse_count: 0,
+            previous_trend: 0.0,
+            usage: BandwidthUsage::Normal,
+        }
+    }
+
+    fn detect(&mut self, trend: f64, send_delta_ms: f64, now_ms: f64) {
+        if trend > self.threshold {
+            let time = self
+                .overuse_time_ms
+                .map_or(send_delta_ms / 2.0, |time| time + send_delta_ms);
+            self.overuse_time_ms = Some(time);
+            self.overuse_count += 1;
+            if time > OVERUSE_TIME_MS && self.overuse_count > 1 && trend >= self.previous_trend {
+                self.overuse_time_ms = None;
+                self.overuse_count = 0;
+                self.usage = BandwidthUsage::Overusing;
+            }
+        } else {
+            self.overuse_time_ms = None;
+            self.overuse_count = 0;
+            self.usage = if trend < -self.threshold {
+                BandwidthUsage::Underusing
+            } else {
+                BandwidthUsage::Normal
+            };
+        }
+        self.previous_trend = trend;
+        self.adapt_threshold(trend, now_ms);
+    }
+
+    fn adapt_threshold(&mut self, trend: f64, now_ms: f64) {
+        let last = self.last_update_ms.replace(now_ms).unwrap_or(now_ms);
+        if trend.abs() > self.threshold + THRESHOLD_SPIKE {
+            return;
+        }
+        let gain = if trend.abs() < self.threshold {
+            THRESHOLD_GAIN_DOWN
+        } else {
+            THRESHOLD_GAIN_UP
+        };
+        let elapsed = (now_ms - last).clamp(0.0, 100.0);
+        self.threshold = (self.threshold + gain * (trend.abs() - self.threshold) * elapsed)
+            .clamp(THRESHOLD_MIN, THRESHOLD_MAX);
+    }
+}
+
+/// Throughput the link sustained when it last saturated, in kbps
+#[derive(Clone, Copy)]
+struct LinkCapacity {
+    estimate: f64,
+    variance: f64,
+}
+
+impl LinkCapacity {
+    fn new(sample: f64) -> Self {
+        Self {
+            estimate: sample,
+            variance: 0.4,
+        }
+    }
+
+    fn update(&mut self, sample: f64) {
+        self.estimate += CAPACITY_WEIGHT * (sample - self.estimate);
+        let error = self.estimate - sample;
+        self.variance = ((1.0 - CAPACITY_WEIGHT) * self.variance
+            + CAPACITY_WEIGHT * error * error / self.estimate.max(1.0))
+        .clamp(0.4, 2.5);
+    }
+
+    /// Half-width of the band the capacity is believed to lie in
+    fn deviation(&self) -> f64 {
+        3.0 * (self.variance * self.estimate).sqrt()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RateState {
+    Hold,
+    Increase,
+    Decrease,
+}
+
+/// Send-side estimate of the bitrate the path can carry
+pub struct BandwidthEstimator {
+    config: BandwidthConfig,
+    sent: BTreeMap<(u64, u16), SentDatagram>,
+    group: Option<ArrivalGroup>,
+    previous_group: Option<ArrivalGroup>,
+    trendline: Trendline,
+    detector: OveruseDetector,
+    /// Recently received datagrams as (arrival, bytes)
+    received: VecDeque<(u64, usize)>,
+    /// Target in kbps
+    rate: f64,
+    state: RateState,
+    capacity: Option<LinkCapacity>,
+    last_increase: Option<Instant>,
+    last_decrease: Option<Instant>,
+    rtt: Duration,
+    loss_rate: f64,
+    /// Cumulative (sent, lost) counters at the last loss update
+    loss_counters: Option<(u64, u64)>,
+}
+
+impl BandwidthEstimator {
+    pub fn new(config: BandwidthConfig) -> Self {
+        let rate = config.initial_bitrate_kbps as f64;
+        Self {
+            config,
+            sent: BTreeMap::new(),
+            group: None,
+            previous_group: None,
+            trendline: Trendline::default(),
+            detector: OveruseDetector::new(),
+            received: VecDeque::new(),
+            rate,
+            state: RateState::Increase,
+            capacity: None,
+            last_increase: None,
+            last_decrease: None,
+            rtt: Duration::from_millis(100),
+            loss_rate: 0.0,
+            loss_counters: None,
+        }
+    }
+
+    /// Record that a video datagram of `size` bytes was sent at `now`
+    ///
+    /// A resent fragment replaces the earlier record, so its delay is
+    /// measured from the resend.
+    pub fn on_sent(&mut self, frame_id: u64, fragment_index: u16, size: usize, now: Instant) {
+        self.sent
+            .insert((frame_id, fragment_index), SentDatagram { at: now, size });
+        while let Some(entry) = self.sent.first_entry() {
+            if now.saturating_duration_since(entry.get().at) <= SENT_HISTORY {
+                break;
+            }
+            entry.remove();
+        }
+    }
+
+    /// Fold in the viewer's report of arrivals and update the target
+    pub fn on_feedback(&mut self, feedback: &TransportFeedback, now: Instant) {
+        for arrival in &feedback.arrivals {
+            let key = (arrival.frame_id, arrival.fragment_index);
+            let Some(sent) = self.sent.remove(&key) else {
+                continue;
+            };
+            self.received.push_back((arrival.arrival_us, sent.size));
+
+            match &mut self.group {
+                Some(group) if group.frame_id == arrival.frame_id => {
+                    group.last_send = group.last_send.max(sent.at);
+                    group.last_arrival_us = group.last_arrival_us.max(arrival.arrival_us);
+                }
+                current => {
+                    let next = ArrivalGroup {
+                        frame_id: arrival.frame_id,
+                        last_send: sent.at,
+                        last_arrival_us: arrival.arrival_us,
+                    };
+                    if let Some(done) = current.replace(next) {
+                        self.complete_group(done);
+                    }
+                }
+            }
+        }
+
+        let newest = self.received.back().map_or(0, |&(at, _)| at);
+        let window = THROUGHPUT_WINDOW.as_micros() as u64;
+        while let Some(&(at, _)) = self.received.front() {
+            if at + window >= newest {
+                break;
+            }
+            self.received.pop_front();
+        }
+
+        self.update_rate(now);
+    }
+
+    /// Fold in the connection's cumulative packet counters
+    ///
+    /// Meant to be called about once a second. Loss over the interval
+    /// since the previous call above 10% cuts the target, and any loss
+    /// above 2% stops it from growing.
+    pub fn update_loss(&mut self, packets_sent: u64, packets_lost: u64) {
+        let previous = self.loss_counters.replace((packets_sent, packets_lost));
+        let Some((previous_sent, previous_lost)) = previous else {
+            return;
+        };
+        let sent = packets_sent.saturating_sub(previous_sent);
+        if sent == 0 {
+            return;
+        }
+        let lost = packets_lost.saturating_sub(previous_lost).min(sent);
+        self.loss_rate = lost as f64 / sent as f64;
+        if self.loss_rate > HIGH_LOSS {
+            self.rate *= 1.0 - 0.5 * self.loss_rate;
+            self.clamp_rate();
+        }
+    }
+
+    /// Use the latest round-trip time, which paces rate decreases
+    pub fn set_rtt(&mut self, rtt: Duration) {
+        self.rtt = rtt;
+    }
+
+    /// Bitrate the encoder should aim for
+    pub fn target_bitrate_kbps(&self) -> u32 {
+        self.rate.round() as u32
+    }
+
+    /// Current reading of the delay trend
+    pub fn usage(&self) -> BandwidthUsage {
+        self.detector.usage
+    }
+
+    /// Throughput the viewer received over the last half second, in kbps
+    pub fn received_bitrate_kbps(&self) -> Option<u32> {
+        self.received_rate().map(|rate| rate.round() as u32)
+    }
+
+    fn received_rate(&self) -> Option<f64> {
+        let (&(first, _), &(last, _)) = (self.received.front()?, self.received.back()?);
+        let span = last.saturating_sub(first);
+        if span < THROUGHPUT_WINDOW.as_micros() as u64 / 4 {
+            return None;
+        }
+        // The first datagram marks the window's start; its bytes arrived
+        // before it
+        let bytes: usize = self.received.iter().skip(1).map(|&(_, size)| size).sum();
+        Some(bytes as f64 * 8.0 * 1000.0 / span as f64)
+    }
+
+    fn complete_group(&mut self, group: ArrivalGroup) {
+        if let Some(previous) = self.previous_group {
+            // Reordered frames say nothing about the queue
+            if group.frame_id <= previous.frame_id {
+                return;
+            }
+            let send_delta = group
+                .last_send
+                .saturating_duration_since(previous.last_send);
+            let send_delta_ms = send_delta.as_secs_f64() * 1000.0;
+            let arrival_delta_ms =
+                (group.last_arrival_us as f64 - previous.last_arrival_us as f64) / 1000.0;
+            if arrival_delta_ms >= 0.0 {
+                let variation = arrival_delta_ms - send_delta_ms;
+                if let Some(trend) = self.trendline.update(variation, group.last_arrival_us) {
+                    let now_ms = group.last_arrival_us as f64 / 1000.0;
+                    self.detector.detect(trend, send_delta_ms, now_ms);
+                }
+            }
+        }
+        self.previous_group = Some(group);
+    }
+
+    fn update_rate(&mut self, now: Instant) {
+        let received = self.received_rate();
+        self.state = match (self.detector.usage, self.state) {
+            (BandwidthUsage::Overusing, _) => RateState::Decrease,
+            (BandwidthUsage::Underusing, _) => RateState::Hold,
+            (BandwidthUsage::Normal, RateState::Hold) => RateState::Increase,
+            (BandwidthUsage::Normal, state) => state,
+        };
+
+        // Throughput outside the known band means the path changed
+        if let (Some(capacity), Some(received)) = (self.capacity, received)
+            && (received - capacity.estimate).abs() > capacity.deviation()
+            && (self.state == RateState::Increase || received < capacity.estimate)
+        {
+            self.capacity = None;
+        }
+
+        match self.state {
+            RateState::Hold => self.last_increase = None,
+            RateState::Increase => {
+                if self.loss_rate >= LOW_LOSS {
+                    self.last_increase = None;
+                    return;
+                }
+                let elapsed = self
+                    .last_increase
+                    .replace(now)
+                    .map_or(Duration::ZERO, |last| now.saturating_duration_since(last))
+                    .min(Duration::from_secs(1))
+                    .as_secs_f64();
+                if self.capacity.is_some() {
+                    // Near the known capacity, probe by about one packet
+                    // per response time
+                    let response = self.rtt + Duration::from_millis(100);
+                    let packet_kbits = (MAX_DATAGRAM_SIZE * 8) as f64 / 1000.0;
+                    self.rate += (packet_kbits / response.as_secs_f64()).max(4.0) * elapsed;
+                } else {
+                    self.rate *= MULTIPLICATIVE_INCREASE.powf(elapsed);
+                }
+                // Don't run far ahead of what actually gets through
+                if let Some(received) = received {
+                    self.rate = self.rate.min(1.5 * received + 10.0);
+                }
+            }
+            RateState::Decrease => {
+                let interval = self
+                    .rtt
+                    .clamp(Duration::from_millis(10), Duration::from_millis(200));
+                if self
+                    .last_decrease
+                    .is_some_and(|last| now.saturating_duration_since(last) < interval)
+                {
+                    return;
+                }
+                let base = received.unwrap_or(self.rate);
+                self.rate = self.rate.min(DECREASE_FACTOR * base);
+                match &mut self.capacity {
+                    Some(capacity) => capacity.update(base),
+                    None => self.capacity = Some(LinkCapacity::new(base)),
+                }
+                self.last_decrease = Some(now);
+                self.last_increase = None;
+                self.state = RateState::Hold;
+            }
+        }
+        self.clamp_rate();
+    }
+
+    fn clamp_rate(&mut self) {
+        self.rate = self.rate.clamp(
+            self.config.min_bitrate_kbps as f64,
+            self.config.max_bitrate_kbps as f64,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bottleneck with a drop-tail queue and fixed propagation delay
+    struct SimLink {
+        capacity_kbps: f64,
+        propagation: Duration,
+        max_queue: Duration,
+        /// When the link finishes sending what is queued
+        free_at: Duration,
+        random_loss: f64,
+        rng: u64,
+        sent: u64,
+        lost: u64,
+    }
+
+    impl SimLink {
+        fn new(capacity_kbps: f64) -> Self {
+            Self {
+                capacity_kbps,
+                propagation: Duration::from_millis(20),
+                max_queue: Duration::from_millis(300),
+                free_at: Duration::ZERO,
+                random_loss: 0.0,
+                rng: 0x2545_f491_4f6c_dd1d,
+                sent: 0,
+                lost: 0,
+            }
+        }
+
+        /// Arrival time of `bytes` sent at `at`, or `None` if dropped
+        fn send(&mut self, at: Duration, bytes: usize) -> Option<Duration> {
+            self.sent += 1;
+            self.rng = self
+                .rng
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            let draw = (self.rng >> 11) as f64 / (1u64 << 53) as f64;
+            let start = self.free_at.max(at);
+            if start - at > self.max_queue || draw < self.random_loss {
+                self.lost += 1;
+                return None;
+            }
+            self.free_at =
+                start + Duration::from_secs_f64(bytes as f64 * 8.0 / (self.capacity_kbps * 1000.0));
+            Some(self.free_at + self.propagation)
+        }
+
+        fn queue_delay(&self, now: Duration) -> Duration {
+            self.free_at.saturating_sub(now)
+        }
+    }
+
+    /// Target and queueing delay sampled every 100ms
+    struct Sample {
+        at: Duration,
+        target_kbps: u32,
+        queue: Duration,
+    }
+
+    /// Stream 30 fps video sized to the estimator's target over a link
+    /// whose capacity follows `script`, a list of (until, kbps)
+    fn simulate(script: &[(Duration, f64)], random_loss: f64) -> Vec<Sample> {
+        let epoch = Instant::now();
+        let mut estimator = BandwidthEstimator::new(BandwidthConfig::default());
+        let mut link = SimLink::new(script[0].1);
+        link.random_loss = random_loss;
+        estimator.set_rtt(2 * link.propagation);
+
+        // Datagrams on their way to the viewer, and feedback on its way back
+        let mut in_flight: VecDeque<(Duration, DatagramArrival)> = VecDeque::new();
+        let mut feedback: VecDeque<(Duration, TransportFeedback)> = VecDeque::new();
+        let mut pending = Vec::new();
+        let mut samples = Vec::new();
+        let mut frame_id = 0u64;
+
+        let end = script.last().unwrap().0;
+        let mut now = Duration::ZERO;
+        while now < end {
+            let ms = now.as_millis() as u64;
+            link.capacity_kbps = script.iter().find(|&&(until, _)| now < until).unwrap().1;
+
+            if ms.is_multiple_of(33) {
+                frame_id += 1;
+                let mut remaining = estimator.target_bitrate_kbps() as usize * 1000 / 8 / 30;
+                let mut fragment_index = 0u16;
+                while remaining > 0 {
+                    let size = remaining.min(MAX_DATAGRAM_SIZE);
+                    remaining -= size;
+                    estimator.on_sent(frame_id, fragment_index, size, epoch + now);
+                    if let Some(arrival) = link.send(now, size) {
+                        let report = DatagramArrival {
+                            frame_id,
+                            fragment_index,
+                            arrival_us: arrival.as_micros() as u64,
+                        };
+                        in_flight.push_back((arrival, report));
+                    }
+                    fragment_index += 1;
+                }
+            }
+
+            // The link is FIFO, so arrivals come out in order
+            while in_flight.front().is_some_and(|&(at, _)| at <= now) {
+                pending.push(in_flight.pop_front().unwrap().1);
+            }
+            if ms.is_multiple_of(50) && !pending.is_empty() {
+                let report = TransportFeedback {
+                    arrivals: std::mem::take(&mut pending),
+                };
+                feedback.push_back((now + link.propagation, report));
+            }
+            while feedback.front().is_some_and(|&(at, _)| at <= now) {
+                estimator.on_feedback(&feedback.pop_front().unwrap().1, epoch + now);
+            }
+            if ms.is_multiple_of(1000) {
+                estimator.update_loss(link.sent, link.lost);
+            }
+            if ms.is_multiple_of(100) {
+                samples.push(Sample {
+                    at: now,
+                    target_kbps: estimator.target_bitrate_kbps(),
+                    queue: link.queue_delay(now),
+                });
+            }
+            now += Duration::from_millis(1);
+        }
+        samples
+    }
+
+    fn between(samples: &[Sample], from: u64, to: u64) -> impl Iterator<Item = &Sample> {
+        samples
+            .iter()
+            .filter(move |s| s.at >= Duration::from_secs(from) && s.at < Duration::from_secs(to))
+    }
+
+    fn mean_target(samples: &[Sample], from: u64, to: u64) -> f64 {
+        let targets: Vec<_> = between(samples, from, to)
+            .map(|s| s.target_kbps as f64)
+            .collect();
+        targets.iter().sum::<f64>() / targets.len() as f64
+    }
+
+    fn max_queue(samples: &[Sample], from: u64, to: u64) -> Duration {
+        between(samples, from, to).map(|s| s.queue).max().unwrap()
+    }
+
+    #[test]
+    fn test_recorder_skips_parity() {
+        let header = VideoPacketHeader {
+            frame_id: 7,
+            fragment_index: 2,
+            total_fragments: 4,
+            timestamp_us: 0,
+            frame_type: shared_protocol::FrameType::Delta,
+            codec: shared_protocol::VideoCodec::H264,
+            width: 64,
+            height: 64,
+            dirty_rect: None,
+            resolution_changed: false,
+            slice_aligned: false,
+            temporal_id: 0,
+            reference_frame_id: 0,
+            parity: None,
+        };
+        let mut recorder = FeedbackRecorder::new();
+        assert!(recorder.take().is_none());
+
+        let now = Instant::now();
+        recorder.record(&header, now);
+        let parity = VideoPacketHeader {
+            parity: Some(shared_protocol::FecParity {
+                first_fragment: 0,
+                fragment_count: 4,
+                length_xor: 0,
+            }),
+            ..header.clone()
+        };
+        recorder.record(&parity, now);
+
+        let feedback = recorder.take().unwrap();
+        assert_eq!(feedback.arrivals.len(), 1);
+        assert_eq!(feedback.arrivals[0].frame_id, 7);
+        assert_eq!(feedback.arrivals[0].fragment_index, 2);
+        assert!(recorder.take().is_none());
+    }
+
+    #[test]
+    fn test_rate_settles_below_capacity() {
+        let samples = simulate(&[(Duration::from_secs(30), 4000.0)], 0.0);
+
+        let settled = mean_target(&samples, 15, 30);
+        assert!(
+            (2500.0..4400.0).contains(&settled),
+            "settled at {} kbps",
+            settled
+        );
+        let queue = max_queue(&samples, 15, 30);
+        assert!(queue < Duration::from_millis(150), "queue {:?}", queue);
+    }
+
+    #[test]
+    fn test_rate_follows_capacity_changes() {
+        let samples = simulate(
+            &[
+                (Duration::from_secs(15), 6000.0),
+                (Duration::from_secs(35), 1500.0),
+                (Duration::from_secs(70), 6000.0),
+            ],
+            0.0,
+        );
+
+        let before = mean_target(&samples, 10, 15);
+        assert!(before > 3500.0, "before the drop: {} kbps", before);
+
+        // Within two seconds of the drop the target is under the new
+        // capacity, and the queue it built drains
+        let after_drop = mean_target(&samples, 17, 35);
+        assert!(after_drop < 1500.0, "after the drop: {} kbps", after_drop);
+        let queue = max_queue(&samples, 25, 35);
+        assert!(queue < Duration::from_millis(150), "queue {:?}", queue);
+
+        let recovered = mean_target(&samples, 60, 70);
+        assert!(recovered > 3500.0, "after recovery: {} kbps", recovered);
+    }
+
+    #[test]
+    fn test_heavy_loss_lowers_rate() {
+        let clean = simulate(&[(Duration::from_secs(20), 50_000.0)], 0.0);
+        let lossy = simulate(&[(Duration::from_secs(20), 50_000.0)], 0.2);
+
+        let (clean, lossy) = (mean_target(&clean, 15, 20), mean_target(&lossy, 15, 20));
+        assert!(clean > 3000.0, "clean: {} kbps", clean);
+        assert!(lossy < 2000.0, "lossy: {} kbps", lossy);
+    }
+}
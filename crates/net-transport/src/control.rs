@@ -0,0 +1,170 @@
+//! Reliable control channel
+//!
+//! Session control messages travel over a single QUIC bidirectional
+//! stream, each framed as a big-endian `u32` length followed by the
+//! bincode-encoded [`SessionMessage`]. The viewer opens the stream and
+//! the host accepts it.
+
+use quinn::{RecvStream, SendStream};
+use shared_protocol::SessionMessage;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{TransportError, TransportResult};
+
+/// Largest control message accepted from the peer
+pub const MAX_CONTROL_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Writing half of the control channel
+pub struct ControlSender<W = SendStream> {
+    stream: W,
+}
+
+/// Reading half of the control channel
+pub struct ControlReceiver<R = RecvStream> {
+    stream: R,
+}
+
+/// Wrap a stream pair as a control channel
+pub fn control_channel<W, R>(send: W, recv: R) -> (ControlSender<W>, ControlReceiver<R>)
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    (
+        ControlSender { stream: send },
+        ControlReceiver { stream: recv },
+    )
+}
+
+impl<W: AsyncWrite + Unpin> ControlSender<W> {
+    /// Send one message
+    pub async fn send(&mut self, message: &SessionMessage) -> TransportResult<()> {
+        let payload = message
+            .to_bytes()
+            .map_err(|e| TransportError::Send(e.to_string()))?;
+        write_frame(
+            &mut self.stream,
+            &payload,
+            MAX_CONTROL_MESSAGE_SIZE,
+            "Control",
+        )
+        .await
+    }
+}
+
+impl<R: AsyncRead + Unpin> ControlReceiver<R> {
+    /// Receive the next message
+    ///
+    /// Returns `ConnectionClosed` once the peer finishes the stream.
+    pub async fn recv(&mut self) -> TransportResult<SessionMessage> {
+        let payload = read_frame(&mut self.stream, MAX_CONTROL_MESSAGE_SIZE, "Control").await?;
+        SessionMessage::from_bytes(&payload).map_err(|e| TransportError::Receive(e.to_string()))
+    }
+}
+
+/// Write `payload` with its length prefix
+pub(crate) async fn write_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    payload: &[u8],
+    max_size: usize,
+    kind: &str,
+) -> TransportResult<()> {
+    if payload.len() > max_size {
+        return Err(TransportError::Send(format!(
+            "{} message too large: {} bytes",
+            kind,
+            payload.len()
+        )));
+    }
+
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read the next length-prefixed payload
+///
+/// Returns `ConnectionClosed` once the peer finishes the stream.
+pub(crate) async fn read_frame<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    max_size: usize,
+    kind: &str,
+) -> TransportResult<Vec<u8>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Err(TransportError::ConnectionClosed(format!(
+                "{} stream finished",
+                kind
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_size {
+        return Err(TransportError::Receive(format!(
+            "{} message too large: {} bytes",
+            kind, len
+        )));
+    }
+
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_protocol::{DisplaySelection, RemoteDisplay};
+
+    #[tokio::test]
+    async fn test_messages_round_trip_in_order() {
+        let (a, b) = tokio::io::duplex(256);
+        let (a_read, a_write) = tokio::io::split(a);
+        let (b_read, b_write) = tokio::io::split(b);
+        let (mut sender, _) = control_channel(a_write, a_read);
+        let (_, mut receiver) = control_channel(b_write, b_read);
+
+        let displays = SessionMessage::Displays {
+            displays: vec![RemoteDisplay {
+                id: 7,
+                name: "DP-1".to_string(),
+                width: 2560,
+                height: 1440,
+                x: 1920,
+                y: 0,
+                is_primary: false,
+            }],
+            active: DisplaySelection::Display(7),
+        };
+
+        let send = async {
+            sender.send(&SessionMessage::ListDisplays).await.unwrap();
+            sender.send(&displays).await.unwrap();
+            drop(sender);
+        };
+        let recv = async {
+            let first = receiver.recv().await.unwrap();
+            let second = receiver.recv().await.unwrap();
+            let closed = receiver.recv().await;
+            (first, second, closed)
+        };
+        let (_, (first, second, closed)) = tokio::join!(send, recv);
+
+        assert!(matches!(first, SessionMessage::ListDisplays));
+        match second {
+            SessionMessage::Displays { displays, active } => {
+                assert_eq!(displays[0].x, 1920);
+                assert_eq!(active, DisplaySelection::Display(7));
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        assert!(matches!(closed, Err(TransportError::ConnectionClosed(_))));
+    }
+}
@@ -0,0 +1,295 @@
+//! Forward error correction for video datagrams
+//!
+//! [`FecEncoder`] splits each packetized frame into groups of consecutive
+//! fragments and adds one XOR parity packet per group, so the
+//! [`FrameAssembler`](crate::FrameAssembler) can rebuild one lost
+//! fragment per group without waiting for a retransmission or keyframe.
+//! Groups shrink as the measured loss rate grows, and no parity is sent
+//! on a loss-free link.
+
+use shared_protocol::{FecParity, VideoPacket, VideoPacketHeader};
+
+/// Largest number of fragments covered by one parity packet
+pub const MAX_FEC_GROUP: usize = 16;
+
+/// Acceptable chance that a group loses more fragments than parity can
+/// rebuild
+const TARGET_GROUP_LOSS: f64 = 0.01;
+
+/// Weight of the newest interval in the smoothed loss rate
+const LOSS_WEIGHT: f64 = 0.25;
+
+/// Adds parity packets to packetized frames
+#[derive(Debug, Clone, Default)]
+pub struct FecEncoder {
+    loss_rate: f64,
+    /// Cumulative (sent, lost) counters at the last update
+    counters: Option<(u64, u64)>,
+}
+
+impl FecEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fold in the connection's cumulative packet counters
+    ///
+    /// The loss over the interval since the previous call is smoothed
+    /// into the loss rate; intervals without sent packets are ignored.
+    pub fn update_loss(&mut self, packets_sent: u64, packets_lost: u64) {
+        let previous = self.counters.replace((packets_sent, packets_lost));
+        let Some((previous_sent, previous_lost)) = previous else {
+            return;
+        };
+        let sent = packets_sent.saturating_sub(previous_sent);
+        if sent == 0 {
+            return;
+        }
+        let lost = packets_lost.saturating_sub(previous_lost).min(sent);
+        let interval = lost as f64 / sent as f64;
+        self.loss_rate += LOSS_WEIGHT * (interval - self.loss_rate);
+    }
+
+    /// Use `loss_rate` as the measured rate, replacing the smoothed one
+    pub fn set_loss_rate(&mut self, loss_rate: f64) {
+        self.loss_rate = loss_rate.clamp(0.0, 1.0);
+    }
+
+    /// Smoothed fraction of packets lost
+    pub fn loss_rate(&self) -> f64 {
+        self.loss_rate
+    }
+
+    /// Fragments covered by each parity packet, or `None` when the link
+    /// is loss-free and no parity is sent
+    ///
+    /// The largest group whose chance of losing two or more packets,
+    /// parity included, stays within the target; at worst every
+    /// fragment is sent twice.
+    pub fn group_size(&self) -> Option<usize> {
+        let p = self.loss_rate;
+        if p <= 0.0 {
+            return None;
+        }
+        let unrecoverable = |group: usize| {
+            let packets = group as i32 + 1;
+            1.0 - (1.0 - p).powi(packets) - packets as f64 * p * (1.0 - p).powi(packets - 1)
+        };
+        Some(
+            (1..=MAX_FEC_GROUP)
+                .rev()
+                .find(|&group| unrecoverable(group) <= TARGET_GROUP_LOSS)
+                .unwrap_or(1),
+        )
+    }
+
+    /// Parity packets for one frame's `packets`, to send after them
+    ///
+    /// The fragments are divided into groups of nearly equal size no
+    /// larger than [`group_size`](Self::group_size).
+    pub fn protect(&self, packets: &[VideoPacket]) -> Vec<VideoPacket> {
+        let Some(group_size) = self.group_size() else {
+            return Vec::new();
+        };
+        if packets.is_empty() {
+            return Vec::new();
+        }
+
+        let groups = packets.len().div_ceil(group_size);
+        let (base, larger) = (packets.len() / groups, packets.len() % groups);
+        let mut parity = Vec::with_capacity(groups);
+        let mut first = 0;
+        for group in 0..groups {
+            let count = base + usize::from(group < larger);
+            let covered = &packets[first..first + count];
+            let mut payload = Vec::new();
+            let mut length_xor = 0u16;
+            for packet in covered {
+                xor_into(&mut payload, &packet.payload);
+                length_xor ^= packet.payload.len() as u16;
+            }
+            parity.push(VideoPacket {
+                header: VideoPacketHeader {
+                    parity: Some(FecParity {
+                        first_fragment: covered[0].header.fragment_index,
+                        fragment_count: count as u16,
+                        length_xor,
+                    }),
+                    ..covered[0].header.clone()
+                },
+                payload,
+            });
+            first += count;
+        }
+        parity
+    }
+}
+
+/// XOR `data` into `accumulator`, growing it with zeros to fit
+pub(crate) fn xor_into(accumulator: &mut Vec<u8>, data: &[u8]) {
+    if accumulator.len() < data.len() {
+        accumulator.resize(data.len(), 0);
+    }
+    for (a, &b) in accumulator.iter_mut().zip(data) {
+        *a ^= b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use shared_protocol::{FrameType, VideoCodec};
+
+    use super::*;
+    use crate::{FrameAssembler, Packetizer};
+
+    fn header(frame_id: u64) -> VideoPacketHeader {
+        VideoPacketHeader {
+            frame_id,
+            fragment_index: 0,
+            total_fragments: 1,
+            timestamp_us: 0,
+            frame_type: FrameType::Delta,
+            codec: VideoCodec::H264,
+            width: 64,
+            height: 64,
+            dirty_rect: None,
+            resolution_changed: false,
+            slice_aligned: false,
+            temporal_id: 0,
+            reference_frame_id: 0,
+            parity: None,
+        }
+    }
+
+    /// Frame payload of `len` bytes that differs per frame
+    fn payload(frame_id: u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u64 * 31 + frame_id * 7) as u8)
+            .collect()
+    }
+
+    /// In-memory link dropping packets pseudo-randomly at `loss_rate`
+    struct LossyLink {
+        state: u64,
+        loss_rate: f64,
+    }
+
+    impl LossyLink {
+        fn new(loss_rate: f64) -> Self {
+            Self {
+                state: 0x2545_f491_4f6c_dd1d,
+                loss_rate,
+            }
+        }
+
+        fn delivers(&mut self) -> bool {
+            self.state = self
+                .state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            ((self.state >> 11) as f64 / (1u64 << 53) as f64) >= self.loss_rate
+        }
+    }
+
+    /// Send `frames` frames over a link losing `loss_rate` of packets;
+    /// returns how many arrived complete
+    fn complete_frames(fec: &FecEncoder, loss_rate: f64, frames: u64) -> u64 {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let mut link = LossyLink::new(loss_rate);
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        let mut complete = 0;
+        for frame_id in 1..=frames {
+            let data = payload(frame_id, 9000 + (frame_id as usize % 7) * 500);
+            let packets = packetizer.packetize(&header(frame_id), &data, &[]).unwrap();
+            let parity = fec.protect(&packets);
+            for packet in packets.into_iter().chain(parity) {
+                if !link.delivers() {
+                    continue;
+                }
+                for frame in assembler.push(packet) {
+                    assert_eq!(frame.packet.payload, payload(frame_id, data.len()));
+                    complete += u64::from(frame.complete);
+                }
+            }
+        }
+        complete
+    }
+
+    #[test]
+    fn test_group_size_follows_loss() {
+        let mut fec = FecEncoder::new();
+        assert_eq!(fec.group_size(), None);
+        assert!(
+            fec.protect(&[VideoPacket {
+                header: header(1),
+                payload: vec![1],
+            }])
+            .is_empty()
+        );
+
+        let mut previous = MAX_FEC_GROUP;
+        for loss in [0.001, 0.01, 0.03, 0.1, 0.3] {
+            fec.set_loss_rate(loss);
+            let group = fec.group_size().unwrap();
+            assert!(group <= previous, "{} at {}", group, loss);
+            previous = group;
+        }
+        fec.set_loss_rate(0.001);
+        assert_eq!(fec.group_size(), Some(MAX_FEC_GROUP));
+        fec.set_loss_rate(0.3);
+        assert_eq!(fec.group_size(), Some(1));
+    }
+
+    #[test]
+    fn test_loss_rate_tracks_counter_deltas() {
+        let mut fec = FecEncoder::new();
+        fec.update_loss(1000, 500);
+        assert_eq!(fec.loss_rate(), 0.0);
+
+        // Each interval loses 10%; the smoothed rate converges on it
+        for interval in 1..=20 {
+            fec.update_loss(1000 + interval * 100, 500 + interval * 10);
+        }
+        assert!((fec.loss_rate() - 0.1).abs() < 0.01);
+
+        // An idle interval changes nothing
+        let before = fec.loss_rate();
+        fec.update_loss(3000, 700);
+        assert_eq!(fec.loss_rate(), before);
+    }
+
+    #[test]
+    fn test_parity_groups_cover_every_fragment_once() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let data = payload(1, 20_000);
+        let packets = packetizer.packetize(&header(1), &data, &[]).unwrap();
+        let mut fec = FecEncoder::new();
+        fec.set_loss_rate(0.02);
+        let group = fec.group_size().unwrap();
+
+        let parity = fec.protect(&packets);
+        assert_eq!(parity.len(), packets.len().div_ceil(group));
+        let mut next = 0;
+        for packet in &parity {
+            let info = packet.header.parity.unwrap();
+            assert_eq!(info.first_fragment, next);
+            assert!(info.fragment_count as usize <= group);
+            assert!(packet.to_bytes().unwrap().len() <= 1200);
+            next += info.fragment_count;
+        }
+        assert_eq!(next as usize, packets.len());
+    }
+
+    #[test]
+    fn test_lossy_link_recovers_with_parity() {
+        let mut fec = FecEncoder::new();
+        let without = complete_frames(&fec, 0.03, 200);
+        fec.set_loss_rate(0.03);
+        let with = complete_frames(&fec, 0.03, 200);
+
+        assert!(without < 170, "without parity: {}", without);
+        assert!(with >= 190, "with parity: {}", with);
+    }
+}
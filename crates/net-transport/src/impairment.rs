@@ -0,0 +1,680 @@
+//! Network impairment for tests
+//!
+//! [`ImpairedLink`] is a UDP proxy that sits between a [`QuicTransport`]
+//! client and server and delays, drops, reorders and rate-limits the
+//! datagrams passing through it, so "works on LAN, breaks on hotel
+//! Wi-Fi" problems can be reproduced on loopback. Each direction runs its
+//! own [`LinkModel`]; a [`LinkScript`] changes their conditions over time.
+//!
+//! The models draw from a seeded generator, so the same traffic meets the
+//! same impairment on every run. Available to this crate's tests, and to
+//! other crates with the `impairment` feature.
+//!
+//! [`QuicTransport`]: crate::QuicTransport
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use crate::TransportResult;
+
+/// How packets are lost, on top of bottleneck overflow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LossModel {
+    None,
+    /// Each packet is lost independently with this probability
+    Random(f64),
+    /// Two-state Gilbert-Elliott channel: losses cluster in bursts while
+    /// the channel is in its bad state
+    GilbertElliott {
+        /// Chance per packet of moving from the good to the bad state
+        good_to_bad: f64,
+        /// Chance per packet of moving from the bad to the good state
+        bad_to_good: f64,
+        /// Loss probability in the good state
+        loss_good: f64,
+        /// Loss probability in the bad state
+        loss_bad: f64,
+    },
+}
+
+/// Impairment applied to one direction of a link
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkConditions {
+    /// One-way propagation delay
+    pub latency: Duration,
+    /// Each packet's delay varies uniformly by up to this much either way;
+    /// packets may overtake each other
+    pub jitter: Duration,
+    pub loss: LossModel,
+    /// Chance that a packet is held back by `reorder_delay`
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    /// Bottleneck rate in kbps; `None` is unlimited
+    pub bandwidth_kbps: Option<u32>,
+    /// Queueing delay at the bottleneck beyond which packets are dropped
+    pub queue_limit: Duration,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: LossModel::None,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            bandwidth_kbps: None,
+            queue_limit: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Packet counts for one direction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Packets that will be or were delivered
+    pub delivered: u64,
+    /// Packets dropped by the loss model
+    pub lost: u64,
+    /// Packets dropped because the bottleneck queue was full
+    pub overflowed: u64,
+    /// Packets that arrive ahead of one sent before them
+    pub reordered: u64,
+}
+
+/// Decides the fate of each packet sent over one direction of a link
+pub struct LinkModel {
+    conditions: LinkConditions,
+    rng: u64,
+    bad_state: bool,
+    /// When the bottleneck finishes sending what is queued
+    free_at: Option<Instant>,
+    /// Latest delivery time handed out so far
+    latest_due: Option<Instant>,
+    stats: LinkStats,
+}
+
+impl LinkModel {
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Self {
+            conditions,
+            // Small seeds still start from well-mixed states
+            rng: seed ^ 0x2545_f491_4f6c_dd1d,
+            bad_state: false,
+            free_at: None,
+            latest_due: None,
+            stats: LinkStats::default(),
+        }
+    }
+
+    pub fn conditions(&self) -> &LinkConditions {
+        &self.conditions
+    }
+
+    /// Change the conditions; packets already queued keep their timing
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// When a packet of `len` bytes sent at `now` arrives, or `None` if
+    /// it is dropped
+    pub fn schedule(&mut self, now: Instant, len: usize) -> Option<Instant> {
+        if self.lose() {
+            self.stats.lost += 1;
+            return None;
+        }
+
+        let mut departure = now;
+        if let Some(kbps) = self.conditions.bandwidth_kbps {
+            let start = self.free_at.map_or(now, |free_at| free_at.max(now));
+            if start - now > self.conditions.queue_limit {
+                self.stats.overflowed += 1;
+                return None;
+            }
+            let bits = len as u64 * 8;
+            departure = start + Duration::from_nanos(bits * 1_000_000 / kbps.max(1) as u64);
+            self.free_at = Some(departure);
+        }
+
+        let jitter = self.conditions.jitter.as_nanos() as f64;
+        let offset = ((self.next_f64() * 2.0 - 1.0) * jitter) as i128;
+        let delay = (self.conditions.latency.as_nanos() as i128 + offset).max(0);
+        let mut due = departure + Duration::from_nanos(delay as u64);
+        if self.conditions.reorder > 0.0 && self.next_f64() < self.conditions.reorder {
+            due += self.conditions.reorder_delay;
+        }
+
+        match self.latest_due {
+            Some(latest) if due < latest => self.stats.reordered += 1,
+            _ => self.latest_due = Some(due),
+        }
+        self.stats.delivered += 1;
+        Some(due)
+    }
+
+    fn lose(&mut self) -> bool {
+        match self.conditions.loss {
+            LossModel::None => false,
+            LossModel::Random(probability) => self.next_f64() < probability,
+            LossModel::GilbertElliott {
+                good_to_bad,
+                bad_to_good,
+                loss_good,
+                loss_bad,
+            } => {
+                let switch = if self.bad_state {
+                    bad_to_good
+                } else {
+                    good_to_bad
+                };
+                if self.next_f64() < switch {
+                    self.bad_state = !self.bad_state;
+                }
+                let probability = if self.bad_state { loss_bad } else { loss_good };
+                self.next_f64() < probability
+            }
+        }
+    }
+
+    /// Uniform in [0, 1) from a 64-bit LCG
+    fn next_f64(&mut self) -> f64 {
+        self.rng = self
+            .rng
+            .wrapping_mul(6_364_136_223_846_793_005)
+            .wrapping_add(1_442_695_040_888_963_407);
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Direction of travel through an [`ImpairedLink`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+/// Conditions to apply at set offsets from the start of a script
+#[derive(Debug, Clone, Default)]
+pub struct LinkScript {
+    steps: Vec<(Duration, Option<Direction>, LinkConditions)>,
+}
+
+impl LinkScript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply `conditions` to both directions `offset` into the script
+    pub fn at(self, offset: Duration, conditions: LinkConditions) -> Self {
+        self.step(offset, None, conditions)
+    }
+
+    /// Apply `conditions` to one direction `offset` into the script
+    pub fn at_direction(
+        self,
+        offset: Duration,
+        direction: Direction,
+        conditions: LinkConditions,
+    ) -> Self {
+        self.step(offset, Some(direction), conditions)
+    }
+
+    fn step(
+        mut self,
+        offset: Duration,
+        direction: Option<Direction>,
+        conditions: LinkConditions,
+    ) -> Self {
+        self.steps.push((offset, direction, conditions));
+        self.steps.sort_by_key(|&(offset, _, _)| offset);
+        self
+    }
+}
+
+type PacketSender = mpsc::UnboundedSender<(Instant, Vec<u8>)>;
+
+/// UDP proxy applying [`LinkConditions`] to the traffic between a client
+/// and a server on this machine
+///
+/// Clients connect to [`local_addr`](Self::local_addr) instead of the
+/// server. The proxy serves one client, the first one to send.
+pub struct ImpairedLink {
+    local_addr: SocketAddr,
+    to_server: Arc<Mutex<LinkModel>>,
+    to_client: Arc<Mutex<LinkModel>>,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ImpairedLink {
+    /// Start proxying to `server`, both directions under `conditions`
+    pub async fn start(server: SocketAddr, conditions: LinkConditions) -> TransportResult<Self> {
+        let client_socket = Arc::new(UdpSocket::bind((server.ip(), 0)).await?);
+        let server_socket = Arc::new(UdpSocket::bind((server.ip(), 0)).await?);
+        server_socket.connect(server).await?;
+        let local_addr = client_socket.local_addr()?;
+
+        let to_server = Arc::new(Mutex::new(LinkModel::new(conditions.clone(), 1)));
+        let to_client = Arc::new(Mutex::new(LinkModel::new(conditions, 2)));
+        let client = Arc::new(Mutex::new(None::<SocketAddr>));
+        let (server_tx, server_rx) = mpsc::unbounded_channel();
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+
+        let tasks = vec![
+            tokio::spawn({
+                let (socket, model, client) =
+                    (client_socket.clone(), to_server.clone(), client.clone());
+                async move {
+                    let mut buf = vec![0u8; 65536];
+                    while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                        client.lock().get_or_insert(from);
+                        forward(&model, &server_tx, &buf[..len]);
+                    }
+                }
+            }),
+            tokio::spawn({
+                let (socket, model) = (server_socket.clone(), to_client.clone());
+                async move {
+                    let mut buf = vec![0u8; 65536];
+                    while let Ok(len) = socket.recv(&mut buf).await {
+                        forward(&model, &client_tx, &buf[..len]);
+                    }
+                }
+            }),
+            tokio::spawn(deliver(server_rx, move |data| {
+                let socket = server_socket.clone();
+                async move {
+                    if let Err(e) = socket.send(&data).await {
+                        debug!("Impaired link failed to reach server: {}", e);
+                    }
+                }
+            })),
+            tokio::spawn(deliver(client_rx, move |data| {
+                let (socket, client) = (client_socket.clone(), *client.lock());
+                async move {
+                    if let Some(client) = client
+                        && let Err(e) = socket.send_to(&data, client).await
+                    {
+                        debug!("Impaired link failed to reach client: {}", e);
+                    }
+                }
+            })),
+        ];
+
+        Ok(Self {
+            local_addr,
+            to_server,
+            to_client,
+            tasks: Mutex::new(tasks),
+        })
+    }
+
+    /// Address clients connect to in place of the server
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Apply `conditions` to both directions
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.to_server.lock().set_conditions(conditions.clone());
+        self.to_client.lock().set_conditions(conditions);
+    }
+
+    /// Apply `conditions` to one direction
+    pub fn set_direction(&self, direction: Direction, conditions: LinkConditions) {
+        self.model(direction).lock().set_conditions(conditions);
+    }
+
+    pub fn stats(&self, direction: Direction) -> LinkStats {
+        self.model(direction).lock().stats()
+    }
+
+    /// Play `script` from now on, in the background
+    pub fn play(&self, script: LinkScript) {
+        let (to_server, to_client) = (self.to_server.clone(), self.to_client.clone());
+        let start = tokio::time::Instant::now();
+        self.tasks.lock().push(tokio::spawn(async move {
+            for (offset, direction, conditions) in script.steps {
+                tokio::time::sleep_until(start + offset).await;
+                debug!("Impaired link now {:?}", conditions);
+                if direction != Some(Direction::ToClient) {
+                    to_server.lock().set_conditions(conditions.clone());
+                }
+                if direction != Some(Direction::ToServer) {
+                    to_client.lock().set_conditions(conditions);
+                }
+            }
+        }));
+    }
+
+    fn model(&self, direction: Direction) -> &Mutex<LinkModel> {
+        match direction {
+            Direction::ToServer => &self.to_server,
+            Direction::ToClient => &self.to_client,
+        }
+    }
+}
+
+impl Drop for ImpairedLink {
+    fn drop(&mut self) {
+        for task in self.tasks.lock().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+/// Run a received packet through `model` and queue it for delivery
+fn forward(model: &Mutex<LinkModel>, queue: &PacketSender, data: &[u8]) {
+    if let Some(due) = model.lock().schedule(Instant::now(), data.len()) {
+        let _ = queue.send((due, data.to_vec()));
+    }
+}
+
+/// Send queued packets with `send` as each falls due, earliest first
+async fn deliver<F, Fut>(mut queue: mpsc::UnboundedReceiver<(Instant, Vec<u8>)>, send: F)
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    // Sequence numbers keep packets due at the same time in order
+    let mut pending = BinaryHeap::new();
+    let mut sequence = 0u64;
+    loop {
+        let next_due = pending.peek().map(|Reverse((due, _, _))| *due);
+        tokio::select! {
+            received = queue.recv() => match received {
+                Some((due, data)) => {
+                    pending.push(Reverse((due, sequence, data)));
+                    sequence += 1;
+                }
+                None => break,
+            },
+            _ = sleep_until(next_due), if next_due.is_some() => {
+                while let Some(Reverse((due, _, _))) = pending.peek()
+                    && *due <= Instant::now()
+                {
+                    let Reverse((_, _, data)) = pending.pop().unwrap();
+                    send(data).await;
+                }
+            }
+        }
+    }
+}
+
+async fn sleep_until(due: Option<Instant>) {
+    if let Some(due) = due {
+        tokio::time::sleep_until(tokio::time::Instant::from_std(due)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::QuicTransport;
+
+    /// Send `count` packets of `len` bytes `interval` apart from `start`;
+    /// returns each one's arrival, if it arrives
+    fn run(
+        model: &mut LinkModel,
+        start: Instant,
+        count: usize,
+        len: usize,
+        interval: Duration,
+    ) -> Vec<Option<Instant>> {
+        (0..count)
+            .map(|i| model.schedule(start + interval * i as u32, len))
+            .collect()
+    }
+
+    /// Lengths of the runs of consecutive losses
+    fn loss_bursts(fates: &[Option<Instant>]) -> Vec<usize> {
+        let mut bursts = Vec::new();
+        let mut run = 0;
+        for fate in fates {
+            if fate.is_none() {
+                run += 1;
+            } else if run > 0 {
+                bursts.push(run);
+                run = 0;
+            }
+        }
+        bursts
+    }
+
+    async fn connected_pair(
+        conditions: LinkConditions,
+    ) -> (QuicTransport, QuicTransport, ImpairedLink) {
+        let server = QuicTransport::new_server("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let link = ImpairedLink::start(server.local_addr().unwrap(), conditions)
+            .await
+            .unwrap();
+        let client = QuicTransport::new_client("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (accepted, connected) = tokio::join!(
+            server.accept(),
+            client.connect(link.local_addr(), "entangle.local")
+        );
+        accepted.unwrap();
+        connected.unwrap();
+        (server, client, link)
+    }
+
+    #[test]
+    fn test_random_and_bursty_loss() {
+        let interval = Duration::from_millis(1);
+        let mut random = LinkModel::new(
+            LinkConditions {
+                loss: LossModel::Random(0.05),
+                ..Default::default()
+            },
+            7,
+        );
+        let fates = run(&mut random, Instant::now(), 20_000, 1200, interval);
+        let lost = fates.iter().filter(|fate| fate.is_none()).count();
+        assert!((800..1200).contains(&lost), "lost {}", lost);
+        assert_eq!(random.stats().lost, lost as u64);
+        let random_bursts = loss_bursts(&fates);
+
+        // About the same average loss, but in runs while the channel is bad
+        let mut bursty = LinkModel::new(
+            LinkConditions {
+                loss: LossModel::GilbertElliott {
+                    good_to_bad: 0.01,
+                    bad_to_good: 0.2,
+                    loss_good: 0.0,
+                    loss_bad: 0.9,
+                },
+                ..Default::default()
+            },
+            7,
+        );
+        let fates = run(&mut bursty, Instant::now(), 20_000, 1200, interval);
+        let lost = fates.iter().filter(|fate| fate.is_none()).count();
+        assert!((600..1400).contains(&lost), "lost {}", lost);
+        let bursty_bursts = loss_bursts(&fates);
+
+        let mean = |bursts: &[usize]| bursts.iter().sum::<usize>() as f64 / bursts.len() as f64;
+        assert!(
+            mean(&bursty_bursts) > 2.0 * mean(&random_bursts),
+            "bursty {} vs random {}",
+            mean(&bursty_bursts),
+            mean(&random_bursts)
+        );
+    }
+
+    #[test]
+    fn test_bandwidth_cap_queues_then_drops() {
+        // 1200-byte packets every millisecond are 9.6 Mbps into a 4.8 Mbps
+        // bottleneck: the queue grows by a millisecond per packet
+        let mut model = LinkModel::new(
+            LinkConditions {
+                latency: Duration::from_millis(20),
+                bandwidth_kbps: Some(4800),
+                queue_limit: Duration::from_millis(50),
+                ..Default::default()
+            },
+            1,
+        );
+        let start = Instant::now();
+        let fates = run(&mut model, start, 200, 1200, Duration::from_millis(1));
+
+        let first = fates[0].unwrap() - start;
+        assert_eq!(first, Duration::from_millis(22));
+        let delivered: Vec<_> = fates.iter().flatten().collect();
+        for pair in delivered.windows(2) {
+            assert_eq!(*pair[1] - *pair[0], Duration::from_millis(2));
+        }
+        // Half gets through once the queue is full
+        let stats = model.stats();
+        assert!(stats.overflowed > 70, "{:?}", stats);
+        assert_eq!(stats.delivered + stats.overflowed, 200);
+        assert!(
+            delivered
+                .iter()
+                .all(|&&due| due - start < Duration::from_millis(275))
+        );
+    }
+
+    #[test]
+    fn test_jitter_and_reordering() {
+        let base = LinkConditions {
+            latency: Duration::from_millis(40),
+            ..Default::default()
+        };
+        let mut steady = LinkModel::new(base.clone(), 3);
+        run(
+            &mut steady,
+            Instant::now(),
+            1000,
+            100,
+            Duration::from_millis(1),
+        );
+        assert_eq!(steady.stats().reordered, 0);
+
+        let mut jittery = LinkModel::new(
+            LinkConditions {
+                jitter: Duration::from_millis(10),
+                ..base.clone()
+            },
+            3,
+        );
+        let start = Instant::now();
+        let fates = run(&mut jittery, start, 1000, 100, Duration::from_millis(1));
+        for (i, due) in fates.iter().flatten().enumerate() {
+            let delay = *due - (start + Duration::from_millis(i as u64));
+            assert!(delay >= Duration::from_millis(30) && delay <= Duration::from_millis(50));
+        }
+        assert!(jittery.stats().reordered > 100);
+
+        let mut reordering = LinkModel::new(
+            LinkConditions {
+                reorder: 0.1,
+                ..base
+            },
+            3,
+        );
+        let fates = run(&mut reordering, start, 1000, 100, Duration::from_millis(1));
+        let held = fates
+            .windows(2)
+            .filter(|pair| pair[0].unwrap() > pair[1].unwrap())
+            .count();
+        assert!((50..150).contains(&held), "held back {}", held);
+        assert!(reordering.stats().reordered >= held as u64);
+    }
+
+    #[tokio::test]
+    async fn test_quic_over_lossy_link() {
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(25),
+            ..Default::default()
+        };
+        let (server, client, link) = connected_pair(conditions.clone()).await;
+        assert!(client.stats().unwrap().rtt >= Duration::from_millis(50));
+
+        link.set_direction(
+            Direction::ToClient,
+            LinkConditions {
+                loss: LossModel::Random(0.2),
+                ..conditions
+            },
+        );
+        // Large enough that QUIC sends each in a packet of its own
+        for i in 0..200u8 {
+            server.send_datagram(Bytes::from(vec![i; 1000])).unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let mut received = 0;
+        while let Ok(Ok(_)) =
+            tokio::time::timeout(Duration::from_millis(300), client.recv_datagram()).await
+        {
+            received += 1;
+        }
+        let stats = link.stats(Direction::ToClient);
+        assert!(stats.lost > 0);
+        assert!((120..190).contains(&received), "received {}", received);
+        assert!(received as u64 <= stats.delivered);
+    }
+
+    #[tokio::test]
+    async fn test_script_drives_congestion_controller() {
+        let (server, client, link) = connected_pair(LinkConditions {
+            latency: Duration::from_millis(5),
+            ..Default::default()
+        })
+        .await;
+        let initial = client.congestion().current_params().bitrate_kbps;
+
+        // The path turns into a slow satellite hop a second in
+        link.play(LinkScript::new().at(
+            Duration::from_secs(1),
+            LinkConditions {
+                latency: Duration::from_millis(150),
+                jitter: Duration::from_millis(20),
+                ..Default::default()
+            },
+        ));
+
+        let mut fastest = None::<u32>;
+        let started = tokio::time::Instant::now();
+        while started.elapsed() < Duration::from_secs(3) {
+            // Keep packets and acknowledgments flowing so RTT samples stay
+            // fresh
+            client.send_datagram(Bytes::from_static(b"ping")).unwrap();
+            let _ = server.send_datagram(Bytes::from_static(b"pong"));
+            tokio::time::sleep(Duration::from_millis(20)).await;
+
+            let rtt = client.stats().unwrap().rtt;
+            client.congestion().record_rtt(rtt);
+            let bitrate = client.congestion().current_params().bitrate_kbps;
+            if started.elapsed() < Duration::from_secs(1) {
+                fastest = Some(fastest.map_or(bitrate, |fastest| fastest.max(bitrate)));
+            }
+        }
+
+        assert!(client.stats().unwrap().rtt >= Duration::from_millis(250));
+        let fastest = fastest.unwrap();
+        assert!(fastest >= initial, "{} then {}", initial, fastest);
+        let bitrate = client.congestion().current_params().bitrate_kbps;
+        assert!(bitrate < fastest, "{} after {}", bitrate, fastest);
+        assert!(link.stats(Direction::ToServer).delivered > 100);
+    }
+}
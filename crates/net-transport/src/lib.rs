@@ -3,12 +3,30 @@
 //! Provides low-latency transport using QUIC datagrams for video
 //! and reliable streams for input/control messages.
 
+mod bandwidth;
 mod congestion;
+mod control;
 mod error;
+mod fec;
+#[cfg(any(test, feature = "impairment"))]
+mod impairment;
+mod pacer;
+mod packetizer;
+mod refinement;
+mod retransmit;
 mod transport;
 
+pub use bandwidth::*;
 pub use congestion::*;
+pub use control::*;
 pub use error::*;
+pub use fec::*;
+#[cfg(any(test, feature = "impairment"))]
+pub use impairment::*;
+pub use pacer::*;
+pub use packetizer::*;
+pub use refinement::*;
+pub use retransmit::*;
 pub use transport::*;
 
 /// Default QUIC port
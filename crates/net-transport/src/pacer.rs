@@ -0,0 +1,367 @@
+//! Send-side pacing of video datagrams
+//!
+//! Writing a whole frame to the socket at once sends a large keyframe as
+//! one burst of back-to-back datagrams, which overflows shallow router
+//! buffers. [`Pacer`] queues datagrams and releases them from a token
+//! bucket filled at a little above the estimated bandwidth, so a frame
+//! leaves spread over the frame interval instead. The time queued video
+//! needs to drain feeds back into the encoder bitrate.
+//!
+//! Only video datagrams are paced. Control messages travel on their own
+//! QUIC stream and input comes from the viewer, so neither ever waits
+//! behind a queued keyframe.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::MAX_DATAGRAM_SIZE;
+
+/// Pacing rate relative to the target bitrate, so an average frame goes
+/// out well within its interval
+const PACING_FACTOR: f64 = 1.5;
+
+/// Sending time the bucket may save up while idle
+const BURST_TIME: Duration = Duration::from_millis(5);
+
+/// Longest queued video is allowed to wait; past this the pacer speeds
+/// up to drain it in time
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(500);
+
+/// Queue delay from which the encoder bitrate is held back
+const PUSHBACK_START: Duration = Duration::from_millis(50);
+
+/// Smallest fraction of the target the encoder is held back to
+const MIN_PUSHBACK: f64 = 0.5;
+
+/// Order in which queued datagrams leave the [`Pacer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketPriority {
+    /// Resent video fragments, already late
+    Retransmission,
+    /// Fresh video fragments and their parity
+    Video,
+}
+
+/// A datagram waiting in the [`Pacer`]
+#[derive(Debug, Clone)]
+pub struct PacedDatagram {
+    pub data: Bytes,
+    /// Video fragment it carries as (frame id, fragment index), for
+    /// bandwidth estimation once it is actually sent
+    pub fragment: Option<(u64, u16)>,
+}
+
+/// Token-bucket pacer with priority queues
+pub struct Pacer {
+    /// Pacing rate in bytes per second
+    rate: f64,
+    /// Rate that drains everything queued so far within the delay limit;
+    /// kept until the queue empties
+    drain_floor: f64,
+    /// Bytes that may be sent now; negative after a datagram larger than
+    /// what was left
+    budget: f64,
+    last_refill: Option<Instant>,
+    retransmissions: VecDeque<PacedDatagram>,
+    video: VecDeque<PacedDatagram>,
+    /// Bytes queued
+    queued_bytes: usize,
+}
+
+impl Pacer {
+    /// Pace for a target bitrate of `target_kbps`
+    pub fn new(target_kbps: u32) -> Self {
+        let mut pacer = Self {
+            rate: 0.0,
+            drain_floor: 0.0,
+            budget: 0.0,
+            last_refill: None,
+            retransmissions: VecDeque::new(),
+            video: VecDeque::new(),
+            queued_bytes: 0,
+        };
+        pacer.set_target_bitrate(target_kbps);
+        pacer.budget = pacer.burst();
+        pacer
+    }
+
+    /// Follow a new target bitrate from the bandwidth estimate
+    pub fn set_target_bitrate(&mut self, target_kbps: u32) {
+        self.rate = target_kbps.max(1) as f64 * 1000.0 / 8.0 * PACING_FACTOR;
+    }
+
+    /// Queue `datagram` to be sent in turn
+    pub fn enqueue(&mut self, priority: PacketPriority, datagram: PacedDatagram) {
+        let queue = match priority {
+            PacketPriority::Retransmission => &mut self.retransmissions,
+            PacketPriority::Video => &mut self.video,
+        };
+        self.queued_bytes += datagram.data.len();
+        queue.push_back(datagram);
+        self.drain_floor = self
+            .drain_floor
+            .max(self.queued_bytes as f64 / MAX_QUEUE_DELAY.as_secs_f64());
+    }
+
+    /// Next datagram to send at `now`, if the budget allows one
+    ///
+    /// Call repeatedly until it returns `None`, then again at
+    /// [`next_send_time`](Self::next_send_time).
+    pub fn poll(&mut self, now: Instant) -> Option<PacedDatagram> {
+        self.budget = self.budget_at(now);
+        self.last_refill = Some(now);
+
+        if self.budget <= 0.0 {
+            return None;
+        }
+        let datagram = self
+            .retransmissions
+            .pop_front()
+            .or_else(|| self.video.pop_front())?;
+        self.queued_bytes -= datagram.data.len();
+        self.budget -= datagram.data.len() as f64;
+        if self.queued_bytes == 0 {
+            self.drain_floor = 0.0;
+        }
+        Some(datagram)
+    }
+
+    /// When [`poll`](Self::poll) next releases a datagram, or `None` with
+    /// nothing queued
+    pub fn next_send_time(&self, now: Instant) -> Option<Instant> {
+        if self.queued_bytes == 0 {
+            return None;
+        }
+        let budget = self.budget_at(now);
+        if budget > 0.0 {
+            return Some(now);
+        }
+        // Just past the moment the budget turns positive
+        let wait = (1.0 - budget) / self.drain_rate();
+        Some(now + Duration::from_secs_f64(wait))
+    }
+
+    /// Datagrams waiting, of any priority
+    pub fn len(&self) -> usize {
+        self.retransmissions.len() + self.video.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Time the queued datagrams need to drain at the current rate
+    pub fn queue_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.queued_bytes as f64 / self.drain_rate())
+    }
+
+    /// Encoder bitrate for `target_kbps`, held back while the queue is
+    /// long so the backlog drains instead of growing
+    ///
+    /// Full target up to 50ms of queued video, falling linearly to half
+    /// of it as the queue nears its 500ms limit.
+    pub fn pushback_bitrate(&self, target_kbps: u32) -> u32 {
+        let delay = self.queue_delay();
+        if delay <= PUSHBACK_START {
+            return target_kbps;
+        }
+        let excess = (delay - PUSHBACK_START).as_secs_f64()
+            / (MAX_QUEUE_DELAY - PUSHBACK_START).as_secs_f64();
+        let factor = (1.0 - excess * (1.0 - MIN_PUSHBACK)).max(MIN_PUSHBACK);
+        (target_kbps as f64 * factor).round() as u32
+    }
+
+    /// Pacing rate, raised when needed to drain the queue within its
+    /// delay limit
+    fn drain_rate(&self) -> f64 {
+        self.rate.max(self.drain_floor)
+    }
+
+    fn budget_at(&self, now: Instant) -> f64 {
+        let elapsed = self
+            .last_refill
+            .map_or(Duration::ZERO, |last| now.saturating_duration_since(last));
+        (self.budget + self.drain_rate() * elapsed.as_secs_f64()).min(self.burst())
+    }
+
+    /// Most the bucket holds: a few milliseconds of sending, and never
+    /// less than two full datagrams
+    fn burst(&self) -> f64 {
+        (self.rate * BURST_TIME.as_secs_f64()).max(2.0 * MAX_DATAGRAM_SIZE as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QuicTransport;
+    use shared_protocol::{DisplaySelection, SessionMessage};
+
+    fn datagram(frame_id: u64, fragment_index: u16, len: usize) -> PacedDatagram {
+        PacedDatagram {
+            data: Bytes::from(vec![0; len]),
+            fragment: Some((frame_id, fragment_index)),
+        }
+    }
+
+    /// Drive the pacer in 1ms steps until it empties; returns when each
+    /// datagram left, as an offset from `start`
+    fn drain(pacer: &mut Pacer, start: Instant) -> Vec<(Duration, PacedDatagram)> {
+        let mut sent = Vec::new();
+        let mut now = start;
+        while !pacer.is_empty() {
+            while let Some(datagram) = pacer.poll(now) {
+                sent.push((now - start, datagram));
+            }
+            now += Duration::from_millis(1);
+        }
+        sent
+    }
+
+    #[test]
+    fn test_keyframe_is_spread_at_pacing_rate() {
+        // A 200 KB keyframe at 8 Mbps: 12 Mbps pacing takes about 133ms
+        let mut pacer = Pacer::new(8000);
+        for index in 0..170 {
+            pacer.enqueue(PacketPriority::Video, datagram(1, index, 1200));
+        }
+        assert!(pacer.queue_delay() > Duration::from_millis(130));
+
+        let sent = drain(&mut pacer, Instant::now());
+        assert_eq!(sent.len(), 170);
+        let (last, _) = sent.last().unwrap();
+        assert!(
+            (Duration::from_millis(125)..Duration::from_millis(140)).contains(last),
+            "took {:?}",
+            last
+        );
+        // Never more than the burst allowance in any one millisecond
+        let most = sent
+            .chunk_by(|a, b| a.0 == b.0)
+            .map(|burst| burst.len())
+            .max()
+            .unwrap();
+        assert!(most <= 7, "{} datagrams in one millisecond", most);
+        // In order
+        let indices: Vec<_> = sent.iter().map(|(_, d)| d.fragment.unwrap().1).collect();
+        assert!(indices.is_sorted());
+    }
+
+    #[test]
+    fn test_priorities_preempt_queued_video() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(1000);
+        for index in 0..20 {
+            pacer.enqueue(PacketPriority::Video, datagram(2, index, 1200));
+        }
+        // Spend the initial burst
+        while pacer.poll(start).is_some() {}
+        assert_eq!(pacer.next_send_time(start).map(|at| at > start), Some(true));
+
+        pacer.enqueue(PacketPriority::Retransmission, datagram(1, 5, 1200));
+        assert!(pacer.poll(start).is_none());
+
+        // The resend leaves ahead of the rest of the frame
+        let sent = drain(&mut pacer, start);
+        assert_eq!(sent[0].1.fragment, Some((1, 5)));
+        assert!(sent[1..].iter().all(|(_, d)| d.fragment.unwrap().0 == 2));
+    }
+
+    /// Mirrors the host loop: the control reply leaves on its stream while
+    /// a keyframe is still being paced out
+    #[tokio::test]
+    async fn test_queued_keyframe_does_not_delay_control_reply() {
+        let host = QuicTransport::new_server("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let viewer = QuicTransport::new_client("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (accepted, connected) = tokio::join!(
+            host.accept(),
+            viewer.connect(host.local_addr().unwrap(), "entangle.local")
+        );
+        accepted.unwrap();
+        connected.unwrap();
+
+        // About half a second of keyframe at 1 Mbps
+        let mut pacer = Pacer::new(1000);
+        for index in 0..200 {
+            pacer.enqueue(PacketPriority::Video, datagram(1, index, 1000));
+        }
+        let queued = pacer.len();
+
+        let (mut viewer_tx, mut viewer_rx) = viewer.open_control().await.unwrap();
+        let asked_at = Instant::now();
+        viewer_tx.send(&SessionMessage::ListDisplays).await.unwrap();
+
+        let host_loop = async {
+            let (mut host_tx, mut host_rx) = host.accept_control().await.unwrap();
+            loop {
+                let next_send = pacer.next_send_time(Instant::now());
+                tokio::select! {
+                    _ = tokio::time::sleep_until(next_send.unwrap_or_else(Instant::now).into()),
+                        if next_send.is_some() =>
+                    {
+                        while let Some(datagram) = pacer.poll(Instant::now()) {
+                            host.send_datagram(datagram.data).unwrap();
+                        }
+                    }
+                    message = host_rx.recv() => {
+                        assert!(matches!(message.unwrap(), SessionMessage::ListDisplays));
+                        let reply = SessionMessage::Displays {
+                            displays: Vec::new(),
+                            active: DisplaySelection::All,
+                        };
+                        host_tx.send(&reply).await.unwrap();
+                        return pacer.len();
+                    }
+                }
+            }
+        };
+        let viewer_loop = async {
+            let reply = viewer_rx.recv().await.unwrap();
+            (reply, asked_at.elapsed())
+        };
+        let (still_queued, (reply, waited)) = tokio::join!(host_loop, viewer_loop);
+
+        assert!(matches!(reply, SessionMessage::Displays { .. }));
+        assert!(waited < Duration::from_millis(100), "waited {:?}", waited);
+        assert!(
+            still_queued > queued / 2,
+            "{} of {} left",
+            still_queued,
+            queued
+        );
+    }
+
+    #[test]
+    fn test_queue_drains_within_limit_and_pushes_back() {
+        // Far more video than the rate carries in the delay limit
+        let mut pacer = Pacer::new(500);
+        assert_eq!(pacer.pushback_bitrate(500), 500);
+        for index in 0..300 {
+            pacer.enqueue(PacketPriority::Video, datagram(1, index, 1200));
+        }
+        assert!(pacer.queue_delay() <= MAX_QUEUE_DELAY);
+        assert_eq!(pacer.pushback_bitrate(500), 250);
+
+        let sent = drain(&mut pacer, Instant::now());
+        let (last, _) = sent.last().unwrap();
+        assert!(*last <= MAX_QUEUE_DELAY, "took {:?}", last);
+
+        // A short queue leaves the target alone; a longer one trims it
+        let mut pacer = Pacer::new(8000);
+        for index in 0..40 {
+            pacer.enqueue(PacketPriority::Video, datagram(1, index, 1200));
+        }
+        assert_eq!(pacer.pushback_bitrate(8000), 8000);
+        for index in 40..200 {
+            pacer.enqueue(PacketPriority::Video, datagram(1, index, 1200));
+        }
+        let trimmed = pacer.pushback_bitrate(8000);
+        assert!((4000..8000).contains(&trimmed), "{}", trimmed);
+    }
+}
@@ -0,0 +1,694 @@
+//! Video frame packetization
+//!
+//! [`Packetizer`] splits an encoded frame into [`VideoPacket`]s that each
+//! fit one datagram. When the encoder reports slice boundaries, whole
+//! slices are packed into fragments and never split, so every fragment
+//! decodes on its own. [`FrameAssembler`] is the receiving side: it
+//! reassembles complete frames, rebuilds fragments lost in transit from
+//! [`FecEncoder`](crate::FecEncoder) parity and, for slice-aligned
+//! frames, hands on whatever arrived once the next frame shows the rest
+//! is lost.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use shared_protocol::{
+    DirtyRect, FecParity, FrameType, VideoAck, VideoCodec, VideoPacket, VideoPacketHeader,
+};
+
+use crate::fec::xor_into;
+use crate::{TransportError, TransportResult};
+
+/// Splits encoded frames into datagram-sized packets
+#[derive(Debug, Clone)]
+pub struct Packetizer {
+    max_payload: usize,
+}
+
+impl Packetizer {
+    /// Create a packetizer for datagrams of at most `max_datagram_size` bytes
+    pub fn new(max_datagram_size: usize) -> TransportResult<Self> {
+        let header_size = worst_case_header_size()?;
+        if max_datagram_size <= header_size {
+            return Err(TransportError::DatagramTooLarge {
+                size: header_size,
+                max: max_datagram_size,
+            });
+        }
+
+        Ok(Self {
+            max_payload: max_datagram_size - header_size,
+        })
+    }
+
+    /// Largest payload that fits one datagram alongside its header
+    ///
+    /// Slices no larger than this are never split across fragments.
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    /// Split one frame into packets
+    ///
+    /// `units` are contiguous byte ranges of `payload` that decode
+    /// independently, such as slices. Consecutive units are packed into
+    /// a fragment while they fit; a unit larger than a datagram is split
+    /// and the frame is then not marked `slice_aligned`. With no units
+    /// the payload is treated as a single unit.
+    pub fn packetize(
+        &self,
+        header: &VideoPacketHeader,
+        payload: &[u8],
+        units: &[Range<usize>],
+    ) -> TransportResult<Vec<VideoPacket>> {
+        let whole = 0..payload.len();
+        let units = if units.is_empty() {
+            std::slice::from_ref(&whole)
+        } else {
+            units
+        };
+
+        let mut fragments = Vec::new();
+        let mut aligned = true;
+        let mut current: Option<Range<usize>> = None;
+        for unit in units {
+            if unit.len() > self.max_payload {
+                fragments.extend(current.take());
+                aligned = false;
+                let mut start = unit.start;
+                while start < unit.end {
+                    let end = (start + self.max_payload).min(unit.end);
+                    fragments.push(start..end);
+                    start = end;
+                }
+                continue;
+            }
+
+            match &mut current {
+                Some(fragment) if unit.end - fragment.start <= self.max_payload => {
+                    fragment.end = unit.end;
+                }
+                _ => {
+                    fragments.extend(current.replace(unit.clone()));
+                }
+            }
+        }
+        fragments.extend(current);
+
+        let total_fragments = u16::try_from(fragments.len()).map_err(|_| {
+            TransportError::Send(format!(
+                "Frame {} needs {} fragments",
+                header.frame_id,
+                fragments.len()
+            ))
+        })?;
+
+        Ok(fragments
+            .into_iter()
+            .enumerate()
+            .map(|(index, range)| VideoPacket {
+                header: VideoPacketHeader {
+                    fragment_index: index as u16,
+                    total_fragments,
+                    slice_aligned: aligned,
+                    ..header.clone()
+                },
+                payload: payload[range].to_vec(),
+            })
+            .collect())
+    }
+}
+
+/// Serialized size of a header with every optional field present
+fn worst_case_header_size() -> TransportResult<usize> {
+    let packet = VideoPacket {
+        header: VideoPacketHeader {
+            frame_id: 0,
+            fragment_index: 0,
+            total_fragments: 0,
+            timestamp_us: 0,
+            frame_type: FrameType::Key,
+            codec: VideoCodec::H264,
+            width: 0,
+            height: 0,
+            dirty_rect: Some(DirtyRect::new(0, 0, 0, 0)),
+            resolution_changed: false,
+            slice_aligned: false,
+            temporal_id: 0,
+            reference_frame_id: 0,
+            parity: Some(FecParity {
+                first_fragment: 0,
+                fragment_count: 0,
+                length_xor: 0,
+            }),
+        },
+        payload: Vec::new(),
+    };
+    packet
+        .to_bytes()
+        .map(|bytes| bytes.len())
+        .map_err(|e| TransportError::Send(e.to_string()))
+}
+
+/// A frame handed on by the [`FrameAssembler`]
+#[derive(Debug)]
+pub struct AssembledFrame {
+    pub packet: VideoPacket,
+    /// Whether every fragment arrived
+    pub complete: bool,
+}
+
+/// Reassembles fragmented video frames
+pub struct FrameAssembler {
+    frames: BTreeMap<u64, FrameAssembly>,
+    max_frames: usize,
+    max_age: Duration,
+    /// How long a frame missing fragments holds back the frames after it,
+    /// waiting for the fragments to be retransmitted
+    retransmit_wait: Duration,
+    /// Newest frame a fragment arrived for
+    newest: Option<u64>,
+    /// Newest frame handed on incomplete; its stragglers are dropped
+    partial_up_to: Option<u64>,
+    /// Frames recently handed on complete, so parity or retransmissions
+    /// arriving after them don't start a new assembly
+    delivered: VecDeque<u64>,
+}
+
+struct FrameAssembly {
+    header: VideoPacketHeader,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    /// Parity packets whose group still misses more than one fragment
+    parity: Vec<(FecParity, Vec<u8>)>,
+    last_update: Instant,
+    /// When a fragment of a newer frame first arrived, showing that the
+    /// missing fragments were lost rather than still on their way
+    superseded_at: Option<Instant>,
+    /// Missing fragments were reported for retransmission
+    nacked: bool,
+}
+
+impl FrameAssembly {
+    fn new(mut header: VideoPacketHeader) -> Self {
+        header.parity = None;
+        Self {
+            fragments: vec![None; header.total_fragments.max(1) as usize],
+            header,
+            received: 0,
+            parity: Vec::new(),
+            last_update: Instant::now(),
+            superseded_at: None,
+            nacked: false,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received == self.fragments.len()
+    }
+
+    /// Rebuild fragments from parity whose group misses exactly one
+    ///
+    /// Each rebuilt fragment may complete another group, so this repeats
+    /// until no parity helps.
+    fn recover(&mut self) {
+        loop {
+            let mut recovered = false;
+            self.parity.retain(|(info, payload)| {
+                let group = info.first_fragment as usize
+                    ..(info.first_fragment as usize + info.fragment_count as usize)
+                        .min(self.fragments.len());
+                let mut missing = group.clone().filter(|&i| self.fragments[i].is_none());
+                let (Some(lost), None) = (missing.next(), missing.next()) else {
+                    // Keep parity only while its group is incomplete
+                    return group.clone().any(|i| self.fragments[i].is_none());
+                };
+
+                let mut data = payload.clone();
+                let mut length = info.length_xor;
+                for fragment in group.filter_map(|i| self.fragments[i].as_ref()) {
+                    xor_into(&mut data, fragment);
+                    length ^= fragment.len() as u16;
+                }
+                data.truncate(length as usize);
+                self.fragments[lost] = Some(data);
+                self.received += 1;
+                recovered = true;
+                false
+            });
+            if !recovered {
+                break;
+            }
+        }
+    }
+
+    /// Concatenate the fragments received so far
+    fn into_frame(self) -> AssembledFrame {
+        AssembledFrame {
+            complete: self.is_complete(),
+            packet: VideoPacket {
+                header: self.header,
+                payload: self.fragments.into_iter().flatten().flatten().collect(),
+            },
+        }
+    }
+}
+
+impl FrameAssembler {
+    /// Keep at most `max_frames` incomplete frames, each for up to `max_age`
+    pub fn new(max_frames: usize, max_age: Duration) -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            max_frames,
+            max_age,
+            retransmit_wait: Duration::ZERO,
+            newest: None,
+            partial_up_to: None,
+            delivered: VecDeque::with_capacity(max_frames),
+        }
+    }
+
+    /// Wait up to `wait` for retransmissions of lost fragments
+    ///
+    /// Once a newer frame shows a frame is missing fragments, it is
+    /// reported by [`nacks`](Self::nacks) and holds back the frames after
+    /// it for `wait`, so frames are still handed on in order if the
+    /// fragments arrive. With no wait, the default, nothing is held back
+    /// or reported.
+    pub fn set_retransmit_wait(&mut self, wait: Duration) {
+        self.retransmit_wait = wait;
+    }
+
+    /// Add a received fragment; returns the frames now ready, oldest first
+    ///
+    /// Once a newer frame's fragment arrives and any retransmit wait has
+    /// passed, older slice-aligned frames are handed on with whatever
+    /// fragments they have; older frames that aren't slice aligned keep
+    /// waiting until they complete or expire. Parity packets rebuild the
+    /// fragment their group misses, if only one.
+    pub fn push(&mut self, mut packet: VideoPacket) -> Vec<AssembledFrame> {
+        let frame_id = packet.header.frame_id;
+        if self.partial_up_to.is_some_and(|id| frame_id <= id) || self.delivered.contains(&frame_id)
+        {
+            return Vec::new();
+        }
+
+        // Parity over a single fragment is a copy of it
+        if let Some(info) = packet.header.parity
+            && info.fragment_count == 1
+        {
+            packet.header.parity = None;
+            packet.header.fragment_index = info.first_fragment;
+            packet.payload.truncate(info.length_xor as usize);
+        }
+
+        self.evict_old();
+
+        let total = packet.header.total_fragments.max(1) as usize;
+        let entry = self
+            .frames
+            .entry(frame_id)
+            .or_insert_with(|| FrameAssembly::new(packet.header.clone()));
+        if entry.fragments.len() != total {
+            *entry = FrameAssembly::new(packet.header.clone());
+        }
+
+        let idx = packet.header.fragment_index as usize;
+        if let Some(info) = packet.header.parity {
+            entry.parity.push((info, packet.payload));
+            entry.last_update = Instant::now();
+        } else if idx < total && entry.fragments[idx].is_none() {
+            entry.fragments[idx] = Some(packet.payload);
+            entry.received += 1;
+            entry.last_update = Instant::now();
+        }
+        entry.recover();
+
+        let newest = *self
+            .newest
+            .insert(self.newest.map_or(frame_id, |id| id.max(frame_id)));
+        let now = Instant::now();
+        for frame in self.frames.range_mut(..newest).map(|(_, frame)| frame) {
+            frame.superseded_at.get_or_insert(now);
+        }
+
+        self.release()
+    }
+
+    /// Hand on frames whose retransmit wait ran out without a packet
+    /// arriving to release them
+    pub fn poll(&mut self) -> Vec<AssembledFrame> {
+        self.evict_old();
+        self.release()
+    }
+
+    /// Missing fragments to ask the host for, once per frame
+    ///
+    /// Reports frames a newer frame showed to be missing fragments while
+    /// they are still within the retransmit wait, one [`VideoAck`] per 64
+    /// fragments with a gap. The caller fills in its timing fields.
+    pub fn nacks(&mut self) -> Vec<VideoAck> {
+        if self.retransmit_wait.is_zero() {
+            return Vec::new();
+        }
+
+        let occupancy = self.frames.len().min(u8::MAX as usize) as u8;
+        let mut acks = Vec::new();
+        for (&frame_id, frame) in &mut self.frames {
+            let waiting = frame
+                .superseded_at
+                .is_some_and(|at| at.elapsed() < self.retransmit_wait);
+            if frame.nacked || !waiting || frame.is_complete() {
+                continue;
+            }
+            frame.nacked = true;
+
+            for (window, fragments) in frame.fragments.chunks(u64::BITS as usize).enumerate() {
+                if fragments.iter().all(Option::is_some) {
+                    continue;
+                }
+                acks.push(VideoAck {
+                    frame_id,
+                    first_fragment: (window * u64::BITS as usize) as u16,
+                    received_fragments: fragments
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, fragment)| fragment.is_some())
+                        .fold(0, |received, (index, _)| received | 1 << index),
+                    rtt_us: 0,
+                    decode_time_us: 0,
+                    render_time_us: 0,
+                    buffer_occupancy: occupancy,
+                });
+            }
+        }
+        acks
+    }
+
+    fn mark_delivered(&mut self, frame_id: u64) {
+        if self.delivered.len() >= self.max_frames.max(1) {
+            self.delivered.pop_front();
+        }
+        self.delivered.push_back(frame_id);
+    }
+
+    /// Hand on frames in order up to the oldest one still worth waiting for
+    ///
+    /// Complete frames go on; a frame missing fragments blocks those after
+    /// it until a newer frame has arrived and the retransmit wait passed.
+    /// Then a slice-aligned frame goes on as it is, and any other stays in
+    /// case the rest arrives before it expires.
+    fn release(&mut self) -> Vec<AssembledFrame> {
+        let mut ready = Vec::new();
+        let ids: Vec<u64> = self.frames.keys().copied().collect();
+        for frame_id in ids {
+            let frame = &self.frames[&frame_id];
+            if !frame.is_complete() {
+                let waiting = frame
+                    .superseded_at
+                    .is_none_or(|at| at.elapsed() < self.retransmit_wait);
+                if waiting {
+                    break;
+                }
+                if !frame.header.slice_aligned || frame.received == 0 {
+                    continue;
+                }
+                self.partial_up_to = Some(frame_id);
+            } else {
+                self.mark_delivered(frame_id);
+            }
+            ready.extend(self.frames.remove(&frame_id).map(FrameAssembly::into_frame));
+        }
+        ready
+    }
+
+    fn evict_old(&mut self) {
+        let cutoff = Instant::now() - self.max_age;
+        self.frames.retain(|_, frame| frame.last_update >= cutoff);
+
+        if self.frames.len() > self.max_frames {
+            let mut entries: Vec<(u64, Instant)> = self
+                .frames
+                .iter()
+                .map(|(id, frame)| (*id, frame.last_update))
+                .collect();
+            entries.sort_by_key(|(_, t)| *t);
+            let to_remove = entries.len().saturating_sub(self.max_frames);
+            for (frame_id, _) in entries.into_iter().take(to_remove) {
+                self.frames.remove(&frame_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FecEncoder;
+
+    fn header(frame_id: u64) -> VideoPacketHeader {
+        VideoPacketHeader {
+            frame_id,
+            fragment_index: 0,
+            total_fragments: 1,
+            timestamp_us: 0,
+            frame_type: FrameType::Delta,
+            codec: VideoCodec::H264,
+            width: 64,
+            height: 64,
+            dirty_rect: None,
+            resolution_changed: false,
+            slice_aligned: false,
+            temporal_id: 0,
+            reference_frame_id: 0,
+            parity: None,
+        }
+    }
+
+    /// Payload of `sizes` slices, each filled with its index
+    fn slices(sizes: &[usize]) -> (Vec<u8>, Vec<Range<usize>>) {
+        let mut payload = Vec::new();
+        let mut units = Vec::new();
+        for (i, &size) in sizes.iter().enumerate() {
+            let start = payload.len();
+            payload.extend(std::iter::repeat_n(i as u8, size));
+            units.push(start..payload.len());
+        }
+        (payload, units)
+    }
+
+    #[test]
+    fn test_packets_fit_datagrams() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let payload = vec![7u8; 5000];
+        let packets = packetizer.packetize(&header(1), &payload, &[]).unwrap();
+
+        assert_eq!(packets.len(), 5000usize.div_ceil(packetizer.max_payload()));
+        for packet in &packets {
+            assert!(packet.to_bytes().unwrap().len() <= 1200);
+            assert!(!packet.header.slice_aligned);
+        }
+    }
+
+    #[test]
+    fn test_slices_are_packed_whole() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let max = packetizer.max_payload();
+        let (payload, units) = slices(&[max / 2, max / 2, max / 3, max, 10]);
+        let packets = packetizer.packetize(&header(1), &payload, &units).unwrap();
+
+        let sizes: Vec<_> = packets.iter().map(|p| p.payload.len()).collect();
+        assert_eq!(sizes, [max / 2 * 2, max / 3, max, 10]);
+        assert!(packets.iter().all(|p| p.header.slice_aligned));
+        assert!(packets.iter().all(|p| p.header.total_fragments == 4));
+    }
+
+    #[test]
+    fn test_oversized_slice_is_split() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let max = packetizer.max_payload();
+        let (payload, units) = slices(&[100, max + 1]);
+        let packets = packetizer.packetize(&header(1), &payload, &units).unwrap();
+
+        let sizes: Vec<_> = packets.iter().map(|p| p.payload.len()).collect();
+        assert_eq!(sizes, [100, max, 1]);
+        assert!(packets.iter().all(|p| !p.header.slice_aligned));
+    }
+
+    #[test]
+    fn test_assembler_rebuilds_complete_frame() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let (payload, units) = slices(&[900, 900, 900]);
+        let mut packets = packetizer.packetize(&header(1), &payload, &units).unwrap();
+        packets.reverse();
+
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        let mut ready = Vec::new();
+        for packet in packets {
+            ready.extend(assembler.push(packet));
+        }
+        assert_eq!(ready.len(), 1);
+        assert!(ready[0].complete);
+        assert_eq!(ready[0].packet.payload, payload);
+    }
+
+    #[test]
+    fn test_assembler_releases_partial_aligned_frame() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let (payload, units) = slices(&[900, 900, 900]);
+        let mut packets = packetizer.packetize(&header(1), &payload, &units).unwrap();
+        let lost = packets.remove(1);
+
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        for packet in packets {
+            assert!(assembler.push(packet).is_empty());
+        }
+
+        // The next frame shows slice 1 is lost; slices 0 and 2 go on
+        let ready = assembler.push(VideoPacket {
+            header: header(2),
+            payload: vec![9],
+        });
+        assert_eq!(ready.len(), 2);
+        assert_eq!(ready[0].packet.header.frame_id, 1);
+        assert!(!ready[0].complete);
+        assert_eq!(
+            ready[0].packet.payload,
+            [&payload[..900], &payload[1800..]].concat()
+        );
+        assert_eq!(ready[1].packet.header.frame_id, 2);
+        assert!(ready[1].complete);
+
+        // The straggler arrives too late and is dropped
+        assert!(assembler.push(lost).is_empty());
+    }
+
+    #[test]
+    fn test_assembler_rebuilds_lost_fragments_from_parity() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let payload: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+        let mut packets = packetizer.packetize(&header(1), &payload, &[]).unwrap();
+        let mut fec = FecEncoder::new();
+        fec.set_loss_rate(0.3);
+        let parity = fec.protect(&packets);
+
+        // The short last fragment is lost; its copy rebuilds it
+        packets.pop();
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        let mut ready = Vec::new();
+        for packet in packets.into_iter().chain(parity.clone()) {
+            ready.extend(assembler.push(packet));
+        }
+        assert_eq!(ready.len(), 1);
+        assert!(ready[0].complete);
+        assert_eq!(ready[0].packet.payload, payload);
+        assert!(ready[0].packet.header.parity.is_none());
+
+        // Parity arriving after the frame completed is dropped
+        for packet in parity {
+            assert!(assembler.push(packet).is_empty());
+        }
+    }
+
+    #[test]
+    fn test_parity_rebuilds_single_fragment_frame() {
+        let packet = VideoPacket {
+            header: header(1),
+            payload: vec![5; 300],
+        };
+        let mut fec = FecEncoder::new();
+        fec.set_loss_rate(0.01);
+        let parity = fec.protect(std::slice::from_ref(&packet));
+        assert_eq!(parity.len(), 1);
+
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        let ready = assembler.push(parity[0].clone());
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].packet.payload, packet.payload);
+
+        // The original arriving late is not handed on twice
+        assert!(assembler.push(packet).is_empty());
+    }
+
+    #[test]
+    fn test_frames_wait_in_order_for_retransmission() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let (payload, units) = slices(&[900, 900, 900]);
+        let mut packets = packetizer.packetize(&header(1), &payload, &units).unwrap();
+        let lost = packets.remove(1);
+
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        assembler.set_retransmit_wait(Duration::from_secs(1));
+        for packet in packets {
+            assert!(assembler.push(packet).is_empty());
+        }
+        assert!(assembler.nacks().is_empty());
+
+        // The next frame shows the gap, and waits behind it
+        let next = VideoPacket {
+            header: header(2),
+            payload: vec![9],
+        };
+        assert!(assembler.push(next).is_empty());
+        let nacks = assembler.nacks();
+        assert_eq!(nacks.len(), 1);
+        assert_eq!((nacks[0].frame_id, nacks[0].first_fragment), (1, 0));
+        assert_eq!(nacks[0].received_fragments, 0b101);
+        assert!(assembler.nacks().is_empty());
+
+        let ready = assembler.push(lost);
+        let ids: Vec<_> = ready.iter().map(|f| f.packet.header.frame_id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert!(ready.iter().all(|f| f.complete));
+        assert_eq!(ready[0].packet.payload, payload);
+    }
+
+    #[test]
+    fn test_retransmit_wait_runs_out() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let (payload, units) = slices(&[900, 900, 900]);
+        let mut packets = packetizer.packetize(&header(1), &payload, &units).unwrap();
+        packets.remove(1);
+
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        assembler.set_retransmit_wait(Duration::from_millis(1));
+        for packet in packets {
+            assert!(assembler.push(packet).is_empty());
+        }
+        assembler.push(VideoPacket {
+            header: header(2),
+            payload: vec![9],
+        });
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(assembler.nacks().is_empty());
+        let ready = assembler.poll();
+        assert_eq!(ready.len(), 2);
+        assert!(!ready[0].complete);
+        assert_eq!(ready[1].packet.header.frame_id, 2);
+    }
+
+    #[test]
+    fn test_assembler_holds_unaligned_partial_frame() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let payload = vec![3u8; 3000];
+        let mut packets = packetizer.packetize(&header(1), &payload, &[]).unwrap();
+        let late = packets.remove(0);
+
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        for packet in packets {
+            assert!(assembler.push(packet).is_empty());
+        }
+        let ready = assembler.push(VideoPacket {
+            header: header(2),
+            payload: vec![9],
+        });
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].packet.header.frame_id, 2);
+
+        let ready = assembler.push(late);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].packet.payload, payload);
+    }
+}
@@ -0,0 +1,118 @@
+//! Refinement tile stream
+//!
+//! The host sends [`RefinementPacket`]s on a single QUIC unidirectional
+//! stream, framed like the control channel. Keeping them on one ordered
+//! stream means an invalidation can never overtake the tile it drops.
+
+use quinn::{RecvStream, SendStream};
+use shared_protocol::RefinementPacket;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::control::{read_frame, write_frame};
+use crate::{TransportError, TransportResult};
+
+/// Largest refinement message accepted from the peer
+pub const MAX_REFINEMENT_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Host half of the refinement stream
+pub struct RefinementSender<W = SendStream> {
+    stream: W,
+}
+
+/// Viewer half of the refinement stream
+pub struct RefinementReceiver<R = RecvStream> {
+    stream: R,
+}
+
+impl<W: AsyncWrite + Unpin> RefinementSender<W> {
+    pub fn new(stream: W) -> Self {
+        Self { stream }
+    }
+
+    /// Send one packet
+    pub async fn send(&mut self, packet: &RefinementPacket) -> TransportResult<()> {
+        let payload = packet
+            .to_bytes()
+            .map_err(|e| TransportError::Send(e.to_string()))?;
+        write_frame(
+            &mut self.stream,
+            &payload,
+            MAX_REFINEMENT_MESSAGE_SIZE,
+            "Refinement",
+        )
+        .await
+    }
+}
+
+impl<R: AsyncRead + Unpin> RefinementReceiver<R> {
+    pub fn new(stream: R) -> Self {
+        Self { stream }
+    }
+
+    /// Receive the next packet
+    ///
+    /// Returns `ConnectionClosed` once the host finishes the stream.
+    pub async fn recv(&mut self) -> TransportResult<RefinementPacket> {
+        let payload =
+            read_frame(&mut self.stream, MAX_REFINEMENT_MESSAGE_SIZE, "Refinement").await?;
+        RefinementPacket::from_bytes(&payload).map_err(|e| TransportError::Receive(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared_protocol::DirtyRect;
+
+    #[tokio::test]
+    async fn test_packets_round_trip_in_order() {
+        let (host, viewer) = tokio::io::duplex(256);
+        let mut sender = RefinementSender::new(host);
+        let mut receiver = RefinementReceiver::new(viewer);
+        let rect = DirtyRect::new(128, 0, 128, 64);
+
+        let send = async {
+            let tile = RefinementPacket::Tile {
+                rect,
+                png: vec![0x89; 1000],
+            };
+            sender.send(&tile).await.unwrap();
+            sender
+                .send(&RefinementPacket::Invalidate(vec![rect]))
+                .await
+                .unwrap();
+            drop(sender);
+        };
+        let recv = async {
+            let tile = receiver.recv().await.unwrap();
+            let invalidate = receiver.recv().await.unwrap();
+            let closed = receiver.recv().await;
+            (tile, invalidate, closed)
+        };
+        let (_, (tile, invalidate, closed)) = tokio::join!(send, recv);
+
+        match tile {
+            RefinementPacket::Tile { rect: got, png } => {
+                assert_eq!(got, rect);
+                assert_eq!(png.len(), 1000);
+            }
+            other => panic!("unexpected packet: {:?}", other),
+        }
+        assert!(matches!(invalidate, RefinementPacket::Invalidate(rects) if rects == vec![rect]));
+        assert!(matches!(closed, Err(TransportError::ConnectionClosed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_tile_is_rejected() {
+        let (host, _viewer) = tokio::io::duplex(256);
+        let mut sender = RefinementSender::new(host);
+        let tile = RefinementPacket::Tile {
+            rect: DirtyRect::new(0, 0, 1, 1),
+            png: vec![0; MAX_REFINEMENT_MESSAGE_SIZE],
+        };
+        assert!(matches!(
+            sender.send(&tile).await,
+            Err(TransportError::Send(_))
+        ));
+    }
+}
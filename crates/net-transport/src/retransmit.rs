@@ -0,0 +1,286 @@
+//! Selective retransmission of lost video fragments
+//!
+//! The host keeps the datagrams of its last few frames in a
+//! [`RetransmitBuffer`]. When the viewer reports missing fragments in a
+//! [`VideoAck`], only those are sent again, and only while the frame left
+//! recently enough that the resend can still arrive in time; past that
+//! the host falls back to loss recovery. A frame counts as sent when its
+//! datagrams leave the pacer, not when they're queued, so a keyframe
+//! spread over a few hundred milliseconds can still be repaired.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use shared_protocol::VideoAck;
+
+/// Recently sent frames, kept for retransmission
+pub struct RetransmitBuffer {
+    frames: VecDeque<SentFrame>,
+    capacity: usize,
+}
+
+struct SentFrame {
+    frame_id: u64,
+    /// When the latest of its datagrams left, once any has
+    sent_at: Option<Instant>,
+    /// Serialized datagram of each fragment, by fragment index
+    fragments: Vec<Bytes>,
+}
+
+impl RetransmitBuffer {
+    /// Keep the datagrams of the newest `capacity` frames
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity.max(1)),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Remember a frame's fragment datagrams, queued to be sent
+    pub fn insert(&mut self, frame_id: u64, fragments: Vec<Bytes>) {
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(SentFrame {
+            frame_id,
+            sent_at: None,
+            fragments,
+        });
+    }
+
+    /// Record that a datagram of `frame_id` went out at `now`
+    pub fn on_sent(&mut self, frame_id: u64, now: Instant) {
+        if let Some(frame) = self
+            .frames
+            .iter_mut()
+            .rev()
+            .find(|frame| frame.frame_id == frame_id)
+        {
+            frame.sent_at = Some(now);
+        }
+    }
+
+    /// Datagrams to resend for the fragments `ack` reports missing, with
+    /// their fragment indices
+    ///
+    /// Returns `None` once the frame was sent more than `budget` before
+    /// `now`, or is no longer buffered; a resend would arrive too late to
+    /// help and the caller should recover from the loss instead.
+    pub fn nack(
+        &self,
+        ack: &VideoAck,
+        budget: Duration,
+        now: Instant,
+    ) -> Option<Vec<(u16, Bytes)>> {
+        let frame = self
+            .frames
+            .iter()
+            .find(|frame| frame.frame_id == ack.frame_id)?;
+        // Still waiting in the pacer, so nothing of it can be lost yet
+        let Some(sent_at) = frame.sent_at else {
+            return Some(Vec::new());
+        };
+        if now.saturating_duration_since(sent_at) > budget {
+            return None;
+        }
+
+        let first = ack.first_fragment as usize;
+        Some(
+            frame
+                .fragments
+                .iter()
+                .enumerate()
+                .skip(first)
+                .take(u64::BITS as usize)
+                .filter(|&(index, _)| ack.received_fragments & (1 << (index - first)) == 0)
+                .map(|(index, datagram)| (index as u16, datagram.clone()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared_protocol::{FrameType, VideoCodec, VideoPacket, VideoPacketHeader};
+
+    use super::*;
+    use crate::{FrameAssembler, PacedDatagram, Pacer, PacketPriority, Packetizer};
+
+    fn ack(frame_id: u64, first_fragment: u16, received_fragments: u64) -> VideoAck {
+        VideoAck {
+            frame_id,
+            first_fragment,
+            received_fragments,
+            rtt_us: 0,
+            decode_time_us: 0,
+            render_time_us: 0,
+            buffer_occupancy: 0,
+        }
+    }
+
+    fn fragments(frame_id: u64, count: usize) -> Vec<Bytes> {
+        (0..count)
+            .map(|index| Bytes::from(vec![frame_id as u8, index as u8]))
+            .collect()
+    }
+
+    #[test]
+    fn test_only_missing_fragments_are_resent() {
+        let sent = fragments(1, 100);
+        let mut buffer = RetransmitBuffer::new(8);
+        buffer.insert(1, sent.clone());
+        let now = Instant::now();
+        buffer.on_sent(1, now);
+        let budget = Duration::from_secs(1);
+
+        let resent = buffer.nack(&ack(1, 0, !0b1010), budget, now).unwrap();
+        assert_eq!(resent, [(1, sent[1].clone()), (3, sent[3].clone())]);
+
+        // The second window ends at the last fragment; bits past it are
+        // clear but ignored
+        let resent = buffer
+            .nack(&ack(1, 64, (1 << 35) - 1), budget, now)
+            .unwrap();
+        assert_eq!(resent, [(99, sent[99].clone())]);
+    }
+
+    #[test]
+    fn test_late_or_unknown_frames_are_not_resent() {
+        let mut buffer = RetransmitBuffer::new(2);
+        let now = Instant::now();
+        for frame_id in 1..=3 {
+            buffer.insert(frame_id, fragments(frame_id, 4));
+            buffer.on_sent(frame_id, now);
+        }
+
+        // Evicted by newer frames
+        let budget = Duration::from_secs(1);
+        assert!(buffer.nack(&ack(1, 0, 0), budget, now).is_none());
+        assert!(buffer.nack(&ack(3, 0, 0), budget, now).is_some());
+
+        // Sent longer ago than the budget
+        let later = now + Duration::from_millis(5);
+        assert!(
+            buffer
+                .nack(&ack(3, 0, 0), Duration::from_millis(1), later)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_budget_counts_from_when_the_pacer_sends() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(1000);
+        let mut buffer = RetransmitBuffer::new(8);
+
+        // A keyframe ahead holds the next frame in the pacer for a while
+        for (frame_id, count) in [(1u64, 40usize), (2, 4)] {
+            let datagrams: Vec<_> = (0..count)
+                .map(|_| Bytes::from(vec![frame_id as u8; 1200]))
+                .collect();
+            for (index, data) in datagrams.iter().enumerate() {
+                pacer.enqueue(
+                    PacketPriority::Video,
+                    PacedDatagram {
+                        data: data.clone(),
+                        fragment: Some((frame_id, index as u16)),
+                    },
+                );
+            }
+            buffer.insert(frame_id, datagrams);
+        }
+
+        // Before it leaves there's nothing to resend, but no reason to
+        // give up either
+        assert_eq!(
+            buffer.nack(&ack(2, 0, !0b1), Duration::ZERO, start),
+            Some(Vec::new())
+        );
+
+        let mut now = start;
+        while !pacer.is_empty() {
+            while let Some(datagram) = pacer.poll(now) {
+                if let Some((frame_id, _)) = datagram.fragment {
+                    buffer.on_sent(frame_id, now);
+                }
+            }
+            now += Duration::from_millis(1);
+        }
+
+        // The viewer notices the loss with the next frame and reports it
+        // half a round trip later, long after frame 2 was queued
+        let rtt = Duration::from_millis(10);
+        let frame_interval = Duration::from_millis(33);
+        let budget = rtt + frame_interval;
+        let nack_at = now + frame_interval + rtt / 2;
+        assert!(nack_at - start > budget);
+
+        let resent = buffer.nack(&ack(2, 0, !0b1), budget, nack_at).unwrap();
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].0, 0);
+    }
+
+    #[test]
+    fn test_nacked_fragments_complete_frames() {
+        let packetizer = Packetizer::new(1200).unwrap();
+        let mut buffer = RetransmitBuffer::new(8);
+        let mut assembler = FrameAssembler::new(16, Duration::from_secs(2));
+        assembler.set_retransmit_wait(Duration::from_secs(1));
+
+        let mut frames = Vec::new();
+        for frame_id in 1..=3u64 {
+            let header = VideoPacketHeader {
+                frame_id,
+                fragment_index: 0,
+                total_fragments: 1,
+                timestamp_us: 0,
+                frame_type: FrameType::Delta,
+                codec: VideoCodec::H264,
+                width: 64,
+                height: 64,
+                dirty_rect: None,
+                resolution_changed: false,
+                slice_aligned: false,
+                temporal_id: 0,
+                reference_frame_id: 0,
+                parity: None,
+            };
+            let payload = vec![frame_id as u8; 100 * 1000];
+            let datagrams: Vec<_> = packetizer
+                .packetize(&header, &payload, &[])
+                .unwrap()
+                .iter()
+                .map(|packet| packet.to_bytes().unwrap())
+                .collect();
+            buffer.insert(frame_id, datagrams.clone());
+            buffer.on_sent(frame_id, Instant::now());
+
+            // Every seventh datagram of the first two frames is lost
+            for (index, datagram) in datagrams.iter().enumerate() {
+                if frame_id < 3 && index % 7 == 3 {
+                    continue;
+                }
+                let packet = VideoPacket::from_bytes(datagram).unwrap();
+                frames.extend(assembler.push(packet));
+            }
+        }
+        assert!(frames.is_empty());
+
+        let nacks = assembler.nacks();
+        assert_eq!(nacks.len(), 4);
+        for ack in nacks {
+            let resent = buffer
+                .nack(&ack, Duration::from_secs(1), Instant::now())
+                .unwrap();
+            assert!(!resent.is_empty());
+            for (_, datagram) in resent {
+                frames.extend(assembler.push(VideoPacket::from_bytes(&datagram).unwrap()));
+            }
+        }
+
+        let ids: Vec<_> = frames.iter().map(|f| f.packet.header.frame_id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert!(frames.iter().all(|f| f.complete));
+    }
+}
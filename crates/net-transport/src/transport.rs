@@ -12,7 +12,10 @@ use quinn::{
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
 
-use crate::{CongestionController, MAX_DATAGRAM_SIZE, TransportError, TransportResult};
+use crate::{
+    CongestionController, ControlReceiver, ControlSender, MAX_DATAGRAM_SIZE, RefinementReceiver,
+    RefinementSender, TransportError, TransportResult, control_channel,
+};
 
 /// QUIC transport for Entangle
 pub struct QuicTransport {
@@ -209,6 +212,14 @@ impl QuicTransport {
         Ok(())
     }
 
+    /// Bytes of datagrams that can be queued before older ones are dropped
+    pub fn datagram_send_buffer_space(&self) -> usize {
+        self.connection
+            .read()
+            .as_ref()
+            .map_or(0, |connection| connection.datagram_send_buffer_space())
+    }
+
     /// Receive a datagram
     pub async fn recv_datagram(&self) -> TransportResult<Bytes> {
         let mut rx_guard = self.datagram_rx.lock().await;
@@ -225,12 +236,17 @@ impl QuicTransport {
             .ok_or(TransportError::Receive("Channel closed".to_string()))
     }
 
+    /// Current connection, without holding the lock across an await
+    fn connection(&self) -> TransportResult<Connection> {
+        self.connection
+            .read()
+            .clone()
+            .ok_or(TransportError::NotConnected)
+    }
+
     /// Open a new bidirectional stream (reliable)
     pub async fn open_bi_stream(&self) -> TransportResult<(SendStream, RecvStream)> {
-        let conn = self.connection.read();
-        let connection = conn.as_ref().ok_or(TransportError::NotConnected)?;
-
-        connection
+        self.connection()?
             .open_bi()
             .await
             .map_err(|e| TransportError::Stream(e.to_string()))
@@ -238,10 +254,7 @@ impl QuicTransport {
 
     /// Open a new unidirectional stream (reliable)
     pub async fn open_uni_stream(&self) -> TransportResult<SendStream> {
-        let conn = self.connection.read();
-        let connection = conn.as_ref().ok_or(TransportError::NotConnected)?;
-
-        connection
+        self.connection()?
             .open_uni()
             .await
             .map_err(|e| TransportError::Stream(e.to_string()))
@@ -249,16 +262,47 @@ impl QuicTransport {
 
     /// Accept an incoming bidirectional stream
     pub async fn accept_bi_stream(&self) -> TransportResult<(SendStream, RecvStream)> {
-        let conn = self.connection.read();
-        let connection = conn.as_ref().ok_or(TransportError::NotConnected)?.clone();
-        drop(conn);
-
-        connection
+        self.connection()?
             .accept_bi()
             .await
             .map_err(|e| TransportError::Stream(e.to_string()))
     }
 
+    /// Accept an incoming unidirectional stream
+    pub async fn accept_uni_stream(&self) -> TransportResult<RecvStream> {
+        self.connection()?
+            .accept_uni()
+            .await
+            .map_err(|e| TransportError::Stream(e.to_string()))
+    }
+
+    /// Open the control channel (viewer side)
+    pub async fn open_control(&self) -> TransportResult<(ControlSender, ControlReceiver)> {
+        let (send, recv) = self.open_bi_stream().await?;
+        Ok(control_channel(send, recv))
+    }
+
+    /// Accept the control channel opened by the peer (host side)
+    ///
+    /// QUIC only announces a stream once data is written on it, so this
+    /// resolves when the viewer sends its first control message.
+    pub async fn accept_control(&self) -> TransportResult<(ControlSender, ControlReceiver)> {
+        let (send, recv) = self.accept_bi_stream().await?;
+        Ok(control_channel(send, recv))
+    }
+
+    /// Open the refinement tile stream (host side)
+    pub async fn open_refinement(&self) -> TransportResult<RefinementSender> {
+        Ok(RefinementSender::new(self.open_uni_stream().await?))
+    }
+
+    /// Accept the refinement tile stream opened by the host (viewer side)
+    ///
+    /// Resolves once the host sends its first tile.
+    pub async fn accept_refinement(&self) -> TransportResult<RefinementReceiver> {
+        Ok(RefinementReceiver::new(self.accept_uni_stream().await?))
+    }
+
     /// Get the congestion controller
     pub fn congestion(&self) -> &CongestionController {
         &self.congestion
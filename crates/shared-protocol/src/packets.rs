@@ -11,6 +11,8 @@ pub enum PacketType {
     VideoFrame = 0x01,
     /// Video frame acknowledgment with RTT info
     VideoAck = 0x02,
+    /// Lossless tile of a static region (reliable stream)
+    Refinement = 0x03,
     /// Input event (reliable stream)
     Input = 0x10,
     /// Clipboard data (reliable stream)
@@ -45,6 +47,8 @@ pub enum FrameType {
     Key,
     /// Delta frame (P-frame) - depends on previous frames
     Delta,
+    /// Delta frame that only references a frame the receiver acknowledged
+    Recovery,
     /// Bidirectional frame (B-frame) - depends on past and future
     Bidirectional,
 }
@@ -98,6 +102,36 @@ pub struct VideoPacketHeader {
     pub height: u32,
     /// Dirty region (if partial update)
     pub dirty_rect: Option<DirtyRect>,
+    /// Set on the keyframe that starts a new resolution; the viewer
+    /// must reconfigure its decoder before decoding it
+    pub resolution_changed: bool,
+    /// Every fragment of this frame holds whole slices, so the fragments
+    /// that arrive can be decoded even if others are lost
+    pub slice_aligned: bool,
+    /// Temporal layer, 0 for the base layer. Frames are only referenced
+    /// by higher layers, so the sender may drop upper-layer frames
+    pub temporal_id: u8,
+    /// Frame this one is predicted from; unused for key and recovery
+    /// frames
+    pub reference_frame_id: u64,
+    /// Set on forward error correction packets, whose payload is parity
+    /// over some of the frame's fragments rather than frame data
+    pub parity: Option<FecParity>,
+}
+
+/// Fragments covered by a parity packet
+///
+/// The payload is the XOR of the covered fragments' payloads, each
+/// zero-padded to the longest, so any one of them can be rebuilt from
+/// the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FecParity {
+    /// First covered fragment
+    pub first_fragment: u16,
+    /// Number of consecutive fragments covered
+    pub fragment_count: u16,
+    /// XOR of the covered fragments' payload lengths
+    pub length_xor: u16,
 }
 
 /// Complete video packet with payload
@@ -126,7 +160,10 @@ impl VideoPacket {
 pub struct VideoAck {
     /// Frame ID being acknowledged
     pub frame_id: u64,
-    /// Fragments successfully received (bitmask)
+    /// Fragment that bit 0 of `received_fragments` stands for
+    pub first_fragment: u16,
+    /// Fragments successfully received (bitmask); bits past the frame's
+    /// last fragment are ignored
     pub received_fragments: u64,
     /// Measured RTT in microseconds
     pub rtt_us: u64,
@@ -138,6 +175,51 @@ pub struct VideoAck {
     pub buffer_occupancy: u8,
 }
 
+/// Arrival times of video datagrams, reported by the viewer so the host
+/// can estimate the available bandwidth
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransportFeedback {
+    /// Datagrams in the order they arrived
+    pub arrivals: Vec<DatagramArrival>,
+}
+
+/// When one video datagram reached the viewer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatagramArrival {
+    pub frame_id: u64,
+    pub fragment_index: u16,
+    /// Viewer clock in microseconds; only differences are meaningful
+    pub arrival_us: u64,
+}
+
+/// Lossless refinement of static screen regions, drawn over the video
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum RefinementPacket {
+    /// PNG-encoded pixels for a region that stopped changing
+    Tile {
+        rect: DirtyRect,
+        #[serde(with = "serde_bytes")]
+        png: Vec<u8>,
+    },
+    /// Previously sent tiles that changed; the viewer drops them
+    Invalidate(Vec<DirtyRect>),
+    /// Every previously sent tile is stale
+    Clear,
+}
+
+impl RefinementPacket {
+    /// Serialize to bytes for transmission
+    pub fn to_bytes(&self) -> Result<Bytes, bincode::Error> {
+        let encoded = bincode::serialize(self)?;
+        Ok(Bytes::from(encoded))
+    }
+
+    /// Deserialize from received bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(data)
+    }
+}
+
 /// Clipboard content type
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClipboardContent {
@@ -3,6 +3,8 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use crate::{TransportFeedback, VideoAck};
+
 /// Unique peer identifier
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct PeerId(pub Uuid);
@@ -123,6 +125,28 @@ impl Default for SessionConfig {
     }
 }
 
+/// A host display as advertised to the viewer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteDisplay {
+    pub id: u32,
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Position in the host's virtual screen
+    pub x: i32,
+    pub y: i32,
+    pub is_primary: bool,
+}
+
+/// Which of the host's displays is streamed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisplaySelection {
+    /// A single display by id
+    Display(u32),
+    /// All displays stitched by their virtual-screen layout
+    All,
+}
+
 /// Session control messages
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SessionMessage {
@@ -143,6 +167,15 @@ pub enum SessionMessage {
     Configure(SessionConfig),
     /// Request keyframe
     RequestKeyframe,
+    /// Viewer decoded every frame up to this one
+    FrameAck { frame_id: u64 },
+    /// Viewer lost frames after `last_good`; recover without a keyframe
+    ReferenceLost { last_good: u64 },
+    /// Viewer is missing the fragments whose bits are clear; resend them
+    /// if still useful
+    Nack(VideoAck),
+    /// When recent video datagrams arrived, for bandwidth estimation
+    TransportFeedback(TransportFeedback),
     /// Pause streaming
     Pause,
     /// Resume streaming
@@ -158,6 +191,15 @@ pub enum SessionMessage {
         target_bitrate_kbps: u32,
         target_fps: u8,
     },
+    /// Ask the host which displays it can stream
+    ListDisplays,
+    /// Host displays and the current selection
+    Displays {
+        displays: Vec<RemoteDisplay>,
+        active: DisplaySelection,
+    },
+    /// Switch the streamed display
+    SelectDisplay(DisplaySelection),
 }
 
 impl SessionMessage {